@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use evdev::uinput::VirtualDevice;
+use evdev::{AttributeSet, KeyCode, KeyEvent};
+use tokio::process::Command;
+
+use crate::audio;
+use crate::config::{ActionConfig, AudioCommand, AudioDevice, MediaCommand};
+use crate::gesture::{Gesture, Wants};
+use crate::hass;
+use crate::media::{self, Request};
+use crate::obs;
+
+// Something that happens when a control is used. Implementations must return
+// quickly, anything long running belongs on the tokio runtime.
+pub trait Action: fmt::Debug + Send + Sync {
+    fn run(&self) -> Result<(), ActionError>;
+}
+
+#[derive(Debug)]
+pub enum ActionError {
+    // Child process couldn't be started
+    Spawn {
+        program: String,
+        source: std::io::Error,
+    },
+
+    // Virtual keyboard couldn't be created or written to
+    Uinput(std::io::Error),
+}
+
+impl fmt::Display for ActionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActionError::Spawn { program, source } => {
+                write!(f, "failed to run '{}': {}", program, source)
+            }
+            ActionError::Uinput(e) => write!(f, "virtual keyboard: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ActionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ActionError::Spawn { source, .. } => Some(source),
+            ActionError::Uinput(e) => Some(e),
+        }
+    }
+}
+
+// Starts a program directly, without going through a shell
+#[derive(Debug)]
+pub struct Spawn {
+    pub program: String,
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub cwd: Option<PathBuf>,
+}
+
+impl Action for Spawn {
+    fn run(&self) -> Result<(), ActionError> {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args).envs(&self.env);
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        spawn(&self.program, command)
+    }
+}
+
+// Runs a snippet through `sh -c`
+#[derive(Debug)]
+pub struct Shell {
+    pub command: String,
+}
+
+impl Action for Shell {
+    fn run(&self) -> Result<(), ActionError> {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(&self.command);
+        spawn(&self.command, command)
+    }
+}
+
+// Opens a URL or file with the desktop's default handler
+#[derive(Debug)]
+pub struct Open {
+    pub target: String,
+}
+
+impl Action for Open {
+    fn run(&self) -> Result<(), ActionError> {
+        let mut command = Command::new("xdg-open");
+        command.arg(&self.target);
+        spawn("xdg-open", command)
+    }
+}
+
+// Presses every key of the chord in order, then releases them in reverse
+#[derive(Debug)]
+pub struct KeyChord {
+    keys: Vec<KeyCode>,
+}
+
+impl KeyChord {
+    pub fn new(keys: Vec<KeyCode>) -> Result<KeyChord, ActionError> {
+        // Create the virtual keyboard up front so the desktop has picked it up
+        // by the time the first chord is sent
+        virtual_keyboard()?;
+        Ok(KeyChord { keys })
+    }
+}
+
+impl Action for KeyChord {
+    fn run(&self) -> Result<(), ActionError> {
+        let press: Vec<_> = self
+            .keys
+            .iter()
+            .map(|key| *KeyEvent::new(*key, 1))
+            .collect();
+        let release: Vec<_> = self
+            .keys
+            .iter()
+            .rev()
+            .map(|key| *KeyEvent::new(*key, 0))
+            .collect();
+
+        // A chord that panicked halfway leaves the keyboard itself perfectly usable
+        let mut keyboard = virtual_keyboard()?
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        keyboard.emit(&press).map_err(ActionError::Uinput)?;
+        keyboard.emit(&release).map_err(ActionError::Uinput)
+    }
+}
+
+// Tells the media player to play, pause or skip, whichever player media.rs follows
+#[derive(Debug)]
+pub struct Media {
+    pub command: MediaCommand,
+}
+
+impl Action for Media {
+    fn run(&self) -> Result<(), ActionError> {
+        media::send(Request::Command(self.command));
+        Ok(())
+    }
+}
+
+// Mutes or steps the default sink or source through audio.rs
+#[derive(Debug)]
+pub struct Audio {
+    pub device: AudioDevice,
+    pub command: AudioCommand,
+}
+
+impl Action for Audio {
+    fn run(&self) -> Result<(), ActionError> {
+        audio::send(audio::Request::Command(self.device, self.command));
+        Ok(())
+    }
+}
+
+// Switches scenes, shows and hides sources or records and streams in OBS
+#[derive(Debug)]
+pub struct Obs {
+    pub request: obs::Request,
+}
+
+impl Action for Obs {
+    fn run(&self) -> Result<(), ActionError> {
+        obs::send(self.request.clone());
+        Ok(())
+    }
+}
+
+// Calls a service in Home Assistant
+#[derive(Debug)]
+pub struct Hass {
+    pub request: hass::Request,
+}
+
+impl Action for Hass {
+    fn run(&self) -> Result<(), ActionError> {
+        hass::send(self.request.clone());
+        Ok(())
+    }
+}
+
+// Page changes need the deck's page stack, so they're handed back to the
+// device loop instead of running as an Action
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Navigation {
+    Open(String),
+    Back,
+    Home,
+}
+
+#[derive(Clone, Debug)]
+pub enum Binding {
+    Run(Arc<dyn Action>),
+    Navigate(Navigation),
+}
+
+impl Binding {
+    // Runs the action, or hands back the page change for the caller
+    pub fn run(&self) -> Result<Option<Navigation>, ActionError> {
+        match self {
+            Binding::Run(action) => action.run().map(|_| None),
+            Binding::Navigate(navigation) => Ok(Some(navigation.clone())),
+        }
+    }
+}
+
+pub fn build(config: &ActionConfig) -> Result<Binding, ActionError> {
+    let action: Arc<dyn Action> = match config {
+        ActionConfig::Shell { command } => Arc::new(Shell {
+            command: command.clone(),
+        }),
+        ActionConfig::Spawn {
+            program,
+            args,
+            env,
+            cwd,
+        } => Arc::new(Spawn {
+            program: program.clone(),
+            args: args.clone(),
+            env: env.clone(),
+            cwd: cwd.clone(),
+        }),
+        ActionConfig::Open { target } => Arc::new(Open {
+            target: target.clone(),
+        }),
+        ActionConfig::Keys { chord } => Arc::new(KeyChord::new(chord.clone())?),
+        ActionConfig::Media { command } => Arc::new(Media { command: *command }),
+        ActionConfig::Audio { device, command } => Arc::new(Audio {
+            device: *device,
+            command: *command,
+        }),
+        ActionConfig::ObsScene { scene } => Arc::new(Obs {
+            request: obs::Request::Scene(scene.clone()),
+        }),
+        ActionConfig::ObsSource { source, scene } => Arc::new(Obs {
+            request: obs::Request::Source {
+                source: source.clone(),
+                scene: scene.clone(),
+            },
+        }),
+        ActionConfig::Obs { command } => Arc::new(Obs {
+            request: obs::Request::Command(*command),
+        }),
+        ActionConfig::Hass {
+            service,
+            entity,
+            data,
+        } => Arc::new(Hass {
+            request: hass::Request::Call {
+                service: service.clone(),
+                entity: entity.clone(),
+                data: data.clone(),
+            },
+        }),
+        ActionConfig::Page { page } => {
+            return Ok(Binding::Navigate(Navigation::Open(page.clone())));
+        }
+        ActionConfig::Back => return Ok(Binding::Navigate(Navigation::Back)),
+        ActionConfig::Home => return Ok(Binding::Navigate(Navigation::Home)),
+    };
+    Ok(Binding::Run(action))
+}
+
+// The action with `{value}` replaced wherever it takes text, for actions
+// that pass a dial's value on
+pub fn substitute(config: &ActionConfig, value: &str) -> ActionConfig {
+    let fill = |text: &String| text.replace("{value}", value);
+    match config {
+        ActionConfig::Shell { command } => ActionConfig::Shell {
+            command: fill(command),
+        },
+        ActionConfig::Spawn {
+            program,
+            args,
+            env,
+            cwd,
+        } => ActionConfig::Spawn {
+            program: program.clone(),
+            args: args.iter().map(fill).collect(),
+            env: env.iter().map(|(k, v)| (k.clone(), fill(v))).collect(),
+            cwd: cwd.clone(),
+        },
+        ActionConfig::Open { target } => ActionConfig::Open {
+            target: fill(target),
+        },
+        ActionConfig::Hass {
+            service,
+            entity,
+            data,
+        } => ActionConfig::Hass {
+            service: service.clone(),
+            entity: entity.clone(),
+            data: data
+                .iter()
+                .map(|(k, v)| {
+                    // A field that's just the value takes it as a number
+                    let v = match v.as_str() {
+                        Some("{value}") => value
+                            .parse::<f64>()
+                            .map_or_else(|_| value.into(), serde_json::Value::from),
+                        Some(text) => text.replace("{value}", value).into(),
+                        None => v.clone(),
+                    };
+                    (k.clone(), v)
+                })
+                .collect(),
+        },
+        other => other.clone(),
+    }
+}
+
+// Parses chords like "ctrl+shift+t" or "KEY_LEFTMETA+KEY_ENTER"
+pub fn parse_chord(chord: &str) -> Result<Vec<KeyCode>, String> {
+    chord
+        .split('+')
+        .map(|name| {
+            let name = name.trim();
+            let code = match name.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => "KEY_LEFTCTRL".to_string(),
+                "shift" => "KEY_LEFTSHIFT".to_string(),
+                "alt" => "KEY_LEFTALT".to_string(),
+                "altgr" => "KEY_RIGHTALT".to_string(),
+                "super" | "meta" | "win" => "KEY_LEFTMETA".to_string(),
+                "return" => "KEY_ENTER".to_string(),
+                "escape" => "KEY_ESC".to_string(),
+                _ if name.starts_with("KEY_") => name.to_string(),
+                lower => format!("KEY_{}", lower.to_ascii_uppercase()),
+            };
+            KeyCode::from_str(&code).map_err(|_| format!("unknown key '{}' in chord", name))
+        })
+        .collect()
+}
+
+// Spawns the child and reaps it on the runtime so it never turns into a zombie
+fn spawn(program: &str, mut command: Command) -> Result<(), ActionError> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .spawn()
+        .map_err(|source| ActionError::Spawn {
+            program: program.to_string(),
+            source,
+        })?;
+
+    let program = program.to_string();
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if !status.success() => eprintln!("'{}' exited with {}", program, status),
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to wait for '{}': {}", program, e),
+        }
+    });
+
+    Ok(())
+}
+
+// One virtual keyboard shared by every chord action
+fn virtual_keyboard() -> Result<&'static Mutex<VirtualDevice>, ActionError> {
+    static KEYBOARD: OnceLock<Mutex<VirtualDevice>> = OnceLock::new();
+
+    if let Some(keyboard) = KEYBOARD.get() {
+        return Ok(keyboard);
+    }
+
+    let mut keys = AttributeSet::<KeyCode>::new();
+    for code in KeyCode::KEY_ESC.code()..=KeyCode::KEY_MICMUTE.code() {
+        keys.insert(KeyCode::new(code));
+    }
+
+    let device = VirtualDevice::builder()
+        .and_then(|builder| builder.name("rust-streamdeck").with_keys(&keys))
+        .and_then(|builder| builder.build())
+        .map_err(ActionError::Uinput)?;
+
+    Ok(KEYBOARD.get_or_init(|| Mutex::new(device)))
+}
+
+// Actions bound to the different ways a control can be used
+#[derive(Clone, Debug, Default)]
+pub struct Bindings {
+    pub press: Option<Binding>,
+    pub release: Option<Binding>,
+    pub long_press: Option<Binding>,
+    pub double_press: Option<Binding>,
+}
+
+impl Bindings {
+    pub fn new(
+        press: &Option<ActionConfig>,
+        release: &Option<ActionConfig>,
+        long_press: &Option<ActionConfig>,
+        double_press: &Option<ActionConfig>,
+    ) -> Result<Bindings, ActionError> {
+        let build = |config: &Option<ActionConfig>| config.as_ref().map(build).transpose();
+        Ok(Bindings {
+            press: build(press)?,
+            release: build(release)?,
+            long_press: build(long_press)?,
+            double_press: build(double_press)?,
+        })
+    }
+
+    pub fn wants(&self) -> Wants {
+        Wants {
+            long_press: self.long_press.is_some(),
+            double_press: self.double_press.is_some(),
+        }
+    }
+
+    // Runs whatever is bound to the gesture, returning any page change for the caller
+    pub fn run(&self, gesture: Gesture) -> Result<Option<Navigation>, ActionError> {
+        let binding = match gesture {
+            Gesture::Press => &self.press,
+            Gesture::Release => &self.release,
+            Gesture::LongPress => &self.long_press,
+            Gesture::DoublePress => &self.double_press,
+        };
+        match binding {
+            Some(binding) => binding.run(),
+            None => Ok(None),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.press.is_none()
+            && self.release.is_none()
+            && self.long_press.is_none()
+            && self.double_press.is_none()
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, DynamicImage, Frames, ImageFormat, ImageReader, ImageResult};
+use tokio::time::Instant;
+
+// Frames asking for less than this get it instead, like browsers do, since
+// plenty of GIFs in the wild say 0 and mean "fast"
+const MIN_DELAY: Duration = Duration::from_millis(20);
+const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+// Frames due this close together go out in the same flush
+const BATCH_WINDOW: Duration = Duration::from_millis(5);
+
+pub struct Frame {
+    pub image: DynamicImage,
+    pub delay: Duration,
+}
+
+// Every frame of an image file, a still image being an animation of one frame
+pub struct Animation {
+    frames: Vec<Frame>,
+    // How many times it plays through, `None` for forever
+    plays: Option<u32>,
+}
+
+impl Animation {
+    // Decodes every frame of GIFs, APNGs and animated WebPs, anything else
+    // the `image` crate can open becomes a single frame
+    pub fn load(path: &Path) -> ImageResult<Animation> {
+        Animation::decode(&std::fs::read(path)?)
+    }
+
+    // The same for an image that's already in memory
+    pub fn decode(data: &[u8]) -> ImageResult<Animation> {
+        let format = ImageReader::new(Cursor::new(data))
+            .with_guessed_format()?
+            .format();
+
+        let frames = match format {
+            Some(ImageFormat::Gif) => Some(GifDecoder::new(Cursor::new(data))?.into_frames()),
+            Some(ImageFormat::Png) => {
+                let decoder = PngDecoder::new(Cursor::new(data))?;
+                match decoder.is_apng()? {
+                    true => Some(decoder.apng()?.into_frames()),
+                    false => None,
+                }
+            }
+            Some(ImageFormat::WebP) => {
+                let decoder = WebPDecoder::new(Cursor::new(data))?;
+                match decoder.has_animation() {
+                    true => Some(decoder.into_frames()),
+                    false => None,
+                }
+            }
+            _ => None,
+        };
+
+        let frames = match frames {
+            Some(frames) => collect(frames)?,
+            None => vec![],
+        };
+        if frames.len() < 2 {
+            let image = image::load_from_memory(data)?;
+            return Ok(Animation {
+                frames: vec![Frame {
+                    image,
+                    delay: Duration::ZERO,
+                }],
+                plays: Some(1),
+            });
+        }
+
+        Ok(Animation {
+            frames,
+            plays: plays(format, data),
+        })
+    }
+
+    pub fn first(&self) -> &DynamicImage {
+        &self.frames[0].image
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+}
+
+fn collect(frames: Frames) -> ImageResult<Vec<Frame>> {
+    frames
+        .map(|frame| {
+            let frame = frame?;
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            let delay = match Duration::from_micros(numer as u64 * 1000 / denom.max(1) as u64) {
+                d if d < MIN_DELAY => DEFAULT_DELAY,
+                d => d,
+            };
+            Ok(Frame {
+                image: DynamicImage::ImageRgba8(frame.into_buffer()),
+                delay,
+            })
+        })
+        .collect()
+}
+
+// Loop count stored in the file, which the decoders don't pass on. 0 means
+// forever in all three formats.
+fn plays(format: Option<ImageFormat>, data: &[u8]) -> Option<u32> {
+    let find = |tag: &[u8]| data.windows(tag.len()).position(|w| w == tag);
+    let forever = |n: u32| (n != 0).then_some(n);
+
+    match format {
+        // The NETSCAPE2.0 extension counts repeats after the first play,
+        // without it a GIF plays once
+        Some(ImageFormat::Gif) => {
+            let Some(at) = find(b"NETSCAPE2.0") else {
+                return Some(1);
+            };
+            match data.get(at + 11..at + 15) {
+                Some([3, 1, lo, hi]) => {
+                    forever(u16::from_le_bytes([*lo, *hi]) as u32).map(|n| n + 1)
+                }
+                _ => Some(1),
+            }
+        }
+        // acTL holds the frame count and then the number of plays
+        Some(ImageFormat::Png) => {
+            let at = find(b"acTL")?;
+            let bytes = data.get(at + 8..at + 12)?;
+            forever(u32::from_be_bytes(bytes.try_into().ok()?))
+        }
+        // ANIM holds its size, the background color and then the loop count
+        Some(ImageFormat::WebP) => {
+            let at = find(b"ANIM")?;
+            let bytes = data.get(at + 12..at + 14)?;
+            forever(u16::from_le_bytes(bytes.try_into().ok()?) as u32)
+        }
+        _ => None,
+    }
+}
+
+// Something on the deck that can play an animation
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum Target {
+    // Keys keep their place in the animation per page
+    Key { page: String, key: u8 },
+    Zone(usize),
+    Screensaver,
+}
+
+struct Playback {
+    animation: Arc<Animation>,
+    frame: usize,
+    played: u32,
+    // When the next frame is up, `None` once the last play is over
+    due: Option<Instant>,
+    // Time left on the current frame while the page is hidden
+    paused: Option<Duration>,
+}
+
+// Where every animation on the deck is at
+#[derive(Default)]
+pub struct Player {
+    playing: HashMap<Target, Playback>,
+}
+
+impl Player {
+    // The frame the target shows right now. Starts the animation the first
+    // time it's asked for and restarts it when the target switched images.
+    pub fn frame(&mut self, target: Target, animation: &Arc<Animation>) -> DynamicImage {
+        let playback = self
+            .playing
+            .entry(target)
+            .and_modify(|p| {
+                if !Arc::ptr_eq(&p.animation, animation) {
+                    *p = Playback::new(animation);
+                }
+            })
+            .or_insert_with(|| Playback::new(animation));
+        playback.animation.frames[playback.frame].image.clone()
+    }
+
+    pub fn stop(&mut self, target: &Target) {
+        self.playing.remove(target);
+    }
+
+    // Holds the keys of every other page where they are and picks the keys
+    // of `page` up where they left off
+    pub fn show_page(&mut self, page: &str) {
+        let now = Instant::now();
+        for (target, playback) in &mut self.playing {
+            let Target::Key { page: of, .. } = target else {
+                continue;
+            };
+            if of == page {
+                if let Some(left) = playback.paused.take() {
+                    playback.due = Some(now + left);
+                }
+            } else if let Some(due) = playback.due.take() {
+                playback.paused = Some(due.saturating_duration_since(now));
+            }
+        }
+    }
+
+    // When the earliest frame is due
+    pub fn next_due(&self) -> Option<Instant> {
+        self.playing.values().filter_map(|p| p.due).min()
+    }
+
+    // Moves every animation whose next frame is due on to it, returning the
+    // targets that need drawing again
+    pub fn advance(&mut self, now: Instant) -> Vec<Target> {
+        let horizon = now + BATCH_WINDOW;
+        self.playing
+            .iter_mut()
+            .filter(|(_, p)| p.due.is_some_and(|due| due <= horizon))
+            .map(|(target, p)| {
+                p.step(now);
+                target.clone()
+            })
+            .collect()
+    }
+}
+
+impl Playback {
+    fn new(animation: &Arc<Animation>) -> Playback {
+        Playback {
+            animation: animation.clone(),
+            frame: 0,
+            played: 0,
+            due: Some(Instant::now() + animation.frames[0].delay),
+            paused: None,
+        }
+    }
+
+    fn step(&mut self, now: Instant) {
+        let frames = &self.animation.frames;
+        let mut next = self.frame + 1;
+        if next == frames.len() {
+            self.played += 1;
+            if self
+                .animation
+                .plays
+                .is_some_and(|plays| self.played >= plays)
+            {
+                // Finished plays stay on their last frame
+                self.due = None;
+                return;
+            }
+            next = 0;
+        }
+        self.frame = next;
+
+        // Late ticks eat into the next frame's time so the animation keeps
+        // its pace, unless it fell behind by more than a whole frame
+        let due = self.due.unwrap_or(now) + frames[next].delay;
+        self.due = Some(if due < now {
+            now + frames[next].delay
+        } else {
+            due
+        });
+    }
+}
@@ -0,0 +1,521 @@
+use std::io;
+use std::process::Stdio;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{Instant, sleep_until};
+
+use crate::config::{AudioBackend, AudioCommand, AudioDevice};
+
+// How far `raise` and `lower` move the volume, in percent
+const STEP: f64 = 5.0;
+// Wait before listening again after the sound server went away
+const RETRY: Duration = Duration::from_secs(5);
+
+// Volume and mute of one device
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Level {
+    // Percent, above 100 when the device is boosted
+    pub volume: f64,
+    pub muted: bool,
+}
+
+impl Level {
+    // "45%", or "Muted"
+    pub fn text(&self) -> String {
+        match self.muted {
+            true => "Muted".to_string(),
+            false => format!("{}%", self.volume.round()),
+        }
+    }
+}
+
+// Levels of the default sink and source, `None` while they can't be read
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Levels {
+    pub sink: Option<Level>,
+    pub source: Option<Level>,
+}
+
+impl Levels {
+    pub fn get(&self, device: AudioDevice) -> Option<Level> {
+        match device {
+            AudioDevice::Sink => self.sink,
+            AudioDevice::Source => self.source,
+        }
+    }
+}
+
+// Something for the sound server to do
+#[derive(Debug)]
+pub enum Request {
+    // Whatever of the level isn't that way already, from a dial
+    Set(AudioDevice, Level),
+    Command(AudioDevice, AudioCommand),
+}
+
+// What the audio task needs from a sound server, so the same keys and dials
+// can drive PulseAudio or PipeWire as well as the fake
+pub trait Backend: Send + 'static {
+    fn level(&mut self, device: AudioDevice) -> impl Future<Output = io::Result<Level>> + Send;
+
+    fn set_volume(
+        &mut self,
+        device: AudioDevice,
+        percent: f64,
+    ) -> impl Future<Output = io::Result<()>> + Send;
+
+    fn set_muted(
+        &mut self,
+        device: AudioDevice,
+        muted: bool,
+    ) -> impl Future<Output = io::Result<()>> + Send;
+
+    // Waits until a level may have changed outside this program
+    fn changed(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+// The task talking to the sound server, started by whatever needs it first
+struct Audio {
+    requests: mpsc::UnboundedSender<Request>,
+    levels: watch::Receiver<Levels>,
+}
+
+static BACKEND: OnceLock<AudioBackend> = OnceLock::new();
+
+// Which sound server to talk to, from the config the program started with
+pub fn configure(backend: AudioBackend) {
+    let _ = BACKEND.set(backend);
+}
+
+// Levels as of the last time the sound server said
+pub fn watch() -> watch::Receiver<Levels> {
+    audio().levels.clone()
+}
+
+// Hands the request to the sound server, failures are logged there
+pub fn send(request: Request) {
+    if let Err(e) = audio().requests.send(request) {
+        eprintln!("Sound server unreachable, dropped {:?}", e.0);
+    }
+}
+
+fn audio() -> &'static Audio {
+    static AUDIO: OnceLock<Audio> = OnceLock::new();
+
+    AUDIO.get_or_init(|| {
+        let (requests, rx) = mpsc::unbounded_channel();
+        let (tx, levels) = watch::channel(Levels::default());
+        match BACKEND.get().copied().unwrap_or_default() {
+            AudioBackend::Pactl => tokio::spawn(serve(Pactl::default(), rx, tx)),
+            AudioBackend::Fake => tokio::spawn(serve(Fake::default(), rx, tx)),
+        };
+        Audio { requests, levels }
+    })
+}
+
+// Reads the levels again after every request and outside change, until the
+// program exits
+async fn serve<B: Backend>(
+    mut backend: B,
+    mut requests: mpsc::UnboundedReceiver<Request>,
+    levels: watch::Sender<Levels>,
+) {
+    let mut last_error = None;
+    // Listening again right away would spin while the sound server is gone
+    let mut listen_after = None;
+    let mut listen_error = None;
+    loop {
+        let mut now = Levels::default();
+        let mut error = None;
+        for device in [AudioDevice::Sink, AudioDevice::Source] {
+            match backend.level(device).await {
+                Ok(level) => match device {
+                    AudioDevice::Sink => now.sink = Some(level),
+                    AudioDevice::Source => now.source = Some(level),
+                },
+                Err(e) => error = Some(format!("{:?}: {}", device, e)),
+            }
+        }
+        if error.is_some() && error != last_error {
+            eprintln!("Sound server: {}", error.as_deref().unwrap_or_default());
+        }
+        last_error = error;
+        levels.send_if_modified(|shown| {
+            let changed = *shown != now;
+            *shown = now;
+            changed
+        });
+
+        tokio::select! {
+            request = requests.recv() => {
+                let Some(request) = request else { return };
+                let mut batch = vec![request];
+                while let Ok(request) = requests.try_recv() {
+                    batch.push(request);
+                }
+                // A spinning dial only needs to end up where it stopped
+                batch.dedup_by(|next, kept| match (&*next, &*kept) {
+                    (Request::Set(a, _), Request::Set(b, _)) if a == b => {
+                        std::mem::swap(next, kept);
+                        true
+                    }
+                    _ => false,
+                });
+                for request in &batch {
+                    apply(&mut backend, &now, request).await;
+                }
+            }
+            changed = async {
+                if let Some(at) = listen_after {
+                    sleep_until(at).await;
+                }
+                backend.changed().await
+            } => {
+                listen_after = None;
+                if let Err(e) = changed {
+                    let e = e.to_string();
+                    if listen_error.as_ref() != Some(&e) {
+                        eprintln!("Sound server stopped reporting changes: {}", e);
+                        listen_error = Some(e);
+                    }
+                    listen_after = Some(Instant::now() + RETRY);
+                } else {
+                    listen_error = None;
+                }
+            }
+        }
+    }
+}
+
+async fn apply<B: Backend>(backend: &mut B, levels: &Levels, request: &Request) {
+    let (device, result) = match *request {
+        Request::Set(device, level) => {
+            let current = levels.get(device);
+            let mut result = Ok(());
+            if current.is_none_or(|c| c.volume != level.volume) {
+                result = backend.set_volume(device, level.volume).await;
+            }
+            if result.is_ok() && current.is_none_or(|c| c.muted != level.muted) {
+                result = backend.set_muted(device, level.muted).await;
+            }
+            (device, result)
+        }
+        Request::Command(device, command) => {
+            let result = match (command, levels.get(device)) {
+                (AudioCommand::Mute, _) => backend.set_muted(device, true).await,
+                (AudioCommand::Unmute, _) => backend.set_muted(device, false).await,
+                (AudioCommand::ToggleMute, Some(level)) => {
+                    backend.set_muted(device, !level.muted).await
+                }
+                (AudioCommand::Raise, Some(level)) => {
+                    let volume = (level.volume + STEP).min(100.0).max(level.volume);
+                    backend.set_volume(device, volume).await
+                }
+                (AudioCommand::Lower, Some(level)) => {
+                    backend
+                        .set_volume(device, (level.volume - STEP).max(0.0))
+                        .await
+                }
+                (_, None) => Err(io::Error::other("level unknown")),
+            };
+            (device, result)
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Sound server {:?}: {:?}: {}", device, request, e);
+    }
+}
+
+// PulseAudio or PipeWire through the `pactl` command line tool, with
+// `pactl subscribe` running for as long as it's needed to hear about changes
+#[derive(Default)]
+struct Pactl {
+    subscription: Option<(Child, Lines<BufReader<ChildStdout>>)>,
+}
+
+impl Pactl {
+    async fn run(args: &[&str]) -> io::Result<String> {
+        let output = Command::new("pactl")
+            .args(args)
+            // Output is parsed, it mustn't be translated
+            .env("LC_ALL", "C")
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| io::Error::new(e.kind(), format!("pactl: {}", e)))?;
+        if !output.status.success() {
+            let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(io::Error::other(format!("pactl {}: {}", args[0], message)));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn subscribe() -> io::Result<(Child, Lines<BufReader<ChildStdout>>)> {
+        let mut child = Command::new("pactl")
+            .arg("subscribe")
+            .env("LC_ALL", "C")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("pactl: {}", e)))?;
+        let stdout = child.stdout.take().expect("piped stdout");
+        Ok((child, BufReader::new(stdout).lines()))
+    }
+
+    fn names(device: AudioDevice) -> (&'static str, &'static str) {
+        match device {
+            AudioDevice::Sink => ("sink", "@DEFAULT_SINK@"),
+            AudioDevice::Source => ("source", "@DEFAULT_SOURCE@"),
+        }
+    }
+}
+
+impl Backend for Pactl {
+    async fn level(&mut self, device: AudioDevice) -> io::Result<Level> {
+        let (kind, name) = Pactl::names(device);
+        let volume = Pactl::run(&[&format!("get-{}-volume", kind), name]).await?;
+        let mute = Pactl::run(&[&format!("get-{}-mute", kind), name]).await?;
+
+        // "Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: ..."
+        let channels: Vec<f64> = volume
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .split('/')
+            .filter_map(|part| part.trim().strip_suffix('%')?.parse().ok())
+            .collect();
+        if channels.is_empty() {
+            return Err(io::Error::other(format!(
+                "unexpected volume '{}'",
+                volume.trim()
+            )));
+        }
+        Ok(Level {
+            volume: channels.iter().sum::<f64>() / channels.len() as f64,
+            muted: mute.trim() == "Mute: yes",
+        })
+    }
+
+    async fn set_volume(&mut self, device: AudioDevice, percent: f64) -> io::Result<()> {
+        let (kind, name) = Pactl::names(device);
+        let volume = format!("{}%", percent.round());
+        Pactl::run(&[&format!("set-{}-volume", kind), name, &volume]).await?;
+        Ok(())
+    }
+
+    async fn set_muted(&mut self, device: AudioDevice, muted: bool) -> io::Result<()> {
+        let (kind, name) = Pactl::names(device);
+        let muted = if muted { "1" } else { "0" };
+        Pactl::run(&[&format!("set-{}-mute", kind), name, muted]).await?;
+        Ok(())
+    }
+
+    async fn changed(&mut self) -> io::Result<()> {
+        let subscription = match self.subscription.take() {
+            Some(subscription) => subscription,
+            None => Pactl::subscribe()?,
+        };
+        let (_, lines) = self.subscription.insert(subscription);
+
+        // "Event 'change' on sink #52", streams coming and going don't matter.
+        // Server changes are new default devices.
+        while let Some(line) = lines.next_line().await? {
+            if line.contains(" on sink #")
+                || line.contains(" on source #")
+                || line.contains(" on server")
+            {
+                return Ok(());
+            }
+        }
+        self.subscription = None;
+        Err(io::Error::other("pactl subscribe exited"))
+    }
+}
+
+// Levels kept in memory that nothing else changes, but tests standing in for
+// someone else using the mixer
+struct Fake {
+    sink: Level,
+    source: Level,
+    outside: Option<mpsc::UnboundedReceiver<(AudioDevice, Level)>>,
+}
+
+impl Default for Fake {
+    fn default() -> Fake {
+        Fake {
+            sink: Level {
+                volume: 50.0,
+                muted: false,
+            },
+            source: Level {
+                volume: 100.0,
+                muted: false,
+            },
+            outside: None,
+        }
+    }
+}
+
+impl Fake {
+    fn level_mut(&mut self, device: AudioDevice) -> &mut Level {
+        match device {
+            AudioDevice::Sink => &mut self.sink,
+            AudioDevice::Source => &mut self.source,
+        }
+    }
+}
+
+impl Backend for Fake {
+    async fn level(&mut self, device: AudioDevice) -> io::Result<Level> {
+        Ok(*self.level_mut(device))
+    }
+
+    async fn set_volume(&mut self, device: AudioDevice, percent: f64) -> io::Result<()> {
+        println!("Fake audio: {:?} volume {}%", device, percent);
+        self.level_mut(device).volume = percent;
+        Ok(())
+    }
+
+    async fn set_muted(&mut self, device: AudioDevice, muted: bool) -> io::Result<()> {
+        println!("Fake audio: {:?} muted {}", device, muted);
+        self.level_mut(device).muted = muted;
+        Ok(())
+    }
+
+    async fn changed(&mut self) -> io::Result<()> {
+        let Some(outside) = &mut self.outside else {
+            return std::future::pending().await;
+        };
+        let (device, level) = outside
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::other("nobody else uses the mixer"))?;
+        *self.level_mut(device) = level;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Running {
+        requests: mpsc::UnboundedSender<Request>,
+        levels: watch::Receiver<Levels>,
+        outside: mpsc::UnboundedSender<(AudioDevice, Level)>,
+    }
+
+    impl Running {
+        fn start() -> Running {
+            let (outside, changes) = mpsc::unbounded_channel();
+            let fake = Fake {
+                outside: Some(changes),
+                ..Fake::default()
+            };
+            let (requests, rx) = mpsc::unbounded_channel();
+            let (tx, levels) = watch::channel(Levels::default());
+            tokio::spawn(serve(fake, rx, tx));
+            Running {
+                requests,
+                levels,
+                outside,
+            }
+        }
+
+        // The levels once the audio task published something new
+        async fn next(&mut self) -> Levels {
+            tokio::time::timeout(Duration::from_secs(1), self.levels.changed())
+                .await
+                .expect("levels didn't change")
+                .unwrap();
+            *self.levels.borrow_and_update()
+        }
+    }
+
+    fn level(volume: f64, muted: bool) -> Level {
+        Level { volume, muted }
+    }
+
+    #[tokio::test]
+    async fn reads_levels() {
+        let mut audio = Running::start();
+        let now = audio.next().await;
+        assert_eq!(now.sink, Some(level(50.0, false)));
+        assert_eq!(now.source, Some(level(100.0, false)));
+        assert_eq!(now.sink.unwrap().text(), "50%");
+    }
+
+    #[tokio::test]
+    async fn sets_volume() {
+        let mut audio = Running::start();
+        audio.next().await;
+
+        let send = |request| audio.requests.send(request).unwrap();
+        send(Request::Set(AudioDevice::Sink, level(30.0, false)));
+        assert_eq!(audio.next().await.sink, Some(level(30.0, false)));
+
+        // A dial spun quickly ends up where it stopped
+        let send = |request| audio.requests.send(request).unwrap();
+        send(Request::Set(AudioDevice::Source, level(90.0, false)));
+        send(Request::Set(AudioDevice::Source, level(80.0, false)));
+        send(Request::Set(AudioDevice::Source, level(0.0, true)));
+        let now = audio.next().await;
+        assert_eq!(now.source, Some(level(0.0, true)));
+        assert_eq!(now.source.unwrap().text(), "Muted");
+    }
+
+    #[tokio::test]
+    async fn toggles_mute() {
+        let mut audio = Running::start();
+        audio.next().await;
+
+        let toggle = Request::Command(AudioDevice::Source, AudioCommand::ToggleMute);
+        audio.requests.send(toggle).unwrap();
+        assert_eq!(audio.next().await.source, Some(level(100.0, true)));
+
+        let toggle = Request::Command(AudioDevice::Source, AudioCommand::ToggleMute);
+        audio.requests.send(toggle).unwrap();
+        assert_eq!(audio.next().await.source, Some(level(100.0, false)));
+    }
+
+    #[tokio::test]
+    async fn raises_and_lowers() {
+        let mut audio = Running::start();
+        audio.next().await;
+
+        let lower = Request::Command(AudioDevice::Sink, AudioCommand::Lower);
+        audio.requests.send(lower).unwrap();
+        assert_eq!(audio.next().await.sink, Some(level(45.0, false)));
+
+        // Already at 100, raising leaves the source alone
+        let raise = Request::Command(AudioDevice::Source, AudioCommand::Raise);
+        audio.requests.send(raise).unwrap();
+        let raise = Request::Command(AudioDevice::Sink, AudioCommand::Raise);
+        audio.requests.send(raise).unwrap();
+        let now = audio.next().await;
+        assert_eq!(now.sink, Some(level(50.0, false)));
+        assert_eq!(now.source, Some(level(100.0, false)));
+    }
+
+    #[tokio::test]
+    async fn follows_outside_changes() {
+        let mut audio = Running::start();
+        audio.next().await;
+
+        audio
+            .outside
+            .send((AudioDevice::Sink, level(70.0, true)))
+            .unwrap();
+        assert_eq!(audio.next().await.sink, Some(level(70.0, true)));
+
+        // Toggling starts from what the outside change left
+        let toggle = Request::Command(AudioDevice::Sink, AudioCommand::ToggleMute);
+        audio.requests.send(toggle).unwrap();
+        assert_eq!(audio.next().await.sink, Some(level(70.0, false)));
+    }
+}
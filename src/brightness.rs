@@ -0,0 +1,155 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{Local, Timelike};
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::config::Config;
+
+const DAY: u32 = 24 * 60 * 60;
+
+// How far the deck has wound down for lack of input
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Power {
+    Awake,
+    Dimmed,
+    // Dark, or showing the screensaver
+    Asleep,
+}
+
+// Time since the deck was last used and what that means for its brightness
+pub struct Idle {
+    dim_after: Option<Duration>,
+    dim_brightness: u8,
+    sleep_after: Option<Duration>,
+    screensaver: Option<PathBuf>,
+    last_input: Instant,
+    power: Power,
+}
+
+impl Idle {
+    pub fn new(config: &Config) -> Idle {
+        let idle = &config.idle;
+        Idle {
+            dim_after: idle.dim_after(),
+            dim_brightness: idle.dim_brightness(),
+            sleep_after: idle.sleep_after(),
+            screensaver: idle
+                .screensaver
+                .as_ref()
+                .map(|path| config.resolve_path(path.get_ref())),
+            last_input: Instant::now(),
+            power: Power::Awake,
+        }
+    }
+
+    // Starts out awake, for a deck that was just plugged in
+    pub fn reset(&mut self, now: Instant) {
+        self.last_input = now;
+        self.power = Power::Awake;
+    }
+
+    pub fn power(&self) -> Power {
+        self.power
+    }
+
+    // Notes that the deck was used, returns what it woke up from if it
+    // wasn't awake
+    pub fn input(&mut self, now: Instant) -> Option<Power> {
+        self.last_input = now;
+        let was = std::mem::replace(&mut self.power, Power::Awake);
+        (was != Power::Awake).then_some(was)
+    }
+
+    // When the deck dims or falls asleep next if nobody touches it
+    pub fn next_change(&self) -> Option<Instant> {
+        let after = match self.power {
+            Power::Awake => self.dim_after.or(self.sleep_after),
+            Power::Dimmed => self.sleep_after,
+            Power::Asleep => None,
+        };
+        Some(self.last_input + after?)
+    }
+
+    // Winds down as far as the idle time calls for, returns whether that changed anything
+    pub fn update(&mut self, now: Instant) -> bool {
+        let idle = now.duration_since(self.last_input);
+        let power = if self.sleep_after.is_some_and(|after| idle >= after) {
+            Power::Asleep
+        } else if self.dim_after.is_some_and(|after| idle >= after) {
+            Power::Dimmed
+        } else {
+            Power::Awake
+        };
+        let changed = power != self.power;
+        self.power = power;
+        changed
+    }
+
+    // How bright the deck is right now when it's set to `normal`. A sleeping
+    // deck goes dark unless it shows a screensaver, which stays dimmed.
+    pub fn brightness(&self, normal: u8) -> u8 {
+        let dimmed = match self.dim_after {
+            Some(_) => normal.min(self.dim_brightness),
+            None => normal,
+        };
+        match self.power {
+            Power::Awake => normal,
+            Power::Dimmed => dimmed,
+            Power::Asleep if self.screensaver.is_some() => dimmed,
+            Power::Asleep => 0,
+        }
+    }
+
+    // The screensaver while it's showing
+    pub fn screensaver(&self) -> Option<&Path> {
+        match self.power {
+            Power::Asleep => self.screensaver.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+// Brightness by time of day, each entry lasting until the next one starts
+pub struct Schedule {
+    // Second of the day each entry starts at, earliest first
+    entries: Vec<(u32, u8)>,
+}
+
+impl Schedule {
+    pub fn new(config: &Config) -> Schedule {
+        let entries = config
+            .schedule()
+            .into_iter()
+            .map(|(minute, brightness)| (minute * 60, brightness))
+            .collect();
+        Schedule { entries }
+    }
+
+    // The brightness the schedule calls for right now, the last entry of the
+    // day still holds in the early hours before the first one
+    pub fn current(&self) -> Option<u8> {
+        let now = Local::now().num_seconds_from_midnight();
+        self.entries
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= now)
+            .or(self.entries.last())
+            .map(|(_, brightness)| *brightness)
+    }
+
+    // When the next entry starts
+    pub fn next_change(&self) -> Option<Instant> {
+        let now = Local::now().num_seconds_from_midnight();
+        let (first, _) = self.entries.first()?;
+        let next = self
+            .entries
+            .iter()
+            .map(|(start, _)| *start)
+            .find(|start| *start > now)
+            .unwrap_or(first + DAY);
+        Some(Instant::now() + Duration::from_secs((next - now) as u64))
+    }
+}
@@ -0,0 +1,1967 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use elgato_streamdeck::info::Kind;
+use evdev::KeyCode;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, de};
+use toml::Spanned;
+
+use crate::action;
+use crate::hass;
+
+const DEFAULT_BRIGHTNESS: u8 = 50;
+const DEFAULT_LONG_PRESS_MS: u64 = 500;
+const DEFAULT_DOUBLE_PRESS_MS: u64 = 250;
+const DEFAULT_SWIPE_DISTANCE: u16 = 40;
+const DEFAULT_MAX_FPS: u32 = 30;
+const DEFAULT_FRAME_BUDGET: usize = 64 * 1024;
+const MAX_FPS: u32 = 120;
+const DEFAULT_STATUS_INTERVAL_MS: u64 = 5000;
+const DEFAULT_DIM_BRIGHTNESS: u8 = 10;
+const DEFAULT_OBS_URL: &str = "ws://localhost:4455";
+const DEFAULT_MQTT_CLIENT_ID: &str = "rust-streamdeck";
+const DEFAULT_MQTT_STATUS_TOPIC: &str = "streamdeck/{serial}/status";
+const DEFAULT_HASS_URL: &str = "ws://localhost:8123/api/websocket";
+// What the top level `[[keys]]` are called wherever pages are named
+pub const HOME_PAGE: &str = "home";
+
+// Top level layout of config.toml
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    brightness: Option<Spanned<u8>>,
+    // How long a control has to be held to count as a long press
+    long_press_ms: Option<u64>,
+    // How quickly the second press has to follow to count as a double press
+    double_press_ms: Option<u64>,
+    // How often images may go out to the deck, and how many bytes each time
+    max_fps: Option<Spanned<u32>>,
+    frame_budget: Option<Spanned<usize>>,
+    #[serde(default)]
+    pub keys: Vec<KeyConfig>,
+    #[serde(default)]
+    pub encoders: Vec<EncoderConfig>,
+    #[serde(default)]
+    pub touchpoints: Vec<TouchpointConfig>,
+    // Widgets on the LCD strip, one zone per encoder unless zones are given
+    #[serde(default)]
+    pub lcd: LcdConfig,
+    // Swipes across the LCD strip
+    #[serde(default)]
+    pub touch: TouchConfig,
+    // Dimming and sleeping when nobody used the deck for a while
+    #[serde(default)]
+    pub idle: IdleConfig,
+    // Brightness changes at times of day
+    #[serde(default)]
+    schedule: Vec<ScheduleConfig>,
+    // Title style every key starts from, keys can override parts of it
+    #[serde(default)]
+    pub text: TextConfig,
+    // The media player that media keys, zones and dials follow
+    #[serde(default)]
+    pub media: MediaConfig,
+    // The sound server that audio keys, zones and dials talk to
+    #[serde(default)]
+    pub audio: AudioConfig,
+    // Where OBS Studio listens, for OBS actions and the keys showing them
+    #[serde(default)]
+    pub obs: ObsConfig,
+    // The MQTT broker that deck input goes out to and that keys and zones
+    // can follow topics on
+    #[serde(default)]
+    pub mqtt: MqttConfig,
+    // Home Assistant, for service calls and the keys and dials showing entities
+    #[serde(default)]
+    pub home_assistant: HassConfig,
+    // Sub-pages reached through `page` actions, keyed by name
+    #[serde(default)]
+    pub pages: HashMap<String, PageConfig>,
+    // Decks that get a profile of their own instead of the layout in this file
+    #[serde(default)]
+    devices: Vec<DeviceConfig>,
+
+    // Profiles loaded for `devices`, in the same order
+    #[serde(skip)]
+    profiles: Vec<Arc<Config>>,
+    // Where the config was loaded from, used to resolve icons and report errors
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    source: String,
+}
+
+// Picks a profile for decks matching the serial and/or kind
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub serial: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_kind")]
+    pub kind: Option<Kind>,
+    // Path to a file laid out like config.toml, without its own `devices`
+    pub profile: Spanned<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PageConfig {
+    // Key that returns to the previous page, defaults to the first key
+    pub back_key: Option<Spanned<u8>>,
+    pub back_icon: Option<Spanned<PathBuf>>,
+    #[serde(default)]
+    pub keys: Vec<KeyConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyConfig {
+    pub index: Spanned<u8>,
+    pub icon: Option<Spanned<PathBuf>>,
+    pub label: Option<String>,
+    // Text drawn on the key, over the icon if there is one
+    pub title: Option<String>,
+    // Fills the key behind the icon and title
+    pub background: Option<[u8; 3]>,
+    pub text: Option<TextConfig>,
+    #[serde(alias = "action")]
+    pub on_press: Option<ActionConfig>,
+    pub on_release: Option<ActionConfig>,
+    pub on_long_press: Option<ActionConfig>,
+    pub on_double_press: Option<ActionConfig>,
+    // Named states the key steps through on every press, each with its own
+    // look and actions in place of the key's
+    #[serde(default)]
+    pub states: Vec<KeyStateConfig>,
+    // Asks which state the key is in, by printing a state's name or exiting
+    // with its position
+    pub state_command: Option<Spanned<String>>,
+    // Command run over and over whose output becomes the key's title and
+    // background
+    pub status: Option<Spanned<StatusConfig>>,
+    // Album art and/or title of what the media player is playing, in place of
+    // the key's own icon and title while there is something
+    pub media: Option<Spanned<MediaFace>>,
+    // Volume of the default sink or source under the key's title, or that
+    // it's muted
+    pub audio: Option<Spanned<AudioDevice>>,
+    // Messages on an MQTT topic, shown as the key's title or icon
+    pub mqtt: Option<Spanned<MqttBinding>>,
+    // State of a Home Assistant entity, in the key's background and title
+    pub hass: Option<Spanned<HassFace>>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaFace {
+    Art,
+    // Scrolls when it's too long for the key
+    Title,
+    ArtAndTitle,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatusConfig {
+    pub command: String,
+    pub interval_ms: Option<Spanned<u64>>,
+    // Commands still running after this are killed, the interval without it
+    pub timeout_ms: Option<Spanned<u64>>,
+    // Picks the value out of the output, its first group or the whole match
+    pub regex: Option<Spanned<String>>,
+    // Picks the value out of JSON output, like "sensors.0.temp"
+    pub json: Option<Spanned<String>>,
+    // Title with `{value}` in it, just the value without one
+    pub title: Option<String>,
+    // Background by exit code, "error" for commands that couldn't run, timed
+    // out or printed nothing to pick a value from
+    #[serde(default)]
+    pub colors: HashMap<String, [u8; 3]>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyStateConfig {
+    pub name: Spanned<String>,
+    pub icon: Option<Spanned<PathBuf>>,
+    pub title: Option<String>,
+    pub background: Option<[u8; 3]>,
+    pub text: Option<TextConfig>,
+    #[serde(alias = "action")]
+    pub on_press: Option<ActionConfig>,
+    pub on_release: Option<ActionConfig>,
+    pub on_long_press: Option<ActionConfig>,
+    pub on_double_press: Option<ActionConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncoderConfig {
+    pub index: Spanned<u8>,
+    pub icon: Option<Spanned<PathBuf>>,
+    pub label: Option<String>,
+    // Value the dial steps up and down, shown on its part of the LCD strip
+    pub dial: Option<Spanned<DialConfig>>,
+    #[serde(alias = "action")]
+    pub on_press: Option<ActionConfig>,
+    pub on_release: Option<ActionConfig>,
+    pub on_long_press: Option<ActionConfig>,
+    pub on_double_press: Option<ActionConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TouchpointConfig {
+    pub index: Spanned<u8>,
+    pub color: Option<[u8; 3]>,
+    // A steady light unless it blinks, pulses or breathes, once per period
+    #[serde(default)]
+    pub effect: LedEffect,
+    pub period_ms: Option<Spanned<u64>>,
+    // Other looks clients can switch the light to by name, like "muted"
+    #[serde(default)]
+    pub states: HashMap<String, LedConfig>,
+    pub label: Option<String>,
+    #[serde(alias = "action")]
+    pub on_press: Option<ActionConfig>,
+    pub on_release: Option<ActionConfig>,
+    pub on_long_press: Option<ActionConfig>,
+    pub on_double_press: Option<ActionConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LedConfig {
+    pub color: [u8; 3],
+    #[serde(default)]
+    pub effect: LedEffect,
+    pub period_ms: Option<Spanned<u64>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedEffect {
+    #[default]
+    Solid,
+    // On for half the period, off for the other half
+    Blink,
+    // A quick flash fading out over the period
+    Pulse,
+    // Slowly brightening and dimming
+    Breathe,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdleConfig {
+    pub dim_after_s: Option<Spanned<u64>>,
+    pub dim_brightness: Option<Spanned<u8>>,
+    // Turns the deck dark, or shows the screensaver across its keys
+    pub sleep_after_s: Option<Spanned<u64>>,
+    pub screensaver: Option<Spanned<PathBuf>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MediaConfig {
+    // Part of the player's bus name like "spotify" or "vlc", followed whenever
+    // it's running. Otherwise whichever player is playing.
+    pub player: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AudioConfig {
+    #[serde(default)]
+    pub backend: AudioBackend,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioBackend {
+    // PulseAudio, or PipeWire through pipewire-pulse
+    #[default]
+    Pactl,
+    // Levels kept in memory, for trying a config without a sound server
+    Fake,
+}
+
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioDevice {
+    // The default output, speakers or headphones
+    Sink,
+    // The default input, usually a microphone
+    Source,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObsConfig {
+    // The obs-websocket server, ws://localhost:4455 unless set
+    url: Option<String>,
+    // Only needed when the server has authentication enabled
+    pub password: Option<String>,
+    // Background of OBS keys while what they control is live: their scene is
+    // on air, their source is showing or OBS is recording or streaming
+    pub tally: Option<[u8; 3]>,
+}
+
+impl ObsConfig {
+    pub fn url(&self) -> &str {
+        self.url.as_deref().unwrap_or(DEFAULT_OBS_URL)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    // Nothing connects to MQTT unless the broker is set
+    pub host: Option<String>,
+    // 1883, or 8883 with TLS, unless set
+    port: Option<u16>,
+    // Each deck connects on its own, as this followed by "-" and its serial
+    client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Checks the broker against the system's certificates, or against `ca`
+    #[serde(default)]
+    pub tls: bool,
+    pub ca: Option<Spanned<PathBuf>>,
+    // For brokers that want a certificate from the client, needs `ca`
+    pub client_cert: Option<Spanned<PathBuf>>,
+    pub client_key: Option<Spanned<PathBuf>>,
+    // Retained "online" while the deck is connected and "offline" once it
+    // isn't, `{serial}` is the deck's serial
+    status_topic: Option<String>,
+    // Buttons, dials and touches go out here as JSON like the control
+    // socket's events, `{event}` is the kind of event. Nothing is published
+    // unless set.
+    pub events_topic: Option<String>,
+}
+
+impl MqttConfig {
+    pub fn port(&self) -> u16 {
+        match (self.port, self.tls) {
+            (Some(port), _) => port,
+            (None, false) => 1883,
+            (None, true) => 8883,
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        self.client_id.as_deref().unwrap_or(DEFAULT_MQTT_CLIENT_ID)
+    }
+
+    pub fn status_topic(&self) -> &str {
+        self.status_topic
+            .as_deref()
+            .unwrap_or(DEFAULT_MQTT_STATUS_TOPIC)
+    }
+}
+
+// A topic a key or zone shows the messages of
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttBinding {
+    // Can have `+` and `#` wildcards
+    pub topic: String,
+    // Picks the value out of JSON messages, like "sensors.0.temp"
+    pub json: Option<String>,
+    // Title with `{value}` in it, just the value without one
+    pub title: Option<String>,
+    // The value is a base64 encoded image, shown in place of the icon
+    #[serde(default)]
+    pub image: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HassConfig {
+    // The WebSocket API, ws://localhost:8123/api/websocket unless set
+    url: Option<String>,
+    // A long-lived access token, from the security tab of the user's profile
+    pub token: Option<String>,
+    // Background of Home Assistant keys while their entity is on, open or
+    // playing
+    pub on_color: Option<[u8; 3]>,
+}
+
+impl HassConfig {
+    pub fn url(&self) -> &str {
+        self.url.as_deref().unwrap_or(DEFAULT_HASS_URL)
+    }
+}
+
+// An entity a key shows the state of
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HassFace {
+    // Like "light.desk" or "sensor.outside_temperature"
+    pub entity: String,
+    // Shown instead of the state, like "brightness" or "forecast.0.condition"
+    pub attribute: Option<String>,
+    // Title with `{value}` in it. Without one the value is the title, unless
+    // the entity is just on or off.
+    pub title: Option<String>,
+    // Shows the value as a percentage of this, like 255 for a light's brightness
+    pub max: Option<f64>,
+    // Background while the entity is on, `on_color` of [home_assistant] when unset
+    pub color: Option<[u8; 3]>,
+}
+
+// From `at` (like "22:30") until the next entry the deck is this bright
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub at: Spanned<String>,
+    pub brightness: Spanned<u8>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LcdConfig {
+    #[serde(default)]
+    pub zones: Vec<ZoneConfig>,
+}
+
+// Part of the LCD strip showing one widget, drawn and sent on its own
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+    // What clients use to update the zone, it can also be addressed by position
+    pub name: Option<String>,
+    // Takes the part of the strip above this encoder
+    pub encoder: Option<Spanned<u8>>,
+    // Or any rectangle of the strip as [x, y, width, height] in pixels
+    pub rect: Option<Spanned<[u16; 4]>>,
+    pub background: Option<[u8; 3]>,
+    // Style of the zone's text, centered vertically unless `align` says otherwise
+    pub text: Option<TextConfig>,
+    pub widget: Spanned<WidgetConfig>,
+    // Run when the zone is tapped or held on the touch screen
+    pub on_tap: Option<ActionConfig>,
+    pub on_long_press: Option<ActionConfig>,
+    // Messages on an MQTT topic: numbers become the widget's value, anything
+    // else its text, and images go on icon widgets
+    pub mqtt: Option<Spanned<MqttBinding>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TouchConfig {
+    // How far in pixels a touch has to travel to count as a swipe
+    pub swipe_distance: Option<Spanned<u16>>,
+    // Run for swipes in that direction wherever they start, whatever page is showing
+    pub on_swipe_left: Option<Spanned<ActionConfig>>,
+    pub on_swipe_right: Option<Spanned<ActionConfig>>,
+    pub on_swipe_up: Option<Spanned<ActionConfig>>,
+    pub on_swipe_down: Option<Spanned<ActionConfig>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum WidgetConfig {
+    Text {
+        #[serde(default)]
+        text: String,
+    },
+    // Arc filling up clockwise with the value in the middle
+    Gauge(MeterConfig),
+    // Bar along the bottom with the value above it
+    Progress(MeterConfig),
+    // Line through the most recent values
+    Sparkline(SparklineConfig),
+    // Image scaled to fit the zone
+    Icon {
+        path: PathBuf,
+    },
+    // The encoder's icon, label and dial, what each encoder gets by default
+    Encoder {
+        encoder: u8,
+    },
+    // Album art with the title and artist of what the media player is playing
+    Media,
+    // Volume of the default sink or source with a bar, or that it's muted
+    Audio {
+        device: AudioDevice,
+        label: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeterConfig {
+    pub label: Option<String>,
+    pub value: Option<f64>,
+    // 0 to 100 when unset
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub unit: Option<String>,
+    // Of the filled part, the text color when unset
+    pub color: Option<[u8; 3]>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SparklineConfig {
+    pub label: Option<String>,
+    // Starting history, oldest first
+    #[serde(default)]
+    pub values: Vec<f64>,
+    // Range of the line, taken from the values shown when unset
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    // How many values are kept, 30 when unset
+    pub capacity: Option<usize>,
+    pub unit: Option<String>,
+    pub color: Option<[u8; 3]>,
+}
+
+// A value moved by twisting an encoder. It's a plain number unless the
+// target says otherwise, and `on_change` is how it reaches anything else.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DialConfig {
+    #[serde(default)]
+    pub target: DialTarget,
+    // Either bound left out lets the value run on forever, e.g. for scrolling
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    // How far one tick moves the value, 1 when unset
+    pub step: Option<f64>,
+    // Value before the dial is first touched, the minimum or 0 when unset
+    pub initial: Option<f64>,
+    // Most steps a single tick can move when the dial is spun fast, 1 turns it off
+    pub acceleration: Option<f64>,
+    // Go from the maximum back to the minimum and the other way around
+    #[serde(default)]
+    pub wrap: bool,
+    // What pushing the dial does to the value, on top of any `on_press`
+    pub press: Option<DialPress>,
+    // Names shown instead of the number, the value picks one of them
+    #[serde(default)]
+    pub options: Vec<String>,
+    // Shown after the number, like "%"
+    pub unit: Option<String>,
+    // The Home Assistant entity an entity dial sets, like "light.desk"
+    pub entity: Option<String>,
+    // Run whenever the value changes, with `{value}` replaced by the new value
+    pub on_change: Option<ActionConfig>,
+    // Run once per step, for dials that scroll rather than hold a value
+    pub on_increase: Option<ActionConfig>,
+    pub on_decrease: Option<ActionConfig>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DialTarget {
+    #[default]
+    Value,
+    // The deck's own brightness, kept between 0 and 100
+    Brightness,
+    // The media player's volume, kept between 0 and 100
+    Volume,
+    // Where the media player is in the track, in seconds
+    Seek,
+    // Volume of the default sink or source, kept between 0 and 100.
+    // Pressing the dial mutes it unless `press` says otherwise.
+    Sink,
+    Source,
+    // A Home Assistant entity: the brightness of a light, speed of a fan,
+    // position of a cover or volume of a media player between 0 and 100, or
+    // a number or thermostat within the entity's own range
+    Entity,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DialPress {
+    // Drop to the minimum, and back to where it was on the next push, like mute
+    Toggle,
+    // Go back to the initial value
+    Reset,
+    // Mute or unmute the sink or source, for audio dials
+    Mute,
+}
+
+// How titles are drawn, anything left out comes from the top level `[text]`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TextConfig {
+    // TrueType or OpenType font, the bundled DejaVu Sans Bold when unset
+    pub font: Option<Spanned<PathBuf>>,
+    // Pixel height, a fifth of the key when unset
+    pub size: Option<Spanned<f32>>,
+    pub color: Option<[u8; 3]>,
+    pub align: Option<Align>,
+    // Width in pixels of the outline around each letter, 0 turns it off
+    pub outline: Option<u32>,
+    pub outline_color: Option<[u8; 3]>,
+}
+
+// Where the title sits on the key, lines are always centered horizontally
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Align {
+    Top,
+    Middle,
+    #[default]
+    Bottom,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ActionConfig {
+    // Run a snippet through `sh -c`
+    Shell {
+        command: String,
+    },
+
+    // Start a program directly with its own arguments and environment
+    Spawn {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        env: HashMap<String, String>,
+        cwd: Option<PathBuf>,
+    },
+
+    // Open a URL or file through xdg-open
+    Open {
+        target: String,
+    },
+
+    // Type a key chord like "ctrl+alt+t" through a virtual keyboard
+    Keys {
+        #[serde(deserialize_with = "deserialize_chord")]
+        chord: Vec<KeyCode>,
+    },
+
+    // Swap the keys to a sub-page, remembering where we came from
+    Page {
+        page: String,
+    },
+
+    // Return to the previous page
+    Back,
+
+    // Return to the top level page and forget the history
+    Home,
+
+    // Play, pause or skip on the media player
+    Media {
+        command: MediaCommand,
+    },
+
+    // Mute or step the volume of the default sink or source
+    Audio {
+        device: AudioDevice,
+        command: AudioCommand,
+    },
+
+    // Put a scene on air in OBS
+    ObsScene {
+        scene: String,
+    },
+
+    // Show or hide a source in OBS, in the scene on air unless `scene` says
+    ObsSource {
+        source: String,
+        scene: Option<String>,
+    },
+
+    // Start, stop or toggle recording or streaming in OBS
+    Obs {
+        command: ObsCommand,
+    },
+
+    // Call a Home Assistant service like "light.toggle" or "scene.turn_on",
+    // on `entity` when given, with `data` as its fields
+    Hass {
+        service: String,
+        entity: Option<String>,
+        #[serde(default)]
+        data: serde_json::Map<String, serde_json::Value>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ObsCommand {
+    ToggleRecording,
+    StartRecording,
+    StopRecording,
+    ToggleStreaming,
+    StartStreaming,
+    StopStreaming,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioCommand {
+    ToggleMute,
+    Mute,
+    Unmute,
+    // Five percent up or down
+    Raise,
+    Lower,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaCommand {
+    PlayPause,
+    Play,
+    Pause,
+    Stop,
+    Next,
+    Previous,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    // Config file couldn't be read
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    // Config file isn't valid TOML or doesn't match the expected layout
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+
+    // Config file parsed fine but doesn't fit the connected device
+    Invalid {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            ConfigError::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{}:{}: {}", path.display(), line, column, message),
+            ConfigError::Invalid {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl Config {
+    // ~/.config/rust-streamdeck/config.toml, honoring XDG_CONFIG_HOME
+    pub fn default_path() -> PathBuf {
+        let base = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => home_dir().join(".config"),
+        };
+        base.join("rust-streamdeck").join("config.toml")
+    }
+
+    // Loads the config along with the profile of every `devices` entry
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let mut config = Config::load_profile(path)?;
+
+        for device in &config.devices {
+            if device.serial.is_none() && device.kind.is_none() {
+                return Err(config.invalid(
+                    device.profile.span().start,
+                    "device needs a serial, a kind or both".to_string(),
+                ));
+            }
+
+            let profile = Config::load_profile(&config.resolve_path(device.profile.get_ref()))?;
+            if let Some(nested) = profile.devices.first() {
+                return Err(profile.invalid(
+                    nested.profile.span().start,
+                    "profiles can't select devices of their own".to_string(),
+                ));
+            }
+            config.profiles.push(Arc::new(profile));
+        }
+
+        // Connecting without one would only be turned away, and the token is
+        // read from this file even for decks with a profile
+        if config.home_assistant.token.is_none() {
+            let configs = std::iter::once(&config).chain(config.profiles.iter().map(|p| &**p));
+            for used in configs {
+                if let Some(offset) = used.hass_use() {
+                    return Err(used.invalid(
+                        offset,
+                        format!(
+                            "Home Assistant needs an access token, set one under [home_assistant] in {}",
+                            config.path.display()
+                        ),
+                    ));
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn load_profile(path: &Path) -> Result<Config, ConfigError> {
+        let source = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Config::parse(path, source)
+    }
+
+    pub fn parse(path: &Path, source: String) -> Result<Config, ConfigError> {
+        let mut config: Config = match toml::from_str(&source) {
+            Ok(config) => config,
+            Err(e) => {
+                let offset = e.span().map(|span| span.start).unwrap_or(0);
+                let (line, column) = line_column(&source, offset);
+                return Err(ConfigError::Parse {
+                    path: path.to_path_buf(),
+                    line,
+                    column,
+                    message: e.message().to_string(),
+                });
+            }
+        };
+        config.path = path.to_path_buf();
+        config.source = source;
+
+        if let Some(brightness) = &config.brightness
+            && *brightness.get_ref() > 100
+        {
+            return Err(config.invalid(
+                brightness.span().start,
+                format!("brightness {} is above 100", brightness.get_ref()),
+            ));
+        }
+
+        if let Some(fps) = &config.max_fps
+            && !(1..=MAX_FPS).contains(fps.get_ref())
+        {
+            return Err(config.invalid(
+                fps.span().start,
+                format!("max_fps has to be between 1 and {}", MAX_FPS),
+            ));
+        }
+        if let Some(budget) = &config.frame_budget
+            && *budget.get_ref() == 0
+        {
+            return Err(config.invalid(
+                budget.span().start,
+                "frame budget has to be above 0".to_string(),
+            ));
+        }
+
+        config.validate_text(&config.text)?;
+        for encoder in &config.encoders {
+            if let Some(dial) = &encoder.dial {
+                config.validate_dial(dial)?;
+            }
+            let actions = [
+                &encoder.on_press,
+                &encoder.on_release,
+                &encoder.on_long_press,
+                &encoder.on_double_press,
+            ];
+            let what = format!("encoder {}", encoder.index.get_ref());
+            for action in actions.into_iter().flatten() {
+                config.validate_opens(action, encoder.index.span().start, &what)?;
+            }
+        }
+        for touchpoint in &config.touchpoints {
+            let actions = [
+                &touchpoint.on_press,
+                &touchpoint.on_release,
+                &touchpoint.on_long_press,
+                &touchpoint.on_double_press,
+            ];
+            let what = format!("touch point {}", touchpoint.index.get_ref());
+            for action in actions.into_iter().flatten() {
+                config.validate_opens(action, touchpoint.index.span().start, &what)?;
+            }
+        }
+        config.validate_zones()?;
+        config.validate_touch()?;
+        config.validate_idle()?;
+        config.validate_leds()?;
+        config.validate_mqtt()?;
+        config.validate_pages(&config.keys)?;
+        for (name, page) in &config.pages {
+            if name.is_empty() || name == HOME_PAGE {
+                let offset = page.keys.first().map(|k| k.index.span().start);
+                return Err(config.invalid(
+                    offset.unwrap_or(0),
+                    format!("page '{}' would clash with the home page", name),
+                ));
+            }
+            config.validate_pages(&page.keys)?;
+
+            let back_key = page.back_key();
+            if let Some(key) = page.keys.iter().find(|k| *k.index.get_ref() == back_key) {
+                return Err(config.invalid(
+                    key.index.span().start,
+                    format!("key {} is the back key of page '{}'", back_key, name),
+                ));
+            }
+        }
+
+        Ok(config)
+    }
+
+    // Config used when no file exists yet, every key stays blank
+    pub fn empty(path: &Path) -> Config {
+        Config {
+            path: path.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Profile for a deck: a serial match wins over a kind match, and decks
+    // matching neither use this file's own layout
+    pub fn profile_for(self: &Arc<Self>, kind: Kind, serial: &str) -> Arc<Config> {
+        let devices = self.devices.iter().zip(&self.profiles);
+        let kind_matches = |d: &DeviceConfig| d.kind.is_none_or(|k| k == kind);
+
+        let by_serial = devices
+            .clone()
+            .find(|(d, _)| d.serial.as_deref() == Some(serial) && kind_matches(d));
+        let by_kind = devices
+            .clone()
+            .find(|(d, _)| d.serial.is_none() && kind_matches(d));
+
+        match by_serial.or(by_kind) {
+            Some((_, profile)) => profile.clone(),
+            None => self.clone(),
+        }
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+            .as_ref()
+            .map(|b| *b.get_ref())
+            .unwrap_or(DEFAULT_BRIGHTNESS)
+    }
+
+    pub fn long_press(&self) -> Duration {
+        Duration::from_millis(self.long_press_ms.unwrap_or(DEFAULT_LONG_PRESS_MS))
+    }
+
+    pub fn double_press(&self) -> Duration {
+        Duration::from_millis(self.double_press_ms.unwrap_or(DEFAULT_DOUBLE_PRESS_MS))
+    }
+
+    pub fn max_fps(&self) -> u32 {
+        self.max_fps
+            .as_ref()
+            .map(|f| *f.get_ref())
+            .unwrap_or(DEFAULT_MAX_FPS)
+    }
+
+    // Bytes sent to the deck per frame at most
+    pub fn frame_budget(&self) -> usize {
+        self.frame_budget
+            .as_ref()
+            .map(|b| *b.get_ref())
+            .unwrap_or(DEFAULT_FRAME_BUDGET)
+    }
+
+    // Brightness by minute of the day it starts at, earliest first
+    pub fn schedule(&self) -> Vec<(u32, u8)> {
+        let mut schedule: Vec<_> = self
+            .schedule
+            .iter()
+            .filter_map(|e| Some((parse_time(e.at.get_ref())?, *e.brightness.get_ref())))
+            .collect();
+        schedule.sort();
+        schedule
+    }
+
+    pub fn swipe_distance(&self) -> u16 {
+        self.touch
+            .swipe_distance
+            .as_ref()
+            .map(|d| *d.get_ref())
+            .unwrap_or(DEFAULT_SWIPE_DISTANCE)
+    }
+
+    pub fn touchpoint(&self, index: u8) -> Option<&TouchpointConfig> {
+        self.touchpoints
+            .iter()
+            .find(|t| *t.index.get_ref() == index)
+    }
+
+    // Icons are looked up relative to the config file, `~/` expands to the home directory
+    pub fn resolve_path(&self, path: &Path) -> PathBuf {
+        if let Ok(rest) = path.strip_prefix("~") {
+            return home_dir().join(rest);
+        }
+        match self.path.parent() {
+            Some(dir) => dir.join(path),
+            None => path.to_path_buf(),
+        }
+    }
+
+    // Checks that everything in the config exists on the given kind of device
+    pub fn validate(&self, kind: Kind) -> Result<(), ConfigError> {
+        let keys = self.keys.iter().map(|k| (&k.index, &k.icon));
+        self.validate_controls("key", kind.key_count(), kind, keys)?;
+        self.validate_state_icons(&self.keys)?;
+
+        for (name, page) in &self.pages {
+            let keys = page.keys.iter().map(|k| (&k.index, &k.icon));
+            self.validate_controls("key", kind.key_count(), kind, keys)?;
+            self.validate_state_icons(&page.keys)?;
+
+            let back_key = page.back_key();
+            if back_key >= kind.key_count() {
+                let offset = page.back_key.as_ref().map(|k| k.span().start).unwrap_or(0);
+                return Err(self.invalid(
+                    offset,
+                    format!(
+                        "back key {} of page '{}' out of range ({:?} has {} keys)",
+                        back_key,
+                        name,
+                        kind,
+                        kind.key_count()
+                    ),
+                ));
+            }
+
+            if let Some(icon) = &page.back_icon {
+                let resolved = self.resolve_path(icon.get_ref());
+                if !resolved.is_file() {
+                    return Err(self.invalid(
+                        icon.span().start,
+                        format!(
+                            "back icon {} of page '{}' not found",
+                            resolved.display(),
+                            name
+                        ),
+                    ));
+                }
+            }
+        }
+
+        let encoders = self.encoders.iter().map(|e| (&e.index, &e.icon));
+        self.validate_controls("encoder", kind.encoder_count(), kind, encoders)?;
+
+        let touchpoints = self.touchpoints.iter().map(|t| (&t.index, &None));
+        self.validate_controls("touchpoint", kind.touchpoint_count(), kind, touchpoints)?;
+
+        if let Some(screensaver) = &self.idle.screensaver {
+            let resolved = self.resolve_path(screensaver.get_ref());
+            if !resolved.is_file() {
+                return Err(self.invalid(
+                    screensaver.span().start,
+                    format!("screensaver {} not found", resolved.display()),
+                ));
+            }
+        }
+
+        self.validate_lcd(kind)
+    }
+
+    // Where each zone sits on the strip of the given kind of deck, as x, y,
+    // width and height
+    pub fn zone_rect(&self, zone: &ZoneConfig, kind: Kind) -> Option<[u16; 4]> {
+        if let Some(rect) = &zone.rect {
+            return Some(*rect.get_ref());
+        }
+        let (w, h) = kind.lcd_strip_size()?;
+        let encoder = *zone.encoder.as_ref()?.get_ref() as u16;
+        let segment = w as u16 / kind.encoder_count().max(1) as u16;
+        Some([segment * encoder, 0, segment, h as u16])
+    }
+
+    fn validate_lcd(&self, kind: Kind) -> Result<(), ConfigError> {
+        let zones = &self.lcd.zones;
+        let Some(first) = zones.first() else {
+            return Ok(());
+        };
+        let Some((w, h)) = kind.lcd_strip_size() else {
+            return Err(self.invalid(
+                first.widget.span().start,
+                format!("{:?} has no LCD strip for zones", kind),
+            ));
+        };
+
+        let mut placed: Vec<[u16; 4]> = vec![];
+        for zone in zones {
+            let offset = zone.widget.span().start;
+            if let Some(encoder) = &zone.encoder
+                && *encoder.get_ref() >= kind.encoder_count()
+            {
+                return Err(self.invalid(
+                    encoder.span().start,
+                    format!(
+                        "zone encoder {} out of range ({:?} has {} encoders)",
+                        encoder.get_ref(),
+                        kind,
+                        kind.encoder_count()
+                    ),
+                ));
+            }
+            let Some([x, y, zw, zh]) = self.zone_rect(zone, kind) else {
+                continue;
+            };
+            if x as usize + zw as usize > w || y as usize + zh as usize > h {
+                return Err(self.invalid(
+                    offset,
+                    format!("zone doesn't fit the {}x{} LCD strip of a {:?}", w, h, kind),
+                ));
+            }
+            // Zones are sent on their own, one drawn over another would be lost
+            let overlaps = |[ox, oy, ow, oh]: &[u16; 4]| {
+                x < ox + ow && *ox < x + zw && y < oy + oh && *oy < y + zh
+            };
+            if placed.iter().any(overlaps) {
+                return Err(self.invalid(offset, "zone overlaps another zone".to_string()));
+            }
+            placed.push([x, y, zw, zh]);
+
+            match zone.widget.get_ref() {
+                WidgetConfig::Encoder { encoder } if *encoder >= kind.encoder_count() => {
+                    return Err(self.invalid(
+                        offset,
+                        format!(
+                            "encoder widget for encoder {} out of range ({:?} has {} encoders)",
+                            encoder,
+                            kind,
+                            kind.encoder_count()
+                        ),
+                    ));
+                }
+                WidgetConfig::Icon { path } => {
+                    let resolved = self.resolve_path(path);
+                    if !resolved.is_file() {
+                        return Err(self.invalid(
+                            offset,
+                            format!("zone icon {} not found", resolved.display()),
+                        ));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_leds(&self) -> Result<(), ConfigError> {
+        for point in &self.touchpoints {
+            let periods = point
+                .states
+                .values()
+                .map(|state| &state.period_ms)
+                .chain([&point.period_ms]);
+            for period in periods.flatten() {
+                if *period.get_ref() == 0 {
+                    return Err(
+                        self.invalid(period.span().start, "period has to be above 0".to_string())
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_touch(&self) -> Result<(), ConfigError> {
+        if let Some(distance) = &self.touch.swipe_distance
+            && *distance.get_ref() == 0
+        {
+            return Err(self.invalid(
+                distance.span().start,
+                "swipe distance has to be above 0".to_string(),
+            ));
+        }
+        let swipes = [
+            &self.touch.on_swipe_left,
+            &self.touch.on_swipe_right,
+            &self.touch.on_swipe_up,
+            &self.touch.on_swipe_down,
+        ];
+        for action in swipes.into_iter().flatten() {
+            self.validate_opens(action.get_ref(), action.span().start, "swipe")?;
+        }
+        Ok(())
+    }
+
+    fn validate_idle(&self) -> Result<(), ConfigError> {
+        let idle = &self.idle;
+        for (name, secs) in [
+            ("dim_after_s", &idle.dim_after_s),
+            ("sleep_after_s", &idle.sleep_after_s),
+        ] {
+            if let Some(secs) = secs
+                && *secs.get_ref() == 0
+            {
+                return Err(self.invalid(secs.span().start, format!("{} has to be above 0", name)));
+            }
+        }
+        if let (Some(dim), Some(sleep)) = (&idle.dim_after_s, &idle.sleep_after_s)
+            && sleep.get_ref() <= dim.get_ref()
+        {
+            return Err(self.invalid(
+                sleep.span().start,
+                "sleep_after_s has to be longer than dim_after_s".to_string(),
+            ));
+        }
+        if let Some(brightness) = &idle.dim_brightness
+            && *brightness.get_ref() > 100
+        {
+            return Err(self.invalid(
+                brightness.span().start,
+                format!("dim brightness {} is above 100", brightness.get_ref()),
+            ));
+        }
+        if let Some(screensaver) = &idle.screensaver
+            && idle.sleep_after_s.is_none()
+        {
+            return Err(self.invalid(
+                screensaver.span().start,
+                "a screensaver needs sleep_after_s".to_string(),
+            ));
+        }
+
+        let mut times = HashSet::new();
+        for entry in &self.schedule {
+            let Some(minute) = parse_time(entry.at.get_ref()) else {
+                return Err(self.invalid(
+                    entry.at.span().start,
+                    format!("'{}' isn't a time like 07:30", entry.at.get_ref()),
+                ));
+            };
+            if !times.insert(minute) {
+                return Err(self.invalid(
+                    entry.at.span().start,
+                    format!("{} is in the schedule more than once", entry.at.get_ref()),
+                ));
+            }
+            if *entry.brightness.get_ref() > 100 {
+                return Err(self.invalid(
+                    entry.brightness.span().start,
+                    format!("brightness {} is above 100", entry.brightness.get_ref()),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // A `page` action has to name a page that exists
+    fn validate_opens(
+        &self,
+        action: &ActionConfig,
+        offset: usize,
+        what: &str,
+    ) -> Result<(), ConfigError> {
+        match action {
+            ActionConfig::Page { page } if !self.pages.contains_key(page) => Err(self.invalid(
+                offset,
+                format!("{} opens page '{}' which doesn't exist", what, page),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // What can be checked about zones without knowing the deck
+    fn validate_zones(&self) -> Result<(), ConfigError> {
+        let mut names = HashSet::new();
+        for zone in &self.lcd.zones {
+            let invalid =
+                |message: &str| Err(self.invalid(zone.widget.span().start, message.to_string()));
+            if zone.encoder.is_some() == zone.rect.is_some() {
+                return invalid("zone needs either an encoder or a rect");
+            }
+            if let Some(rect) = &zone.rect
+                && (rect.get_ref()[2] == 0 || rect.get_ref()[3] == 0)
+            {
+                return invalid("zone rect can't be empty");
+            }
+            if let Some(name) = &zone.name
+                && !names.insert(name)
+            {
+                return invalid(&format!("zone '{}' is configured more than once", name));
+            }
+            if let Some(text) = &zone.text {
+                self.validate_text(text)?;
+            }
+            for action in [&zone.on_tap, &zone.on_long_press].into_iter().flatten() {
+                self.validate_opens(action, zone.widget.span().start, "zone")?;
+            }
+            if let Some(mqtt) = &zone.mqtt {
+                self.validate_binding(mqtt)?;
+                let invalid =
+                    |message: &str| Err(self.invalid(mqtt.span().start, message.to_string()));
+                match (zone.widget.get_ref(), mqtt.get_ref().image) {
+                    (WidgetConfig::Icon { .. }, false) => {
+                        return invalid("icon zones only show MQTT messages with image = true");
+                    }
+                    (WidgetConfig::Icon { .. }, true) => {}
+                    (_, true) => return invalid("only icon zones show MQTT images"),
+                    (
+                        WidgetConfig::Encoder { .. }
+                        | WidgetConfig::Media
+                        | WidgetConfig::Audio { .. },
+                        false,
+                    ) => return invalid("zone can't show MQTT messages along with its widget"),
+                    _ => {}
+                }
+            }
+
+            let (min, max) = match zone.widget.get_ref() {
+                WidgetConfig::Gauge(meter) | WidgetConfig::Progress(meter) => (
+                    Some(meter.min.unwrap_or(0.0)),
+                    Some(meter.max.unwrap_or(100.0)),
+                ),
+                WidgetConfig::Sparkline(sparkline) => {
+                    if sparkline.capacity == Some(0) {
+                        return invalid("sparkline capacity has to be above 0");
+                    }
+                    (sparkline.min, sparkline.max)
+                }
+                _ => (None, None),
+            };
+            if let (Some(min), Some(max)) = (min, max)
+                && min.partial_cmp(&max) != Some(std::cmp::Ordering::Less)
+            {
+                return invalid("widget min has to be below its max");
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_controls<'a>(
+        &self,
+        name: &str,
+        count: u8,
+        kind: Kind,
+        controls: impl Iterator<Item = (&'a Spanned<u8>, &'a Option<Spanned<PathBuf>>)>,
+    ) -> Result<(), ConfigError> {
+        let mut seen = HashSet::new();
+
+        for (index, icon) in controls {
+            let i = *index.get_ref();
+            if i >= count {
+                return Err(self.invalid(
+                    index.span().start,
+                    format!(
+                        "{} index {} out of range ({:?} has {} {}s)",
+                        name, i, kind, count, name
+                    ),
+                ));
+            }
+            if !seen.insert(i) {
+                return Err(self.invalid(
+                    index.span().start,
+                    format!("{} {} is configured more than once", name, i),
+                ));
+            }
+            if let Some(icon) = icon {
+                let resolved = self.resolve_path(icon.get_ref());
+                if !resolved.is_file() {
+                    return Err(self.invalid(
+                        icon.span().start,
+                        format!("icon {} for {} {} not found", resolved.display(), name, i),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_text(&self, text: &TextConfig) -> Result<(), ConfigError> {
+        // NaN slips through a plain `<= 0.0`
+        if let Some(size) = &text.size
+            && size.get_ref().partial_cmp(&0.0) != Some(std::cmp::Ordering::Greater)
+        {
+            return Err(self.invalid(
+                size.span().start,
+                format!("text size {} has to be above 0", size.get_ref()),
+            ));
+        }
+        if let Some(font) = &text.font {
+            let resolved = self.resolve_path(font.get_ref());
+            if !resolved.is_file() {
+                return Err(self.invalid(
+                    font.span().start,
+                    format!("font {} not found", resolved.display()),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_dial(&self, dial: &Spanned<DialConfig>) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(self.invalid(dial.span().start, message.to_string()));
+        let config = dial.get_ref();
+        let numbers = [
+            config.min,
+            config.max,
+            config.step,
+            config.initial,
+            config.acceleration,
+        ];
+        if numbers.into_iter().flatten().any(|n| !n.is_finite()) {
+            return invalid("dial values have to be finite numbers");
+        }
+
+        if !config.options.is_empty() && (config.min.is_some() || config.max.is_some()) {
+            return invalid("dial options set their own min and max");
+        }
+        if config.step.is_some_and(|step| step <= 0.0) {
+            return invalid("dial step has to be above 0");
+        }
+        if config.acceleration.is_some_and(|a| a < 1.0) {
+            return invalid("dial acceleration can't be below 1");
+        }
+        if let (Some(min), Some(max)) = (config.min, config.max)
+            && min >= max
+        {
+            return invalid("dial min has to be below its max");
+        }
+        if config.wrap
+            && config.options.is_empty()
+            && (config.min.is_none() || config.max.is_none())
+        {
+            return invalid("a dial needs a min and a max to wrap around");
+        }
+        if let Some(initial) = config.initial
+            && (config.min.is_some_and(|min| initial < min)
+                || config.max.is_some_and(|max| initial > max))
+        {
+            return invalid("dial initial value is outside its min and max");
+        }
+        match (config.target, &config.entity) {
+            (DialTarget::Entity, None) => return invalid("entity dials need an entity"),
+            (DialTarget::Entity, Some(entity)) if !hass::settable(entity) => {
+                return invalid(
+                    "entity dials set lights, fans, covers, media players, thermostats or numbers",
+                );
+            }
+            (DialTarget::Entity, _) | (_, None) => {}
+            (_, Some(_)) => return invalid("only entity dials take an entity"),
+        }
+        let percent = matches!(
+            config.target,
+            DialTarget::Brightness | DialTarget::Volume | DialTarget::Sink | DialTarget::Source
+        ) || config.entity.as_deref().is_some_and(hass::percent);
+        if percent
+            && (config.min.is_some_and(|min| min < 0.0)
+                || config.max.is_some_and(|max| max > 100.0))
+        {
+            return invalid("brightness, volume and percentage dials stay between 0 and 100");
+        }
+        if config.press == Some(DialPress::Mute)
+            && !matches!(config.target, DialTarget::Sink | DialTarget::Source)
+        {
+            return invalid("only sink and source dials can mute");
+        }
+        if config.target == DialTarget::Seek && (!config.options.is_empty() || config.wrap) {
+            return invalid("seek dials follow the track, they can't have options or wrap");
+        }
+
+        let actions = [&config.on_change, &config.on_increase, &config.on_decrease];
+        for action in actions.into_iter().flatten() {
+            if matches!(
+                action,
+                ActionConfig::Page { .. } | ActionConfig::Back | ActionConfig::Home
+            ) {
+                return invalid("dial actions can't change pages");
+            }
+        }
+        Ok(())
+    }
+
+    // Every page a key opens has to exist, and every title style has to be usable
+    fn validate_pages(&self, keys: &[KeyConfig]) -> Result<(), ConfigError> {
+        for key in keys {
+            if let Some(text) = &key.text {
+                self.validate_text(text)?;
+            }
+            self.validate_states(key)?;
+
+            let actions = [
+                &key.on_press,
+                &key.on_release,
+                &key.on_long_press,
+                &key.on_double_press,
+            ];
+            let state_actions = key.states.iter().flat_map(|state| {
+                [
+                    &state.on_press,
+                    &state.on_release,
+                    &state.on_long_press,
+                    &state.on_double_press,
+                ]
+            });
+            for action in actions.into_iter().chain(state_actions).flatten() {
+                if let ActionConfig::Page { page } = action
+                    && !self.pages.contains_key(page)
+                {
+                    return Err(self.invalid(
+                        key.index.span().start,
+                        format!(
+                            "key {} opens page '{}' which doesn't exist",
+                            key.index.get_ref(),
+                            page
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_states(&self, key: &KeyConfig) -> Result<(), ConfigError> {
+        let index = key.index.get_ref();
+        if let Some(command) = &key.state_command
+            && key.states.is_empty()
+        {
+            return Err(self.invalid(
+                command.span().start,
+                format!("key {} has a state command but no states", index),
+            ));
+        }
+        if let Some(status) = &key.status {
+            self.validate_status(status)?;
+            if !key.states.is_empty() {
+                return Err(self.invalid(
+                    status.span().start,
+                    format!("key {} can't have both states and a status", index),
+                ));
+            }
+        }
+        if let Some(media) = &key.media
+            && (key.status.is_some() || !key.states.is_empty())
+        {
+            return Err(self.invalid(
+                media.span().start,
+                format!(
+                    "key {} can't show media along with states or a status",
+                    index
+                ),
+            ));
+        }
+        if let Some(audio) = &key.audio
+            && (key.media.is_some() || key.status.is_some() || !key.states.is_empty())
+        {
+            return Err(self.invalid(
+                audio.span().start,
+                format!(
+                    "key {} can't show audio along with media, states or a status",
+                    index
+                ),
+            ));
+        }
+        if let Some(mqtt) = &key.mqtt {
+            self.validate_binding(mqtt)?;
+            if key.media.is_some()
+                || key.audio.is_some()
+                || key.status.is_some()
+                || !key.states.is_empty()
+            {
+                return Err(self.invalid(
+                    mqtt.span().start,
+                    format!(
+                        "key {} can't show MQTT messages along with media, audio, states or a status",
+                        index
+                    ),
+                ));
+            }
+        }
+        if let Some(hass) = &key.hass
+            && (key.media.is_some()
+                || key.audio.is_some()
+                || key.mqtt.is_some()
+                || key.status.is_some()
+                || !key.states.is_empty())
+        {
+            return Err(self.invalid(
+                hass.span().start,
+                format!(
+                    "key {} can't show an entity along with media, audio, MQTT, states or a status",
+                    index
+                ),
+            ));
+        }
+        if key.states.len() == 1 {
+            return Err(self.invalid(
+                key.states[0].name.span().start,
+                format!("key {} needs at least two states", index),
+            ));
+        }
+
+        let mut names = HashSet::new();
+        for state in &key.states {
+            let name = state.name.get_ref();
+            if name.trim().is_empty() {
+                return Err(self.invalid(
+                    state.name.span().start,
+                    "state name can't be empty".to_string(),
+                ));
+            }
+            if !names.insert(name) {
+                return Err(self.invalid(
+                    state.name.span().start,
+                    format!("key {} has state '{}' more than once", index, name),
+                ));
+            }
+            if let Some(text) = &state.text {
+                self.validate_text(text)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_status(&self, status: &Spanned<StatusConfig>) -> Result<(), ConfigError> {
+        let at = status.span().start;
+        let status = status.get_ref();
+        for (name, ms) in [
+            ("interval_ms", &status.interval_ms),
+            ("timeout_ms", &status.timeout_ms),
+        ] {
+            if let Some(ms) = ms
+                && *ms.get_ref() == 0
+            {
+                return Err(self.invalid(ms.span().start, format!("{} has to be above 0", name)));
+            }
+        }
+        if let (Some(regex), Some(_)) = (&status.regex, &status.json) {
+            return Err(self.invalid(
+                regex.span().start,
+                "status takes either regex or json, not both".to_string(),
+            ));
+        }
+        if let Some(regex) = &status.regex
+            && let Err(e) = Regex::new(regex.get_ref())
+        {
+            return Err(self.invalid(regex.span().start, format!("invalid regex: {}", e)));
+        }
+        if let Some(code) = status
+            .colors
+            .keys()
+            .find(|code| *code != "error" && code.parse::<i32>().is_err())
+        {
+            return Err(self.invalid(
+                at,
+                format!("status color '{}' isn't an exit code or \"error\"", code),
+            ));
+        }
+        Ok(())
+    }
+
+    fn validate_mqtt(&self) -> Result<(), ConfigError> {
+        let mqtt = &self.mqtt;
+        let files = [
+            ("ca", &mqtt.ca),
+            ("client_cert", &mqtt.client_cert),
+            ("client_key", &mqtt.client_key),
+        ];
+        for (name, file) in files {
+            let Some(file) = file else { continue };
+            if !mqtt.tls {
+                return Err(
+                    self.invalid(file.span().start, format!("mqtt {} needs tls = true", name))
+                );
+            }
+            let resolved = self.resolve_path(file.get_ref());
+            if !resolved.is_file() {
+                return Err(self.invalid(
+                    file.span().start,
+                    format!("mqtt {} {} not found", name, resolved.display()),
+                ));
+            }
+        }
+        match (&mqtt.client_cert, &mqtt.client_key) {
+            (Some(file), None) | (None, Some(file)) => Err(self.invalid(
+                file.span().start,
+                "mqtt client_cert and client_key go together".to_string(),
+            )),
+            // A client certificate only goes along with a CA of its own
+            (Some(file), Some(_)) if mqtt.ca.is_none() => {
+                Err(self.invalid(file.span().start, "mqtt client_cert needs a ca".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn validate_binding(&self, binding: &Spanned<MqttBinding>) -> Result<(), ConfigError> {
+        let at = binding.span().start;
+        let binding = binding.get_ref();
+        if !rumqttc::valid_filter(&binding.topic) {
+            return Err(self.invalid(at, format!("'{}' isn't a valid MQTT topic", binding.topic)));
+        }
+        if binding.image && binding.title.is_some() {
+            return Err(self.invalid(
+                at,
+                "mqtt takes either a title or image, not both".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn validate_state_icons(&self, keys: &[KeyConfig]) -> Result<(), ConfigError> {
+        for key in keys {
+            for state in &key.states {
+                if let Some(icon) = &state.icon {
+                    let resolved = self.resolve_path(icon.get_ref());
+                    if !resolved.is_file() {
+                        return Err(self.invalid(
+                            icon.span().start,
+                            format!(
+                                "icon {} for state '{}' of key {} not found",
+                                resolved.display(),
+                                state.name.get_ref(),
+                                key.index.get_ref()
+                            ),
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Where Home Assistant is first needed, to call a service or show or set
+    // an entity
+    fn hass_use(&self) -> Option<usize> {
+        let calls = |actions: &[&Option<ActionConfig>]| {
+            actions
+                .iter()
+                .any(|action| matches!(action, Some(ActionConfig::Hass { .. })))
+        };
+        let keys = self
+            .keys
+            .iter()
+            .chain(self.pages.values().flat_map(|page| &page.keys));
+        for key in keys {
+            if let Some(face) = &key.hass {
+                return Some(face.span().start);
+            }
+            let states = key.states.iter().map(|state| {
+                [
+                    &state.on_press,
+                    &state.on_release,
+                    &state.on_long_press,
+                    &state.on_double_press,
+                ]
+            });
+            let own = [
+                &key.on_press,
+                &key.on_release,
+                &key.on_long_press,
+                &key.on_double_press,
+            ];
+            if std::iter::once(own)
+                .chain(states)
+                .any(|actions| calls(&actions))
+            {
+                return Some(key.index.span().start);
+            }
+        }
+        for encoder in &self.encoders {
+            if let Some(dial) = &encoder.dial {
+                let config = dial.get_ref();
+                let actions = [&config.on_change, &config.on_increase, &config.on_decrease];
+                if config.target == DialTarget::Entity || calls(&actions) {
+                    return Some(dial.span().start);
+                }
+            }
+            let actions = [
+                &encoder.on_press,
+                &encoder.on_release,
+                &encoder.on_long_press,
+                &encoder.on_double_press,
+            ];
+            if calls(&actions) {
+                return Some(encoder.index.span().start);
+            }
+        }
+        for touchpoint in &self.touchpoints {
+            let actions = [
+                &touchpoint.on_press,
+                &touchpoint.on_release,
+                &touchpoint.on_long_press,
+                &touchpoint.on_double_press,
+            ];
+            if calls(&actions) {
+                return Some(touchpoint.index.span().start);
+            }
+        }
+        for zone in &self.lcd.zones {
+            if calls(&[&zone.on_tap, &zone.on_long_press]) {
+                return Some(zone.widget.span().start);
+            }
+        }
+        let swipes = [
+            &self.touch.on_swipe_left,
+            &self.touch.on_swipe_right,
+            &self.touch.on_swipe_up,
+            &self.touch.on_swipe_down,
+        ];
+        swipes
+            .into_iter()
+            .flatten()
+            .find(|action| matches!(action.get_ref(), ActionConfig::Hass { .. }))
+            .map(|action| action.span().start)
+    }
+
+    fn invalid(&self, offset: usize, message: String) -> ConfigError {
+        let (line, _) = line_column(&self.source, offset);
+        ConfigError::Invalid {
+            path: self.path.clone(),
+            line,
+            message,
+        }
+    }
+}
+
+impl TextConfig {
+    // This style with the gaps filled in from `defaults`
+    pub fn or(&self, defaults: &TextConfig) -> TextConfig {
+        TextConfig {
+            font: self.font.clone().or_else(|| defaults.font.clone()),
+            size: self.size.clone().or_else(|| defaults.size.clone()),
+            color: self.color.or(defaults.color),
+            align: self.align.or(defaults.align),
+            outline: self.outline.or(defaults.outline),
+            outline_color: self.outline_color.or(defaults.outline_color),
+        }
+    }
+}
+
+impl PageConfig {
+    pub fn back_key(&self) -> u8 {
+        self.back_key.as_ref().map(|k| *k.get_ref()).unwrap_or(0)
+    }
+}
+
+impl IdleConfig {
+    pub fn dim_after(&self) -> Option<Duration> {
+        let secs = self.dim_after_s.as_ref()?;
+        Some(Duration::from_secs(*secs.get_ref()))
+    }
+
+    pub fn dim_brightness(&self) -> u8 {
+        self.dim_brightness
+            .as_ref()
+            .map(|b| *b.get_ref())
+            .unwrap_or(DEFAULT_DIM_BRIGHTNESS)
+    }
+
+    pub fn sleep_after(&self) -> Option<Duration> {
+        let secs = self.sleep_after_s.as_ref()?;
+        Some(Duration::from_secs(*secs.get_ref()))
+    }
+}
+
+impl StatusConfig {
+    pub fn interval(&self) -> Duration {
+        let ms = self.interval_ms.as_ref().map(|ms| *ms.get_ref());
+        Duration::from_millis(ms.unwrap_or(DEFAULT_STATUS_INTERVAL_MS))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        match &self.timeout_ms {
+            Some(ms) => Duration::from_millis(*ms.get_ref()),
+            None => self.interval(),
+        }
+    }
+}
+
+// Minutes since midnight of a time like "7:30" or "22:05"
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    if minutes.len() != 2 {
+        return None;
+    }
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+// Every kind of deck, used to look kinds up by name
+pub const KINDS: [Kind; 14] = [
+    Kind::Original,
+    Kind::OriginalV2,
+    Kind::Mini,
+    Kind::Xl,
+    Kind::XlV2,
+    Kind::Mk2,
+    Kind::Mk2Scissor,
+    Kind::MiniMk2,
+    Kind::Neo,
+    Kind::Pedal,
+    Kind::Plus,
+    Kind::MiniMk2Module,
+    Kind::Mk2Module,
+    Kind::XlV2Module,
+];
+
+// Accepts "xl_v2", "XlV2", "xl-v2" and so on
+pub fn parse_kind(name: &str) -> Option<Kind> {
+    let wanted: String = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    KINDS
+        .into_iter()
+        .find(|kind| format!("{:?}", kind).to_ascii_lowercase() == wanted)
+}
+
+fn deserialize_kind<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Kind>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    match parse_kind(&name) {
+        Some(kind) => Ok(Some(kind)),
+        None => Err(de::Error::custom(format!("unknown device kind '{}'", name))),
+    }
+}
+
+fn deserialize_chord<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<KeyCode>, D::Error> {
+    let chord = String::deserialize(deserializer)?;
+    action::parse_chord(&chord).map_err(de::Error::custom)
+}
+
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+    (line, column)
+}
+
+fn home_dir() -> PathBuf {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/"))
+}
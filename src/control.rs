@@ -0,0 +1,518 @@
+use std::collections::HashMap;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use elgato_streamdeck::info::Kind;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::action::Navigation;
+use crate::brightness::Power;
+use crate::framebuffer::FrameStats;
+use crate::gesture::{Control, Gesture, Swipe};
+
+// One line of JSON from a client
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Request {
+    // Connected decks
+    List,
+    // Page, brightness and keys of the deck, or of every deck without a serial
+    State {
+        serial: Option<String>,
+    },
+    // Shows an image file on a key of the current page, no path goes back to blank
+    SetImage {
+        serial: Option<String>,
+        key: u8,
+        path: Option<PathBuf>,
+    },
+    // Sets the title of a key on the current page, no text removes it
+    SetText {
+        serial: Option<String>,
+        key: u8,
+        text: Option<String>,
+    },
+    // Moves a key with states on to its next state, or into the named one
+    Toggle {
+        serial: Option<String>,
+        key: u8,
+        state: Option<String>,
+    },
+    Page {
+        serial: Option<String>,
+        page: String,
+    },
+    Back {
+        serial: Option<String>,
+    },
+    Home {
+        serial: Option<String>,
+    },
+    Brightness {
+        serial: Option<String>,
+        percent: u8,
+    },
+    // Changes what an LCD zone shows, addressed by name or position
+    SetZone {
+        serial: Option<String>,
+        zone: String,
+        value: Option<f64>,
+        text: Option<String>,
+    },
+    // Switches a touch point light to one of its configured states, no
+    // state goes back to its normal color
+    Led {
+        serial: Option<String>,
+        touchpoint: u8,
+        state: Option<String>,
+    },
+    // Turns the connection into a stream of events, one JSON line each
+    Subscribe,
+}
+
+// Something a client asked a deck's task to do
+#[derive(Clone, Debug)]
+pub enum DeckRequest {
+    SetImage {
+        key: u8,
+        path: Option<PathBuf>,
+    },
+    SetText {
+        key: u8,
+        text: Option<String>,
+    },
+    Toggle {
+        key: u8,
+        state: Option<String>,
+    },
+    Navigate(Navigation),
+    Brightness(u8),
+    SetZone {
+        zone: String,
+        value: Option<f64>,
+        text: Option<String>,
+    },
+    Led {
+        touchpoint: u8,
+        state: Option<String>,
+    },
+    State,
+}
+
+// A request on its way to a deck's task, answered with the deck's state
+// after handling it
+pub struct Command {
+    pub request: DeckRequest,
+    pub reply: oneshot::Sender<Result<DeckStatus, String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeckStatus {
+    pub serial: String,
+    pub kind: String,
+    pub page: String,
+    pub brightness: u8,
+    pub power: Power,
+    pub keys: Vec<KeyStatus>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dials: Vec<DialStatus>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub touchpoints: Vec<TouchpointStatus>,
+    // What the deck's frame scheduler got through since it was plugged in
+    pub frames: FrameStats,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyStatus {
+    pub index: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TouchpointStatus {
+    pub index: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DialStatus {
+    pub encoder: u8,
+    pub value: f64,
+    pub text: String,
+}
+
+// What subscribers are told about, tagged with the deck it happened on
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    pub serial: String,
+    #[serde(flatten)]
+    pub what: EventKind,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    Connected {
+        kind: String,
+    },
+    Disconnected,
+    Down {
+        control: Control,
+    },
+    Up {
+        control: Control,
+    },
+    Gesture {
+        control: Control,
+        gesture: Gesture,
+    },
+    Twist {
+        encoder: u8,
+        ticks: i8,
+    },
+    // A dial's value changed, `text` is the value as shown on the LCD
+    Dial {
+        encoder: u8,
+        value: f64,
+        text: String,
+    },
+    // `zone` is the LCD zone that was touched, by position
+    Touch {
+        x: u16,
+        y: u16,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        zone: Option<usize>,
+    },
+    LongTouch {
+        x: u16,
+        y: u16,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        zone: Option<usize>,
+    },
+    // `direction` is missing for touches that moved too little or too diagonally
+    Swipe {
+        from: (u16, u16),
+        to: (u16, u16),
+        #[serde(skip_serializing_if = "Option::is_none")]
+        direction: Option<Swipe>,
+    },
+    Page {
+        page: String,
+    },
+    // The deck dimmed, fell asleep or woke up
+    Power {
+        power: Power,
+    },
+    // A key with states switched to another one, by press, client or its
+    // state command
+    KeyState {
+        key: u8,
+        state: String,
+    },
+}
+
+// Where a deck's task picks up commands
+struct DeckHandle {
+    kind: Kind,
+    commands: mpsc::Sender<Command>,
+}
+
+// Decks that are running right now and the events they publish, shared by
+// the manager, every deck's task and the socket server
+#[derive(Clone)]
+pub struct Hub {
+    decks: Arc<Mutex<HashMap<String, DeckHandle>>>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Hub {
+    pub fn new() -> Hub {
+        Hub {
+            decks: Arc::default(),
+            events: broadcast::channel(256).0,
+        }
+    }
+
+    // Makes the deck reachable from clients until it's detached
+    pub fn attach(&self, serial: &str, kind: Kind) -> Remote {
+        let (tx, commands) = mpsc::channel(16);
+        let handle = DeckHandle { kind, commands: tx };
+        self.decks
+            .lock()
+            .unwrap()
+            .insert(serial.to_string(), handle);
+        self.publish(
+            serial,
+            EventKind::Connected {
+                kind: format!("{:?}", kind),
+            },
+        );
+        Remote {
+            serial: serial.to_string(),
+            commands,
+            events: self.events.clone(),
+        }
+    }
+
+    pub fn detach(&self, serial: &str) {
+        if self.decks.lock().unwrap().remove(serial).is_some() {
+            self.publish(serial, EventKind::Disconnected);
+        }
+    }
+
+    fn publish(&self, serial: &str, what: EventKind) {
+        // Nobody subscribed is fine
+        let _ = self.events.send(Event {
+            serial: serial.to_string(),
+            what,
+        });
+    }
+}
+
+// A deck's end of the hub: commands for it and where its events go
+pub struct Remote {
+    serial: String,
+    pub commands: mpsc::Receiver<Command>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Remote {
+    pub fn publish(&self, what: EventKind) {
+        let _ = self.events.send(Event {
+            serial: self.serial.clone(),
+            what,
+        });
+    }
+
+    // Everything published from now on, for any deck
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+}
+
+// $XDG_RUNTIME_DIR/rust-streamdeck.sock, or a per-user file in /tmp
+pub fn default_socket() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("rust-streamdeck.sock"),
+        _ => {
+            let user = std::env::var("USER").unwrap_or_else(|_| "default".to_string());
+            PathBuf::from(format!("/tmp/rust-streamdeck-{}.sock", user))
+        }
+    }
+}
+
+// Removes the socket file once the daemon is done with it
+pub struct Server {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Server {
+    pub async fn bind(path: &Path) -> std::io::Result<Server> {
+        // Anyone who can reach the socket can type on this user's keyboard.
+        // It's bound in a directory only this user can enter and moved into
+        // place once nobody else can connect, so there's no moment it's open.
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let private = path.with_file_name(format!(".{}.{}", name, std::process::id()));
+        std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+        let staged = private.join("control.sock");
+        let listener = Server::bind_staged(&private, &staged, path).await;
+        let _ = std::fs::remove_file(&staged);
+        let _ = std::fs::remove_dir(&private);
+
+        Ok(Server {
+            listener: listener?,
+            path: path.to_path_buf(),
+        })
+    }
+
+    async fn bind_staged(
+        private: &Path,
+        staged: &Path,
+        path: &Path,
+    ) -> std::io::Result<UnixListener> {
+        // A socket of this user's nobody answers on is left over from a
+        // daemon that didn't exit cleanly, anything else isn't ours to remove
+        if let Ok(existing) = std::fs::symlink_metadata(path) {
+            // The directory was just made, so it belongs to this user
+            let user = std::fs::metadata(private)?.uid();
+            if !existing.file_type().is_socket() || existing.uid() != user {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{} exists and isn't a socket of this user's",
+                        path.display()
+                    ),
+                ));
+            }
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another daemon", path.display()),
+                ));
+            }
+        }
+
+        let listener = UnixListener::bind(staged)?;
+        std::fs::set_permissions(staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(staged, path)?;
+        Ok(listener)
+    }
+
+    // Accepts clients until the daemon exits, each on its own task
+    pub async fn run(&self, hub: Hub) {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, _)) => {
+                    let hub = hub.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, hub).await {
+                            eprintln!("Control client: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept control client: {}", e),
+            }
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+async fn serve(stream: UnixStream, hub: Hub) -> std::io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                send(&mut write, &json!({"ok": false, "error": e.to_string()})).await?;
+                continue;
+            }
+        };
+
+        if let Request::Subscribe = request {
+            let mut events = hub.events.subscribe();
+            send(&mut write, &json!({"ok": true})).await?;
+            loop {
+                match events.recv().await {
+                    Ok(event) => send(&mut write, &json!(event)).await?,
+                    // A slow reader misses events rather than holding up the decks
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                }
+            }
+        }
+
+        let response = match handle(request, &hub).await {
+            Ok(mut response) => {
+                response["ok"] = json!(true);
+                response
+            }
+            Err(e) => json!({"ok": false, "error": e}),
+        };
+        send(&mut write, &response).await?;
+    }
+
+    Ok(())
+}
+
+async fn send(write: &mut tokio::net::unix::OwnedWriteHalf, value: &Value) -> std::io::Result<()> {
+    let mut line = value.to_string();
+    line.push('\n');
+    write.write_all(line.as_bytes()).await
+}
+
+async fn handle(request: Request, hub: &Hub) -> Result<Value, String> {
+    let (serial, request) = match request {
+        Request::List => {
+            let decks = hub.decks.lock().unwrap();
+            let mut devices: Vec<Value> = decks
+                .iter()
+                .map(|(serial, deck)| json!({"serial": serial, "kind": format!("{:?}", deck.kind)}))
+                .collect();
+            devices.sort_by_key(|d| d["serial"].as_str().unwrap_or_default().to_string());
+            return Ok(json!({ "devices": devices }));
+        }
+        Request::Subscribe => unreachable!("subscriptions are handled by serve"),
+        Request::State { serial } => (serial, DeckRequest::State),
+        Request::SetImage { serial, key, path } => (serial, DeckRequest::SetImage { key, path }),
+        Request::SetText { serial, key, text } => (serial, DeckRequest::SetText { key, text }),
+        Request::Toggle { serial, key, state } => (serial, DeckRequest::Toggle { key, state }),
+        Request::Page { serial, page } => (serial, DeckRequest::Navigate(Navigation::Open(page))),
+        Request::Back { serial } => (serial, DeckRequest::Navigate(Navigation::Back)),
+        Request::Home { serial } => (serial, DeckRequest::Navigate(Navigation::Home)),
+        Request::Brightness { serial, percent } => (serial, DeckRequest::Brightness(percent)),
+        Request::SetZone {
+            serial,
+            zone,
+            value,
+            text,
+        } => (serial, DeckRequest::SetZone { zone, value, text }),
+        Request::Led {
+            serial,
+            touchpoint,
+            state,
+        } => (serial, DeckRequest::Led { touchpoint, state }),
+    };
+
+    let targets = targets(hub, serial.as_deref())?;
+    let single = targets.len() == 1;
+    let mut decks = vec![];
+    for (serial, commands) in targets {
+        let (reply, replied) = oneshot::channel();
+        let request = request.clone();
+        let gone = || format!("{} went away", serial);
+        commands
+            .send(Command { request, reply })
+            .await
+            .map_err(|_| gone())?;
+        match replied.await.map_err(|_| gone())? {
+            Ok(status) => decks.push(json!(status)),
+            // Sent to every deck, so one without the page or key isn't fatal
+            Err(e) if !single => decks.push(json!({"serial": serial, "error": e})),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(json!({ "decks": decks }))
+}
+
+// The deck with the serial, or every running deck without one
+fn targets(
+    hub: &Hub,
+    serial: Option<&str>,
+) -> Result<Vec<(String, mpsc::Sender<Command>)>, String> {
+    let decks = hub.decks.lock().unwrap();
+    let mut targets: Vec<_> = decks
+        .iter()
+        .filter(|(s, _)| serial.is_none_or(|serial| serial == s.as_str()))
+        .map(|(s, deck)| (s.clone(), deck.commands.clone()))
+        .collect();
+    targets.sort_by(|a, b| a.0.cmp(&b.0));
+
+    match (targets.is_empty(), serial) {
+        (true, Some(serial)) => Err(format!("no deck with serial {}", serial)),
+        (true, None) => Err("no decks connected".to_string()),
+        _ => Ok(targets),
+    }
+}
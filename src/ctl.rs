@@ -0,0 +1,203 @@
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use serde_json::{Map, Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+use crate::control;
+
+pub const USAGE: &str = "usage: rust-streamdeck ctl [--socket PATH] [--serial SERIAL] COMMAND
+
+commands:
+  list                  connected decks
+  state                 page, brightness, keys and frame stats of each deck
+  image KEY [PATH]      show an image on a key, without PATH the key goes back to blank
+  text KEY [TEXT]       set the title of a key, without TEXT it's removed
+  toggle KEY [STATE]    move a key with states to STATE, or on to its next state
+  page NAME             open a page
+  back                  go back to the previous page
+  home                  go back to the top level keys
+  brightness PERCENT    set the brightness
+  zone ZONE VALUE|TEXT  update an LCD zone by name or position, numbers feed
+                        gauges, bars and sparklines, text sets their label
+  led POINT [STATE]     switch a touch point light to one of its states, without
+                        STATE it goes back to its normal color
+  subscribe             print button events as they happen";
+
+// Sends one request to the daemon and prints what comes back, one JSON
+// object per line
+pub async fn main(args: impl Iterator<Item = String>) -> ExitCode {
+    let (socket, request) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match send(&socket, request).await {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("Can't talk to the daemon at {}: {}", socket.display(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Returns whether the daemon accepted the request
+async fn send(socket: &Path, request: Value) -> std::io::Result<bool> {
+    let stream = UnixStream::connect(socket).await?;
+    let (read, mut write) = stream.into_split();
+    let mut line = request.to_string();
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+
+    let subscribe = request["cmd"] == "subscribe";
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response: Value = serde_json::from_str(&line).unwrap_or(Value::Null);
+        if response["ok"] == false {
+            eprintln!("{}", response["error"].as_str().unwrap_or(&line));
+            return Ok(false);
+        }
+        // Subscriptions start with an empty acknowledgement, only events are worth printing
+        if !(subscribe && response.get("ok").is_some()) {
+            println!("{}", line);
+        }
+        if !subscribe {
+            return Ok(true);
+        }
+    }
+
+    if subscribe {
+        Ok(true)
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "closed the connection without answering",
+        ))
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<(PathBuf, Value), String> {
+    let mut socket = None;
+    let mut serial = None;
+    let mut words = vec![];
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--socket" => socket = Some(PathBuf::from(value()?)),
+            "--serial" => serial = Some(value()?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => words.push(arg),
+        }
+    }
+
+    let Some((command, rest)) = words.split_first() else {
+        return Err("missing command".to_string());
+    };
+    let key = || -> Result<u8, String> {
+        let key = rest.first().ok_or(format!("'{}' needs a key", command))?;
+        key.parse()
+            .map_err(|_| format!("'{}' isn't a key index", key))
+    };
+    let arity = |max: usize| {
+        if rest.len() > max {
+            Err(format!("too many arguments for '{}'", command))
+        } else {
+            Ok(())
+        }
+    };
+
+    let mut request = Map::new();
+    match command.as_str() {
+        "list" | "state" | "back" | "home" | "subscribe" => arity(0)?,
+        "image" => {
+            arity(2)?;
+            request.insert("key".to_string(), json!(key()?));
+            if let Some(path) = rest.get(1) {
+                // The daemon has its own working directory
+                let path = std::fs::canonicalize(path)
+                    .map_err(|e| format!("can't open {}: {}", path, e))?;
+                request.insert("path".to_string(), json!(path));
+            }
+        }
+        "text" => {
+            request.insert("key".to_string(), json!(key()?));
+            if rest.len() > 1 {
+                request.insert("text".to_string(), json!(rest[1..].join(" ")));
+            }
+        }
+        "toggle" => {
+            arity(2)?;
+            request.insert("key".to_string(), json!(key()?));
+            if let Some(state) = rest.get(1) {
+                request.insert("state".to_string(), json!(state));
+            }
+        }
+        "page" => {
+            arity(1)?;
+            let page = rest.first().ok_or("'page' needs a page name")?;
+            request.insert("page".to_string(), json!(page));
+        }
+        "zone" => {
+            let zone = rest.first().ok_or("'zone' needs a zone name or position")?;
+            request.insert("zone".to_string(), json!(zone));
+            match &rest[1..] {
+                [] => return Err("'zone' needs a value or text".to_string()),
+                [value] if value.parse::<f64>().is_ok_and(f64::is_finite) => {
+                    let value: f64 = value.parse().unwrap_or_default();
+                    request.insert("value".to_string(), json!(value));
+                }
+                words => {
+                    request.insert("text".to_string(), json!(words.join(" ")));
+                }
+            }
+        }
+        "led" => {
+            arity(2)?;
+            let point = rest.first().ok_or("'led' needs a touch point")?;
+            let point: u8 = point
+                .parse()
+                .map_err(|_| format!("'{}' isn't a touch point index", point))?;
+            request.insert("touchpoint".to_string(), json!(point));
+            if let Some(state) = rest.get(1) {
+                request.insert("state".to_string(), json!(state));
+            }
+        }
+        "brightness" => {
+            arity(1)?;
+            let percent = rest.first().ok_or("'brightness' needs a percentage")?;
+            let percent: u8 = percent
+                .parse()
+                .ok()
+                .filter(|p| *p <= 100)
+                .ok_or(format!("'{}' isn't a percentage", percent))?;
+            request.insert("percent".to_string(), json!(percent));
+        }
+        other => return Err(format!("unknown command '{}'", other)),
+    }
+
+    let cmd = match command.as_str() {
+        "image" => "set_image",
+        "text" => "set_text",
+        "zone" => "set_zone",
+        other => other,
+    };
+    request.insert("cmd".to_string(), json!(cmd));
+    if let Some(serial) = serial {
+        if matches!(cmd, "list" | "subscribe") {
+            return Err(format!("'{}' doesn't take a serial", command));
+        }
+        request.insert("serial".to_string(), json!(serial));
+    }
+
+    let socket = socket.unwrap_or_else(control::default_socket);
+    Ok((socket, Value::Object(request)))
+}
@@ -0,0 +1,144 @@
+use std::sync::Arc;
+
+use elgato_streamdeck::asynchronous::AsyncDeviceStateReader;
+use elgato_streamdeck::images::ImageRect;
+use elgato_streamdeck::info::Kind;
+use elgato_streamdeck::{AsyncStreamDeck, DeviceStateUpdate, StreamDeckError};
+
+// What the device loop needs from a deck, so it can drive real hardware as
+// well as the simulated deck in sim.rs
+pub trait Deck: Send + Sync + 'static {
+    type Reader: DeckReader;
+
+    fn kind(&self) -> Kind;
+
+    fn set_brightness(
+        &self,
+        percent: u8,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send;
+
+    // Queues already encoded image data for the key until the next flush
+    fn write_image(
+        &self,
+        key: u8,
+        data: &[u8],
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send;
+
+    // Blanks the key right away, without waiting for a flush
+    fn clear_button_image(
+        &self,
+        key: u8,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send;
+
+    fn clear_all_button_images(&self) -> impl Future<Output = Result<(), StreamDeckError>> + Send;
+
+    fn set_touchpoint_color(
+        &self,
+        point: u8,
+        red: u8,
+        green: u8,
+        blue: u8,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send;
+
+    fn write_lcd(
+        &self,
+        x: u16,
+        y: u16,
+        rect: &ImageRect,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send;
+
+    fn write_lcd_fill(
+        &self,
+        data: &[u8],
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send;
+
+    // Sends every queued key image
+    fn flush(&self) -> impl Future<Output = Result<(), StreamDeckError>> + Send;
+
+    fn get_reader(&self) -> Self::Reader;
+}
+
+// Source of input events, read on its own task
+pub trait DeckReader: Send + Sync + 'static {
+    fn read(
+        &self,
+        poll_rate: f32,
+    ) -> impl Future<Output = Result<Vec<DeviceStateUpdate>, StreamDeckError>> + Send;
+}
+
+impl Deck for AsyncStreamDeck {
+    type Reader = Arc<AsyncDeviceStateReader>;
+
+    fn kind(&self) -> Kind {
+        AsyncStreamDeck::kind(self)
+    }
+
+    fn set_brightness(
+        &self,
+        percent: u8,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        AsyncStreamDeck::set_brightness(self, percent)
+    }
+
+    fn write_image(
+        &self,
+        key: u8,
+        data: &[u8],
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        AsyncStreamDeck::write_image(self, key, data)
+    }
+
+    fn clear_button_image(
+        &self,
+        key: u8,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        AsyncStreamDeck::clear_button_image(self, key)
+    }
+
+    fn clear_all_button_images(&self) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        AsyncStreamDeck::clear_all_button_images(self)
+    }
+
+    fn set_touchpoint_color(
+        &self,
+        point: u8,
+        red: u8,
+        green: u8,
+        blue: u8,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        AsyncStreamDeck::set_touchpoint_color(self, point, red, green, blue)
+    }
+
+    fn write_lcd(
+        &self,
+        x: u16,
+        y: u16,
+        rect: &ImageRect,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        AsyncStreamDeck::write_lcd(self, x, y, rect)
+    }
+
+    fn write_lcd_fill(
+        &self,
+        data: &[u8],
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        AsyncStreamDeck::write_lcd_fill(self, data)
+    }
+
+    fn flush(&self) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        AsyncStreamDeck::flush(self)
+    }
+
+    fn get_reader(&self) -> Self::Reader {
+        AsyncStreamDeck::get_reader(self)
+    }
+}
+
+impl DeckReader for Arc<AsyncDeviceStateReader> {
+    fn read(
+        &self,
+        poll_rate: f32,
+    ) -> impl Future<Output = Result<Vec<DeviceStateUpdate>, StreamDeckError>> + Send {
+        AsyncDeviceStateReader::read(self, poll_rate)
+    }
+}
@@ -0,0 +1,1621 @@
+use image::{DynamicImage, Rgb, RgbImage, Rgba, RgbaImage, imageops};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use elgato_streamdeck::DeviceStateUpdate;
+use elgato_streamdeck::images::ImageRect;
+use elgato_streamdeck::info::Kind;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{Instant, sleep_until};
+
+use crate::action::Navigation;
+use crate::animation::Target;
+use crate::audio::{self, Level, Levels};
+use crate::brightness::{Idle, Power, Schedule};
+use crate::config::{AudioDevice, Config, DialTarget};
+use crate::control::{
+    Command, DeckRequest, DeckStatus, DialStatus, EventKind, KeyStatus, Remote, TouchpointStatus,
+};
+use crate::deck::{Deck, DeckReader};
+use crate::dial::Dial;
+use crate::error::{Error, retry};
+use crate::framebuffer::Framebuffer;
+use crate::gesture::{Control, Gesture, GestureTracker, Swipe, classify_swipe};
+use crate::hass::{self, HassState};
+use crate::layout::{Face, Icon, Layout};
+use crate::lcd::Lcd;
+use crate::led::Leds;
+use crate::media::{self, NowPlaying, Request};
+use crate::mqtt;
+use crate::obs;
+use crate::query::{self, Output};
+use crate::render::{self, Fonts, Icons};
+use crate::status;
+
+// How long after a press a key's state command is asked again, so whatever
+// the press changed has taken effect
+const STATE_SETTLE: Duration = Duration::from_millis(500);
+const STATE_TIMEOUT: Duration = Duration::from_secs(5);
+// Media titles too long to fit scroll by this many pixels a second, moved
+// along every tick after holding still at the start for a moment
+const SCROLL_SPEED: f32 = 30.0;
+const SCROLL_TICK: Duration = Duration::from_millis(50);
+const SCROLL_PAUSE: Duration = Duration::from_secs(1);
+
+// What a key's state command said, for the key on `page` as the layout names it
+struct StateAnswer {
+    page: String,
+    key: u8,
+    result: Result<Output, String>,
+}
+
+// Everything about a deck that outlives its connection, so a replugged deck
+// comes back on the page it was showing
+pub struct DeckState {
+    pub layout: Layout,
+    // Starts out as configured, clients can change it while running
+    brightness: u8,
+    // Values of the encoders that have a dial, by encoder
+    dials: HashMap<u8, Dial>,
+    lcd: Lcd,
+    leds: Leds,
+    idle: Idle,
+    schedule: Schedule,
+    // What the media player was playing when the deck last heard, titles
+    // scroll from when the track started showing
+    playing: Option<NowPlaying>,
+    heard: Instant,
+    track_shown: Instant,
+    scroll_due: Option<Instant>,
+    icons: Icons,
+    fonts: Fonts,
+}
+
+impl DeckState {
+    pub fn new(config: &Config, kind: Kind) -> DeckState {
+        let dials = config
+            .encoders
+            .iter()
+            .filter_map(|e| {
+                let dial = Dial::new(e.dial.as_ref()?.get_ref(), config.brightness());
+                Some((*e.index.get_ref(), dial))
+            })
+            .collect();
+        DeckState {
+            layout: Layout::new(config),
+            brightness: config.brightness(),
+            dials,
+            lcd: Lcd::new(config, kind),
+            leds: Leds::new(config, kind),
+            idle: Idle::new(config),
+            schedule: Schedule::new(config),
+            playing: None,
+            heard: Instant::now(),
+            track_shown: Instant::now(),
+            scroll_due: None,
+            icons: Icons::default(),
+            fonts: Fonts::default(),
+        }
+    }
+
+    // Whether any key, zone or dial follows the media player
+    fn uses_media(&self) -> bool {
+        let dials = self
+            .dials
+            .values()
+            .any(|d| matches!(d.target(), DialTarget::Volume | DialTarget::Seek));
+        self.layout.has_media() || self.lcd.has_media() || dials
+    }
+
+    // Whether any key, zone or dial shows a sink or source
+    fn uses_audio(&self) -> bool {
+        let dials = self
+            .dials
+            .values()
+            .any(|d| matches!(d.target(), DialTarget::Sink | DialTarget::Source));
+        self.layout.has_audio() || self.lcd.has_audio() || dials
+    }
+
+    // Entities keys and dials show, none on decks that don't use Home Assistant
+    fn hass_entities(&self) -> Vec<String> {
+        let mut entities = self.layout.hass_entities();
+        entities.extend(
+            self.dials
+                .values()
+                .filter_map(|d| d.entity().map(str::to_string)),
+        );
+        entities.sort();
+        entities.dedup();
+        entities
+    }
+}
+
+// Why the manager stopped a deck's task
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Stop {
+    // The deck is no longer on the bus, there's nothing left to talk to
+    Unplugged,
+    // The program is exiting, the deck is cleared so it doesn't keep showing stale keys
+    Shutdown,
+}
+
+// Paints the profile onto the deck and handles its input until it goes away
+// or `stop` fires, then hands the state back for the next time it's plugged
+// in along with why it ended
+pub async fn run<D: Deck>(
+    device: D,
+    serial: String,
+    config: Arc<Config>,
+    mut state: DeckState,
+    mut remote: Remote,
+    stop: oneshot::Receiver<Stop>,
+) -> (DeckState, Result<Stop, Error>) {
+    let mut framebuffer = Framebuffer::new(device.kind(), config.max_fps(), config.frame_budget());
+    let result = drive(
+        &device,
+        &mut framebuffer,
+        &serial,
+        &config,
+        &mut state,
+        &mut remote,
+        stop,
+    )
+    .await;
+    match &result {
+        Ok(Stop::Unplugged) => println!("{} disconnected", serial),
+        Ok(Stop::Shutdown) => match blank(&device, &mut framebuffer).await {
+            Ok(()) => println!("{} cleared", serial),
+            Err(e) => eprintln!("Failed to clear {}: {}", serial, e),
+        },
+        Err(e) => eprintln!("{} disconnected: {}", serial, e),
+    }
+
+    let stats = framebuffer.stats();
+    println!(
+        "{}: {} uploads, {} skipped as unchanged, {} dropped for newer frames, {} deferred",
+        serial, stats.uploaded, stats.skipped, stats.dropped, stats.deferred
+    );
+    (state, result)
+}
+
+async fn drive<D: Deck>(
+    device: &D,
+    framebuffer: &mut Framebuffer,
+    serial: &str,
+    config: &Config,
+    state: &mut DeckState,
+    remote: &mut Remote,
+    mut stop: oneshot::Receiver<Stop>,
+) -> Result<Stop, Error> {
+    let kind = device.kind();
+
+    // Freshly connected decks keep whatever they showed before, start from
+    // blank and as bright as the schedule says
+    state.idle.reset(Instant::now());
+    let brightness = state.schedule.current().unwrap_or(state.brightness);
+    set_brightness(device, state, brightness).await?;
+    framebuffer.clear_all(device).await?;
+
+    // Media keys, zones and dials follow the media player while connected
+    let mut media = state.uses_media().then(media::watch);
+    if let Some(media) = &mut media {
+        let playing = media.borrow_and_update().clone();
+        show_media(kind, state, playing);
+    }
+    // Audio keys, zones and dials follow the sound server the same way, and
+    // OBS keys light up while what they control is live
+    let mut levels = state.uses_audio().then(audio::watch);
+    if let Some(levels) = &mut levels {
+        let now = *levels.borrow_and_update();
+        show_audio(state, &now);
+    }
+    let mut obs = state.layout.has_obs().then(obs::watch);
+    if let Some(obs) = &mut obs {
+        let now = obs.borrow_and_update().clone();
+        state.layout.show_obs(&now);
+    }
+    // Home Assistant keys and dials follow their entities
+    let entities = state.hass_entities();
+    let mut hass = (!entities.is_empty()).then(|| hass::watch(entities));
+    if let Some(hass) = &mut hass {
+        let now = hass.borrow_and_update().clone();
+        show_hass(state, &now);
+    }
+    // Input goes out to the MQTT broker, and keys and zones bound to a topic
+    // show what comes in on it
+    let mut topics = state.layout.mqtt_topics();
+    topics.extend(state.lcd.mqtt_topics());
+    topics.sort();
+    topics.dedup();
+    let mut broker = mqtt::connect(serial, topics, remote.subscribe());
+
+    println!("{}: key count: {}", serial, kind.key_count());
+    paint_keys(kind, framebuffer, state)?;
+
+    println!("{}: touch point count: {}", serial, kind.touchpoint_count());
+    paint_leds(framebuffer, state);
+
+    paint_lcd(kind, framebuffer, state, true)?;
+
+    // Read input on its own task so running actions never delays it
+    let reader = device.get_reader();
+    let (tx, mut rx) = mpsc::channel(64);
+    let reading = tokio::spawn(async move {
+        loop {
+            match retry(|| reader.read(100.0)).await {
+                Ok(updates) => {
+                    for update in updates {
+                        if tx.send(Ok(update)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                // Passed on so the device loop knows why the deck went away
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            }
+        }
+    });
+    let _reading = AbortOnDrop(reading);
+
+    // Keys with states start out in whatever state their command reports
+    let (answer_tx, mut answers) = mpsc::channel(16);
+    for query in state.layout.state_commands() {
+        ask_state(&answer_tx, query, Duration::ZERO);
+    }
+
+    // Status keys poll on their own tasks, which stop with the connection
+    let (reading_tx, mut readings) = mpsc::channel(16);
+    let _polling: Vec<AbortOnDrop> = state
+        .layout
+        .statuses()
+        .into_iter()
+        .map(|(page, key, s)| AbortOnDrop(status::spawn(s, page, key, reading_tx.clone())))
+        .collect();
+
+    let mut gestures = GestureTracker::new(config.long_press(), config.double_press());
+    // Controls whose press woke the deck, their release is ignored too
+    let mut swallowed = HashSet::new();
+
+    loop {
+        // Long presses and single presses waiting out the double press window
+        let expired = until(gestures.next_deadline());
+        // Next frame of any animation that's playing
+        let animate = until(state.icons.player().next_due());
+        let send = until(framebuffer.next_send());
+        let glow = until(state.leds.next_change());
+        let idling = until(state.idle.next_change());
+        let scheduled = until(state.schedule.next_change());
+        let scroll = until(state.scroll_due);
+
+        // In order of urgency, animations last so a deck that can't keep up
+        // with them still reacts to everything else
+        let fired = tokio::select! {
+            biased;
+
+            update = rx.recv() => {
+                let Some(update) = update else { return Ok(Stop::Unplugged) };
+                let now = Instant::now();
+                let update = update.map_err(|e| Error::DeviceGone {
+                    serial: serial.to_string(),
+                    source: Box::new(e),
+                })?;
+
+                // The input that wakes a dimmed or sleeping deck only does
+                // that, a press meant to wake it can't set anything off
+                if state.idle.input(now).is_some() {
+                    power_changed(device, framebuffer, serial, state, remote).await?;
+                    swallowed.extend(pressed(&update));
+                    continue;
+                }
+                if let Some(control) = released(&update)
+                    && swallowed.remove(&control)
+                {
+                    continue;
+                }
+
+                let layout = &state.layout;
+                match update {
+                    DeviceStateUpdate::ButtonDown(key) => {
+                        let control = Control::Key(key);
+                        println!("{}: button {} down", serial, describe(layout, control));
+                        remote.publish(EventKind::Down { control });
+                        framebuffer.pressed(key);
+                        gestures.down(control, layout.wants(control), now)
+                    }
+                    DeviceStateUpdate::ButtonUp(key) => {
+                        let control = Control::Key(key);
+                        println!("{}: button {} up", serial, key);
+                        remote.publish(EventKind::Up { control });
+                        gestures.up(control, now)
+                    }
+                    DeviceStateUpdate::EncoderTwist(encoder, ticks) => {
+                        println!("{}: dial {} twisted by {}", serial, encoder, ticks);
+                        remote.publish(EventKind::Twist { encoder, ticks });
+                        if let Some(dial) = state.dials.get_mut(&encoder) {
+                            catch_up(dial, state.playing.as_ref(), state.heard, now);
+                            let steps = dial.twist(ticks, now);
+                            if steps != 0 {
+                                dial_changed(device, framebuffer, state, remote, encoder, steps).await?;
+                            }
+                        }
+                        vec![]
+                    }
+                    DeviceStateUpdate::EncoderDown(dial) => {
+                        let control = Control::Encoder(dial);
+                        println!("{}: dial {} down", serial, describe(layout, control));
+                        remote.publish(EventKind::Down { control });
+                        gestures.down(control, layout.wants(control), now)
+                    }
+                    DeviceStateUpdate::EncoderUp(dial) => {
+                        let control = Control::Encoder(dial);
+                        println!("{}: dial {} up", serial, dial);
+                        remote.publish(EventKind::Up { control });
+                        gestures.up(control, now)
+                    }
+
+                    DeviceStateUpdate::TouchPointDown(point) => {
+                        let control = Control::Touchpoint(point);
+                        println!("{}: touch point {} down", serial, describe(layout, control));
+                        remote.publish(EventKind::Down { control });
+                        gestures.down(control, layout.wants(control), now)
+                    }
+                    DeviceStateUpdate::TouchPointUp(point) => {
+                        let control = Control::Touchpoint(point);
+                        println!("{}: touch point {} up", serial, point);
+                        remote.publish(EventKind::Up { control });
+                        gestures.up(control, now)
+                    }
+
+                    // The deck tells taps from holds itself, so these skip the gesture tracker
+                    DeviceStateUpdate::TouchScreenPress(x, y) => {
+                        let zone = state.lcd.zone_at(x, y);
+                        println!("{serial}: touch screen press at {x}, {y}{}", in_zone(zone));
+                        remote.publish(EventKind::Touch { x, y, zone });
+                        let navigation = zone.and_then(|z| touch_zone(layout, z, Gesture::Press));
+                        follow(kind, framebuffer, serial, state, remote, navigation)?;
+                        vec![]
+                    }
+
+                    DeviceStateUpdate::TouchScreenLongPress(x, y) => {
+                        let zone = state.lcd.zone_at(x, y);
+                        println!("{serial}: touch screen long press at {x}, {y}{}", in_zone(zone));
+                        remote.publish(EventKind::LongTouch { x, y, zone });
+                        let navigation = zone.and_then(|z| touch_zone(layout, z, Gesture::LongPress));
+                        follow(kind, framebuffer, serial, state, remote, navigation)?;
+                        vec![]
+                    }
+
+                    DeviceStateUpdate::TouchScreenSwipe(from, to) => {
+                        let ((sx, sy), (ex, ey)) = (from, to);
+                        let direction = classify_swipe(from, to, layout.swipe_distance());
+                        match direction {
+                            Some(d) => println!("{serial}: touch screen swipe {d:?} from {sx}, {sy} to {ex}, {ey}"),
+                            None => println!("{serial}: touch screen swipe from {sx}, {sy} to {ex}, {ey}"),
+                        }
+                        remote.publish(EventKind::Swipe { from, to, direction });
+                        let navigation = direction.and_then(|d| swipe(layout, d));
+                        follow(kind, framebuffer, serial, state, remote, navigation)?;
+                        vec![]
+                    }
+                }
+            }
+
+            Some(command) = remote.commands.recv() => {
+                let Command { request, reply } = command;
+                let result = match command_for(device, framebuffer, serial, state, remote, request).await? {
+                    Ok(()) => Ok(status(serial, kind, state, framebuffer)),
+                    Err(e) => Err(e),
+                };
+                // The client may have hung up already
+                let _ = reply.send(result);
+                vec![]
+            }
+
+            Some(answer) = answers.recv() => {
+                let StateAnswer { page, key, result } = answer;
+                let changed = result.and_then(|output| state.layout.answer_state(&page, key, &output));
+                match changed {
+                    Ok(true) => key_state_changed(kind, framebuffer, state, remote, key)?,
+                    Ok(false) => {}
+                    Err(e) => eprintln!("{}: state command of key {}: {}", serial, key, e),
+                }
+                vec![]
+            }
+
+            Some((page, key, reading)) = readings.recv() => {
+                if state.layout.show_status(&page, key, reading) {
+                    paint_keys(kind, framebuffer, state)?;
+                }
+                vec![]
+            }
+
+            Ok(()) = watched(&mut media) => {
+                let playing = media.as_mut().and_then(|m| m.borrow_and_update().clone());
+                if show_media(kind, state, playing) {
+                    paint_keys(kind, framebuffer, state)?;
+                }
+                paint_lcd(kind, framebuffer, state, false)?;
+                vec![]
+            }
+
+            Ok(()) = watched(&mut levels) => {
+                let now = levels.as_mut().map(|l| *l.borrow_and_update()).unwrap_or_default();
+                if show_audio(state, &now) {
+                    paint_keys(kind, framebuffer, state)?;
+                }
+                paint_lcd(kind, framebuffer, state, false)?;
+                vec![]
+            }
+
+            Ok(()) = watched(&mut obs) => {
+                let now = obs.as_mut().map(|o| o.borrow_and_update().clone()).unwrap_or_default();
+                if state.layout.show_obs(&now) {
+                    paint_keys(kind, framebuffer, state)?;
+                }
+                vec![]
+            }
+
+            Ok(()) = watched(&mut hass) => {
+                let now = hass.as_mut().map(|h| h.borrow_and_update().clone()).unwrap_or_default();
+                if show_hass(state, &now) {
+                    paint_keys(kind, framebuffer, state)?;
+                }
+                paint_lcd(kind, framebuffer, state, false)?;
+                vec![]
+            }
+
+            Some(message) = received(&mut broker) => {
+                if state.layout.show_mqtt(&message, &mut state.icons) {
+                    paint_keys(kind, framebuffer, state)?;
+                }
+                state.lcd.show_mqtt(&message, &mut state.icons);
+                paint_lcd(kind, framebuffer, state, false)?;
+                vec![]
+            }
+
+            // The manager no longer sees the deck or is shutting down
+            reason = &mut stop => return Ok(reason.unwrap_or(Stop::Unplugged)),
+
+            _ = expired => gestures.expire(Instant::now()),
+
+            // Everything drawn since the last frame goes out together
+            _ = send => {
+                framebuffer.send(device).await?;
+                vec![]
+            }
+
+            _ = glow => {
+                paint_leds(framebuffer, state);
+                vec![]
+            }
+
+            _ = idling => {
+                if state.idle.update(Instant::now()) {
+                    power_changed(device, framebuffer, serial, state, remote).await?;
+                }
+                vec![]
+            }
+
+            _ = scheduled => {
+                if let Some(brightness) = state.schedule.current() {
+                    println!("{}: brightness {}% as scheduled", serial, brightness);
+                    set_brightness(device, state, brightness).await?;
+                    paint_lcd(kind, framebuffer, state, false)?;
+                }
+                vec![]
+            }
+
+            _ = scroll => {
+                paint_scrolling(kind, framebuffer, state)?;
+                state.scroll_due = Some(Instant::now() + SCROLL_TICK);
+                vec![]
+            }
+
+            _ = animate => {
+                paint_frames(kind, framebuffer, state)?;
+                vec![]
+            }
+        };
+
+        let mut page_changed = false;
+        for (control, gesture) in fired {
+            remote.publish(EventKind::Gesture { control, gesture });
+            if let (Control::Encoder(encoder), Gesture::Press) = (control, gesture)
+                && state
+                    .dials
+                    .get_mut(&encoder)
+                    .is_some_and(|dial| dial.press())
+            {
+                dial_changed(device, framebuffer, state, remote, encoder, 0).await?;
+            }
+            let navigation = fire(&state.layout, control, gesture);
+            // The press acted on the state the key showed, it moves on
+            // before any page change takes it off the deck
+            if let (Control::Key(key), Gesture::Press) = (control, gesture)
+                && state.layout.advance(key)
+            {
+                key_state_changed(kind, framebuffer, state, remote, key)?;
+                if let Some(query) = state.layout.state_command(key) {
+                    ask_state(&answer_tx, query, STATE_SETTLE);
+                }
+            }
+            if let Some(navigation) = navigation {
+                page_changed |= state.layout.navigate(&navigation);
+            }
+        }
+        if page_changed {
+            show_page(kind, framebuffer, serial, state, remote)?;
+        }
+    }
+}
+
+// Puts a dial's new value into effect: runs its actions, moves the deck's
+// brightness, the media player, the sound server or a Home Assistant entity
+// if that's what it drives, and redraws its part of the LCD
+async fn dial_changed<D: Deck>(
+    device: &D,
+    framebuffer: &mut Framebuffer,
+    state: &mut DeckState,
+    remote: &Remote,
+    encoder: u8,
+    steps: i32,
+) -> Result<(), Error> {
+    let Some(dial) = state.dials.get(&encoder) else {
+        return Ok(());
+    };
+    dial.run_actions(steps);
+    remote.publish(EventKind::Dial {
+        encoder,
+        value: dial.value(),
+        text: dial.display(),
+    });
+
+    match dial.target() {
+        DialTarget::Brightness => {
+            // Through the idle state, so a dimmed or sleeping deck stays dark
+            let percent = dial.value().round() as u8;
+            set_brightness(device, state, percent).await?;
+        }
+        DialTarget::Volume => media::send(Request::Volume(dial.value())),
+        DialTarget::Seek => media::send(Request::Position(dial.value())),
+        DialTarget::Sink | DialTarget::Source => {
+            let device = match dial.target() {
+                DialTarget::Sink => AudioDevice::Sink,
+                _ => AudioDevice::Source,
+            };
+            let level = Level {
+                volume: dial.value(),
+                muted: dial.muted(),
+            };
+            audio::send(audio::Request::Set(device, level));
+        }
+        DialTarget::Entity => {
+            if let Some(entity) = dial.entity() {
+                hass::send(hass::Request::Level {
+                    entity: entity.to_string(),
+                    value: dial.value(),
+                });
+            }
+        }
+        DialTarget::Value => {}
+    }
+    state.lcd.encoder_changed(encoder);
+    paint_lcd(device.kind(), framebuffer, state, false)
+}
+
+// Sets how bright the deck is when it's awake, a dimmed or sleeping deck
+// stays as dark as it is. Brightness dials follow along so their next tick
+// starts from here.
+async fn set_brightness<D: Deck>(
+    device: &D,
+    state: &mut DeckState,
+    percent: u8,
+) -> Result<(), Error> {
+    let shown = state.idle.brightness(percent);
+    retry(|| device.set_brightness(shown)).await?;
+    state.brightness = percent;
+
+    let synced: Vec<u8> = state
+        .dials
+        .iter_mut()
+        .filter(|(_, dial)| dial.target() == DialTarget::Brightness)
+        .filter_map(|(encoder, dial)| dial.set(percent as f64).then_some(*encoder))
+        .collect();
+    for encoder in synced {
+        state.lcd.encoder_changed(encoder);
+    }
+    Ok(())
+}
+
+// Puts the deck's new power level into effect: its brightness and, for a
+// deck that fell asleep or woke up, the screensaver or the keys again
+async fn power_changed<D: Deck>(
+    device: &D,
+    framebuffer: &mut Framebuffer,
+    serial: &str,
+    state: &mut DeckState,
+    remote: &Remote,
+) -> Result<(), Error> {
+    let power = state.idle.power();
+    let what = match power {
+        Power::Awake => "woke up",
+        Power::Dimmed => "dimmed",
+        Power::Asleep => "fell asleep",
+    };
+    println!("{}: {}", serial, what);
+    remote.publish(EventKind::Power { power });
+
+    let brightness = state.idle.brightness(state.brightness);
+    retry(|| device.set_brightness(brightness)).await?;
+    paint_keys(device.kind(), framebuffer, state)
+}
+
+// Puts what the media player is playing on media keys, zones and dials, and
+// starts or stops titles scrolling. Returns whether a key on the current page
+// changed.
+fn show_media(kind: Kind, state: &mut DeckState, playing: Option<NowPlaying>) -> bool {
+    let track = |p: &Option<NowPlaying>| p.as_ref().map(|p| (p.title.clone(), p.art.clone()));
+    if track(&playing) != track(&state.playing) {
+        state.track_shown = Instant::now();
+        // Players tend to write the art of every track to the same file
+        if let Some(art) = playing.as_ref().and_then(|p| p.art.as_ref()) {
+            state.icons.forget(art);
+        }
+    }
+
+    let now = Instant::now();
+    for (encoder, dial) in &mut state.dials {
+        if dial.is_turning(now) {
+            continue;
+        }
+        let changed = match dial.target() {
+            DialTarget::Volume => playing
+                .as_ref()
+                .and_then(|p| p.volume)
+                .is_some_and(|volume| dial.set(volume)),
+            DialTarget::Seek => {
+                let moved = dial.set_max(playing.as_ref().and_then(|p| p.length));
+                let position = playing.as_ref().and_then(|p| p.position);
+                moved | position.is_some_and(|position| dial.set(position))
+            }
+            _ => false,
+        };
+        if changed {
+            state.lcd.encoder_changed(*encoder);
+        }
+    }
+
+    let changed = state.layout.show_media(playing.as_ref());
+    state.lcd.show_media(playing.as_ref());
+    state.playing = playing;
+    state.heard = now;
+    rescroll(kind, state);
+    changed
+}
+
+// Moves a seek dial that isn't being turned to where the track has got to
+// since the player last said, so a twist starts from there
+fn catch_up(dial: &mut Dial, playing: Option<&NowPlaying>, heard: Instant, now: Instant) {
+    if dial.target() == DialTarget::Seek
+        && !dial.is_turning(now)
+        && let Some(position) = playing.and_then(|p| p.position_at(heard, now))
+    {
+        dial.set(position);
+    }
+}
+
+// Puts the levels of the sink and source on audio keys, zones and dials.
+// Returns whether a key on the current page changed.
+fn show_audio(state: &mut DeckState, levels: &Levels) -> bool {
+    let now = Instant::now();
+    for (encoder, dial) in &mut state.dials {
+        let level = match dial.target() {
+            DialTarget::Sink => levels.sink,
+            DialTarget::Source => levels.source,
+            _ => continue,
+        };
+        if dial.is_turning(now) {
+            continue;
+        }
+        if let Some(level) = level
+            && (dial.set(level.volume) | dial.set_muted(level.muted))
+        {
+            state.lcd.encoder_changed(*encoder);
+        }
+    }
+
+    state.lcd.show_audio(levels);
+    state.layout.show_audio(levels)
+}
+
+// Puts the state of their entities on Home Assistant keys and dials. Returns
+// whether a key on the current page changed.
+fn show_hass(state: &mut DeckState, hass: &HassState) -> bool {
+    let now = Instant::now();
+    for (encoder, dial) in &mut state.dials {
+        let Some(id) = dial.entity() else { continue };
+        let Some(entity) = hass.get(id) else { continue };
+        if dial.is_turning(now) {
+            continue;
+        }
+        let range = entity.range(id);
+        if let Some(level) = entity.level(id)
+            && dial.follow(level, range)
+        {
+            state.lcd.encoder_changed(*encoder);
+        }
+    }
+
+    state.layout.show_hass(hass)
+}
+
+// Waits for the media player, sound server, OBS or Home Assistant to change,
+// forever on decks that don't show it
+async fn watched<T>(
+    watched: &mut Option<watch::Receiver<T>>,
+) -> Result<(), watch::error::RecvError> {
+    match watched {
+        Some(watched) => watched.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Waits for a message on a topic the deck follows, forever without a broker
+async fn received(broker: &mut Option<mqtt::Connection>) -> Option<mqtt::Message> {
+    match broker {
+        Some(broker) => broker.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Keeps titles scrolling while any media title showing is too long to fit
+fn rescroll(kind: Kind, state: &mut DeckState) {
+    let DeckState {
+        layout, lcd, fonts, ..
+    } = state;
+    let (w, h) = kind.key_image_format().size;
+    let keys = kind.is_visual()
+        && layout.media_keys().into_iter().any(|key| {
+            let face = layout.face(key);
+            let title = face.title.as_deref().filter(|_| face.scroll);
+            title.is_some_and(|t| render::overflows(t, &face.text, fonts, (w as u32, h as u32)))
+        });
+    state.scroll_due = match keys || lcd.scrolls(fonts) {
+        true => state.scroll_due.or(Some(Instant::now() + SCROLL_TICK)),
+        false => None,
+    };
+}
+
+// How far scrolling titles have moved since the track started showing
+fn scrolled(state: &DeckState) -> f32 {
+    let moving = state.track_shown.elapsed().saturating_sub(SCROLL_PAUSE);
+    moving.as_secs_f32() * SCROLL_SPEED
+}
+
+// Tells clients which state a key on the current page is in now and redraws it
+fn key_state_changed(
+    kind: Kind,
+    framebuffer: &mut Framebuffer,
+    state: &mut DeckState,
+    remote: &Remote,
+    key: u8,
+) -> Result<(), Error> {
+    if let Some(name) = state.layout.key_state(key) {
+        remote.publish(EventKind::KeyState {
+            key,
+            state: name.to_string(),
+        });
+    }
+    paint_keys(kind, framebuffer, state)
+}
+
+// Runs a key's state command on its own task after `delay`, the answer comes
+// back to the device loop
+fn ask_state(
+    answers: &mpsc::Sender<StateAnswer>,
+    (page, key, command): (String, u8, String),
+    delay: Duration,
+) {
+    let answers = answers.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        let result = query::run(&command, STATE_TIMEOUT).await;
+        // The deck may be gone by now
+        let _ = answers.send(StateAnswer { page, key, result }).await;
+    });
+}
+
+// Carries out a page change from an action that isn't tied to a gesture
+fn follow(
+    kind: Kind,
+    framebuffer: &mut Framebuffer,
+    serial: &str,
+    state: &mut DeckState,
+    remote: &Remote,
+    navigation: Option<Navigation>,
+) -> Result<(), Error> {
+    match navigation {
+        Some(navigation) if state.layout.navigate(&navigation) => {
+            show_page(kind, framebuffer, serial, state, remote)
+        }
+        _ => Ok(()),
+    }
+}
+
+// Repaints the keys after the page changed and tells clients about it
+fn show_page(
+    kind: Kind,
+    framebuffer: &mut Framebuffer,
+    serial: &str,
+    state: &mut DeckState,
+    remote: &Remote,
+) -> Result<(), Error> {
+    let page = state.layout.current().to_string();
+    println!("{}: showing page {}", serial, page);
+    remote.publish(EventKind::Page { page });
+    rescroll(kind, state);
+    paint_keys(kind, framebuffer, state)
+}
+
+// Carries out a client's request. The outer error means the deck is gone,
+// the inner one is the client's mistake and only goes back to them.
+async fn command_for<D: Deck>(
+    device: &D,
+    framebuffer: &mut Framebuffer,
+    serial: &str,
+    state: &mut DeckState,
+    remote: &Remote,
+    request: DeckRequest,
+) -> Result<Result<(), String>, Error> {
+    let kind = device.kind();
+    let check_key = |key: u8| {
+        if key < kind.key_count() {
+            Ok(())
+        } else {
+            Err(format!("{:?} has no key {}", kind, key))
+        }
+    };
+
+    let changed = match request {
+        DeckRequest::State => false,
+        DeckRequest::SetImage { key, path } => {
+            if let Err(e) = check_key(key) {
+                return Ok(Err(e));
+            }
+            if let Some(path) = &path {
+                // The file may have been rewritten since it was last shown
+                state.icons.forget(path);
+                if state.icons.get(path).is_none() {
+                    return Ok(Err(format!("can't load image {}", path.display())));
+                }
+            }
+            state.layout.set_icon(key, path)
+        }
+        DeckRequest::SetText { key, text } => {
+            if let Err(e) = check_key(key) {
+                return Ok(Err(e));
+            }
+            state.layout.set_title(key, text)
+        }
+        DeckRequest::Toggle { key, state: name } => {
+            if let Err(e) = check_key(key) {
+                return Ok(Err(e));
+            }
+            let changed = match name {
+                Some(name) => state.layout.set_key_state(key, &name),
+                None if state.layout.advance(key) => Ok(true),
+                None => Err(format!("key {} has no states", key)),
+            };
+            match changed {
+                Ok(true) => key_state_changed(kind, framebuffer, state, remote, key)?,
+                Ok(false) => {}
+                Err(e) => return Ok(Err(e)),
+            }
+            false
+        }
+        DeckRequest::Navigate(navigation) => {
+            if let Navigation::Open(page) = &navigation
+                && !state.layout.has_page(page)
+            {
+                return Ok(Err(format!("no page '{}'", page)));
+            }
+            if state.layout.navigate(&navigation) {
+                show_page(kind, framebuffer, serial, state, remote)?;
+            }
+            false
+        }
+        DeckRequest::Brightness(percent) => {
+            set_brightness(device, state, percent.min(100)).await?;
+            paint_lcd(kind, framebuffer, state, false)?;
+            false
+        }
+        DeckRequest::Led {
+            touchpoint,
+            state: led,
+        } => {
+            if let Err(e) = state.leds.set_state(touchpoint, led) {
+                return Ok(Err(e));
+            }
+            paint_leds(framebuffer, state);
+            false
+        }
+        DeckRequest::SetZone { zone, value, text } => {
+            if let Err(e) = state.lcd.update(&zone, value, text) {
+                return Ok(Err(e));
+            }
+            paint_lcd(kind, framebuffer, state, false)?;
+            false
+        }
+    };
+
+    if changed {
+        paint_keys(kind, framebuffer, state)?;
+    }
+    Ok(Ok(()))
+}
+
+// What `state` reports to clients
+fn status(serial: &str, kind: Kind, state: &DeckState, framebuffer: &Framebuffer) -> DeckStatus {
+    let keys = (0..kind.key_count())
+        .filter_map(|index| {
+            let face = state.layout.face(index);
+            if face.is_blank() {
+                return None;
+            }
+            let icon = match face.icon {
+                Icon::File(path) | Icon::Back(Some(path)) => Some(path),
+                Icon::None | Icon::Back(None) => None,
+            };
+            Some(KeyStatus {
+                index,
+                title: face.title,
+                icon,
+                state: state.layout.key_state(index).map(str::to_string),
+            })
+        })
+        .collect();
+
+    let mut dials: Vec<DialStatus> = state
+        .dials
+        .iter()
+        .map(|(encoder, dial)| DialStatus {
+            encoder: *encoder,
+            value: dial.value(),
+            text: dial.display(),
+        })
+        .collect();
+    dials.sort_by_key(|d| d.encoder);
+
+    DeckStatus {
+        serial: serial.to_string(),
+        kind: format!("{:?}", kind),
+        page: state.layout.current().to_string(),
+        brightness: state.brightness,
+        power: state.idle.power(),
+        keys,
+        dials,
+        touchpoints: (0..kind.touchpoint_count())
+            .map(|index| TouchpointStatus {
+                index,
+                state: state.leds.state(index).map(str::to_string),
+            })
+            .collect(),
+        frames: framebuffer.stats(),
+    }
+}
+
+// Leaves every key, the LCD and the touch points dark
+async fn blank<D: Deck>(device: &D, framebuffer: &mut Framebuffer) -> Result<(), Error> {
+    framebuffer.clear_all(device).await?;
+    framebuffer.clear_lcd(device).await?;
+    framebuffer.clear_touchpoints(device).await?;
+    retry(|| device.flush()).await
+}
+
+// Waits for the deadline, forever without one. Deadlines that already passed
+// are ready right away, even when a long paint kept the timer from catching up.
+async fn until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) if deadline > Instant::now() => sleep_until(deadline).await,
+        Some(_) => {}
+        None => std::future::pending().await,
+    }
+}
+
+// The reader task would otherwise keep polling a deck nobody listens to
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// Sets every touch point to the color its state and effect call for
+fn paint_leds(framebuffer: &mut Framebuffer, state: &DeckState) {
+    for (point, color) in state.leds.colors(Instant::now()) {
+        framebuffer.set_touchpoint(point, color);
+    }
+}
+
+// Draws every key of the current page, the framebuffer drops the ones the deck
+// already shows and queues the rest for the next frame
+fn paint_keys(
+    kind: Kind,
+    framebuffer: &mut Framebuffer,
+    state: &mut DeckState,
+) -> Result<(), Error> {
+    if !kind.is_visual() {
+        return Ok(());
+    }
+
+    let scrolled = scrolled(state);
+    let DeckState {
+        layout,
+        idle,
+        icons,
+        fonts,
+        ..
+    } = state;
+
+    if let Some(screensaver) = idle.screensaver() {
+        return paint_screensaver(kind, framebuffer, icons, screensaver);
+    }
+    icons.player().stop(&Target::Screensaver);
+
+    // Animations on the page that was showing hold still until it's back
+    let page = layout.current();
+    icons.player().show_page(page);
+
+    for key in 0..kind.key_count() {
+        let face = layout.face(key);
+        let image = key_image(kind, &face, page, key, scrolled, icons, fonts);
+        match image {
+            Some(image) => framebuffer.set_key(key, image)?,
+            None => framebuffer.clear_key(key),
+        }
+    }
+    Ok(())
+}
+
+// Draws the next frame of every animation that's due, they go out with
+// whatever else changed in the next frame
+fn paint_frames(
+    kind: Kind,
+    framebuffer: &mut Framebuffer,
+    state: &mut DeckState,
+) -> Result<(), Error> {
+    let scrolled = scrolled(state);
+    let DeckState {
+        layout,
+        lcd,
+        idle,
+        icons,
+        fonts,
+        ..
+    } = state;
+
+    for target in icons.player().advance(Instant::now()) {
+        match target {
+            Target::Screensaver => {
+                if let Some(screensaver) = idle.screensaver() {
+                    paint_screensaver(kind, framebuffer, icons, screensaver)?;
+                }
+            }
+            // The screensaver covers the keys while it's showing
+            Target::Key { .. } if idle.screensaver().is_some() => {}
+            Target::Key { page, key } if page == layout.current() => {
+                let face = layout.face(key);
+                if let Some(image) = key_image(kind, &face, &page, key, scrolled, icons, fonts) {
+                    framebuffer.set_key(key, image)?;
+                }
+            }
+            Target::Key { .. } => {}
+            Target::Zone(zone) => lcd.redraw(zone),
+        }
+    }
+
+    paint_lcd(kind, framebuffer, state, false)
+}
+
+// Moves scrolling media titles along on the keys and the LCD
+fn paint_scrolling(
+    kind: Kind,
+    framebuffer: &mut Framebuffer,
+    state: &mut DeckState,
+) -> Result<(), Error> {
+    let scrolled = scrolled(state);
+    state.lcd.scroll(scrolled);
+    let DeckState {
+        layout,
+        idle,
+        icons,
+        fonts,
+        ..
+    } = state;
+
+    if kind.is_visual() && idle.screensaver().is_none() {
+        let page = layout.current();
+        for key in layout.media_keys() {
+            let face = layout.face(key);
+            if let Some(image) = key_image(kind, &face, page, key, scrolled, icons, fonts) {
+                framebuffer.set_key(key, image)?;
+            }
+        }
+    }
+    paint_lcd(kind, framebuffer, state, false)
+}
+
+// Spreads the screensaver over all keys as if they were one screen
+fn paint_screensaver(
+    kind: Kind,
+    framebuffer: &mut Framebuffer,
+    icons: &mut Icons,
+    path: &Path,
+) -> Result<(), Error> {
+    let Some(image) = icons.frame(Target::Screensaver, path) else {
+        for key in 0..kind.key_count() {
+            framebuffer.clear_key(key);
+        }
+        return Ok(());
+    };
+
+    let (w, h) = kind.key_image_format().size;
+    let (w, h) = (w as u32, h as u32);
+    let columns = kind.column_count() as u32;
+    let rows = kind.row_count() as u32;
+    let image = image.resize_to_fill(columns * w, rows * h, imageops::FilterType::Triangle);
+    for key in 0..kind.key_count() {
+        let (column, row) = (key as u32 % columns, key as u32 / columns);
+        framebuffer.set_key(key, image.crop_imm(column * w, row * h, w, h))?;
+    }
+    Ok(())
+}
+
+// Background, icon and title composed at the key's exact resolution, `None`
+// for keys with nothing to show. Titles that scroll have moved `scrolled`
+// pixels along.
+fn key_image(
+    kind: Kind,
+    face: &Face,
+    page: &str,
+    key: u8,
+    scrolled: f32,
+    icons: &mut Icons,
+    fonts: &mut Fonts,
+) -> Option<DynamicImage> {
+    let target = Target::Key {
+        page: page.to_string(),
+        key,
+    };
+    if face.is_blank() {
+        icons.player().stop(&target);
+        return None;
+    }
+
+    let (w, h) = kind.key_image_format().size;
+    let (w, h) = (w as u32, h as u32);
+    let [r, g, b] = face.background.unwrap_or([0, 0, 0]);
+    let mut canvas = RgbaImage::from_pixel(w, h, Rgba([r, g, b, 255]));
+
+    let icon = match &face.icon {
+        Icon::File(path) => icons.frame(target, path),
+        Icon::Back(Some(path)) => icons.frame(target, path).or_else(|| Some(back_arrow(kind))),
+        Icon::None => {
+            icons.player().stop(&target);
+            None
+        }
+        Icon::Back(None) => {
+            icons.player().stop(&target);
+            Some(back_arrow(kind))
+        }
+    };
+    if let Some(icon) = icon {
+        let icon = icon.resize_to_fill(w, h, imageops::FilterType::Nearest);
+        imageops::overlay(&mut canvas, &icon.to_rgba8(), 0, 0);
+    }
+
+    match &face.title {
+        Some(title) if face.scroll => {
+            render::draw_marquee(&mut canvas, title, &face.text, fonts, scrolled)
+        }
+        Some(title) => render::draw_text(&mut canvas, title, &face.text, fonts),
+        None => {}
+    }
+
+    Some(DynamicImage::ImageRgba8(canvas))
+}
+
+// Draws the LCD zones that changed, or all of them on a freshly connected
+// deck. The Plus takes each zone on its own, the Neo only the whole strip.
+fn paint_lcd(
+    kind: Kind,
+    framebuffer: &mut Framebuffer,
+    state: &mut DeckState,
+    all: bool,
+) -> Result<(), Error> {
+    let DeckState {
+        lcd,
+        dials,
+        icons,
+        fonts,
+        ..
+    } = state;
+    let dirty = lcd.take_dirty(all);
+    if dirty.is_empty() {
+        return Ok(());
+    }
+
+    if kind != Kind::Plus {
+        let image = lcd.render_all(dials, icons, fonts);
+        return framebuffer.fill_lcd(image);
+    }
+    for zone in dirty {
+        let (x, y, image) = lcd.render(zone, dials, icons, fonts);
+        let rect = ImageRect::from_image(image)?;
+        framebuffer.write_lcd(x, y, rect);
+    }
+    Ok(())
+}
+
+// Default icon for the automatic back key on sub-pages
+fn back_arrow(kind: Kind) -> DynamicImage {
+    let size = kind.key_image_format().size.0 as i32;
+    let mid = size / 2;
+
+    let image = RgbImage::from_fn(size as u32, size as u32, |x, y| {
+        let (x, y) = (x as i32, y as i32);
+        let head = x >= size / 4 && x < size / 2 && (y - mid).abs() <= x - size / 4;
+        let shaft = x >= size / 2 && x < size * 3 / 4 && (y - mid).abs() <= size / 12;
+        if head || shaft {
+            Rgb([255, 255, 255])
+        } else {
+            Rgb([40, 40, 40])
+        }
+    });
+
+    DynamicImage::ImageRgb8(image)
+}
+
+fn describe(layout: &Layout, control: Control) -> String {
+    let index = match control {
+        Control::Key(i) | Control::Encoder(i) | Control::Touchpoint(i) => i,
+    };
+    match layout.label(control) {
+        Some(label) => format!("{} '{}'", index, label),
+        None => index.to_string(),
+    }
+}
+
+// The control an update presses down, if any
+fn pressed(update: &DeviceStateUpdate) -> Option<Control> {
+    match *update {
+        DeviceStateUpdate::ButtonDown(key) => Some(Control::Key(key)),
+        DeviceStateUpdate::EncoderDown(dial) => Some(Control::Encoder(dial)),
+        DeviceStateUpdate::TouchPointDown(point) => Some(Control::Touchpoint(point)),
+        _ => None,
+    }
+}
+
+// The control an update lets go of, if any
+fn released(update: &DeviceStateUpdate) -> Option<Control> {
+    match *update {
+        DeviceStateUpdate::ButtonUp(key) => Some(Control::Key(key)),
+        DeviceStateUpdate::EncoderUp(dial) => Some(Control::Encoder(dial)),
+        DeviceStateUpdate::TouchPointUp(point) => Some(Control::Touchpoint(point)),
+        _ => None,
+    }
+}
+
+fn in_zone(zone: Option<usize>) -> String {
+    match zone {
+        Some(zone) => format!(" in zone {}", zone),
+        None => String::new(),
+    }
+}
+
+fn touch_zone(layout: &Layout, zone: usize, gesture: Gesture) -> Option<Navigation> {
+    let bindings = layout.zone_bindings(zone)?;
+    match bindings.run(gesture) {
+        Ok(navigation) => navigation,
+        Err(e) => {
+            eprintln!("Zone {} {:?}: {}", zone, gesture, e);
+            None
+        }
+    }
+}
+
+fn swipe(layout: &Layout, direction: Swipe) -> Option<Navigation> {
+    match layout.swipe(direction)?.run() {
+        Ok(navigation) => navigation,
+        Err(e) => {
+            eprintln!("Swipe {:?}: {}", direction, e);
+            None
+        }
+    }
+}
+
+fn fire(layout: &Layout, control: Control, gesture: Gesture) -> Option<Navigation> {
+    let bindings = layout.bindings(control)?;
+    match bindings.run(gesture) {
+        Ok(navigation) => navigation,
+        Err(e) => {
+            eprintln!("{:?} {:?}: {}", control, gesture, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::Hub;
+    use crate::sim::{Frame, Injector, SimDeck};
+
+    const SERIAL: &str = "TEST";
+
+    type Driving = tokio::task::JoinHandle<(DeckState, Result<Stop, Error>)>;
+
+    // Drives a simulated deck with the config on its own task, like the manager does
+    fn start(source: &str, kind: Kind) -> (SimDeck, Injector, oneshot::Sender<Stop>, Driving) {
+        let config = Config::parse(Path::new("test.toml"), source.to_string()).unwrap();
+        config.validate(kind).unwrap();
+        let (deck, injector) = SimDeck::new(kind, None);
+        let (stop, stopped) = oneshot::channel();
+        let device = deck.clone();
+        let driving = tokio::spawn(async move {
+            let mut framebuffer = Framebuffer::new(kind, config.max_fps(), config.frame_budget());
+            let mut state = DeckState::new(&config, kind);
+            let mut remote = Hub::new().attach(SERIAL, kind);
+            let result = drive(
+                &device,
+                &mut framebuffer,
+                SERIAL,
+                &config,
+                &mut state,
+                &mut remote,
+                stopped,
+            )
+            .await;
+            (state, result)
+        });
+        (deck, injector, stop, driving)
+    }
+
+    // Gives the deck time to handle input and send the frames that follow
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    // Images sent to a key so far, oldest first
+    fn images(deck: &SimDeck, key: u8) -> Vec<Vec<u8>> {
+        deck.frames()
+            .into_iter()
+            .filter_map(|frame| match frame {
+                Frame::Key { key: k, data } if k == key => Some(data),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn brightness(deck: &SimDeck) -> Vec<u8> {
+        deck.frames()
+            .into_iter()
+            .filter_map(|frame| match frame {
+                Frame::Brightness(percent) => Some(percent),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn press(key: u8) -> Vec<DeviceStateUpdate> {
+        vec![
+            DeviceStateUpdate::ButtonDown(key),
+            DeviceStateUpdate::ButtonUp(key),
+        ]
+    }
+
+    const STATES: &str = r#"
+brightness = 40
+
+[[keys]]
+index = 1
+title = "Mic"
+
+[[keys.states]]
+name = "muted"
+background = [0, 0, 0]
+
+[[keys.states]]
+name = "live"
+background = [200, 0, 0]
+"#;
+
+    #[tokio::test]
+    async fn starts_as_configured() {
+        let (deck, _injector, stop, driving) = start(STATES, Kind::Mk2);
+        settle().await;
+
+        assert_eq!(brightness(&deck), vec![40]);
+        // Cleared first, then painted
+        let painted = images(&deck, 1);
+        assert!(painted.len() >= 2);
+        assert_ne!(painted.first(), painted.last());
+
+        stop.send(Stop::Shutdown).unwrap();
+        let (_, result) = driving.await.unwrap();
+        assert_eq!(result.unwrap(), Stop::Shutdown);
+    }
+
+    #[tokio::test]
+    async fn press_steps_key_state() {
+        let (deck, injector, _stop, _driving) = start(STATES, Kind::Mk2);
+        settle().await;
+        let muted = images(&deck, 1).pop().unwrap();
+
+        injector.inject(press(1));
+        settle().await;
+        let live = images(&deck, 1).pop().unwrap();
+        assert_ne!(live, muted);
+
+        // Only the pressed key changed
+        let untouched = images(&deck, 0).len();
+        injector.inject(press(1));
+        settle().await;
+        assert_eq!(images(&deck, 1).pop().unwrap(), muted);
+        assert_eq!(images(&deck, 0).len(), untouched);
+    }
+
+    const PAGES: &str = r#"
+[[keys]]
+index = 3
+title = "Media"
+on_press = { type = "page", page = "media" }
+
+[pages.media]
+back_key = 0
+
+[[pages.media.keys]]
+index = 3
+title = "Next"
+"#;
+
+    #[tokio::test]
+    async fn page_switch_repaints_keys() {
+        let (deck, injector, _stop, _driving) = start(PAGES, Kind::Mk2);
+        settle().await;
+        let home = [0, 3].map(|key| images(&deck, key).pop().unwrap());
+
+        injector.inject(press(3));
+        settle().await;
+        let media = [0, 3].map(|key| images(&deck, key).pop().unwrap());
+        // The back key shows up and key 3 shows the page's own key
+        assert_ne!(media[0], home[0]);
+        assert_ne!(media[1], home[1]);
+
+        // Back home, both keys look like they did
+        injector.inject(press(0));
+        settle().await;
+        assert_eq!([0, 3].map(|key| images(&deck, key).pop().unwrap()), home);
+    }
+
+    #[tokio::test]
+    async fn brightness_dial_sets_brightness() {
+        let source = r#"
+brightness = 50
+
+[[encoders]]
+index = 2
+dial = { target = "brightness", step = 5, press = "reset" }
+"#;
+        let (deck, injector, _stop, _driving) = start(source, Kind::Plus);
+        settle().await;
+
+        injector.inject(vec![DeviceStateUpdate::EncoderTwist(2, 2)]);
+        settle().await;
+        injector.inject(vec![DeviceStateUpdate::EncoderTwist(2, -1)]);
+        settle().await;
+        assert_eq!(brightness(&deck), vec![50, 60, 55]);
+
+        // Pressed, it goes back to where it started
+        injector.inject(vec![
+            DeviceStateUpdate::EncoderDown(2),
+            DeviceStateUpdate::EncoderUp(2),
+        ]);
+        settle().await;
+        assert_eq!(brightness(&deck).last(), Some(&50));
+    }
+
+    #[tokio::test]
+    async fn waking_press_is_swallowed() {
+        let source = format!("{}\n[idle]\ndim_after_s = 1\ndim_brightness = 5\n", STATES);
+        let (deck, injector, _stop, _driving) = start(&source, Kind::Mk2);
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert_eq!(brightness(&deck), vec![40, 5]);
+        let muted = images(&deck, 1).pop().unwrap();
+
+        // Down wakes the dimmed deck, neither it nor the up it comes with
+        // step the key
+        injector.inject(vec![DeviceStateUpdate::ButtonDown(1)]);
+        settle().await;
+        assert_eq!(brightness(&deck), vec![40, 5, 40]);
+        injector.inject(vec![DeviceStateUpdate::ButtonUp(1)]);
+        settle().await;
+        assert_eq!(images(&deck, 1).pop().unwrap(), muted);
+
+        // The next press does
+        injector.inject(press(1));
+        settle().await;
+        assert_ne!(images(&deck, 1).pop().unwrap(), muted);
+    }
+
+    #[tokio::test]
+    async fn unplugged_deck_is_gone() {
+        let (deck, injector, _stop, driving) = start(PAGES, Kind::Mk2);
+        settle().await;
+        injector.inject(press(3));
+        settle().await;
+        let sent = deck.frames().len();
+
+        injector.unplug();
+        let (state, result) = driving.await.unwrap();
+        assert!(matches!(result, Err(Error::DeviceGone { serial, .. }) if serial == SERIAL));
+        assert_eq!(deck.frames().len(), sent);
+        // The state comes back still on the page the deck was showing
+        assert_eq!(state.layout.current(), "media");
+    }
+
+    #[test]
+    fn seek_dial_catches_up_with_the_track() {
+        let source = r#"
+[[encoders]]
+index = 0
+dial = { target = "seek" }
+"#;
+        let config = Config::parse(Path::new("test.toml"), source.to_string()).unwrap();
+        let mut state = DeckState::new(&config, Kind::Plus);
+        state.lcd.take_dirty(true);
+
+        let mut playing = NowPlaying::default();
+        playing.playback = media::Playback::Playing;
+        playing.position = Some(42.0);
+        playing.length = Some(180.0);
+        show_media(Kind::Plus, &mut state, Some(playing.clone()));
+        assert_eq!(state.dials[&0].value(), 42.0);
+        assert_eq!(state.lcd.take_dirty(false), [0]);
+        // Hearing the same again leaves the zone alone
+        show_media(Kind::Plus, &mut state, Some(playing));
+        assert!(state.lcd.take_dirty(false).is_empty());
+
+        // The player said nothing for 78 seconds, so it's at 2:00 by now and
+        // a twist moves on from there instead of jumping back to 0:47
+        let heard = state.heard;
+        let later = heard + Duration::from_secs(78);
+        let dial = state.dials.get_mut(&0).unwrap();
+        catch_up(dial, state.playing.as_ref(), heard, later);
+        assert_eq!(dial.twist(1, later), 1);
+        assert_eq!(dial.text(), "2:05");
+
+        // Still turning, it goes from where the twist left it
+        let turning = later + Duration::from_millis(100);
+        catch_up(dial, state.playing.as_ref(), heard, turning);
+        assert_eq!(dial.value(), 125.0);
+
+        // Paused, the position stays where the player left it
+        let paused = state.playing.as_mut().unwrap();
+        paused.playback = media::Playback::Paused;
+        let dial = state.dials.get_mut(&0).unwrap();
+        catch_up(
+            dial,
+            state.playing.as_ref(),
+            heard,
+            later + later.duration_since(heard),
+        );
+        assert_eq!(dial.value(), 42.0);
+    }
+}
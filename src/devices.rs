@@ -0,0 +1,333 @@
+use std::ffi::CStr;
+use std::process::ExitCode;
+
+use elgato_streamdeck::info::{
+    ImageFormat, ImageMirroring, ImageMode, ImageRotation, Kind, is_vendor_familiar,
+};
+use elgato_streamdeck::{StreamDeck, new_hidapi};
+use hidapi::HidApi;
+use serde::Serialize;
+
+pub const USAGE: &str = "usage: rust-streamdeck devices [--json]
+
+Lists every Elgato device on the system with what it can do and how it
+wants images, opening each deck to read its firmware version";
+
+// Shown whenever a deck is there but can't be opened
+const UDEV_HINT: &str = "The current user can't open the deck. Add a udev rule like
+
+  SUBSYSTEM==\"hidraw\", ATTRS{idVendor}==\"0fd9\", TAG+=\"uaccess\"
+
+to /etc/udev/rules.d/70-streamdeck.rules, then run
+`sudo udevadm control --reload-rules && sudo udevadm trigger` and replug the deck.";
+
+#[derive(Debug, Serialize)]
+struct DeviceReport {
+    // "unknown" for Elgato devices this version has no model for
+    kind: String,
+    product_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    serial: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    firmware: Option<String>,
+    // What the model can do, only known for models the library supports
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    model: Option<Model>,
+    path: String,
+    // Why the deck couldn't be opened
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    // Opening failed because of the device node's permissions
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    permission_denied: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Model {
+    keys: u8,
+    rows: u8,
+    columns: u8,
+    encoders: u8,
+    touchpoints: u8,
+    // How key images are sent, none for the Pedal
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_image: Option<Format>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lcd: Option<Format>,
+}
+
+impl Model {
+    fn new(kind: Kind) -> Model {
+        Model {
+            keys: kind.key_count(),
+            rows: kind.row_count(),
+            columns: kind.column_count(),
+            encoders: kind.encoder_count(),
+            touchpoints: kind.touchpoint_count(),
+            key_image: kind
+                .is_visual()
+                .then(|| Format::new(kind.key_image_format())),
+            lcd: kind.lcd_image_format().map(Format::new),
+        }
+    }
+}
+
+// Size and encoding the deck wants images in, and how they're turned and
+// flipped on the way so they show up the right way round
+#[derive(Debug, Serialize)]
+struct Format {
+    size: (usize, usize),
+    mode: &'static str,
+    // Degrees clockwise
+    rotation: u16,
+    mirror: &'static str,
+}
+
+impl Format {
+    fn new(format: ImageFormat) -> Format {
+        Format {
+            size: format.size,
+            mode: match format.mode {
+                ImageMode::None => "none",
+                ImageMode::BMP => "bmp",
+                ImageMode::JPEG => "jpeg",
+            },
+            rotation: match format.rotation {
+                ImageRotation::Rot0 => 0,
+                ImageRotation::Rot90 => 90,
+                ImageRotation::Rot180 => 180,
+                ImageRotation::Rot270 => 270,
+            },
+            mirror: match format.mirror {
+                ImageMirroring::None => "none",
+                ImageMirroring::X => "x",
+                ImageMirroring::Y => "y",
+                ImageMirroring::Both => "both",
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (w, h) = self.size;
+        write!(f, "{}x{} {}", w, h, self.mode.to_uppercase())?;
+        if self.rotation != 0 {
+            write!(f, ", rotated {}°", self.rotation)?;
+        }
+        if self.mirror != "none" {
+            write!(f, ", mirrored {}", self.mirror)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn main(args: impl Iterator<Item = String>) -> ExitCode {
+    let mut json = false;
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            other => {
+                eprintln!("unknown argument '{}'\n{}", other, USAGE);
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    let hid = match new_hidapi() {
+        Ok(hid) => hid,
+        Err(e) => {
+            eprintln!("Can't use HID devices: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let reports = probe(&hid);
+
+    if json {
+        match serde_json::to_string_pretty(&reports) {
+            Ok(text) => println!("{}", text),
+            Err(e) => {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    } else if reports.is_empty() {
+        println!("No Elgato devices found");
+    } else {
+        for report in &reports {
+            print(report);
+        }
+    }
+
+    if reports.iter().any(|r| r.permission_denied) {
+        eprintln!("\n{}", UDEV_HINT);
+    }
+    if reports.iter().any(|r| r.error.is_some()) {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+// Every Elgato device hidapi sees, once per deck even when it shows up as
+// several interfaces
+fn probe(hid: &HidApi) -> Vec<DeviceReport> {
+    let mut reports: Vec<DeviceReport> = vec![];
+    for info in hid.device_list() {
+        if !is_vendor_familiar(&info.vendor_id()) {
+            continue;
+        }
+        let kind = Kind::from_vid_pid(info.vendor_id(), info.product_id());
+        let product_id = format!("{:04x}", info.product_id());
+        let serial = info.serial_number().map(str::to_string);
+        let path = info.path().to_string_lossy().into_owned();
+        let seen = reports.iter().any(|r| match (&r.serial, &serial) {
+            (Some(a), Some(b)) => a == b && r.product_id == product_id,
+            _ => r.path == path,
+        });
+        if seen {
+            continue;
+        }
+
+        let mut report = DeviceReport {
+            kind: kind.map_or("unknown".to_string(), |kind| format!("{:?}", kind)),
+            product_id,
+            serial: serial.clone(),
+            firmware: None,
+            model: kind.map(Model::new),
+            path,
+            error: None,
+            permission_denied: false,
+        };
+        // Without a model there's no way to talk to it, but it's still listed
+        let Some(kind) = kind else {
+            reports.push(report);
+            continue;
+        };
+
+        let opened = match &serial {
+            Some(serial) => StreamDeck::connect(hid, kind, serial).map_err(|e| e.to_string()),
+            None => Err("no serial number, the device couldn't be read".to_string()),
+        };
+        match opened.and_then(|deck| deck.firmware_version().map_err(|e| e.to_string())) {
+            Ok(firmware) => report.firmware = Some(firmware.trim().to_string()),
+            Err(e) => {
+                report.permission_denied = permission_denied(info.path());
+                report.error = Some(e);
+            }
+        }
+        reports.push(report);
+    }
+    reports
+}
+
+// hidapi's errors don't say why opening failed, the device node does
+fn permission_denied(path: &CStr) -> bool {
+    let Ok(path) = path.to_str() else {
+        return false;
+    };
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+    {
+        Ok(_) => false,
+        Err(e) => e.kind() == std::io::ErrorKind::PermissionDenied,
+    }
+}
+
+fn print(report: &DeviceReport) {
+    let serial = report.serial.as_deref().unwrap_or("unknown serial");
+    println!("{} (0fd9:{}) {}", report.kind, report.product_id, serial);
+    if let Some(firmware) = &report.firmware {
+        println!("  firmware {}", firmware);
+    }
+
+    match &report.model {
+        Some(model) => {
+            println!(
+                "  {} keys in {} rows of {}",
+                model.keys, model.rows, model.columns
+            );
+            if let Some(format) = &model.key_image {
+                println!("  key images {}", format);
+            }
+            if model.encoders > 0 {
+                println!("  {} encoders", model.encoders);
+            }
+            if let Some(format) = &model.lcd {
+                println!("  LCD {}", format);
+            }
+            if model.touchpoints > 0 {
+                println!("  {} touch points", model.touchpoints);
+            }
+        }
+        None => println!("  not a model this version knows, so it can't be used"),
+    }
+    println!("  path {}", report.path);
+    if let Some(error) = &report.error {
+        println!("  can't open: {}", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn report(kind: Option<Kind>) -> DeviceReport {
+        DeviceReport {
+            kind: kind.map_or("unknown".to_string(), |kind| format!("{:?}", kind)),
+            product_id: "0084".to_string(),
+            serial: Some("A1".to_string()),
+            firmware: None,
+            model: kind.map(Model::new),
+            path: "/dev/hidraw3".to_string(),
+            error: None,
+            permission_denied: false,
+        }
+    }
+
+    #[test]
+    fn reports_image_formats() {
+        let plus = Model::new(Kind::Plus);
+        assert_eq!(plus.key_image.as_ref().unwrap().to_string(), "120x120 JPEG");
+        assert_eq!(plus.lcd.as_ref().unwrap().to_string(), "800x100 JPEG");
+        let original = Model::new(Kind::Original);
+        assert_eq!(
+            original.key_image.unwrap().to_string(),
+            "72x72 BMP, mirrored both"
+        );
+        assert_eq!(
+            Model::new(Kind::Neo).lcd.unwrap().to_string(),
+            "248x58 JPEG, rotated 180°"
+        );
+        assert!(Model::new(Kind::Pedal).key_image.is_none());
+
+        let json = serde_json::to_value(report(Some(Kind::Plus))).unwrap();
+        assert_eq!(json["keys"], 8);
+        assert_eq!(
+            json["lcd"],
+            json!({ "size": [800, 100], "mode": "jpeg", "rotation": 0, "mirror": "none" })
+        );
+        assert_eq!(json["key_image"]["size"], json!([120, 120]));
+    }
+
+    #[test]
+    fn reports_unknown_devices() {
+        let json = serde_json::to_value(report(None)).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "kind": "unknown",
+                "product_id": "0084",
+                "serial": "A1",
+                "path": "/dev/hidraw3",
+            })
+        );
+    }
+}
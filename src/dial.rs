@@ -0,0 +1,472 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::action::{self, Binding};
+use crate::config::{ActionConfig, DialConfig, DialPress, DialTarget};
+use crate::hass;
+
+// Spinning slower than this many ticks a second moves one step per tick
+const SLOW_RATE: f64 = 5.0;
+// Spinning this fast or faster gets the dial's full acceleration
+const FAST_RATE: f64 = 25.0;
+// Ticks further apart than this count as a fresh start at normal speed
+const ACCELERATION_WINDOW: Duration = Duration::from_millis(300);
+// Most times a twist runs `on_increase` or `on_decrease`, a hard spin
+// shouldn't start hundreds of processes
+const MAX_STEP_ACTIONS: u32 = 20;
+// Seconds a seek dial moves per step when the config doesn't say
+const SEEK_STEP: f64 = 5.0;
+// A dial this recently twisted is still being turned, so what the media
+// player, sound server or Home Assistant reports back doesn't pull it back to
+// where it was a moment ago
+const TURNING: Duration = Duration::from_millis(500);
+
+// An encoder turned into a value, with everything needed to step it and show it
+pub struct Dial {
+    target: DialTarget,
+    entity: Option<String>,
+    min: Option<f64>,
+    max: Option<f64>,
+    // Bounds from the config, an entity dial takes the rest from the entity
+    bounds: (Option<f64>, Option<f64>),
+    step: f64,
+    initial: f64,
+    acceleration: f64,
+    wrap: bool,
+    press: Option<DialPress>,
+    options: Vec<String>,
+    unit: Option<String>,
+    decimals: usize,
+    on_change: Option<ActionConfig>,
+    on_increase: Option<Binding>,
+    on_decrease: Option<Binding>,
+
+    value: f64,
+    // Audio dials show that their device is muted instead of the value
+    muted: bool,
+    // Where a toggle left off, so the next push brings it back
+    saved: Option<f64>,
+    last_twist: Option<Instant>,
+}
+
+impl Dial {
+    // `brightness` is where a brightness dial starts, whatever its `initial` says
+    pub fn new(config: &DialConfig, brightness: u8) -> Dial {
+        let (mut min, mut max) = (config.min, config.max);
+        if !config.options.is_empty() {
+            min = Some(0.0);
+            max = Some(config.options.len() as f64 - 1.0);
+        }
+        match config.target {
+            DialTarget::Brightness | DialTarget::Volume | DialTarget::Sink | DialTarget::Source => {
+                min = Some(min.unwrap_or(0.0));
+                max = Some(max.unwrap_or(100.0));
+            }
+            DialTarget::Entity if config.entity.as_deref().is_some_and(hass::percent) => {
+                min = Some(min.unwrap_or(0.0));
+                max = Some(max.unwrap_or(100.0));
+            }
+            DialTarget::Seek => min = Some(min.unwrap_or(0.0)),
+            DialTarget::Entity | DialTarget::Value => {}
+        }
+
+        let step = match (config.options.is_empty(), config.target) {
+            (false, _) => 1.0,
+            (true, DialTarget::Seek) => config.step.unwrap_or(SEEK_STEP),
+            (true, _) => config.step.unwrap_or(1.0),
+        };
+        let audio = matches!(config.target, DialTarget::Sink | DialTarget::Source);
+        let initial = match config.target {
+            DialTarget::Brightness => brightness as f64,
+            _ => config.initial.or(min).unwrap_or(0.0),
+        };
+        let build = |config: &Option<ActionConfig>| {
+            config
+                .as_ref()
+                .and_then(|config| match action::build(config) {
+                    Ok(binding) => Some(binding),
+                    Err(e) => {
+                        eprintln!("Dial action left unbound: {}", e);
+                        None
+                    }
+                })
+        };
+
+        let mut dial = Dial {
+            target: config.target,
+            entity: config.entity.clone(),
+            min,
+            max,
+            bounds: (config.min, config.max),
+            step,
+            initial,
+            acceleration: config.acceleration.unwrap_or(1.0),
+            wrap: config.wrap,
+            press: config.press.or(audio.then_some(DialPress::Mute)),
+            options: config.options.clone(),
+            unit: config.unit.clone(),
+            decimals: decimals(step),
+            on_change: config.on_change.clone(),
+            on_increase: build(&config.on_increase),
+            on_decrease: build(&config.on_decrease),
+            value: initial,
+            muted: false,
+            saved: None,
+            last_twist: None,
+        };
+        dial.value = dial.clamp(initial);
+        dial.initial = dial.value;
+        dial
+    }
+
+    pub fn target(&self) -> DialTarget {
+        self.target
+    }
+
+    // The Home Assistant entity of an entity dial
+    pub fn entity(&self) -> Option<&str> {
+        self.entity.as_deref()
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted
+    }
+
+    pub fn is_turning(&self, now: Instant) -> bool {
+        self.last_twist.is_some_and(|last| now - last < TURNING)
+    }
+
+    // Moves the value by `ticks` steps, more when the dial is spun fast.
+    // Returns how many steps it actually moved, 0 when it's pinned at a bound.
+    pub fn twist(&mut self, ticks: i8, now: Instant) -> i32 {
+        let rate = match self.last_twist {
+            Some(last) if now - last < ACCELERATION_WINDOW => {
+                let elapsed = (now - last).as_secs_f64().max(0.001);
+                ticks.unsigned_abs() as f64 / elapsed
+            }
+            _ => 0.0,
+        };
+        self.last_twist = Some(now);
+
+        let speed = ((rate - SLOW_RATE) / (FAST_RATE - SLOW_RATE)).clamp(0.0, 1.0);
+        let multiplier = 1.0 + (self.acceleration - 1.0) * speed;
+        let steps = (ticks as f64 * multiplier).round() as i32;
+
+        let mut value = self.value + steps as f64 * self.step;
+        if self.wrap
+            && let (Some(min), Some(max)) = (self.min, self.max)
+        {
+            if value > max && self.value >= max {
+                value = min;
+            } else if value < min && self.value <= min {
+                value = max;
+            }
+        }
+
+        if self.set(value) { steps } else { 0 }
+    }
+
+    // Applies the dial's press behavior, returns whether the value changed
+    pub fn press(&mut self) -> bool {
+        let value = match self.press {
+            None => return false,
+            Some(DialPress::Mute) => {
+                self.muted = !self.muted;
+                return true;
+            }
+            Some(DialPress::Reset) => self.initial,
+            Some(DialPress::Toggle) => {
+                let bottom = self.min.unwrap_or(0.0);
+                if self.value != bottom {
+                    self.saved = Some(self.value);
+                    bottom
+                } else {
+                    // Nothing to go back to yet, so somewhere that isn't the bottom
+                    self.saved.take().unwrap_or(match self.initial != bottom {
+                        true => self.initial,
+                        false => self.max.unwrap_or(bottom + self.step),
+                    })
+                }
+            }
+        };
+        self.set(value)
+    }
+
+    // Moves the value to `value` within the bounds, returns whether it changed
+    pub fn set(&mut self, value: f64) -> bool {
+        let value = self.clamp(value);
+        if value == self.value {
+            return false;
+        }
+        self.value = value;
+        true
+    }
+
+    // Follows the sound server, returns whether that changed anything
+    pub fn set_muted(&mut self, muted: bool) -> bool {
+        std::mem::replace(&mut self.muted, muted) != muted
+    }
+
+    // Moves the upper bound, for a seek dial when the track changes. Returns
+    // whether that changed anything.
+    pub fn set_max(&mut self, max: Option<f64>) -> bool {
+        let (before, value) = (self.max, self.value);
+        self.max = max;
+        self.value = self.clamp(self.value);
+        (self.max, self.value) != (before, value)
+    }
+
+    // Follows a Home Assistant entity, taking its range for bounds the config
+    // leaves out. Returns whether that changed anything.
+    pub fn follow(&mut self, value: f64, range: Option<(f64, f64)>) -> bool {
+        let bounds = (self.min, self.max);
+        if let Some((min, max)) = range {
+            self.min = self.bounds.0.or(Some(min));
+            self.max = self.bounds.1.or(Some(max));
+        }
+        let moved = self.set(value);
+        moved || (self.min, self.max) != bounds
+    }
+
+    // The value the way it's shown and passed to `on_change`, without the unit
+    pub fn text(&self) -> String {
+        if self.target == DialTarget::Seek {
+            let seconds = self.value as u64;
+            return format!("{}:{:02}", seconds / 60, seconds % 60);
+        }
+        match self.options.get(self.value as usize) {
+            Some(option) => option.clone(),
+            None => format!("{:.*}", self.decimals, self.value),
+        }
+    }
+
+    pub fn display(&self) -> String {
+        if self.muted {
+            return "Muted".to_string();
+        }
+        match &self.unit {
+            Some(unit) => format!("{}{}", self.text(), unit),
+            None => self.text(),
+        }
+    }
+
+    // How far along its range the value is, for dials with both bounds
+    pub fn fraction(&self) -> Option<f64> {
+        let (min, max) = (self.min?, self.max?);
+        Some(((self.value - min) / (max - min)).clamp(0.0, 1.0))
+    }
+
+    // Runs `on_change` with the new value and the per-step actions for a twist
+    // that moved `steps` steps
+    pub fn run_actions(&self, steps: i32) {
+        if let Some(config) = &self.on_change {
+            let config = action::substitute(config, &self.text());
+            let result = action::build(&config).and_then(|binding| match binding {
+                Binding::Run(action) => action.run(),
+                Binding::Navigate(_) => Ok(()),
+            });
+            if let Err(e) = result {
+                eprintln!("Dial on_change: {}", e);
+            }
+        }
+
+        let binding = match steps {
+            0 => return,
+            s if s > 0 => &self.on_increase,
+            _ => &self.on_decrease,
+        };
+        if let Some(Binding::Run(action)) = binding {
+            for _ in 0..steps.unsigned_abs().min(MAX_STEP_ACTIONS) {
+                if let Err(e) = action.run() {
+                    eprintln!("Dial step: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn clamp(&self, value: f64) -> f64 {
+        // Steps like 0.1 would otherwise drift into 0.30000000000000004
+        let scale = 10f64.powi(self.decimals as i32);
+        let mut value = (value * scale).round() / scale;
+        if let Some(min) = self.min {
+            value = value.max(min);
+        }
+        if let Some(max) = self.max {
+            value = value.min(max);
+        }
+        value
+    }
+}
+
+// Decimal places needed to show every multiple of the step, at most 3
+fn decimals(step: f64) -> usize {
+    (0..3)
+        .find(|&d| {
+            let scaled = step * 10f64.powi(d as i32);
+            (scaled - scaled.round()).abs() < 1e-9
+        })
+        .unwrap_or(3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dial(config: &str) -> Dial {
+        Dial::new(&toml::from_str(config).unwrap(), 50)
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn steps_within_bounds() {
+        let mut knob = dial("min = 0\nmax = 10\ninitial = 5");
+        let start = Instant::now();
+        assert_eq!(knob.twist(1, start), 1);
+        assert_eq!(knob.value(), 6.0);
+        assert_eq!(knob.twist(-3, start + ms(1000)), -3);
+        assert_eq!(knob.value(), 3.0);
+
+        knob.twist(20, start + ms(2000));
+        assert_eq!(knob.value(), 10.0);
+        // Pinned at the top, more turning goes nowhere
+        assert_eq!(knob.twist(1, start + ms(3000)), 0);
+        assert_eq!(knob.value(), 10.0);
+    }
+
+    #[test]
+    fn accelerates_with_spin_rate() {
+        let mut knob = dial("acceleration = 5");
+        let start = Instant::now();
+        // Nothing to measure against, so normal speed
+        assert_eq!(knob.twist(1, start), 1);
+        // 100 ticks a second is past FAST_RATE
+        assert_eq!(knob.twist(1, start + ms(10)), 5);
+        // 10 ticks a second is a quarter of the way from SLOW_RATE to FAST_RATE
+        assert_eq!(knob.twist(1, start + ms(110)), 2);
+        // 4 ticks a second is below SLOW_RATE
+        assert_eq!(knob.twist(1, start + ms(360)), 1);
+        // A pause longer than the window starts over
+        assert_eq!(knob.twist(-1, start + ms(1000)), -1);
+        assert_eq!(knob.value(), 8.0);
+
+        let mut plain = dial("");
+        plain.twist(1, start);
+        assert_eq!(plain.twist(1, start + ms(10)), 1);
+    }
+
+    #[test]
+    fn wraps_only_from_the_bound() {
+        let mut knob = dial("min = 0\nmax = 3\ninitial = 2\nwrap = true");
+        let start = Instant::now();
+        // Overshooting stops at the top first
+        knob.twist(5, start);
+        assert_eq!(knob.value(), 3.0);
+        knob.twist(1, start + ms(1000));
+        assert_eq!(knob.value(), 0.0);
+        knob.twist(-1, start + ms(2000));
+        assert_eq!(knob.value(), 3.0);
+
+        let mut stuck = dial("min = 0\nmax = 3\ninitial = 3");
+        assert_eq!(stuck.twist(1, start), 0);
+        assert_eq!(stuck.value(), 3.0);
+    }
+
+    #[test]
+    fn toggle_brings_the_value_back() {
+        let mut knob = dial("min = 0\nmax = 100\ninitial = 40\npress = \"toggle\"");
+        let start = Instant::now();
+        knob.twist(2, start);
+        assert!(knob.press());
+        assert_eq!(knob.value(), 0.0);
+        assert!(knob.press());
+        assert_eq!(knob.value(), 42.0);
+
+        // Turned down to the bottom by hand, so the initial value is next
+        knob.set(0.0);
+        assert!(knob.press());
+        assert_eq!(knob.value(), 40.0);
+
+        // Starting at the bottom, the top is somewhere that isn't
+        let mut bottom = dial("min = 0\nmax = 100\npress = \"toggle\"");
+        assert!(bottom.press());
+        assert_eq!(bottom.value(), 100.0);
+        let mut unbounded = dial("step = 5\npress = \"toggle\"");
+        assert!(unbounded.press());
+        assert_eq!(unbounded.value(), 5.0);
+    }
+
+    #[test]
+    fn reset_goes_back_to_initial() {
+        let mut knob = dial("initial = 20\npress = \"reset\"");
+        knob.twist(3, Instant::now());
+        assert!(knob.press());
+        assert_eq!(knob.value(), 20.0);
+        assert!(!knob.press());
+
+        let mut unset = dial("");
+        assert!(!unset.press());
+    }
+
+    #[test]
+    fn rounds_to_the_step() {
+        let mut knob = dial("step = 0.1\nmin = 0\nmax = 1");
+        let start = Instant::now();
+        for second in 0..3 {
+            knob.twist(1, start + ms(1000 * second));
+        }
+        assert_eq!(knob.value(), 0.3);
+        assert_eq!(knob.text(), "0.3");
+
+        let mut halves = dial("step = 0.5\nmin = 0\nmax = 2\nunit = \"x\"");
+        assert!(halves.set(2.26));
+        assert_eq!(halves.value(), 2.0);
+        assert_eq!(halves.display(), "2.0x");
+        assert!(!halves.set(2.04));
+        assert!(halves.set(-1.0));
+        assert_eq!(halves.value(), 0.0);
+
+        assert_eq!(decimals(1.0), 0);
+        assert_eq!(decimals(0.25), 2);
+        assert_eq!(decimals(0.0001), 3);
+    }
+
+    #[tokio::test]
+    async fn caps_step_actions() {
+        let dir = std::env::temp_dir().join(format!("dial-steps-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (up, down) = (dir.join("up"), dir.join("down"));
+        let mut knob = dial(&format!(
+            "on_increase = {{ type = \"shell\", command = \"echo >> {}\" }}\n\
+             on_decrease = {{ type = \"shell\", command = \"echo >> {}\" }}",
+            up.display(),
+            down.display()
+        ));
+        knob.twist(1, Instant::now());
+        knob.run_actions(50);
+        knob.run_actions(-3);
+        knob.run_actions(0);
+
+        let lines = |path: &std::path::Path| {
+            std::fs::read_to_string(path).map_or(0, |text| text.lines().count())
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while (lines(&up), lines(&down)) != (MAX_STEP_ACTIONS as usize, 3) {
+            assert!(Instant::now() < deadline, "actions didn't all run");
+            tokio::time::sleep(ms(20)).await;
+        }
+        // Give any extra runs a chance to show up
+        tokio::time::sleep(ms(200)).await;
+        assert_eq!(lines(&up), MAX_STEP_ACTIONS as usize);
+        assert_eq!(lines(&down), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
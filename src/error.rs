@@ -0,0 +1,118 @@
+use std::fmt;
+use std::time::Duration;
+
+use elgato_streamdeck::StreamDeckError;
+use hidapi::HidError;
+use image::ImageError;
+
+use crate::config::ConfigError;
+
+// Tries a USB call this many times before the deck is given up on
+const RETRY_ATTEMPTS: u32 = 4;
+// Wait before the first retry, doubled for each one after it
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum Error {
+    // Talking to the deck over USB failed
+    Hid(HidError),
+
+    // An image couldn't be decoded or encoded for the deck
+    Image(ImageError),
+
+    // Config file couldn't be loaded or doesn't fit the deck
+    Config(ConfigError),
+
+    // The deck was unplugged or stopped answering
+    DeviceGone { serial: String, source: Box<Error> },
+
+    // Anything else the deck library refuses, like an unsupported operation
+    Device(StreamDeckError),
+
+    // Local I/O that has nothing to do with the deck, like installing signal handlers
+    Io(std::io::Error),
+}
+
+impl Error {
+    // Errors worth another try, a USB hiccup can go away on its own
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Hid(HidError::InitializationError) => false,
+            Error::Hid(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Hid(e) => write!(f, "USB: {}", e),
+            Error::Image(e) => write!(f, "image: {}", e),
+            Error::Config(e) => write!(f, "config: {}", e),
+            Error::DeviceGone { serial, source } => write!(f, "{} went away: {}", serial, source),
+            Error::Device(e) => write!(f, "deck: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Hid(e) => Some(e),
+            Error::Image(e) => Some(e),
+            Error::Config(e) => Some(e),
+            Error::DeviceGone { source, .. } => Some(source.as_ref()),
+            Error::Device(_) => None,
+            Error::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<StreamDeckError> for Error {
+    fn from(e: StreamDeckError) -> Error {
+        match e {
+            StreamDeckError::HidError(e) => Error::Hid(e),
+            StreamDeckError::ImageError(e) => Error::Image(e),
+            e => Error::Device(e),
+        }
+    }
+}
+
+impl From<HidError> for Error {
+    fn from(e: HidError) -> Error {
+        Error::Hid(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Error {
+        Error::Config(e)
+    }
+}
+
+// Runs a deck call again with a growing delay while it fails with a transient error
+pub async fn retry<T, F, Fut>(mut call: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, StreamDeckError>>,
+{
+    let mut delay = RETRY_DELAY;
+    for _ in 1..RETRY_ATTEMPTS {
+        match call().await.map_err(Error::from) {
+            Err(e) if e.is_transient() => {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    call().await.map_err(Error::from)
+}
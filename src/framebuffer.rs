@@ -0,0 +1,353 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::Duration;
+
+use elgato_streamdeck::images::{ImageRect, convert_image, convert_image_with_format};
+use elgato_streamdeck::info::Kind;
+use image::DynamicImage;
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::deck::Deck;
+use crate::error::{Error, retry};
+
+// Part of the LCD strip as x, y, width and height
+type Region = (u16, u16, u16, u16);
+
+// Keys pressed this recently go out before anything else
+const PRESS_PRIORITY: Duration = Duration::from_secs(1);
+
+// How many uploads went to the deck, how many were skipped because the deck
+// already showed those exact bytes, how many were replaced by a newer frame
+// before they could be sent and how many had to wait for a later frame
+// because the byte budget ran out
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct FrameStats {
+    pub uploaded: u64,
+    pub skipped: u64,
+    pub dropped: u64,
+    pub deferred: u64,
+}
+
+// What the deck is showing, remembered as a hash of the bytes last sent to
+// each key and LCD region, so an unchanged frame never goes over USB again.
+// Changes queue up and go out together at most `fps` times a second, each
+// time sending no more than the byte budget.
+pub struct Framebuffer {
+    kind: Kind,
+    keys: Vec<Option<u64>>,
+    lcd: HashMap<Region, u64>,
+    pending_keys: BTreeMap<u8, Pending>,
+    // In the order they were drawn, later writes may cover earlier ones
+    pending_lcd: Vec<(Region, Pending)>,
+    // Touch point colors are a few bytes each, they always go out with the
+    // next frame and don't count towards the stats
+    touchpoints: Vec<Option<[u8; 3]>>,
+    pending_touchpoints: BTreeMap<u8, [u8; 3]>,
+    pressed: HashMap<u8, Instant>,
+    interval: Duration,
+    budget: usize,
+    last_send: Option<Instant>,
+    stats: FrameStats,
+}
+
+struct Pending {
+    hash: u64,
+    write: Write,
+}
+
+enum Write {
+    Key(Vec<u8>),
+    BlankKey,
+    Lcd(ImageRect),
+    Fill(Vec<u8>),
+}
+
+impl Write {
+    fn len(&self, kind: Kind) -> usize {
+        match self {
+            Write::Key(data) | Write::Fill(data) => data.len(),
+            Write::BlankKey => kind.blank_image().len(),
+            Write::Lcd(rect) => rect.data.len(),
+        }
+    }
+}
+
+impl Framebuffer {
+    pub fn new(kind: Kind, fps: u32, budget: usize) -> Framebuffer {
+        Framebuffer {
+            kind,
+            keys: vec![None; kind.key_count() as usize],
+            lcd: HashMap::new(),
+            pending_keys: BTreeMap::new(),
+            pending_lcd: vec![],
+            touchpoints: vec![None; kind.touchpoint_count() as usize],
+            pending_touchpoints: BTreeMap::new(),
+            pressed: HashMap::new(),
+            interval: Duration::from_secs(1) / fps.max(1),
+            budget,
+            last_send: None,
+            stats: FrameStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    // Encodes the image for the key and queues it unless the key already shows it
+    pub fn set_key(&mut self, key: u8, image: DynamicImage) -> Result<(), Error> {
+        let data = convert_image(self.kind, image).map_err(Error::Image)?;
+        self.queue_key(key, hash(&data), Write::Key(data));
+        Ok(())
+    }
+
+    // Queues blanking the key unless it's already blank
+    pub fn clear_key(&mut self, key: u8) {
+        let blank = hash(&self.kind.blank_image());
+        self.queue_key(key, blank, Write::BlankKey);
+    }
+
+    // Blanks every key right away, whatever the deck was showing before
+    pub async fn clear_all<D: Deck>(&mut self, device: &D) -> Result<(), Error> {
+        retry(|| device.clear_all_button_images()).await?;
+        let blank = hash(&self.kind.blank_image());
+        self.keys.fill(Some(blank));
+        self.stats.dropped += self.pending_keys.len() as u64;
+        self.pending_keys.clear();
+        self.stats.uploaded += self.keys.len() as u64;
+        Ok(())
+    }
+
+    // Queues a part of the LCD strip unless it already shows the same bytes
+    pub fn write_lcd(&mut self, x: u16, y: u16, rect: ImageRect) {
+        let region = (x, y, rect.w, rect.h);
+        self.queue_lcd(region, hash(&rect.data), Write::Lcd(rect));
+    }
+
+    // Queues the whole LCD unless it already shows the same bytes, for decks
+    // that don't take partial writes
+    pub fn fill_lcd(&mut self, image: DynamicImage) -> Result<(), Error> {
+        let Some(format) = self.kind.lcd_image_format() else {
+            return Ok(());
+        };
+        let (w, h) = format.size;
+        let data = convert_image_with_format(format, image).map_err(Error::Image)?;
+        self.queue_lcd((0, 0, w as u16, h as u16), hash(&data), Write::Fill(data));
+        Ok(())
+    }
+
+    // Blacks out the whole LCD right away, whatever the deck was showing before
+    pub async fn clear_lcd<D: Deck>(&mut self, device: &D) -> Result<(), Error> {
+        let Some(format) = self.kind.lcd_image_format() else {
+            return Ok(());
+        };
+        let (w, h) = format.size;
+        let black = DynamicImage::new_rgb8(w as u32, h as u32);
+        let data = convert_image_with_format(format, black).map_err(Error::Image)?;
+
+        retry(|| device.write_lcd_fill(&data)).await?;
+        self.lcd.clear();
+        self.stats.dropped += self.pending_lcd.len() as u64;
+        self.pending_lcd.clear();
+        self.stats.uploaded += 1;
+        Ok(())
+    }
+
+    // Queues a touch point color unless it already shows it
+    pub fn set_touchpoint(&mut self, point: u8, color: [u8; 3]) {
+        let Some(shown) = self.touchpoints.get(point as usize) else {
+            return;
+        };
+        if *shown == Some(color) {
+            self.pending_touchpoints.remove(&point);
+        } else {
+            self.pending_touchpoints.insert(point, color);
+        }
+    }
+
+    // Turns every touch point off right away
+    pub async fn clear_touchpoints<D: Deck>(&mut self, device: &D) -> Result<(), Error> {
+        for point in 0..self.kind.touchpoint_count() {
+            retry(|| device.set_touchpoint_color(point, 0, 0, 0)).await?;
+        }
+        self.touchpoints.fill(Some([0, 0, 0]));
+        self.pending_touchpoints.clear();
+        Ok(())
+    }
+
+    // The user is looking at this key, its next frame jumps the queue
+    pub fn pressed(&mut self, key: u8) {
+        self.pressed.insert(key, Instant::now());
+    }
+
+    // When the queued changes may go out, `None` with nothing queued
+    pub fn next_send(&self) -> Option<Instant> {
+        if self.pending_keys.is_empty()
+            && self.pending_lcd.is_empty()
+            && self.pending_touchpoints.is_empty()
+        {
+            return None;
+        }
+        let now = Instant::now();
+        Some(match self.last_send {
+            Some(last) => (last + self.interval).max(now),
+            None => now,
+        })
+    }
+
+    // Sends what fits in one frame's byte budget and flushes the keys once.
+    // The first write always goes, however big, so nothing waits forever.
+    pub async fn send<D: Deck>(&mut self, device: &D) -> Result<(), Error> {
+        let now = Instant::now();
+        self.last_send = Some(now);
+        self.pressed
+            .retain(|_, at| now.duration_since(*at) < PRESS_PRIORITY);
+
+        for (point, [r, g, b]) in std::mem::take(&mut self.pending_touchpoints) {
+            retry(|| device.set_touchpoint_color(point, r, g, b)).await?;
+            self.touchpoints[point as usize] = Some([r, g, b]);
+        }
+
+        // Keys just pressed, most recent first, then the LCD, then the other keys
+        let mut urgent: Vec<u8> = self
+            .pending_keys
+            .keys()
+            .copied()
+            .filter(|key| self.pressed.contains_key(key))
+            .collect();
+        urgent.sort_by_key(|key| std::cmp::Reverse(self.pressed[key]));
+
+        // Keys that aren't urgent go in index order
+        let rest: Vec<u8> = self
+            .pending_keys
+            .keys()
+            .copied()
+            .filter(|key| !urgent.contains(key))
+            .collect();
+        let lcd = self.pending_lcd.len();
+
+        let mut sent = 0;
+        let mut flush = false;
+        let order = urgent
+            .into_iter()
+            .map(Some)
+            .chain(std::iter::repeat_n(None, lcd))
+            .chain(rest.into_iter().map(Some));
+        for slot in order {
+            let len = match slot {
+                Some(key) => self.pending_keys[&key].write.len(self.kind),
+                None => self.pending_lcd[0].1.write.len(self.kind),
+            };
+            if sent > 0 && sent + len > self.budget {
+                break;
+            }
+            sent += len;
+
+            match slot {
+                Some(key) => {
+                    let pending = self.pending_keys.remove(&key).expect("key is pending");
+                    self.send_key(device, key, pending).await?;
+                    flush = true;
+                }
+                None => {
+                    let (region, pending) = self.pending_lcd.remove(0);
+                    self.send_lcd(device, region, pending).await?;
+                }
+            }
+        }
+
+        self.stats.deferred += (self.pending_keys.len() + self.pending_lcd.len()) as u64;
+        if flush {
+            retry(|| device.flush()).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_key<D: Deck>(
+        &mut self,
+        device: &D,
+        key: u8,
+        pending: Pending,
+    ) -> Result<(), Error> {
+        match &pending.write {
+            Write::Key(data) => retry(|| device.write_image(key, data)).await?,
+            _ => retry(|| device.clear_button_image(key)).await?,
+        }
+        self.keys[key as usize] = Some(pending.hash);
+        self.stats.uploaded += 1;
+        Ok(())
+    }
+
+    async fn send_lcd<D: Deck>(
+        &mut self,
+        device: &D,
+        region: Region,
+        pending: Pending,
+    ) -> Result<(), Error> {
+        match &pending.write {
+            Write::Lcd(rect) => retry(|| device.write_lcd(region.0, region.1, rect)).await?,
+            Write::Fill(data) => retry(|| device.write_lcd_fill(data)).await?,
+            _ => {}
+        }
+        // Whatever overlapped the region has been drawn over
+        self.lcd.retain(|other, _| !overlaps(*other, region));
+        self.lcd.insert(region, pending.hash);
+        self.stats.uploaded += 1;
+        Ok(())
+    }
+
+    fn queue_key(&mut self, key: u8, hash: u64, write: Write) {
+        let Some(shown) = self.keys.get(key as usize) else {
+            return;
+        };
+        if self.pending_keys.get(&key).is_some_and(|p| p.hash == hash) {
+            self.stats.skipped += 1;
+            return;
+        }
+        // A newer frame makes the queued one pointless, whether or not the
+        // newer one has to be sent at all
+        if self.pending_keys.remove(&key).is_some() {
+            self.stats.dropped += 1;
+        }
+
+        if *shown == Some(hash) {
+            self.stats.skipped += 1;
+        } else {
+            self.pending_keys.insert(key, Pending { hash, write });
+        }
+    }
+
+    fn queue_lcd(&mut self, region: Region, hash: u64, write: Write) {
+        let queued = self
+            .pending_lcd
+            .iter()
+            .any(|(r, p)| *r == region && p.hash == hash);
+        if queued {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        let before = self.pending_lcd.len();
+        self.pending_lcd.retain(|(r, _)| !overlaps(*r, region));
+        self.stats.dropped += (before - self.pending_lcd.len()) as u64;
+
+        // Nothing queued draws over the region any more, so the deck still
+        // shows what was last sent there
+        if self.lcd.get(&region) == Some(&hash) {
+            self.stats.skipped += 1;
+            return;
+        }
+        self.pending_lcd.push((region, Pending { hash, write }));
+    }
+}
+
+fn overlaps((ax, ay, aw, ah): Region, (bx, by, bw, bh): Region) -> bool {
+    ax < bx + bw && bx < ax + aw && ay < by + bh && by < ay + ah
+}
+
+fn hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
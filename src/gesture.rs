@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+// Physical control on the deck that can be pressed
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Control {
+    Key(u8),
+    Encoder(u8),
+    Touchpoint(u8),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Gesture {
+    Press,
+    Release,
+    LongPress,
+    DoublePress,
+}
+
+// Which gestures the control has bindings for. Press is delayed until a
+// long or double press has been ruled out, so it's only done when needed.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Wants {
+    pub long_press: bool,
+    pub double_press: bool,
+}
+
+#[derive(Debug)]
+enum State {
+    Held {
+        since: Instant,
+        wants: Wants,
+        // A long or double press already fired for this hold
+        consumed: bool,
+    },
+    WaitingForSecond {
+        until: Instant,
+    },
+}
+
+// Turns raw down/up events into press, release, long-press and double-press
+pub struct GestureTracker {
+    long_press: Duration,
+    double_press: Duration,
+    states: HashMap<Control, State>,
+}
+
+impl GestureTracker {
+    pub fn new(long_press: Duration, double_press: Duration) -> GestureTracker {
+        GestureTracker {
+            long_press,
+            double_press,
+            states: HashMap::new(),
+        }
+    }
+
+    pub fn down(
+        &mut self,
+        control: Control,
+        wants: Wants,
+        now: Instant,
+    ) -> Vec<(Control, Gesture)> {
+        let mut gestures = vec![];
+        let mut consumed = false;
+
+        match self.states.get(&control) {
+            Some(State::WaitingForSecond { until }) if now < *until => {
+                gestures.push((control, Gesture::DoublePress));
+                consumed = true;
+            }
+            state => {
+                // The double press window ran out before `expire` got to it,
+                // the first press still happened
+                if let Some(State::WaitingForSecond { .. }) = state {
+                    gestures.push((control, Gesture::Press));
+                }
+                if !wants.long_press && !wants.double_press {
+                    gestures.push((control, Gesture::Press));
+                }
+            }
+        }
+
+        self.states.insert(
+            control,
+            State::Held {
+                since: now,
+                wants,
+                consumed,
+            },
+        );
+        gestures
+    }
+
+    pub fn up(&mut self, control: Control, now: Instant) -> Vec<(Control, Gesture)> {
+        let mut gestures = vec![];
+
+        if let Some(State::Held {
+            wants, consumed, ..
+        }) = self.states.remove(&control)
+            && !consumed
+        {
+            if wants.double_press {
+                self.states.insert(
+                    control,
+                    State::WaitingForSecond {
+                        until: now + self.double_press,
+                    },
+                );
+            } else if wants.long_press {
+                gestures.push((control, Gesture::Press));
+            }
+        }
+
+        gestures.push((control, Gesture::Release));
+        gestures
+    }
+
+    // Earliest time `expire` has something to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.states
+            .values()
+            .filter_map(|state| match state {
+                State::Held {
+                    since,
+                    wants,
+                    consumed: false,
+                } if wants.long_press => Some(*since + self.long_press),
+                State::WaitingForSecond { until } => Some(*until),
+                _ => None,
+            })
+            .min()
+    }
+
+    // Fires long presses that have been held long enough and single presses
+    // whose double press window ran out
+    pub fn expire(&mut self, now: Instant) -> Vec<(Control, Gesture)> {
+        let mut gestures = vec![];
+
+        self.states.retain(|control, state| match state {
+            State::Held {
+                since,
+                wants,
+                consumed,
+            } => {
+                if wants.long_press && !*consumed && now >= *since + self.long_press {
+                    gestures.push((*control, Gesture::LongPress));
+                    *consumed = true;
+                }
+                true
+            }
+            State::WaitingForSecond { until } => {
+                if now >= *until {
+                    gestures.push((*control, Gesture::Press));
+                    false
+                } else {
+                    true
+                }
+            }
+        });
+
+        gestures
+    }
+}
+
+// Which way a swipe across the touch screen went
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Swipe {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+// How many times longer the movement along one axis has to be than along the
+// other, anything closer to diagonal isn't a swipe in either direction
+const SWIPE_DOMINANCE: u32 = 2;
+
+// Direction of a touch that moved from `from` to `to`, `None` when it moved
+// less than `distance` pixels or too diagonally to tell
+pub fn classify_swipe(from: (u16, u16), to: (u16, u16), distance: u16) -> Option<Swipe> {
+    let dx = to.0 as i32 - from.0 as i32;
+    let dy = to.1 as i32 - from.1 as i32;
+    let (ax, ay) = (dx.unsigned_abs(), dy.unsigned_abs());
+
+    if ax.max(ay) < distance as u32 {
+        None
+    } else if ax >= ay * SWIPE_DOMINANCE {
+        Some(if dx < 0 { Swipe::Left } else { Swipe::Right })
+    } else if ay >= ax * SWIPE_DOMINANCE {
+        Some(if dy < 0 { Swipe::Up } else { Swipe::Down })
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG: Duration = Duration::from_millis(500);
+    const DOUBLE: Duration = Duration::from_millis(300);
+    const KEY: Control = Control::Key(3);
+
+    fn tracker() -> GestureTracker {
+        GestureTracker::new(LONG, DOUBLE)
+    }
+
+    fn wants(long_press: bool, double_press: bool) -> Wants {
+        Wants {
+            long_press,
+            double_press,
+        }
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn press_fires_on_down_when_nothing_else_is_bound() {
+        let mut tracker = tracker();
+        let start = Instant::now();
+        assert_eq!(
+            tracker.down(KEY, Wants::default(), start),
+            [(KEY, Gesture::Press)]
+        );
+        assert_eq!(tracker.next_deadline(), None);
+        assert_eq!(tracker.up(KEY, start + ms(50)), [(KEY, Gesture::Release)]);
+    }
+
+    #[test]
+    fn long_press() {
+        let mut tracker = tracker();
+        let start = Instant::now();
+        assert_eq!(tracker.down(KEY, wants(true, false), start), []);
+        assert_eq!(tracker.next_deadline(), Some(start + LONG));
+        assert_eq!(tracker.expire(start + LONG - ms(1)), []);
+        assert_eq!(tracker.expire(start + LONG), [(KEY, Gesture::LongPress)]);
+        assert_eq!(tracker.next_deadline(), None);
+        // The hold was used up by the long press, letting go doesn't press
+        assert_eq!(
+            tracker.up(KEY, start + LONG + ms(100)),
+            [(KEY, Gesture::Release)]
+        );
+
+        // Let go early and it's an ordinary press
+        assert_eq!(tracker.down(KEY, wants(true, false), start), []);
+        assert_eq!(
+            tracker.up(KEY, start + ms(100)),
+            [(KEY, Gesture::Press), (KEY, Gesture::Release)]
+        );
+    }
+
+    #[test]
+    fn double_press() {
+        let mut tracker = tracker();
+        let start = Instant::now();
+        assert_eq!(tracker.down(KEY, wants(false, true), start), []);
+        assert_eq!(tracker.up(KEY, start + ms(50)), [(KEY, Gesture::Release)]);
+        assert_eq!(tracker.next_deadline(), Some(start + ms(50) + DOUBLE));
+        assert_eq!(
+            tracker.down(KEY, wants(false, true), start + ms(150)),
+            [(KEY, Gesture::DoublePress)]
+        );
+        // Nothing is left waiting after the second press comes up
+        assert_eq!(tracker.up(KEY, start + ms(200)), [(KEY, Gesture::Release)]);
+        assert_eq!(tracker.next_deadline(), None);
+    }
+
+    #[test]
+    fn press_fires_once_the_double_press_window_expires() {
+        let mut tracker = tracker();
+        let start = Instant::now();
+        tracker.down(KEY, wants(false, true), start);
+        tracker.up(KEY, start);
+        assert_eq!(tracker.expire(start + DOUBLE - ms(1)), []);
+        assert_eq!(tracker.expire(start + DOUBLE), [(KEY, Gesture::Press)]);
+        assert_eq!(tracker.next_deadline(), None);
+
+        // Other controls keep their own windows
+        let other = Control::Encoder(0);
+        tracker.down(KEY, wants(false, true), start);
+        tracker.up(KEY, start);
+        tracker.down(other, wants(false, true), start + ms(100));
+        tracker.up(other, start + ms(100));
+        assert_eq!(tracker.expire(start + DOUBLE), [(KEY, Gesture::Press)]);
+        assert_eq!(
+            tracker.expire(start + ms(100) + DOUBLE),
+            [(other, Gesture::Press)]
+        );
+    }
+
+    #[test]
+    fn late_second_press_keeps_the_first() {
+        let mut tracker = tracker();
+        let start = Instant::now();
+        tracker.down(KEY, wants(false, true), start);
+        tracker.up(KEY, start);
+
+        // The window is over but `expire` hasn't run yet
+        let late = start + DOUBLE + ms(10);
+        assert_eq!(
+            tracker.down(KEY, wants(false, true), late),
+            [(KEY, Gesture::Press)]
+        );
+        assert_eq!(tracker.up(KEY, late + ms(50)), [(KEY, Gesture::Release)]);
+        assert_eq!(
+            tracker.expire(late + ms(50) + DOUBLE),
+            [(KEY, Gesture::Press)]
+        );
+
+        // Nothing bound but press, so both presses fire right away
+        tracker.down(KEY, wants(false, true), start);
+        tracker.up(KEY, start);
+        assert_eq!(
+            tracker.down(KEY, Wants::default(), late),
+            [(KEY, Gesture::Press), (KEY, Gesture::Press)]
+        );
+    }
+
+    #[test]
+    fn classifies_swipes() {
+        assert_eq!(classify_swipe((100, 50), (20, 55), 40), Some(Swipe::Left));
+        assert_eq!(classify_swipe((20, 50), (100, 45), 40), Some(Swipe::Right));
+        assert_eq!(classify_swipe((50, 90), (52, 10), 40), Some(Swipe::Up));
+        assert_eq!(classify_swipe((50, 10), (48, 90), 40), Some(Swipe::Down));
+        assert_eq!(classify_swipe((50, 50), (80, 50), 40), None);
+        assert_eq!(classify_swipe((0, 0), (60, 50), 40), None);
+    }
+}
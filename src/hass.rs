@@ -0,0 +1,820 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+
+use serde_json::{Map, Value, json};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use crate::config::{HassConfig, HassFace};
+use crate::status::json_field;
+use crate::websocket::{self, Link, Service, SessionError, TIMEOUT};
+
+// What the entities decks follow are doing, as of their last change
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HassState {
+    pub connected: bool,
+    entities: HashMap<String, Entity>,
+}
+
+impl HassState {
+    pub fn get(&self, entity: &str) -> Option<&Entity> {
+        self.entities.get(entity)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entity {
+    // Like "on", "off", "unavailable" or a sensor's reading
+    pub state: String,
+    pub attributes: Value,
+}
+
+impl Entity {
+    // Whether keys showing the entity light up
+    pub fn is_on(&self) -> bool {
+        matches!(self.state.as_str(), "on" | "open" | "playing" | "home")
+    }
+
+    // Where a dial on the entity stands, `None` while that isn't known
+    pub fn level(&self, id: &str) -> Option<f64> {
+        let attribute = |name: &str| self.attributes[name].as_f64();
+        match domain(id) {
+            // Brightness goes up to 255, lights that can't dim are all or nothing
+            "light" if self.state == "on" => {
+                Some(attribute("brightness").map_or(100.0, |b| b / 2.55))
+            }
+            "light" => Some(0.0),
+            "fan" => Some(attribute("percentage").unwrap_or(0.0)),
+            "cover" => attribute("current_position"),
+            "media_player" => Some(attribute("volume_level")? * 100.0),
+            "climate" => attribute("temperature"),
+            "number" | "input_number" => self.state.parse().ok(),
+            _ => None,
+        }
+    }
+
+    // What a dial on the entity moves between, `None` for entities that
+    // don't say
+    pub fn range(&self, id: &str) -> Option<(f64, f64)> {
+        let attribute = |name: &str| self.attributes[name].as_f64();
+        match domain(id) {
+            "climate" => Some((attribute("min_temp")?, attribute("max_temp")?)),
+            "number" | "input_number" => Some((attribute("min")?, attribute("max")?)),
+            _ if percent(id) => Some((0.0, 100.0)),
+            _ => None,
+        }
+    }
+}
+
+// Whether a dial can set the entity
+pub fn settable(entity: &str) -> bool {
+    percent(entity) || matches!(domain(entity), "climate" | "number" | "input_number")
+}
+
+// Whether dials on the entity set a percentage
+pub fn percent(entity: &str) -> bool {
+    matches!(domain(entity), "light" | "fan" | "cover" | "media_player")
+}
+
+// "light" of "light.desk"
+fn domain(entity: &str) -> &str {
+    entity.split_once('.').map_or("", |(domain, _)| domain)
+}
+
+// An entity a key shows the state or an attribute of
+pub struct Binding {
+    entity: String,
+    attribute: Option<Vec<String>>,
+    title: Option<String>,
+    max: Option<f64>,
+}
+
+impl Binding {
+    pub fn new(config: &HassFace) -> Binding {
+        Binding {
+            entity: config.entity.clone(),
+            attribute: config.attribute.as_ref().map(|path| {
+                path.split('.')
+                    .filter(|part| !part.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }),
+            title: config.title.clone(),
+            max: config.max,
+        }
+    }
+
+    pub fn entity(&self) -> &str {
+        &self.entity
+    }
+
+    // What the key's title becomes, `None` to keep its own. Entities that are
+    // just on or off show that by their background alone, unless the title
+    // asks for it.
+    pub fn title(&self, entity: &Entity) -> Option<String> {
+        let value = self.value(entity)?;
+        if let Some(title) = &self.title {
+            return Some(title.replace("{value}", &value));
+        }
+        let switch = self.attribute.is_none() && matches!(entity.state.as_str(), "on" | "off");
+        (!switch).then_some(value)
+    }
+
+    fn value(&self, entity: &Entity) -> Option<String> {
+        let (value, unit) = match &self.attribute {
+            Some(path) => (json_field(&entity.attributes, path)?, None),
+            None => {
+                let unit = entity.attributes["unit_of_measurement"].as_str();
+                (entity.state.clone(), unit)
+            }
+        };
+        // Lights that are off have no brightness at all
+        if value == "null" {
+            return None;
+        }
+        if let (Some(max), Ok(number)) = (self.max, value.parse::<f64>()) {
+            return Some(format!("{}%", (number / max * 100.0).round()));
+        }
+        Some(match unit {
+            Some(unit) => format!("{} {}", value, unit),
+            None => value,
+        })
+    }
+}
+
+// Something for Home Assistant to do
+#[derive(Clone, Debug)]
+pub enum Request {
+    // A service like "light.toggle", on the entity when there is one
+    Call {
+        service: String,
+        entity: Option<String>,
+        data: Map<String, Value>,
+    },
+    // Where a dial on the entity was turned to
+    Level {
+        entity: String,
+        value: f64,
+    },
+    // Entities a deck shows, kept in the state from now on
+    Follow(Vec<String>),
+}
+
+// The task talking to Home Assistant, started by whatever needs it first
+struct Hass {
+    requests: mpsc::UnboundedSender<Request>,
+    state: watch::Receiver<HassState>,
+}
+
+static SERVER: OnceLock<(String, Option<String>)> = OnceLock::new();
+
+// Where Home Assistant listens, from the config the program started with
+pub fn configure(config: &HassConfig) {
+    let _ = SERVER.set((config.url().to_string(), config.token.clone()));
+}
+
+// What the entities are doing, none of them while Home Assistant isn't
+// connected
+pub fn watch(entities: Vec<String>) -> watch::Receiver<HassState> {
+    send(Request::Follow(entities));
+    hass().state.clone()
+}
+
+// Hands the request to Home Assistant, failures are logged there
+pub fn send(request: Request) {
+    if let Err(e) = hass().requests.send(request) {
+        eprintln!("Home Assistant unreachable, dropped {:?}", e.0);
+    }
+}
+
+fn hass() -> &'static Hass {
+    static HASS: OnceLock<Hass> = OnceLock::new();
+
+    HASS.get_or_init(|| {
+        let (requests, rx) = mpsc::unbounded_channel();
+        let (tx, state) = watch::channel(HassState::default());
+        let (url, token) = SERVER
+            .get()
+            .cloned()
+            .unwrap_or_else(|| (HassConfig::default().url().to_string(), None));
+        let client = Client {
+            token,
+            followed: HashSet::new(),
+        };
+        tokio::spawn(websocket::serve(client, url, rx, tx));
+        Hass { requests, state }
+    })
+}
+
+// What it takes to connect to Home Assistant, and the entities decks follow
+// from one connection to the next
+struct Client {
+    token: Option<String>,
+    followed: HashSet<String>,
+}
+
+impl Service for Client {
+    type Session = Session;
+    type Request = Request;
+    type State = HassState;
+
+    const NAME: &'static str = "Home Assistant";
+
+    async fn connect(&mut self, url: &str) -> Result<Session, SessionError> {
+        // Home Assistant would only turn it away, no use connecting
+        let token = self.token.as_deref().ok_or_else(|| {
+            SessionError::Auth(
+                "Home Assistant wants an access token, set one under [home_assistant]".to_string(),
+            )
+        })?;
+        Session::connect(url, token).await
+    }
+
+    async fn run(
+        &mut self,
+        session: &mut Session,
+        requests: &mut mpsc::UnboundedReceiver<Request>,
+        state: &watch::Sender<HassState>,
+    ) -> Result<(), SessionError> {
+        session.run(requests, &mut self.followed, state).await
+    }
+
+    fn offline(&mut self, request: Request) -> Option<Request> {
+        match request {
+            Request::Follow(entities) => {
+                self.followed.extend(entities);
+                None
+            }
+            request => Some(request),
+        }
+    }
+}
+
+// One connection to Home Assistant, authenticated and subscribed to state
+// changes
+struct Session {
+    link: Link,
+    next_id: u64,
+    // Every entity there is, only the followed ones go out to decks
+    entities: HashMap<String, Entity>,
+}
+
+impl Session {
+    async fn connect(url: &str, token: &str) -> Result<Session, SessionError> {
+        let deadline = Instant::now() + TIMEOUT;
+        let mut session = Session {
+            link: Link::open(url, deadline).await?,
+            next_id: 0,
+            entities: HashMap::new(),
+        };
+
+        session.expect("auth_required", deadline).await?;
+        session
+            .send(json!({ "type": "auth", "access_token": token }))
+            .await?;
+        let answer = session.expect_any(deadline).await?;
+        match answer["type"].as_str().unwrap_or_default() {
+            "auth_ok" => {}
+            "auth_invalid" => {
+                let message = answer["message"].as_str().unwrap_or("invalid access token");
+                return Err(SessionError::Auth(message.to_string()));
+            }
+            other => {
+                return Err(SessionError::Protocol(format!(
+                    "expected auth_ok, got '{}'",
+                    other
+                )));
+            }
+        }
+
+        // Subscribed first so no change slips in between
+        session
+            .call(json!({ "type": "subscribe_events", "event_type": "state_changed" }))
+            .await?;
+        let states = session.call(json!({ "type": "get_states" })).await?;
+        for state in states.as_array().into_iter().flatten() {
+            if let (Some(id), Some(entity)) = (state["entity_id"].as_str(), entity(state)) {
+                session.entities.insert(id.to_string(), entity);
+            }
+        }
+        Ok(session)
+    }
+
+    // Does what's asked and follows state changes until the connection drops,
+    // or the program no longer has requests for it
+    async fn run(
+        &mut self,
+        requests: &mut mpsc::UnboundedReceiver<Request>,
+        followed: &mut HashSet<String>,
+        state: &watch::Sender<HassState>,
+    ) -> Result<(), SessionError> {
+        loop {
+            state.send_if_modified(|shown| {
+                let now = HassState {
+                    connected: true,
+                    entities: followed
+                        .iter()
+                        .filter_map(|id| Some((id.clone(), self.entities.get(id)?.clone())))
+                        .collect(),
+                };
+                let changed = *shown != now;
+                *shown = now;
+                changed
+            });
+
+            tokio::select! {
+                request = requests.recv() => {
+                    let Some(request) = request else { return Ok(()) };
+                    let mut batch = vec![request];
+                    while let Ok(request) = requests.try_recv() {
+                        batch.push(request);
+                    }
+                    // A spinning dial only needs to end up where it stopped
+                    batch.dedup_by(|next, kept| match (&*next, &*kept) {
+                        (Request::Level { entity: a, .. }, Request::Level { entity: b, .. })
+                            if a == b =>
+                        {
+                            std::mem::swap(next, kept);
+                            true
+                        }
+                        _ => false,
+                    });
+                    for request in batch {
+                        if let Request::Follow(entities) = request {
+                            followed.extend(entities);
+                            continue;
+                        }
+                        match self.handle(&request).await {
+                            Err(e) if e.is_fatal() => return Err(e),
+                            Err(e) => eprintln!("Home Assistant {:?}: {}", request, e),
+                            Ok(()) => {}
+                        }
+                    }
+                }
+                message = self.link.next() => self.event(&typed(message?)?),
+            }
+        }
+    }
+
+    async fn handle(&mut self, request: &Request) -> Result<(), SessionError> {
+        let (service, entity, data) = match request {
+            Request::Call {
+                service,
+                entity,
+                data,
+            } => (
+                service.clone(),
+                entity.as_deref(),
+                Value::from(data.clone()),
+            ),
+            Request::Level { entity, value } => {
+                let (service, data) = level(entity, *value)?;
+                (service, Some(entity.as_str()), data)
+            }
+            Request::Follow(_) => return Ok(()),
+        };
+        let Some((domain, service)) = service.split_once('.') else {
+            return Err(SessionError::Refused(format!(
+                "'{}' isn't a service like light.toggle",
+                service
+            )));
+        };
+        let mut message = json!({
+            "type": "call_service",
+            "domain": domain,
+            "service": service,
+            "service_data": data,
+        });
+        if let Some(entity) = entity {
+            message["target"] = json!({ "entity_id": entity });
+        }
+        self.call(message).await?;
+        Ok(())
+    }
+
+    // Keeps the entities in step with a state change
+    fn event(&mut self, message: &Value) {
+        if message["type"] != "event" {
+            return;
+        }
+        let data = &message["event"]["data"];
+        let Some(id) = data["entity_id"].as_str() else {
+            return;
+        };
+        match entity(&data["new_state"]) {
+            Some(entity) => self.entities.insert(id.to_string(), entity),
+            // Removed
+            None => self.entities.remove(id),
+        };
+    }
+
+    // Sends a command and waits for its result, keeping up with state
+    // changes that arrive in the meantime
+    async fn call(&mut self, mut message: Value) -> Result<Value, SessionError> {
+        self.next_id += 1;
+        let id = self.next_id;
+        message["id"] = id.into();
+        let command = message["type"].as_str().unwrap_or_default().to_string();
+        self.send(message).await?;
+
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let message = typed(self.link.next_before(deadline).await?)?;
+            if message["type"] != "result" || message["id"] != id {
+                self.event(&message);
+                continue;
+            }
+            if message["success"].as_bool() == Some(true) {
+                return Ok(message["result"].clone());
+            }
+            let error = &message["error"];
+            return Err(SessionError::Refused(format!(
+                "{}: {} ({})",
+                command,
+                error["message"].as_str().unwrap_or("refused"),
+                error["code"].as_str().unwrap_or_default()
+            )));
+        }
+    }
+
+    async fn send(&mut self, message: Value) -> Result<(), SessionError> {
+        self.link.send(&message).await
+    }
+
+    // Waits for the message that comes next in the handshake
+    async fn expect(&mut self, kind: &str, deadline: Instant) -> Result<Value, SessionError> {
+        let message = self.expect_any(deadline).await?;
+        if message["type"] != kind {
+            return Err(SessionError::Protocol(format!(
+                "expected {}, got '{}'",
+                kind,
+                message["type"].as_str().unwrap_or_default()
+            )));
+        }
+        Ok(message)
+    }
+
+    async fn expect_any(&mut self, deadline: Instant) -> Result<Value, SessionError> {
+        typed(self.link.next_before(deadline).await?)
+    }
+}
+
+// A protocol message, as long as it says what type it is
+fn typed(message: Value) -> Result<Value, SessionError> {
+    if !message["type"].is_string() {
+        return Err(SessionError::Protocol("message without a type".to_string()));
+    }
+    Ok(message)
+}
+
+// The entity in a state object of get_states or a state change, `None` for
+// the null new state of a removed entity
+fn entity(state: &Value) -> Option<Entity> {
+    Some(Entity {
+        state: state["state"].as_str()?.to_string(),
+        attributes: state["attributes"].clone(),
+    })
+}
+
+// The service and data that move the entity to where a dial was turned
+fn level(entity: &str, value: f64) -> Result<(String, Value), SessionError> {
+    let domain = domain(entity);
+    let (service, data) = match domain {
+        "light" if value <= 0.0 => ("turn_off", json!({})),
+        "light" => ("turn_on", json!({ "brightness_pct": value.round() as i64 })),
+        "fan" => (
+            "set_percentage",
+            json!({ "percentage": value.round() as i64 }),
+        ),
+        "cover" => (
+            "set_cover_position",
+            json!({ "position": value.round() as i64 }),
+        ),
+        "media_player" => ("volume_set", json!({ "volume_level": value / 100.0 })),
+        "climate" => ("set_temperature", json!({ "temperature": value })),
+        "number" | "input_number" => ("set_value", json!({ "value": value })),
+        _ => {
+            return Err(SessionError::Refused(format!(
+                "{} can't be set by a dial",
+                entity
+            )));
+        }
+    };
+    Ok((format!("{}.{}", domain, service), data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ActionConfig, Config};
+    use crate::dial::Dial;
+    use crate::layout::Layout;
+    use crate::websocket::fake::{self, Fake, Peer, Server, until};
+    use std::path::Path;
+
+    const TOKEN: &str = "secret";
+
+    // What the fake Home Assistant has, a desk light and a thermostat
+    struct Entities {
+        light: Value,
+        climate: Value,
+        // Id of the state_changed subscription, events go out under it
+        subscription: Option<u64>,
+    }
+
+    impl Entities {
+        fn new() -> Entities {
+            Entities {
+                light: light(false),
+                climate: climate(20.5),
+                subscription: None,
+            }
+        }
+
+        fn state_changed(&self, change: Value) -> Value {
+            json!({
+                "id": self.subscription.unwrap(),
+                "type": "event",
+                "event": { "event_type": "state_changed", "data": change },
+            })
+        }
+    }
+
+    // Speaks the WebSocket API the way Home Assistant does. The fake sees the
+    // services called, as domain, service, target and data.
+    impl Fake for Entities {
+        async fn greet(&mut self, peer: &mut Peer) -> bool {
+            peer.send(json!({ "type": "auth_required", "ha_version": "2025.1.0" }))
+                .await;
+            let Some(auth) = peer.receive().await else {
+                return false;
+            };
+            assert_eq!(auth["type"], "auth");
+            if auth["access_token"] != TOKEN {
+                let invalid = json!({ "type": "auth_invalid", "message": "Invalid access token" });
+                peer.send(invalid).await;
+                peer.close(1000, "").await;
+                return false;
+            }
+            peer.send(json!({ "type": "auth_ok", "ha_version": "2025.1.0" }))
+                .await;
+            true
+        }
+
+        async fn answer(&mut self, peer: &mut Peer, message: Value) {
+            let id = message["id"].as_u64().unwrap();
+            let state = |id: &str, state: &Value| {
+                json!({
+                    "entity_id": id,
+                    "state": state["state"],
+                    "attributes": state["attributes"],
+                })
+            };
+            let result = match message["type"].as_str().unwrap() {
+                "subscribe_events" => {
+                    assert_eq!(message["event_type"], "state_changed");
+                    self.subscription = Some(id);
+                    Ok(json!(null))
+                }
+                "get_states" => Ok(json!([
+                    state(
+                        "sun.sun",
+                        &json!({ "state": "above_horizon", "attributes": {} })
+                    ),
+                    state("light.desk", &self.light),
+                    state("climate.hall", &self.climate),
+                ])),
+                "call_service" => {
+                    peer.saw(json!({
+                        "domain": message["domain"],
+                        "service": message["service"],
+                        "target": message["target"],
+                        "service_data": message["service_data"],
+                    }));
+                    let service = (message["domain"].as_str(), message["service"].as_str());
+                    match service {
+                        (Some("light"), Some("toggle")) => {
+                            self.light = light(self.light["state"] != "on");
+                            // The change goes out before the result, like it does
+                            let change = change("light.desk", self.light.clone());
+                            peer.send(self.state_changed(change)).await;
+                            Ok(json!({ "context": {} }))
+                        }
+                        (Some("light" | "climate"), _) => Ok(json!({ "context": {} })),
+                        _ => Err(json!({ "code": "not_found", "message": "Service not found." })),
+                    }
+                }
+                other => panic!("unexpected {}", other),
+            };
+            let answer = match result {
+                Ok(result) => {
+                    json!({ "id": id, "type": "result", "success": true, "result": result })
+                }
+                Err(error) => {
+                    json!({ "id": id, "type": "result", "success": false, "error": error })
+                }
+            };
+            peer.send(answer).await;
+        }
+
+        async fn push(&mut self, peer: &mut Peer, change: Value) {
+            peer.send(self.state_changed(change)).await;
+        }
+    }
+
+    // An entity changing like someone at the wall switch would change it
+    fn change(entity: &str, state: Value) -> Value {
+        json!({ "entity_id": entity, "new_state": state })
+    }
+
+    fn light(on: bool) -> Value {
+        match on {
+            true => json!({ "state": "on", "attributes": { "brightness": 255 } }),
+            false => json!({ "state": "off", "attributes": { "brightness": null } }),
+        }
+    }
+
+    fn climate(temperature: f64) -> Value {
+        json!({
+            "state": "heat",
+            "attributes": { "temperature": temperature, "min_temp": 7, "max_temp": 35 },
+        })
+    }
+
+    fn client(token: Option<&str>) -> Client {
+        Client {
+            token: token.map(str::to_string),
+            followed: HashSet::new(),
+        }
+    }
+
+    // Serves the program's side against the fake at `url`, following the
+    // light and the thermostat
+    fn connect(
+        url: &str,
+        token: Option<&str>,
+    ) -> (mpsc::UnboundedSender<Request>, watch::Receiver<HassState>) {
+        let (requests, state) = fake::connect(client(token), url);
+        let follow = vec!["light.desk".to_string(), "climate.hall".to_string()];
+        requests.send(Request::Follow(follow)).unwrap();
+        (requests, state)
+    }
+
+    const CONFIG: &str = r#"
+[[keys]]
+index = 0
+hass = { entity = "light.desk" }
+on_press = { type = "hass", service = "light.toggle", entity = "light.desk" }
+
+[[encoders]]
+index = 1
+dial = { target = "entity", entity = "climate.hall", step = 0.5 }
+
+[home_assistant]
+on_color = [1, 2, 3]
+"#;
+
+    #[tokio::test]
+    async fn authenticates() {
+        let hass = Server::start("/api/websocket").await;
+        let (_requests, mut state) = connect(&hass.url, Some(TOKEN));
+        let _connection = hass.accept(Entities::new()).await;
+        until(&mut state, |s| s.connected).await;
+
+        let now = state.borrow().clone();
+        let light = now.get("light.desk").unwrap();
+        assert_eq!(light.state, "off");
+        assert!(!light.is_on());
+        assert_eq!(
+            now.get("climate.hall").unwrap().level("climate.hall"),
+            Some(20.5)
+        );
+        // Entities nobody follows stay out of it
+        assert_eq!(now.get("sun.sun"), None);
+    }
+
+    #[tokio::test]
+    async fn refused_token_gives_up() {
+        let hass = Server::start("/api/websocket").await;
+        let (_requests, state) = connect(&hass.url, Some("guess"));
+        hass.accept(Entities::new()).await.closed().await;
+        // Trying again would only be refused again
+        hass.unvisited().await;
+        assert!(!state.borrow().connected);
+
+        let mut guess = client(Some("guess"));
+        let session = guess.connect(&hass.url);
+        let (session, _connection) = tokio::join!(session, hass.accept(Entities::new()));
+        assert!(matches!(session, Err(SessionError::Auth(m)) if m == "Invalid access token"));
+    }
+
+    #[tokio::test]
+    async fn missing_token_never_connects() {
+        let hass = Server::start("/api/websocket").await;
+        let (_requests, state) = connect(&hass.url, None);
+        hass.unvisited().await;
+        assert!(!state.borrow().connected);
+    }
+
+    #[tokio::test]
+    async fn state_changes_reach_keys_and_dials() {
+        let config = Config::parse(Path::new("test.toml"), CONFIG.to_string()).unwrap();
+        let mut layout = Layout::new(&config);
+        let dial_config = config.encoders[0].dial.as_ref().unwrap().get_ref();
+        let mut dial = Dial::new(dial_config, config.brightness());
+        let hass = Server::start("/api/websocket").await;
+        let (_requests, mut state) = connect(&hass.url, Some(TOKEN));
+        let connection = hass.accept(Entities::new()).await;
+        until(&mut state, |s| s.connected).await;
+
+        let now = state.borrow().clone();
+        layout.show_hass(&now);
+        assert_eq!(layout.face(0).background, None);
+
+        connection.push(change("light.desk", light(true)));
+        until(&mut state, |s| {
+            s.get("light.desk").is_some_and(Entity::is_on)
+        })
+        .await;
+        let now = state.borrow().clone();
+        assert!(layout.show_hass(&now));
+        assert_eq!(layout.face(0).background, Some([1, 2, 3]));
+
+        connection.push(change("climate.hall", climate(22.0)));
+        until(&mut state, |s| {
+            s.get("climate.hall").and_then(|e| e.level("climate.hall")) == Some(22.0)
+        })
+        .await;
+        let now = state.borrow().clone();
+        let entity = now.get("climate.hall").unwrap();
+        assert!(dial.follow(
+            entity.level("climate.hall").unwrap(),
+            entity.range("climate.hall")
+        ));
+        assert_eq!(dial.value(), 22.0);
+    }
+
+    #[tokio::test]
+    async fn key_press_calls_service() {
+        let config = Config::parse(Path::new("test.toml"), CONFIG.to_string()).unwrap();
+        let Some(ActionConfig::Hass {
+            service,
+            entity,
+            data,
+        }) = config.keys[0].on_press.clone()
+        else {
+            panic!("not a hass action");
+        };
+        let hass = Server::start("/api/websocket").await;
+        let (requests, mut state) = connect(&hass.url, Some(TOKEN));
+        let mut connection = hass.accept(Entities::new()).await;
+        until(&mut state, |s| s.connected).await;
+
+        requests
+            .send(Request::Call {
+                service,
+                entity,
+                data,
+            })
+            .unwrap();
+        assert_eq!(
+            connection.seen().await,
+            json!({
+                "domain": "light",
+                "service": "toggle",
+                "target": { "entity_id": "light.desk" },
+                "service_data": {},
+            })
+        );
+        until(&mut state, |s| {
+            s.get("light.desk").is_some_and(Entity::is_on)
+        })
+        .await;
+
+        // A spinning dial only sets where it stopped
+        for value in [21.0, 21.5, 23.0] {
+            let entity = "climate.hall".to_string();
+            requests.send(Request::Level { entity, value }).unwrap();
+        }
+        let call = connection.seen().await;
+        assert_eq!(call["service"], "set_temperature");
+        assert_eq!(call["service_data"], json!({ "temperature": 23.0 }));
+
+        // Refused services leave the connection up
+        let refused = Request::Call {
+            service: "vacuum.start".to_string(),
+            entity: None,
+            data: Map::new(),
+        };
+        requests.send(refused).unwrap();
+        assert_eq!(connection.seen().await["target"], Value::Null);
+        requests
+            .send(Request::Level {
+                entity: "light.desk".to_string(),
+                value: 0.0,
+            })
+            .unwrap();
+        assert_eq!(connection.seen().await["service"], "turn_off");
+        assert!(state.borrow().connected);
+    }
+}
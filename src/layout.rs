@@ -0,0 +1,867 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::action::{self, Binding, Bindings, Navigation};
+use crate::audio::Levels;
+use crate::config::{self, ActionConfig, AudioDevice, Config, KeyConfig, MediaFace};
+use crate::gesture::{Control, Swipe, Wants};
+use crate::hass::{self, HassState};
+use crate::media::NowPlaying;
+use crate::mqtt::{self, Message, Shown};
+use crate::obs::{ObsState, Tally};
+use crate::query::Output;
+use crate::render::{Icons, TextStyle};
+use crate::status::{Reading, Status};
+
+// The top level `[[keys]]` of the config
+const HOME: &str = "";
+// Background of live OBS keys when the config doesn't say
+const TALLY: [u8; 3] = [200, 0, 0];
+// Background of Home Assistant keys whose entity is on when the config doesn't say
+const ON_COLOR: [u8; 3] = [230, 150, 0];
+
+// What a key is showing, keys with the same face on both pages aren't re-uploaded
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Face {
+    pub background: Option<[u8; 3]>,
+    pub icon: Icon,
+    pub title: Option<String>,
+    pub text: TextStyle,
+    // The title runs along one line, scrolling when it doesn't fit, instead
+    // of wrapping
+    pub scroll: bool,
+}
+
+impl Face {
+    // Nothing to draw, whatever the title would look like
+    pub fn is_blank(&self) -> bool {
+        self.background.is_none() && self.icon == Icon::None && self.title.is_none()
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum Icon {
+    #[default]
+    None,
+    File(PathBuf),
+    // Automatic back key on sub-pages, with an optional custom icon
+    Back(Option<PathBuf>),
+}
+
+struct Page {
+    keys: HashMap<u8, Bindings>,
+    faces: HashMap<u8, Face>,
+    labels: HashMap<u8, String>,
+    states: HashMap<u8, KeyStates>,
+    statuses: HashMap<u8, Arc<Status>>,
+    media: HashMap<u8, MediaKey>,
+    audio: HashMap<u8, AudioKey>,
+    obs: HashMap<u8, ObsKey>,
+    mqtt: HashMap<u8, mqtt::Binding>,
+    hass: HashMap<u8, HassKey>,
+}
+
+// A key showing what the media player is playing, with the icon and title it
+// falls back to when there's nothing
+struct MediaKey {
+    show: MediaFace,
+    icon: Icon,
+    title: Option<String>,
+}
+
+// A key showing the volume of a sink or source below its own title
+struct AudioKey {
+    device: AudioDevice,
+    title: Option<String>,
+}
+
+// A key pressed for an OBS action, in the tally color while what it
+// controls is live
+struct ObsKey {
+    tally: Tally,
+    color: [u8; 3],
+    background: Option<[u8; 3]>,
+}
+
+// A key showing a Home Assistant entity, in its color while the entity is on
+// and with its own title while the entity has nothing to show
+struct HassKey {
+    binding: hass::Binding,
+    color: [u8; 3],
+    background: Option<[u8; 3]>,
+    title: Option<String>,
+}
+
+// A key that steps through named states. The current state's face and
+// actions are what `faces` and `keys` hold for it.
+struct KeyStates {
+    states: Vec<KeyState>,
+    current: usize,
+    // Asks which state the key is in
+    command: Option<String>,
+}
+
+struct KeyState {
+    name: String,
+    face: Face,
+    bindings: Option<Bindings>,
+}
+
+// Everything bound on the deck: keys per page plus the encoders and touch points
+// that stay the same whatever page is showing
+pub struct Layout {
+    pages: HashMap<String, Page>,
+    current: String,
+    history: Vec<String>,
+    controls: HashMap<Control, Bindings>,
+    labels: HashMap<Control, String>,
+    // Taps and holds on the LCD zones, by their position in `[[lcd.zones]]`
+    zones: HashMap<usize, Bindings>,
+    swipes: HashMap<Swipe, Binding>,
+    swipe_distance: u16,
+    // Title style of keys that aren't configured, in case they get a title
+    text: TextStyle,
+}
+
+impl Layout {
+    pub fn new(config: &Config) -> Layout {
+        let mut pages = HashMap::new();
+        pages.insert(HOME.to_string(), build_page(config, &config.keys));
+
+        for (name, page_config) in &config.pages {
+            let mut page = build_page(config, &page_config.keys);
+            let back_key = page_config.back_key();
+            let back_icon = page_config
+                .back_icon
+                .as_ref()
+                .map(|icon| config.resolve_path(icon.get_ref()));
+
+            page.keys.insert(
+                back_key,
+                Bindings {
+                    press: Some(Binding::Navigate(Navigation::Back)),
+                    ..Default::default()
+                },
+            );
+            page.faces.insert(
+                back_key,
+                Face {
+                    icon: Icon::Back(back_icon),
+                    ..Default::default()
+                },
+            );
+            pages.insert(name.clone(), page);
+        }
+
+        let mut controls = HashMap::new();
+        let mut labels = HashMap::new();
+        for e in &config.encoders {
+            let control = Control::Encoder(*e.index.get_ref());
+            let actions = [
+                &e.on_press,
+                &e.on_release,
+                &e.on_long_press,
+                &e.on_double_press,
+            ];
+            if let Some(b) = bindings(control, actions) {
+                controls.insert(control, b);
+            }
+            if let Some(label) = &e.label {
+                labels.insert(control, label.clone());
+            }
+        }
+        for t in &config.touchpoints {
+            let control = Control::Touchpoint(*t.index.get_ref());
+            let actions = [
+                &t.on_press,
+                &t.on_release,
+                &t.on_long_press,
+                &t.on_double_press,
+            ];
+            if let Some(b) = bindings(control, actions) {
+                controls.insert(control, b);
+            }
+            if let Some(label) = &t.label {
+                labels.insert(control, label.clone());
+            }
+        }
+
+        let mut zones = HashMap::new();
+        for (i, zone) in config.lcd.zones.iter().enumerate() {
+            let name = zone.name.clone().unwrap_or_else(|| i.to_string());
+            match Bindings::new(&zone.on_tap, &None, &zone.on_long_press, &None) {
+                Ok(b) if !b.is_empty() => {
+                    zones.insert(i, b);
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Zone {} left unbound: {}", name, e),
+            }
+        }
+
+        let mut swipes = HashMap::new();
+        let touch = &config.touch;
+        for (swipe, action) in [
+            (Swipe::Left, &touch.on_swipe_left),
+            (Swipe::Right, &touch.on_swipe_right),
+            (Swipe::Up, &touch.on_swipe_up),
+            (Swipe::Down, &touch.on_swipe_down),
+        ] {
+            let Some(action) = action else { continue };
+            match action::build(action.get_ref()) {
+                Ok(binding) => {
+                    swipes.insert(swipe, binding);
+                }
+                Err(e) => eprintln!("Swipe {:?} left unbound: {}", swipe, e),
+            }
+        }
+
+        Layout {
+            pages,
+            current: HOME.to_string(),
+            history: vec![],
+            controls,
+            labels,
+            zones,
+            swipes,
+            swipe_distance: config.swipe_distance(),
+            text: TextStyle::new(config, &config.text),
+        }
+    }
+
+    // Name of the page that's showing, for logging
+    pub fn current(&self) -> &str {
+        if self.current == HOME {
+            config::HOME_PAGE
+        } else {
+            &self.current
+        }
+    }
+
+    pub fn bindings(&self, control: Control) -> Option<&Bindings> {
+        match control {
+            Control::Key(key) => self.pages[&self.current].keys.get(&key),
+            _ => self.controls.get(&control),
+        }
+    }
+
+    pub fn zone_bindings(&self, zone: usize) -> Option<&Bindings> {
+        self.zones.get(&zone)
+    }
+
+    pub fn swipe(&self, swipe: Swipe) -> Option<&Binding> {
+        self.swipes.get(&swipe)
+    }
+
+    // How far a touch has to travel before it's a swipe
+    pub fn swipe_distance(&self) -> u16 {
+        self.swipe_distance
+    }
+
+    pub fn label(&self, control: Control) -> Option<&str> {
+        match control {
+            Control::Key(key) => self.pages[&self.current].labels.get(&key),
+            _ => self.labels.get(&control),
+        }
+        .map(|label| label.as_str())
+    }
+
+    pub fn wants(&self, control: Control) -> Wants {
+        self.bindings(control)
+            .map(|b| b.wants())
+            .unwrap_or_default()
+    }
+
+    pub fn face(&self, key: u8) -> Face {
+        self.pages[&self.current]
+            .faces
+            .get(&key)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Changes the title of a key on the current page, returns whether it changed
+    pub fn set_title(&mut self, key: u8, title: Option<String>) -> bool {
+        let face = self.face_mut(key);
+        if face.title == title {
+            return false;
+        }
+        face.title = title;
+        true
+    }
+
+    // Swaps the icon of a key on the current page, returns whether it changed
+    pub fn set_icon(&mut self, key: u8, path: Option<PathBuf>) -> bool {
+        let icon = path.map(Icon::File).unwrap_or_default();
+        let face = self.face_mut(key);
+        if face.icon == icon {
+            return false;
+        }
+        face.icon = icon;
+        true
+    }
+
+    // Name of the state a key on the current page is in, for keys with states
+    pub fn key_state(&self, key: u8) -> Option<&str> {
+        let states = self.pages[&self.current].states.get(&key)?;
+        Some(&states.states[states.current].name)
+    }
+
+    // Moves a key on the current page on to its next state, returns whether
+    // it has states at all
+    pub fn advance(&mut self, key: u8) -> bool {
+        let page = self
+            .pages
+            .get_mut(&self.current)
+            .expect("current page exists");
+        let Some(states) = page.states.get(&key) else {
+            return false;
+        };
+        let next = (states.current + 1) % states.states.len();
+        page.show_state(key, next);
+        true
+    }
+
+    // Puts a key on the current page into a state by name, returns whether it
+    // changed
+    pub fn set_key_state(&mut self, key: u8, name: &str) -> Result<bool, String> {
+        let page = self
+            .pages
+            .get_mut(&self.current)
+            .expect("current page exists");
+        let states = page
+            .states
+            .get(&key)
+            .ok_or(format!("key {} has no states", key))?;
+        let Some(index) = states.states.iter().position(|s| s.name == name) else {
+            let known: Vec<_> = states.states.iter().map(|s| s.name.as_str()).collect();
+            return Err(format!(
+                "key {} has no state '{}' (it has: {})",
+                key,
+                name,
+                known.join(", ")
+            ));
+        };
+        Ok(page.show_state(key, index))
+    }
+
+    // Every key's state command with the page and key it's for. Pages are
+    // named the way `answer_state` wants them back.
+    pub fn state_commands(&self) -> Vec<(String, u8, String)> {
+        let mut commands = vec![];
+        for (name, page) in &self.pages {
+            for (key, states) in &page.states {
+                if let Some(command) = &states.command {
+                    commands.push((name.clone(), *key, command.clone()));
+                }
+            }
+        }
+        commands
+    }
+
+    // The state command of a key on the current page
+    pub fn state_command(&self, key: u8) -> Option<(String, u8, String)> {
+        let command = self.pages[&self.current]
+            .states
+            .get(&key)?
+            .command
+            .clone()?;
+        Some((self.current.clone(), key, command))
+    }
+
+    // Puts a key into the state its command reported: the state named by what
+    // it printed, or else the state at the position of its exit code. Returns
+    // whether a key on the current page changed.
+    pub fn answer_state(&mut self, page: &str, key: u8, output: &Output) -> Result<bool, String> {
+        let showing = page == self.current;
+        let Some(states) = self.pages.get_mut(page).and_then(|p| p.states.get(&key)) else {
+            return Ok(false);
+        };
+        let printed = output.stdout.trim();
+        let index = states
+            .states
+            .iter()
+            .position(|s| s.name == printed)
+            .or_else(|| {
+                let code = usize::try_from(output.code?).ok()?;
+                (code < states.states.len()).then_some(code)
+            });
+        let Some(index) = index else {
+            let code = match output.code {
+                Some(code) => code.to_string(),
+                None => "a signal".to_string(),
+            };
+            return Err(format!(
+                "printed '{}' and exited with {}, which is none of its states",
+                printed, code
+            ));
+        };
+
+        let page = self.pages.get_mut(page).expect("page exists");
+        Ok(page.show_state(key, index) && showing)
+    }
+
+    // Every status key with the page and key it's for. Pages are named the
+    // way `show_status` wants them back.
+    pub fn statuses(&self) -> Vec<(String, u8, Arc<Status>)> {
+        let mut statuses = vec![];
+        for (name, page) in &self.pages {
+            for (key, status) in &page.statuses {
+                statuses.push((name.clone(), *key, status.clone()));
+            }
+        }
+        statuses
+    }
+
+    // Shows the latest reading of a status key, returns whether a key on the
+    // current page changed
+    pub fn show_status(&mut self, page: &str, key: u8, reading: Reading) -> bool {
+        let showing = page == self.current;
+        let Some(face) = self.pages.get_mut(page).and_then(|p| p.faces.get_mut(&key)) else {
+            return false;
+        };
+        let title = Some(reading.title);
+        if face.title == title && face.background == reading.background {
+            return false;
+        }
+        face.title = title;
+        face.background = reading.background;
+        showing
+    }
+
+    // Shows the media player's track on every media key, returns whether a
+    // key on the current page changed
+    pub fn show_media(&mut self, playing: Option<&NowPlaying>) -> bool {
+        let art = playing.and_then(|p| p.art.clone()).map(Icon::File);
+        let title = playing.and_then(|p| p.title.clone());
+        let mut changed = false;
+
+        for (name, page) in &mut self.pages {
+            for (key, media) in &page.media {
+                let (icon, shown) = match media.show {
+                    MediaFace::Art => (art.clone(), None),
+                    MediaFace::Title => (None, title.clone()),
+                    MediaFace::ArtAndTitle => (art.clone(), title.clone()),
+                };
+                let icon = icon.unwrap_or_else(|| media.icon.clone());
+                let shown = shown.or_else(|| media.title.clone());
+                let Some(face) = page.faces.get_mut(key) else {
+                    continue;
+                };
+                if face.icon != icon || face.title != shown {
+                    face.icon = icon;
+                    face.title = shown;
+                    changed |= *name == self.current;
+                }
+            }
+        }
+        changed
+    }
+
+    // Whether any page has a key showing the media player
+    pub fn has_media(&self) -> bool {
+        self.pages.values().any(|page| !page.media.is_empty())
+    }
+
+    // Keys on the current page showing the media player
+    pub fn media_keys(&self) -> Vec<u8> {
+        self.pages[&self.current].media.keys().copied().collect()
+    }
+
+    // Shows the level of its device on every audio key, returns whether a key
+    // on the current page changed
+    pub fn show_audio(&mut self, levels: &Levels) -> bool {
+        let mut changed = false;
+        for (name, page) in &mut self.pages {
+            for (key, audio) in &page.audio {
+                let level = levels.get(audio.device).map(|level| level.text());
+                let shown = match (&audio.title, level) {
+                    (Some(title), Some(level)) => Some(format!("{}\n{}", title, level)),
+                    (title, level) => level.or(title.clone()),
+                };
+                let Some(face) = page.faces.get_mut(key) else {
+                    continue;
+                };
+                if face.title != shown {
+                    face.title = shown;
+                    changed |= *name == self.current;
+                }
+            }
+        }
+        changed
+    }
+
+    // Whether any page has a key showing a sink or source
+    pub fn has_audio(&self) -> bool {
+        self.pages.values().any(|page| !page.audio.is_empty())
+    }
+
+    // Lights up OBS keys whose scene, source or output is live, returns
+    // whether a key on the current page changed
+    pub fn show_obs(&mut self, state: &ObsState) -> bool {
+        let mut changed = false;
+        for (name, page) in &mut self.pages {
+            for (key, obs) in &page.obs {
+                let background = match state.is_live(&obs.tally) {
+                    true => Some(obs.color),
+                    false => obs.background,
+                };
+                let Some(face) = page.faces.get_mut(key) else {
+                    continue;
+                };
+                if face.background != background {
+                    face.background = background;
+                    changed |= *name == self.current;
+                }
+            }
+        }
+        changed
+    }
+
+    // Whether any page has a key for an OBS action
+    pub fn has_obs(&self) -> bool {
+        self.pages.values().any(|page| !page.obs.is_empty())
+    }
+
+    // Shows the message on every key bound to its topic, returns whether a
+    // key on the current page changed
+    pub fn show_mqtt(&mut self, message: &Message, icons: &mut Icons) -> bool {
+        let mut changed = false;
+        for (name, page) in &mut self.pages {
+            for (key, binding) in &page.mqtt {
+                let shown = match binding.read(message, icons) {
+                    Some(Ok(shown)) => shown,
+                    Some(Err(e)) => {
+                        eprintln!("MQTT message on {} for key {}: {}", message.topic, key, e);
+                        continue;
+                    }
+                    None => continue,
+                };
+                let Some(face) = page.faces.get_mut(key) else {
+                    continue;
+                };
+                match shown {
+                    Shown::Value(value) => {
+                        let title = Some(binding.title(&value).unwrap_or(value));
+                        if face.title != title {
+                            face.title = title;
+                            changed |= *name == self.current;
+                        }
+                    }
+                    // A new image under the same path still has to be drawn
+                    Shown::Image(path) => {
+                        face.icon = Icon::File(path);
+                        changed |= *name == self.current;
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    // Topics keys on any page follow
+    pub fn mqtt_topics(&self) -> Vec<String> {
+        self.pages
+            .values()
+            .flat_map(|page| page.mqtt.values().map(|b| b.topic().to_string()))
+            .collect()
+    }
+
+    // Shows the state of its entity on every Home Assistant key, returns
+    // whether a key on the current page changed
+    pub fn show_hass(&mut self, state: &HassState) -> bool {
+        let mut changed = false;
+        for (name, page) in &mut self.pages {
+            for (key, hass) in &page.hass {
+                let entity = state.get(hass.binding.entity());
+                let background = match entity.is_some_and(|e| e.is_on()) {
+                    true => Some(hass.color),
+                    false => hass.background,
+                };
+                let title = entity
+                    .and_then(|e| hass.binding.title(e))
+                    .or_else(|| hass.title.clone());
+                let Some(face) = page.faces.get_mut(key) else {
+                    continue;
+                };
+                if face.background != background || face.title != title {
+                    face.background = background;
+                    face.title = title;
+                    changed |= *name == self.current;
+                }
+            }
+        }
+        changed
+    }
+
+    // Entities keys on any page show
+    pub fn hass_entities(&self) -> Vec<String> {
+        self.pages
+            .values()
+            .flat_map(|page| page.hass.values().map(|k| k.binding.entity().to_string()))
+            .collect()
+    }
+
+    pub fn has_page(&self, name: &str) -> bool {
+        self.pages.contains_key(name)
+    }
+
+    fn face_mut(&mut self, key: u8) -> &mut Face {
+        let text = &self.text;
+        self.pages
+            .get_mut(&self.current)
+            .expect("current page exists")
+            .faces
+            .entry(key)
+            .or_insert_with(|| Face {
+                text: text.clone(),
+                ..Default::default()
+            })
+    }
+
+    // Moves through the page stack, returns whether the visible page changed
+    pub fn navigate(&mut self, navigation: &Navigation) -> bool {
+        let previous = self.current.clone();
+
+        match navigation {
+            // What `current` calls the home page, so a page read back from
+            // the deck's state can be opened again
+            Navigation::Open(page) if page == config::HOME_PAGE => {
+                return self.navigate(&Navigation::Home);
+            }
+            Navigation::Open(page) => {
+                if !self.pages.contains_key(page) || *page == self.current {
+                    return false;
+                }
+                let from = std::mem::replace(&mut self.current, page.clone());
+                self.history.push(from);
+            }
+            Navigation::Back => match self.history.pop() {
+                Some(page) => self.current = page,
+                None => return false,
+            },
+            Navigation::Home => {
+                self.history.clear();
+                self.current = HOME.to_string();
+            }
+        }
+
+        self.current != previous
+    }
+}
+
+impl Page {
+    // Shows the key in one of its states, returns whether that's a change
+    fn show_state(&mut self, key: u8, index: usize) -> bool {
+        let Some(states) = self.states.get_mut(&key) else {
+            return false;
+        };
+        if states.current == index {
+            return false;
+        }
+        states.current = index;
+
+        let state = &states.states[index];
+        match state.face.is_blank() {
+            true => self.faces.remove(&key),
+            false => self.faces.insert(key, state.face.clone()),
+        };
+        match &state.bindings {
+            Some(b) => self.keys.insert(key, b.clone()),
+            None => self.keys.remove(&key),
+        };
+        true
+    }
+}
+
+fn build_page(config: &Config, keys: &[KeyConfig]) -> Page {
+    let mut page = Page {
+        keys: HashMap::new(),
+        faces: HashMap::new(),
+        labels: HashMap::new(),
+        states: HashMap::new(),
+        statuses: HashMap::new(),
+        media: HashMap::new(),
+        audio: HashMap::new(),
+        obs: HashMap::new(),
+        mqtt: HashMap::new(),
+        hass: HashMap::new(),
+    };
+
+    for k in keys {
+        let index = *k.index.get_ref();
+        let control = Control::Key(index);
+        let actions = [
+            &k.on_press,
+            &k.on_release,
+            &k.on_long_press,
+            &k.on_double_press,
+        ];
+        if let Some(label) = &k.label {
+            page.labels.insert(index, label.clone());
+        }
+
+        let text = match &k.text {
+            Some(text) => text.or(&config.text),
+            None => config.text.clone(),
+        };
+        let mut face = Face {
+            background: k.background,
+            icon: match &k.icon {
+                Some(icon) => Icon::File(config.resolve_path(icon.get_ref())),
+                None => Icon::None,
+            },
+            title: k.title.clone(),
+            text: TextStyle::new(config, &text),
+            scroll: false,
+        };
+        // Media keys fall back to their own icon and title while nothing plays
+        if let Some(show) = &k.media {
+            let show = *show.get_ref();
+            face.scroll = show != MediaFace::Art;
+            page.media.insert(
+                index,
+                MediaKey {
+                    show,
+                    icon: face.icon.clone(),
+                    title: face.title.clone(),
+                },
+            );
+        }
+        if let Some(device) = &k.audio {
+            page.audio.insert(
+                index,
+                AudioKey {
+                    device: *device.get_ref(),
+                    title: face.title.clone(),
+                },
+            );
+        }
+        // Keys with states or a status have a face of their own to show
+        if let Some(tally) = k.on_press.as_ref().and_then(Tally::of)
+            && k.states.is_empty()
+            && k.status.is_none()
+        {
+            page.obs.insert(
+                index,
+                ObsKey {
+                    tally,
+                    color: config.obs.tally.unwrap_or(TALLY),
+                    background: face.background,
+                },
+            );
+        }
+        if let Some(binding) = &k.mqtt {
+            page.mqtt
+                .insert(index, mqtt::Binding::new(binding.get_ref()));
+        }
+        if let Some(binding) = &k.hass {
+            page.hass.insert(
+                index,
+                HassKey {
+                    binding: hass::Binding::new(binding.get_ref()),
+                    color: binding
+                        .get_ref()
+                        .color
+                        .or(config.home_assistant.on_color)
+                        .unwrap_or(ON_COLOR),
+                    background: face.background,
+                    title: face.title.clone(),
+                },
+            );
+        }
+        // Status keys keep their face, the title comes and goes with readings
+        if let Some(status) = &k.status {
+            let status = Status::new(status.get_ref(), k.background);
+            face.title = Some(status.waiting().title);
+            page.statuses.insert(index, Arc::new(status));
+        }
+
+        // States start out in the first one and fill in what they leave out
+        // from the key
+        let states: Vec<KeyState> = k
+            .states
+            .iter()
+            .map(|s| {
+                let text = match &s.text {
+                    Some(own) => own.or(&text),
+                    None => text.clone(),
+                };
+                let face = Face {
+                    background: s.background.or(face.background),
+                    icon: match &s.icon {
+                        Some(icon) => Icon::File(config.resolve_path(icon.get_ref())),
+                        None => face.icon.clone(),
+                    },
+                    title: s.title.clone().or(face.title.clone()),
+                    text: TextStyle::new(config, &text),
+                    scroll: false,
+                };
+                let actions = [
+                    s.on_press.as_ref().or(k.on_press.as_ref()).cloned(),
+                    s.on_release.as_ref().or(k.on_release.as_ref()).cloned(),
+                    s.on_long_press
+                        .as_ref()
+                        .or(k.on_long_press.as_ref())
+                        .cloned(),
+                    s.on_double_press
+                        .as_ref()
+                        .or(k.on_double_press.as_ref())
+                        .cloned(),
+                ];
+                let [press, release, long, double] = &actions;
+                KeyState {
+                    name: s.name.get_ref().clone(),
+                    face,
+                    bindings: bindings(control, [press, release, long, double]),
+                }
+            })
+            .collect();
+
+        let (face, bindings) = match states.first() {
+            Some(first) => (first.face.clone(), first.bindings.clone()),
+            None => (face, bindings(control, actions)),
+        };
+        if let Some(b) = bindings {
+            page.keys.insert(index, b);
+        }
+        // Keys following something outside always have a face to change
+        let live = page.media.contains_key(&index)
+            || page.audio.contains_key(&index)
+            || page.obs.contains_key(&index)
+            || page.mqtt.contains_key(&index)
+            || page.hass.contains_key(&index);
+        if !face.is_blank() || live {
+            page.faces.insert(index, face);
+        }
+        if !states.is_empty() {
+            let command = k.state_command.as_ref().map(|c| c.get_ref().clone());
+            page.states.insert(
+                index,
+                KeyStates {
+                    states,
+                    current: 0,
+                    command,
+                },
+            );
+        }
+    }
+
+    page
+}
+
+// A control whose actions can't be set up stays unbound instead of taking the
+// whole deck down
+fn bindings(
+    control: Control,
+    [press, release, long, double]: [&Option<ActionConfig>; 4],
+) -> Option<Bindings> {
+    match Bindings::new(press, release, long, double) {
+        Ok(b) if !b.is_empty() => Some(b),
+        Ok(_) => None,
+        Err(e) => {
+            eprintln!("{:?} left unbound: {}", control, e);
+            None
+        }
+    }
+}
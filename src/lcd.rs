@@ -0,0 +1,756 @@
+use std::collections::{HashMap, VecDeque};
+use std::f32::consts::PI;
+use std::path::PathBuf;
+
+use elgato_streamdeck::info::Kind;
+use image::{DynamicImage, Rgba, RgbaImage, imageops};
+
+use crate::animation::Target;
+use crate::audio::Levels;
+use crate::config::{Align, AudioDevice, Config, MeterConfig, TextConfig, WidgetConfig};
+use crate::dial::Dial;
+use crate::media::NowPlaying;
+use crate::mqtt::{self, Message, Shown};
+use crate::render::{self, Fonts, Icons, TextStyle};
+
+// Values a sparkline keeps when the config doesn't say
+const DEFAULT_CAPACITY: usize = 30;
+// Unfilled part of bars and gauges
+const TRACK: [u8; 3] = [60, 60, 60];
+
+// The LCD strip split into zones, each remembering whether it has to be drawn again
+pub struct Lcd {
+    zones: Vec<Zone>,
+    size: (u32, u32),
+    // What media zones show, and how far their titles have scrolled
+    media: Option<NowPlaying>,
+    scrolled: f32,
+    // What audio zones show
+    audio: Levels,
+}
+
+struct Zone {
+    name: Option<String>,
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+    background: [u8; 3],
+    style: TextStyle,
+    widget: Widget,
+    // Topic the zone shows the messages of
+    mqtt: Option<mqtt::Binding>,
+    dirty: bool,
+}
+
+enum Widget {
+    Text(String),
+    Meter {
+        gauge: bool,
+        label: Option<String>,
+        value: f64,
+        min: f64,
+        max: f64,
+        unit: Option<String>,
+        color: Option<[u8; 3]>,
+    },
+    Sparkline {
+        label: Option<String>,
+        values: VecDeque<f64>,
+        capacity: usize,
+        min: Option<f64>,
+        max: Option<f64>,
+        unit: Option<String>,
+        color: Option<[u8; 3]>,
+    },
+    Icon(PathBuf),
+    Encoder {
+        encoder: u8,
+        icon: Option<PathBuf>,
+        label: Option<String>,
+    },
+    Media,
+    Audio {
+        device: AudioDevice,
+        label: Option<String>,
+    },
+}
+
+impl Lcd {
+    pub fn new(config: &Config, kind: Kind) -> Lcd {
+        let Some((w, h)) = kind.lcd_strip_size() else {
+            return Lcd {
+                zones: vec![],
+                size: (0, 0),
+                media: None,
+                scrolled: 0.0,
+                audio: Levels::default(),
+            };
+        };
+
+        let zone = |rect: [u16; 4], name, background, style, widget, mqtt| {
+            let [x, y, w, h] = rect.map(u32::from);
+            Zone {
+                name,
+                x,
+                y,
+                w,
+                h,
+                background,
+                style,
+                widget,
+                mqtt,
+                dirty: true,
+            }
+        };
+
+        // Without zones of its own every encoder shows its icon and dial
+        let zones = if config.lcd.zones.is_empty() {
+            let segment = w as u16 / kind.encoder_count().max(1) as u16;
+            (0..kind.encoder_count())
+                .map(|encoder| {
+                    zone(
+                        [segment * encoder as u16, 0, segment, h as u16],
+                        None,
+                        [0, 0, 0],
+                        zone_style(config, None),
+                        Widget::new(config, &WidgetConfig::Encoder { encoder }),
+                        None,
+                    )
+                })
+                .collect()
+        } else {
+            config
+                .lcd
+                .zones
+                .iter()
+                .filter_map(|z| {
+                    Some(zone(
+                        config.zone_rect(z, kind)?,
+                        z.name.clone(),
+                        z.background.unwrap_or([0, 0, 0]),
+                        zone_style(config, z.text.as_ref()),
+                        Widget::new(config, z.widget.get_ref()),
+                        z.mqtt.as_ref().map(|b| mqtt::Binding::new(b.get_ref())),
+                    ))
+                })
+                .collect()
+        };
+
+        Lcd {
+            zones,
+            size: (w as u32, h as u32),
+            media: None,
+            scrolled: 0.0,
+            audio: Levels::default(),
+        }
+    }
+
+    // Zones that need drawing, or all of them when the deck shows nothing yet.
+    // They count as drawn from here on.
+    pub fn take_dirty(&mut self, all: bool) -> Vec<usize> {
+        self.zones
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, zone)| (std::mem::take(&mut zone.dirty) || all).then_some(i))
+            .collect()
+    }
+
+    // Position of the zone under a touch, `None` for gaps between zones
+    pub fn zone_at(&self, x: u16, y: u16) -> Option<usize> {
+        let (x, y) = (x as u32, y as u32);
+        self.zones
+            .iter()
+            .position(|z| x >= z.x && x < z.x + z.w && y >= z.y && y < z.y + z.h)
+    }
+
+    // The zone's animation moved on a frame
+    pub fn redraw(&mut self, index: usize) {
+        if let Some(zone) = self.zones.get_mut(index) {
+            zone.dirty = true;
+        }
+    }
+
+    // Zones showing the encoder, after its dial moved
+    pub fn encoder_changed(&mut self, encoder: u8) {
+        for zone in &mut self.zones {
+            if matches!(zone.widget, Widget::Encoder { encoder: e, .. } if e == encoder) {
+                zone.dirty = true;
+            }
+        }
+    }
+
+    pub fn has_media(&self) -> bool {
+        self.zones.iter().any(|z| matches!(z.widget, Widget::Media))
+    }
+
+    // What media zones show from now on
+    pub fn show_media(&mut self, playing: Option<&NowPlaying>) {
+        if self.media.as_ref() == playing {
+            return;
+        }
+        self.media = playing.cloned();
+        self.media_changed();
+    }
+
+    // Moves the titles of media zones along
+    pub fn scroll(&mut self, offset: f32) {
+        self.scrolled = offset;
+        self.media_changed();
+    }
+
+    // Whether any media zone has text too long for it, which has to scroll
+    pub fn scrolls(&self, fonts: &mut Fonts) -> bool {
+        let Some(media) = &self.media else {
+            return false;
+        };
+        self.zones
+            .iter()
+            .filter(|z| matches!(z.widget, Widget::Media))
+            .any(|zone| {
+                zone.media_lines(media)
+                    .into_iter()
+                    .any(|(text, style, _, size)| render::overflows(&text, &style, fonts, size))
+            })
+    }
+
+    pub fn has_audio(&self) -> bool {
+        self.zones
+            .iter()
+            .any(|z| matches!(z.widget, Widget::Audio { .. }))
+    }
+
+    // What audio zones show from now on, only zones whose device changed are
+    // drawn again
+    pub fn show_audio(&mut self, levels: &Levels) {
+        for zone in &mut self.zones {
+            if let Widget::Audio { device, .. } = zone.widget
+                && levels.get(device) != self.audio.get(device)
+            {
+                zone.dirty = true;
+            }
+        }
+        self.audio = *levels;
+    }
+
+    fn media_changed(&mut self) {
+        for zone in &mut self.zones {
+            if matches!(zone.widget, Widget::Media) {
+                zone.dirty = true;
+            }
+        }
+    }
+
+    // Changes what a zone shows, by name or by position. Text widgets take
+    // either, a number for the others is their value and text their label.
+    pub fn update(
+        &mut self,
+        zone: &str,
+        value: Option<f64>,
+        text: Option<String>,
+    ) -> Result<(), String> {
+        let index = self
+            .zones
+            .iter()
+            .position(|z| z.name.as_deref() == Some(zone))
+            .or_else(|| zone.parse().ok().filter(|i| *i < self.zones.len()))
+            .ok_or(format!("no LCD zone '{}'", zone))?;
+        if value.is_none() && text.is_none() {
+            return Err("nothing to show, give a value or text".to_string());
+        }
+        self.zones[index].set(value, text)
+    }
+
+    // Topics zones follow
+    pub fn mqtt_topics(&self) -> Vec<String> {
+        self.zones
+            .iter()
+            .filter_map(|z| Some(z.mqtt.as_ref()?.topic().to_string()))
+            .collect()
+    }
+
+    // Shows the message on every zone bound to its topic. Numbers are the
+    // value of widgets that have one, with the title as their label.
+    pub fn show_mqtt(&mut self, message: &Message, icons: &mut Icons) {
+        for (index, zone) in self.zones.iter_mut().enumerate() {
+            let Some(binding) = &zone.mqtt else {
+                continue;
+            };
+            let Some(shown) = binding.read(message, icons) else {
+                continue;
+            };
+            let result = shown.map(|shown| match shown {
+                Shown::Value(shown) => match shown.parse::<f64>() {
+                    Ok(number) => (Some(number), binding.title(&shown)),
+                    Err(_) => (None, Some(binding.title(&shown).unwrap_or(shown))),
+                },
+                Shown::Image(path) => (None, Some(path.display().to_string())),
+            });
+            let result = result.and_then(|(value, text)| zone.set(value, text));
+            if let Err(e) = result {
+                eprintln!(
+                    "MQTT message on {} for zone {}: {}",
+                    message.topic, index, e
+                );
+            }
+        }
+    }
+
+    // Draws one zone, returning where it goes on the strip
+    pub fn render(
+        &self,
+        index: usize,
+        dials: &HashMap<u8, Dial>,
+        icons: &mut Icons,
+        fonts: &mut Fonts,
+    ) -> (u16, u16, DynamicImage) {
+        let zone = &self.zones[index];
+        let image = zone.render(index, dials, self.media(), &self.audio, icons, fonts);
+        (
+            zone.x as u16,
+            zone.y as u16,
+            DynamicImage::ImageRgba8(image),
+        )
+    }
+
+    // Every zone composited onto the whole strip, for decks that can only
+    // take the strip at once
+    pub fn render_all(
+        &self,
+        dials: &HashMap<u8, Dial>,
+        icons: &mut Icons,
+        fonts: &mut Fonts,
+    ) -> DynamicImage {
+        let (w, h) = self.size;
+        let mut strip = RgbaImage::from_pixel(w, h, Rgba([0, 0, 0, 255]));
+        for (index, zone) in self.zones.iter().enumerate() {
+            let image = zone.render(index, dials, self.media(), &self.audio, icons, fonts);
+            imageops::overlay(&mut strip, &image, zone.x as i64, zone.y as i64);
+        }
+        DynamicImage::ImageRgba8(strip)
+    }
+
+    fn media(&self) -> Option<(&NowPlaying, f32)> {
+        Some((self.media.as_ref()?, self.scrolled))
+    }
+}
+
+impl Widget {
+    fn new(config: &Config, widget: &WidgetConfig) -> Widget {
+        match widget {
+            WidgetConfig::Text { text } => Widget::Text(text.clone()),
+            WidgetConfig::Gauge(meter) => Widget::meter(meter, true),
+            WidgetConfig::Progress(meter) => Widget::meter(meter, false),
+            WidgetConfig::Sparkline(sparkline) => {
+                let capacity = sparkline.capacity.unwrap_or(DEFAULT_CAPACITY);
+                let skip = sparkline.values.len().saturating_sub(capacity);
+                Widget::Sparkline {
+                    label: sparkline.label.clone(),
+                    values: sparkline.values.iter().skip(skip).copied().collect(),
+                    capacity,
+                    min: sparkline.min,
+                    max: sparkline.max,
+                    unit: sparkline.unit.clone(),
+                    color: sparkline.color,
+                }
+            }
+            WidgetConfig::Icon { path } => Widget::Icon(config.resolve_path(path)),
+            WidgetConfig::Encoder { encoder } => {
+                let encoder_config = config
+                    .encoders
+                    .iter()
+                    .find(|e| *e.index.get_ref() == *encoder);
+                Widget::Encoder {
+                    encoder: *encoder,
+                    icon: encoder_config
+                        .and_then(|e| e.icon.as_ref())
+                        .map(|icon| config.resolve_path(icon.get_ref())),
+                    label: encoder_config.and_then(|e| e.label.clone()),
+                }
+            }
+            WidgetConfig::Media => Widget::Media,
+            WidgetConfig::Audio { device, label } => Widget::Audio {
+                device: *device,
+                label: label.clone(),
+            },
+        }
+    }
+
+    fn meter(meter: &MeterConfig, gauge: bool) -> Widget {
+        Widget::Meter {
+            gauge,
+            label: meter.label.clone(),
+            value: meter.value.unwrap_or(0.0),
+            min: meter.min.unwrap_or(0.0),
+            max: meter.max.unwrap_or(100.0),
+            unit: meter.unit.clone(),
+            color: meter.color,
+        }
+    }
+}
+
+impl Zone {
+    // Shows the value and/or text the way the widget takes them
+    fn set(&mut self, value: Option<f64>, text: Option<String>) -> Result<(), String> {
+        match &mut self.widget {
+            Widget::Text(shown) => {
+                *shown = text.unwrap_or_else(|| format_value(value.unwrap_or_default()));
+            }
+            Widget::Meter {
+                label,
+                value: shown,
+                ..
+            } => {
+                *shown = value.unwrap_or(*shown);
+                *label = text.or(label.take());
+            }
+            Widget::Sparkline {
+                label,
+                values,
+                capacity,
+                ..
+            } => {
+                if let Some(value) = value {
+                    values.push_back(value);
+                    while values.len() > *capacity {
+                        values.pop_front();
+                    }
+                }
+                *label = text.or(label.take());
+            }
+            Widget::Icon(path) => match text {
+                Some(text) => *path = PathBuf::from(text),
+                None => return Err("icon zones take the path of an image".to_string()),
+            },
+            Widget::Encoder { encoder, .. } => {
+                return Err(format!("zone shows encoder {}, twist it instead", encoder));
+            }
+            Widget::Media => return Err("zone shows the media player".to_string()),
+            Widget::Audio { .. } => return Err("zone shows the sound server".to_string()),
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn render(
+        &self,
+        index: usize,
+        dials: &HashMap<u8, Dial>,
+        media: Option<(&NowPlaying, f32)>,
+        audio: &Levels,
+        icons: &mut Icons,
+        fonts: &mut Fonts,
+    ) -> RgbaImage {
+        let (w, h) = (self.w, self.h);
+        let [r, g, b] = self.background;
+        let mut canvas = RgbaImage::from_pixel(w, h, Rgba([r, g, b, 255]));
+        let mut style = self.style.clone();
+
+        match &self.widget {
+            Widget::Text(text) => render::draw_text(&mut canvas, text, &style, fonts),
+
+            Widget::Meter {
+                gauge,
+                label,
+                value,
+                min,
+                max,
+                unit,
+                color,
+            } => {
+                let fraction = ((value - min) / (max - min)).clamp(0.0, 1.0);
+                let color = color.unwrap_or(style.color);
+                let text = with_unit(&format_value(*value), unit);
+                if *gauge {
+                    draw_gauge(&mut canvas, fraction, color);
+                    if let Some(label) = label {
+                        style.align = Align::Bottom;
+                        style.size = Some(h as f32 / 6.0);
+                        render::draw_text(&mut canvas, label, &style, fonts);
+                    }
+                    style.align = Align::Middle;
+                    style.size = Some(h as f32 / 4.0);
+                    render::draw_text(&mut canvas, &text, &style, fonts);
+                } else {
+                    draw_labelled(&mut canvas, label.as_deref(), &text, &mut style, fonts);
+                    draw_bar(&mut canvas, fraction, color);
+                }
+            }
+
+            Widget::Sparkline {
+                label,
+                values,
+                min,
+                max,
+                unit,
+                color,
+                ..
+            } => {
+                let color = color.unwrap_or(style.color);
+                draw_sparkline(&mut canvas, values, *min, *max, color);
+                let latest = values
+                    .back()
+                    .map(|v| with_unit(&format_value(*v), unit))
+                    .unwrap_or_default();
+                let caption = match label {
+                    Some(label) => format!("{} {}", label, latest),
+                    None => latest,
+                };
+                style.align = Align::Top;
+                style.size = Some(h as f32 / 5.0);
+                render::draw_text(&mut canvas, caption.trim(), &style, fonts);
+            }
+
+            Widget::Icon(path) => {
+                if let Some(icon) = icons.frame(Target::Zone(index), path) {
+                    let icon = icon.resize(w, h, imageops::FilterType::Triangle).to_rgba8();
+                    let x = (w - icon.width()) / 2;
+                    let y = (h - icon.height()) / 2;
+                    imageops::overlay(&mut canvas, &icon, x as i64, y as i64);
+                }
+            }
+
+            Widget::Encoder {
+                encoder,
+                icon,
+                label,
+            } => {
+                let icon = icon
+                    .as_ref()
+                    .and_then(|icon| icons.frame(Target::Zone(index), icon));
+                if let Some(icon) = icon {
+                    let icon = icon.resize_to_fill(w, h, imageops::FilterType::Nearest);
+                    imageops::overlay(&mut canvas, &icon.to_rgba8(), 0, 0);
+                }
+                if let Some(dial) = dials.get(encoder) {
+                    draw_labelled(
+                        &mut canvas,
+                        label.as_deref(),
+                        &dial.display(),
+                        &mut style,
+                        fonts,
+                    );
+                    if let Some(fraction) = dial.fraction() {
+                        draw_bar(&mut canvas, fraction, style.color);
+                    }
+                }
+            }
+
+            Widget::Media => {
+                let Some((media, scrolled)) = media else {
+                    style.size = Some(h as f32 / 6.0);
+                    render::draw_text(&mut canvas, "Nothing playing", &style, fonts);
+                    return canvas;
+                };
+                let art = media
+                    .art
+                    .as_ref()
+                    .and_then(|art| icons.frame(Target::Zone(index), art));
+                if let Some(art) = art {
+                    let art = art.resize_to_fill(h, h, imageops::FilterType::Triangle);
+                    imageops::overlay(&mut canvas, &art.to_rgba8(), 0, 0);
+                }
+                let left = self.media_left(media);
+                for (text, style, y, (lw, lh)) in self.media_lines(media) {
+                    let mut line = RgbaImage::new(lw, lh);
+                    render::draw_marquee(&mut line, &text, &style, fonts, scrolled);
+                    imageops::overlay(&mut canvas, &line, left as i64, y as i64);
+                }
+            }
+
+            Widget::Audio { device, label } => {
+                let level = audio.get(*device);
+                let text = level.map(|l| l.text()).unwrap_or_else(|| "?".to_string());
+                draw_labelled(&mut canvas, label.as_deref(), &text, &mut style, fonts);
+                if let Some(level) = level {
+                    // A muted device keeps its level, dimmed
+                    let color = match level.muted {
+                        true => style.color.map(|c| c / 2),
+                        false => style.color,
+                    };
+                    draw_bar(&mut canvas, (level.volume / 100.0).clamp(0.0, 1.0), color);
+                }
+            }
+        }
+
+        canvas
+    }
+
+    // Where the text of a media zone starts, right of the album art
+    fn media_left(&self, media: &NowPlaying) -> u32 {
+        match media.art {
+            Some(_) if self.h < self.w => self.h,
+            _ => 0,
+        }
+    }
+
+    // The title in the top half of a media zone and the artist below, with
+    // their style and where each goes
+    fn media_lines(&self, media: &NowPlaying) -> Vec<(String, TextStyle, u32, (u32, u32))> {
+        let width = self.w - self.media_left(media);
+        let height = self.h / 2;
+        let mut lines = vec![];
+        if let Some(title) = &media.title {
+            let mut style = self.style.clone();
+            style.align = Align::Bottom;
+            style.size = Some(self.h as f32 / 4.0);
+            lines.push((title.clone(), style, 0, (width, height)));
+        }
+        if let Some(artist) = &media.artist {
+            let mut style = self.style.clone();
+            style.align = Align::Top;
+            style.size = Some(self.h as f32 / 6.0);
+            lines.push((artist.clone(), style, height, (width, height)));
+        }
+        lines
+    }
+}
+
+// Zone text follows the top level `[text]` except for where it sits, keys
+// want titles at the bottom but zones read better centered
+fn zone_style(config: &Config, text: Option<&TextConfig>) -> TextStyle {
+    let mut text = text.cloned().unwrap_or_default();
+    text.align = text.align.or(Some(Align::Middle));
+    TextStyle::new(config, &text.or(&config.text))
+}
+
+// Small label along the top and a big value in the middle
+fn draw_labelled(
+    canvas: &mut RgbaImage,
+    label: Option<&str>,
+    value: &str,
+    style: &mut TextStyle,
+    fonts: &mut Fonts,
+) {
+    let h = canvas.height() as f32;
+    if let Some(label) = label {
+        style.align = Align::Top;
+        style.size = Some(h / 6.0);
+        render::draw_text(canvas, label, style, fonts);
+    }
+    style.align = Align::Middle;
+    style.size = Some(h / 3.0);
+    render::draw_text(canvas, value, style, fonts);
+}
+
+// Thin bar along the bottom, filled from the left
+fn draw_bar(canvas: &mut RgbaImage, fraction: f64, color: [u8; 3]) {
+    let (w, h) = canvas.dimensions();
+    let (left, right) = (w / 10, w - w / 10);
+    let (top, bottom) = (h - h / 8, h - h / 16);
+    let filled = left + ((right - left) as f64 * fraction).round() as u32;
+    for y in top..bottom {
+        for x in left..right {
+            let [r, g, b] = if x < filled { color } else { TRACK };
+            canvas.put_pixel(x, y, Rgba([r, g, b, 255]));
+        }
+    }
+}
+
+// Three quarters of a ring, open at the bottom and filled clockwise
+fn draw_gauge(canvas: &mut RgbaImage, fraction: f64, color: [u8; 3]) {
+    let (w, h) = canvas.dimensions();
+    let (cx, cy) = (w as f32 / 2.0, h as f32 / 2.0);
+    let radius = cx.min(cy) - 2.0;
+    let thickness = (radius / 5.0).max(2.0);
+    let middle = radius - thickness / 2.0;
+    let sweep = 1.5 * PI;
+    let filled = sweep * fraction as f32;
+
+    for y in 0..h {
+        for x in 0..w {
+            let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+            // Softened over a pixel at both edges of the ring
+            let coverage = (thickness / 2.0 - ((dx * dx + dy * dy).sqrt() - middle).abs() + 0.5)
+                .clamp(0.0, 1.0);
+            if coverage == 0.0 {
+                continue;
+            }
+            // Measured clockwise from the bottom left, where the ring starts
+            let angle = (dy.atan2(dx) - 0.75 * PI).rem_euclid(2.0 * PI);
+            if angle > sweep {
+                continue;
+            }
+            let over = if angle <= filled { color } else { TRACK };
+            let pixel = canvas.get_pixel_mut(x, y);
+            for (under, over) in pixel.0.iter_mut().zip(over) {
+                *under = (*under as f32 * (1.0 - coverage) + over as f32 * coverage) as u8;
+            }
+        }
+    }
+}
+
+// Line through the values below the caption, oldest on the left
+fn draw_sparkline(
+    canvas: &mut RgbaImage,
+    values: &VecDeque<f64>,
+    min: Option<f64>,
+    max: Option<f64>,
+    color: [u8; 3],
+) {
+    if values.is_empty() {
+        return;
+    }
+    let (w, h) = canvas.dimensions();
+    let (left, right) = (4.0, w as f64 - 4.0);
+    let (top, bottom) = (h as f64 / 3.0 + 2.0, h as f64 - 4.0);
+
+    let lowest = min.unwrap_or_else(|| values.iter().copied().fold(f64::INFINITY, f64::min));
+    let highest = max.unwrap_or_else(|| values.iter().copied().fold(f64::NEG_INFINITY, f64::max));
+    // A flat line sits in the middle rather than dividing by zero
+    let span = if highest > lowest {
+        highest - lowest
+    } else {
+        1.0
+    };
+    let offset = if highest > lowest { 0.0 } else { 0.5 };
+
+    let points: Vec<(f64, f64)> = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let x = match values.len() {
+                1 => right,
+                n => left + (right - left) * i as f64 / (n - 1) as f64,
+            };
+            let level = ((value - lowest) / span + offset).clamp(0.0, 1.0);
+            (x, bottom - (bottom - top) * level)
+        })
+        .collect();
+
+    let [r, g, b] = color;
+    let mut plot = |x: f64, y: f64| {
+        for (px, py) in [(x, y), (x + 1.0, y), (x, y + 1.0), (x + 1.0, y + 1.0)] {
+            if px >= 0.0 && py >= 0.0 && (px as u32) < w && (py as u32) < h {
+                canvas.put_pixel(px as u32, py as u32, Rgba([r, g, b, 255]));
+            }
+        }
+    };
+    if let [(x, y)] = points[..] {
+        plot(x, y);
+    }
+    for pair in points.windows(2) {
+        let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+        let steps = (x1 - x0).abs().max((y1 - y0).abs()).ceil().max(1.0);
+        for step in 0..=steps as u32 {
+            let t = step as f64 / steps;
+            plot(x0 + (x1 - x0) * t, y0 + (y1 - y0) * t);
+        }
+    }
+}
+
+// Whole numbers without a fraction, anything else to one decimal
+fn format_value(value: f64) -> String {
+    if value.fract() == 0.0 {
+        format!("{}", value)
+    } else {
+        format!("{:.1}", value)
+    }
+}
+
+fn with_unit(text: &str, unit: &Option<String>) -> String {
+    match unit {
+        Some(unit) => format!("{}{}", text, unit),
+        None => text.to_string(),
+    }
+}
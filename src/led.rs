@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::time::Duration;
+
+use elgato_streamdeck::info::Kind;
+use tokio::time::Instant;
+use toml::Spanned;
+
+use crate::config::{Config, LedEffect};
+
+// How often pulsing and breathing lights move on, the frame scheduler caps
+// it further
+const FADE_STEP: Duration = Duration::from_millis(40);
+// Dimmest a breathing light gets, fully off looks like a blink
+const BREATHE_FLOOR: f64 = 0.1;
+
+// A color and how it's shown
+#[derive(Clone, Debug)]
+struct Look {
+    color: [u8; 3],
+    effect: LedEffect,
+    period: Duration,
+}
+
+impl Look {
+    fn new(color: [u8; 3], effect: LedEffect, period_ms: &Option<Spanned<u64>>) -> Look {
+        let default = match effect {
+            LedEffect::Breathe => 3000,
+            _ => 1000,
+        };
+        let period = period_ms.as_ref().map(|p| *p.get_ref()).unwrap_or(default);
+        Look {
+            color,
+            effect,
+            period: Duration::from_millis(period.max(1)),
+        }
+    }
+
+    // The color `elapsed` into the effect
+    fn color(&self, elapsed: Duration) -> [u8; 3] {
+        let phase = (elapsed.as_secs_f64() / self.period.as_secs_f64()).fract();
+        let level = match self.effect {
+            LedEffect::Solid => 1.0,
+            LedEffect::Blink if phase < 0.5 => 1.0,
+            LedEffect::Blink => 0.0,
+            LedEffect::Pulse => (1.0 - phase).powi(2),
+            LedEffect::Breathe => {
+                BREATHE_FLOOR + (1.0 - BREATHE_FLOOR) * (0.5 - 0.5 * (2.0 * PI * phase).cos())
+            }
+        };
+        self.color.map(|c| (c as f64 * level).round() as u8)
+    }
+
+    // When an effect that started at `since` next changes the color
+    fn next_change(&self, since: Instant, now: Instant) -> Option<Instant> {
+        match self.effect {
+            LedEffect::Solid => None,
+            LedEffect::Blink => {
+                let half = (self.period / 2).as_nanos().max(1);
+                let elapsed = now.duration_since(since).as_nanos();
+                let next = (elapsed / half + 1) * half;
+                Some(since + Duration::from_nanos(next as u64))
+            }
+            LedEffect::Pulse | LedEffect::Breathe => Some(now + FADE_STEP),
+        }
+    }
+}
+
+struct Led {
+    normal: Look,
+    states: HashMap<String, Look>,
+    state: Option<String>,
+    // Effects start over whenever the state changes
+    since: Instant,
+}
+
+impl Led {
+    fn look(&self) -> &Look {
+        self.state
+            .as_ref()
+            .and_then(|state| self.states.get(state))
+            .unwrap_or(&self.normal)
+    }
+}
+
+// The touch point lights of a deck and the state each one is in
+pub struct Leds {
+    leds: Vec<Led>,
+}
+
+impl Leds {
+    pub fn new(config: &Config, kind: Kind) -> Leds {
+        let now = Instant::now();
+        let leds = (0..kind.touchpoint_count())
+            .map(|i| match config.touchpoint(i) {
+                Some(t) => Led {
+                    normal: Look::new(t.color.unwrap_or([255, 255, 255]), t.effect, &t.period_ms),
+                    states: t
+                        .states
+                        .iter()
+                        .map(|(name, s)| (name.clone(), Look::new(s.color, s.effect, &s.period_ms)))
+                        .collect(),
+                    state: None,
+                    since: now,
+                },
+                None => Led {
+                    normal: Look::new([255, 255, 255], LedEffect::Solid, &None),
+                    states: HashMap::new(),
+                    state: None,
+                    since: now,
+                },
+            })
+            .collect();
+        Leds { leds }
+    }
+
+    // Switches the light to one of its configured states, `None` goes back
+    // to its normal look
+    pub fn set_state(&mut self, point: u8, state: Option<String>) -> Result<(), String> {
+        let led = self
+            .leds
+            .get_mut(point as usize)
+            .ok_or(format!("no touch point {}", point))?;
+        if let Some(name) = &state
+            && !led.states.contains_key(name)
+        {
+            let mut known: Vec<_> = led.states.keys().map(|s| s.as_str()).collect();
+            known.sort();
+            return Err(format!(
+                "touch point {} has no state '{}' (it has: {})",
+                point,
+                name,
+                known.join(", ")
+            ));
+        }
+        if led.state != state {
+            led.state = state;
+            led.since = Instant::now();
+        }
+        Ok(())
+    }
+
+    pub fn state(&self, point: u8) -> Option<&str> {
+        self.leds.get(point as usize)?.state.as_deref()
+    }
+
+    // What every light should show right now
+    pub fn colors(&self, now: Instant) -> Vec<(u8, [u8; 3])> {
+        self.leds
+            .iter()
+            .enumerate()
+            .map(|(i, led)| (i as u8, led.look().color(now.duration_since(led.since))))
+            .collect()
+    }
+
+    // When the next light changes by itself
+    pub fn next_change(&self) -> Option<Instant> {
+        let now = Instant::now();
+        self.leds
+            .iter()
+            .filter_map(|led| led.look().next_change(led.since, now))
+            .min()
+    }
+}
@@ -1,158 +1,175 @@
-use image::open;
-use std::time::Duration;
-
-use elgato_streamdeck::images::{ImageRect, convert_image_with_format};
-use elgato_streamdeck::{AsyncStreamDeck, DeviceStateUpdate, list_devices, new_hidapi};
-use tokio::time::sleep;
+mod action;
+mod animation;
+mod audio;
+mod brightness;
+mod config;
+mod control;
+mod ctl;
+mod deck;
+mod device;
+mod devices;
+mod dial;
+mod error;
+mod framebuffer;
+mod gesture;
+mod hass;
+mod layout;
+mod lcd;
+mod led;
+mod manager;
+mod media;
+mod mqtt;
+mod obs;
+mod query;
+mod render;
+mod sim;
+mod status;
+mod websocket;
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use config::Config;
+use control::{Hub, Server};
+use elgato_streamdeck::info::Kind;
+use elgato_streamdeck::new_hidapi;
+use error::Error;
+use manager::DeviceManager;
+
+const USAGE: &str =
+    "usage: rust-streamdeck [--config PATH] [--socket PATH] [--simulate KIND [--dump DIR]]
+       rust-streamdeck ctl [--socket PATH] [--serial SERIAL] COMMAND
+       rust-streamdeck devices [--json]";
+
+// Command line options
+#[derive(Default)]
+struct Args {
+    config: Option<PathBuf>,
+    // Where the control socket is created, see control::default_socket
+    socket: Option<PathBuf>,
+    // Run against a simulated deck of this kind instead of real hardware
+    simulate: Option<Kind>,
+    // Where the simulated deck writes the images it's sent
+    dump: Option<PathBuf>,
+}
 
 #[tokio::main]
-async fn main() {
-    // Create instance of HidApi
-    match new_hidapi() {
-        Ok(hid) => {
-            // Refresh device list
-            for (kind, serial) in list_devices(&hid) {
-                println!("{:?} {} {}", kind, serial, kind.product_id());
-
-                // Connect to the device
-                let device =
-                    AsyncStreamDeck::connect(&hid, kind, &serial).expect("Failed to connect");
-                // Print out some info from the device
-                println!(
-                    "Connected to '{}' with version '{}'",
-                    device.serial_number().await.unwrap(),
-                    device.firmware_version().await.unwrap()
-                );
-
-                device.set_brightness(50).await.unwrap();
-                device.clear_all_button_images().await.unwrap();
-
-                // Use image-rs to load an image
-                let image = open("src/no-place-like-localhost.jpg").unwrap();
-                let alternative = image.grayscale().brighten(-50);
-
-                println!("Key count: {}", kind.key_count());
-                // Write it to the device
-                for i in 0..kind.key_count() {
-                    device.set_button_image(i, image.clone()).await.unwrap();
-                }
-
-                println!("Touch point count: {}", kind.touchpoint_count());
-                for i in 0..kind.touchpoint_count() {
-                    device.set_touchpoint_color(i, 255, 255, 255).await.unwrap();
-                }
-
-                if let Some(format) = device.kind().lcd_image_format() {
-                    let scaled_image = image.clone().resize_to_fill(
-                        format.size.0 as u32,
-                        format.size.1 as u32,
-                        image::imageops::FilterType::Nearest,
-                    );
-                    let converted_image = convert_image_with_format(format, scaled_image).unwrap();
-                    let _ = device.write_lcd_fill(&converted_image).await;
-                }
-
-                let small = match device.kind().lcd_strip_size() {
-                    Some((w, h)) => {
-                        let min = w.min(h) as u32;
-                        let scaled_image =
-                            image
-                                .clone()
-                                .resize_to_fill(min, min, image::imageops::Nearest);
-                        Some(ImageRect::from_image(scaled_image).unwrap())
-                    }
-                    None => None,
-                };
-
-                // Flush
-                device.flush().await.unwrap();
-
-                // Start new task to animate the button images
-                let device_clone = device.clone();
-                tokio::spawn(async move {
-                    let mut index = 0;
-                    let mut previous = 0;
-
-                    loop {
-                        device_clone
-                            .set_button_image(index, image.clone())
-                            .await
-                            .unwrap();
-                        device_clone
-                            .set_button_image(previous, alternative.clone())
-                            .await
-                            .unwrap();
-
-                        device_clone.flush().await.unwrap();
-
-                        sleep(Duration::from_secs_f32(0.5)).await;
-
-                        previous = index;
-
-                        index += 1;
-                        if index >= kind.key_count() {
-                            index = 0;
-                        }
-                    }
-                });
-
-                let reader = device.get_reader();
-
-                'infinite: loop {
-                    let updates = match reader.read(100.0).await {
-                        Ok(updates) => updates,
-                        Err(_) => break,
-                    };
-                    for update in updates {
-                        match update {
-                            DeviceStateUpdate::ButtonDown(key) => {
-                                println!("Button {} down", key);
-                            }
-                            DeviceStateUpdate::ButtonUp(key) => {
-                                println!("Button {} up", key);
-                                if key == device.kind().key_count() - 1 {
-                                    break 'infinite;
-                                }
-                            }
-                            DeviceStateUpdate::EncoderTwist(dial, ticks) => {
-                                println!("Dial {} twisted by {}", dial, ticks);
-                            }
-                            DeviceStateUpdate::EncoderDown(dial) => {
-                                println!("Dial {} down", dial);
-                            }
-                            DeviceStateUpdate::EncoderUp(dial) => {
-                                println!("Dial {} up", dial);
-                            }
-
-                            DeviceStateUpdate::TouchPointDown(point) => {
-                                println!("Touch point {} down", point);
-                            }
-                            DeviceStateUpdate::TouchPointUp(point) => {
-                                println!("Touch point {} up", point);
-                            }
-
-                            DeviceStateUpdate::TouchScreenPress(x, y) => {
-                                println!("Touch Screen press at {x}, {y}");
-                                if let Some(small) = &small {
-                                    device.write_lcd(x, y, small).await.unwrap();
-                                }
-                            }
-
-                            DeviceStateUpdate::TouchScreenLongPress(x, y) => {
-                                println!("Touch Screen long press at {x}, {y}")
-                            }
-
-                            DeviceStateUpdate::TouchScreenSwipe((sx, sy), (ex, ey)) => {
-                                println!("Touch Screen swipe from {sx}, {sy} to {ex}, {ey}")
-                            }
-                        }
-                    }
-                }
-
-                drop(reader);
+async fn main() -> ExitCode {
+    match std::env::args().nth(1).as_deref() {
+        Some("ctl") => return ctl::main(std::env::args().skip(2)).await,
+        Some("devices") => return devices::main(std::env::args().skip(2)),
+        _ => {}
+    }
+
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<(), Error> {
+    let config = Arc::new(load_config(args.config)?);
+    println!("Using config {}", config.path().display());
+    media::prefer(config.media.player.clone());
+    audio::configure(config.audio.backend);
+    obs::configure(&config.obs);
+    mqtt::configure(&config)?;
+    hass::configure(&config.home_assistant);
+
+    let hub = Hub::new();
+    let socket = args.socket.unwrap_or_else(control::default_socket);
+    let server = match Server::bind(&socket).await {
+        Ok(server) => {
+            println!("Listening on {}", socket.display());
+            Some(server)
+        }
+        // Simulating next to a running daemon shouldn't need a socket of its own
+        Err(e) if args.simulate.is_some() => {
+            eprintln!("No control socket at {}: {}", socket.display(), e);
+            None
+        }
+        Err(e) => {
+            let message = format!("control socket {}: {}", socket.display(), e);
+            return Err(Error::Io(std::io::Error::new(e.kind(), message)));
+        }
+    };
+    let serve = async {
+        match &server {
+            Some(server) => server.run(hub.clone()).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let work = async {
+        if let Some(kind) = args.simulate {
+            return sim::simulate(config, kind, args.dump, hub.clone()).await;
+        }
+
+        // Create instance of HidApi
+        let hid = new_hidapi()?;
+
+        // Runs until SIGINT or SIGTERM, picking up decks as they're plugged in
+        DeviceManager::new(hid, config, hub.clone()).run().await
+    };
+
+    // The server only stops with the program, dropping it removes the socket
+    tokio::select! {
+        result = work => result,
+        _ = serve => Ok(()),
+    }
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--config" => parsed.config = Some(PathBuf::from(value()?)),
+            "--socket" => parsed.socket = Some(PathBuf::from(value()?)),
+            "--simulate" => {
+                let name = value()?;
+                let kind = config::parse_kind(&name)
+                    .ok_or_else(|| format!("unknown device kind '{}'", name))?;
+                parsed.simulate = Some(kind);
+            }
+            "--dump" => parsed.dump = Some(PathBuf::from(value()?)),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
             }
+            _ => return Err(format!("unknown argument '{}'", arg)),
         }
-        Err(e) => eprintln!("Failed to create HidApi instance: {}", e),
     }
+
+    Ok(parsed)
 }
 
+// Loads the config given with `--config`, falling back to the default location
+fn load_config(explicit: Option<PathBuf>) -> Result<Config, config::ConfigError> {
+    match explicit {
+        Some(path) => Config::load(&path),
+        None => {
+            let path = Config::default_path();
+            if path.exists() {
+                Config::load(&path)
+            } else {
+                println!("No config at {}, leaving keys blank", path.display());
+                Ok(Config::empty(&path))
+            }
+        }
+    }
+}
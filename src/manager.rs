@@ -0,0 +1,242 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use elgato_streamdeck::info::Kind;
+use elgato_streamdeck::{AsyncStreamDeck, list_devices, refresh_device_list};
+use hidapi::HidApi;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep};
+
+use crate::config::Config;
+use crate::control::Hub;
+use crate::device::{self, DeckState, Stop};
+use crate::error::{Error, retry};
+
+// How often the USB bus is checked for decks coming and going
+const SCAN_INTERVAL: Duration = Duration::from_secs(2);
+// Longest wait between attempts to connect to a deck that keeps failing
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+struct Running {
+    stop: oneshot::Sender<Stop>,
+    task: JoinHandle<(DeckState, Result<Stop, Error>)>,
+    started: Instant,
+}
+
+// When to next try a deck that failed to connect or whose task failed, and
+// how long to wait after that
+struct Backoff {
+    at: Instant,
+    delay: Duration,
+}
+
+// Keeps one task per connected deck, starting tasks for decks that show up
+// and stopping them for decks that go away
+pub struct DeviceManager {
+    hid: HidApi,
+    config: Arc<Config>,
+    hub: Hub,
+    running: HashMap<String, Running>,
+    // State of decks that were unplugged, restored when they come back
+    parked: HashMap<String, DeckState>,
+    // Decks the config doesn't fit, ignored until they're unplugged
+    rejected: HashSet<String>,
+    // Decks that failed to connect or kept failing once connected, retried
+    // less and less often
+    backoff: HashMap<String, Backoff>,
+}
+
+impl DeviceManager {
+    pub fn new(hid: HidApi, config: Arc<Config>, hub: Hub) -> DeviceManager {
+        DeviceManager {
+            hid,
+            config,
+            hub,
+            running: HashMap::new(),
+            parked: HashMap::new(),
+            rejected: HashSet::new(),
+            backoff: HashMap::new(),
+        }
+    }
+
+    // Runs until SIGINT or SIGTERM, then clears every deck before returning
+    pub async fn run(mut self) -> Result<(), Error> {
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+
+        loop {
+            self.scan().await;
+            tokio::select! {
+                _ = sleep(SCAN_INTERVAL) => {}
+                _ = interrupt.recv() => break,
+                _ = terminate.recv() => break,
+            }
+        }
+
+        println!("Shutting down");
+        let serials: Vec<String> = self.running.keys().cloned().collect();
+        for serial in serials {
+            self.stop(serial, Stop::Shutdown).await;
+        }
+        Ok(())
+    }
+
+    async fn scan(&mut self) {
+        // Tasks end on their own when reading fails, e.g. when a hub resets
+        let finished: Vec<String> = self
+            .running
+            .iter()
+            .filter(|(_, running)| running.task.is_finished())
+            .map(|(serial, _)| serial.clone())
+            .collect();
+        let mut failed = vec![];
+        for serial in finished {
+            if self.stop(serial.clone(), Stop::Unplugged).await {
+                failed.push(serial);
+            }
+        }
+
+        if let Err(e) = refresh_device_list(&mut self.hid) {
+            eprintln!("Failed to list devices: {}", e);
+            return;
+        }
+        let present: HashMap<String, Kind> = list_devices(&self.hid)
+            .into_iter()
+            .map(|(kind, serial)| (serial, kind))
+            .collect();
+
+        let vanished: Vec<String> = self
+            .running
+            .keys()
+            .filter(|serial| !present.contains_key(*serial))
+            .cloned()
+            .collect();
+        for serial in vanished {
+            println!("{} unplugged", serial);
+            self.stop(serial, Stop::Unplugged).await;
+        }
+        self.rejected.retain(|serial| present.contains_key(serial));
+        self.backoff
+            .retain(|serial, _| present.contains_key(serial));
+        // A deck still there after its task failed would fail the same way
+        // right after connecting again, like on an image it can't take
+        for serial in failed {
+            if present.contains_key(&serial) {
+                let delay = self.back_off(&serial);
+                println!("Reconnecting to {} in {}s", serial, delay.as_secs());
+            }
+        }
+
+        let now = Instant::now();
+        for (serial, kind) in present {
+            let waiting = self.backoff.get(&serial).is_some_and(|b| now < b.at);
+            if self.running.contains_key(&serial) || self.rejected.contains(&serial) || waiting {
+                continue;
+            }
+
+            match self.attach(kind, serial.clone()).await {
+                // The wait keeps growing until the deck stays up, a task that
+                // fails right after connecting would otherwise reconnect on
+                // every scan
+                Ok(()) => {}
+                Err(Error::Config(e)) => {
+                    eprintln!("Skipping {:?} {}: {}", kind, serial, e);
+                    self.rejected.insert(serial);
+                }
+                // A deck that was just plugged in can take a moment to accept
+                // connections, so it's tried again later
+                Err(e) => {
+                    let delay = self.back_off(&serial);
+                    eprintln!(
+                        "Failed to connect to {}: {}, retrying in {}s",
+                        serial,
+                        e,
+                        delay.as_secs()
+                    );
+                }
+            }
+        }
+    }
+
+    // Puts off the next attempt at the deck, twice as long as the last time.
+    // Returns how long that is.
+    fn back_off(&mut self, serial: &str) -> Duration {
+        let delay = self
+            .backoff
+            .get(serial)
+            .map(|b| (b.delay * 2).min(MAX_CONNECT_BACKOFF))
+            .unwrap_or(SCAN_INTERVAL);
+        let at = Instant::now() + delay;
+        self.backoff
+            .insert(serial.to_string(), Backoff { at, delay });
+        delay
+    }
+
+    async fn attach(&mut self, kind: Kind, serial: String) -> Result<(), Error> {
+        println!("{:?} {} {}", kind, serial, kind.product_id());
+
+        let profile = self.config.profile_for(kind, &serial);
+        println!("Using profile {} for {}", profile.path().display(), serial);
+
+        // Make sure the layout fits this device before touching it
+        profile.validate(kind)?;
+
+        let device = AsyncStreamDeck::connect(&self.hid, kind, &serial)?;
+        let version = retry(|| device.firmware_version()).await?;
+        println!("Connected to '{}' with version '{}'", serial, version);
+
+        let state = self
+            .parked
+            .remove(&serial)
+            .unwrap_or_else(|| DeckState::new(&profile, kind));
+        let remote = self.hub.attach(&serial, kind);
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(device::run(
+            device,
+            serial.clone(),
+            profile,
+            state,
+            remote,
+            stopped,
+        ));
+        let started = Instant::now();
+        self.running.insert(
+            serial,
+            Running {
+                stop,
+                task,
+                started,
+            },
+        );
+        Ok(())
+    }
+
+    // Stops the deck's task and keeps its state for when it's plugged back in.
+    // Returns whether the task failed.
+    async fn stop(&mut self, serial: String, reason: Stop) -> bool {
+        let Some(running) = self.running.remove(&serial) else {
+            return false;
+        };
+        self.hub.detach(&serial);
+        let _ = running.stop.send(reason);
+        let result = match running.task.await {
+            Ok((state, result)) => {
+                self.parked.insert(serial.clone(), state);
+                result
+            }
+            Err(e) => {
+                eprintln!("Task for {} failed: {}", serial, e);
+                return true;
+            }
+        };
+
+        // A deck that ran fine for a while starts over from the shortest wait
+        if Instant::now() - running.started >= MAX_CONNECT_BACKOFF {
+            self.backoff.remove(&serial);
+        }
+        result.is_err()
+    }
+}
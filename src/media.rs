@@ -0,0 +1,697 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use futures_util::StreamExt;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use zbus::message::Type;
+use zbus::zvariant::{ObjectPath, OwnedValue, Value};
+use zbus::{Connection, MatchRule, MessageStream};
+
+use crate::config::MediaCommand;
+
+// Every MPRIS player owns a bus name under this and serves one object
+const PREFIX: &str = "org.mpris.MediaPlayer2.";
+const PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER: &str = "org.mpris.MediaPlayer2.Player";
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+const DBUS: &str = "org.freedesktop.DBus";
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Playback {
+    #[default]
+    Stopped,
+    Playing,
+    Paused,
+}
+
+// What the followed player is playing
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NowPlaying {
+    // The player's bus name without the MPRIS prefix, like "spotify"
+    pub player: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    // Album art, when the player keeps it in a local file
+    pub art: Option<PathBuf>,
+    pub playback: Playback,
+    // In percent, for players that have a volume
+    pub volume: Option<f64>,
+    // In seconds, as of the last time the player said
+    pub position: Option<f64>,
+    pub length: Option<f64>,
+    bus: String,
+    track: Option<String>,
+}
+
+impl NowPlaying {
+    // Where the track is by `now` for a position heard at `heard`. Players
+    // don't say when the position moves on by itself, only when it jumps.
+    pub fn position_at(&self, heard: Instant, now: Instant) -> Option<f64> {
+        let mut position = self.position?;
+        if self.playback == Playback::Playing {
+            position += now.saturating_duration_since(heard).as_secs_f64();
+        }
+        Some(self.length.map_or(position, |length| position.min(length)))
+    }
+}
+
+// Something for the followed player to do
+#[derive(Debug)]
+pub enum Request {
+    Command(MediaCommand),
+    // Percent
+    Volume(f64),
+    // Seconds into the track
+    Position(f64),
+}
+
+// The task talking to the session bus, started by whatever needs it first
+struct Media {
+    requests: mpsc::UnboundedSender<Request>,
+    playing: watch::Receiver<Option<NowPlaying>>,
+}
+
+static PREFERRED: OnceLock<Option<String>> = OnceLock::new();
+
+// Which player to follow whenever it's running, from the config the program
+// started with
+pub fn prefer(player: Option<String>) {
+    let _ = PREFERRED.set(player);
+}
+
+// What the followed player is playing, `None` while there's no player
+pub fn watch() -> watch::Receiver<Option<NowPlaying>> {
+    media().playing.clone()
+}
+
+// Hands the request to the followed player, failures are logged there
+pub fn send(request: Request) {
+    if let Err(e) = media().requests.send(request) {
+        eprintln!("Media player unreachable, dropped {:?}", e.0);
+    }
+}
+
+fn media() -> &'static Media {
+    static MEDIA: OnceLock<Media> = OnceLock::new();
+
+    MEDIA.get_or_init(|| {
+        let (requests, rx) = mpsc::unbounded_channel();
+        let (tx, playing) = watch::channel(None);
+        let preferred = PREFERRED.get().cloned().flatten();
+        tokio::spawn(serve(preferred, rx, tx));
+        Media { requests, playing }
+    })
+}
+
+// Follows the players on the session bus until the program exits, looking
+// again whenever one of them changes or comes and goes
+async fn serve(
+    preferred: Option<String>,
+    mut requests: mpsc::UnboundedReceiver<Request>,
+    playing: watch::Sender<Option<NowPlaying>>,
+) {
+    let connection = match Connection::session().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Media keys can't reach the session bus: {}", e);
+            return;
+        }
+    };
+    let (mut changes, mut owners) = match subscribe(&connection).await {
+        Ok(streams) => streams,
+        Err(e) => {
+            eprintln!("Media keys can't watch the session bus: {}", e);
+            return;
+        }
+    };
+
+    let mut followed: Option<String> = None;
+    let mut last_error = None;
+    loop {
+        match look(&connection, preferred.as_deref(), followed.as_deref()).await {
+            Ok(now) => {
+                let bus = now.as_ref().map(|n| n.bus.clone());
+                if bus != followed {
+                    match &now {
+                        Some(now) => println!("Media keys follow {}", now.player),
+                        None => println!("Media keys have no player to follow"),
+                    }
+                    followed = bus;
+                }
+                playing.send_if_modified(|shown| {
+                    let changed = *shown != now;
+                    *shown = now;
+                    changed
+                });
+                last_error = None;
+            }
+            Err(e) => {
+                let e = e.to_string();
+                if last_error.as_ref() != Some(&e) {
+                    eprintln!("Media player: {}", e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        tokio::select! {
+            request = requests.recv() => {
+                let Some(request) = request else { return };
+                let now = playing.borrow().clone();
+                match now {
+                    Some(now) => {
+                        if let Err(e) = call(&connection, &now, &request).await {
+                            eprintln!("Media player {}: {:?}: {}", now.player, request, e);
+                        }
+                    }
+                    None => eprintln!("No media player for {:?}", request),
+                }
+            }
+            change = changes.next() => {
+                if change.is_none() {
+                    eprintln!("Media keys lost the session bus");
+                    return;
+                }
+            }
+            owner = owners.next() => {
+                if owner.is_none() {
+                    eprintln!("Media keys lost the session bus");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// Signals from any player's object, like Seeked when its position jumps, and
+// players coming and going
+async fn subscribe(connection: &Connection) -> zbus::Result<(MessageStream, MessageStream)> {
+    let changes = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .path(PATH)?
+        .build();
+    let owners = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .sender(DBUS)?
+        .interface(DBUS)?
+        .member("NameOwnerChanged")?
+        .arg0ns("org.mpris.MediaPlayer2")?
+        .build();
+    Ok((
+        MessageStream::for_match_rule(changes, connection, None).await?,
+        MessageStream::for_match_rule(owners, connection, None).await?,
+    ))
+}
+
+// Picks the player to follow: the preferred one, else one that's playing,
+// sticking with the one followed so far when that's still a choice
+async fn look(
+    connection: &Connection,
+    preferred: Option<&str>,
+    followed: Option<&str>,
+) -> zbus::Result<Option<NowPlaying>> {
+    let reply = connection
+        .call_method(
+            Some(DBUS),
+            "/org/freedesktop/DBus",
+            Some(DBUS),
+            "ListNames",
+            &(),
+        )
+        .await?;
+    let mut names: Vec<String> = reply.body().deserialize()?;
+    names.retain(|name| name.starts_with(PREFIX));
+    names.sort();
+
+    let mut players = vec![];
+    for name in names {
+        // A player that quit in the meantime just isn't there
+        if let Ok(reply) = connection
+            .call_method(
+                Some(name.as_str()),
+                PATH,
+                Some(PROPERTIES),
+                "GetAll",
+                &PLAYER,
+            )
+            .await
+            && let Ok(properties) = reply.body().deserialize::<HashMap<String, OwnedValue>>()
+        {
+            players.push(now_playing(&name, &properties));
+        }
+    }
+
+    let preferred = preferred.map(str::to_lowercase);
+    let is_preferred = |now: &NowPlaying| {
+        preferred
+            .as_ref()
+            .is_some_and(|p| now.player.to_lowercase().contains(p))
+    };
+    let is_followed = |now: &NowPlaying| Some(now.bus.as_str()) == followed;
+    let is_playing = |now: &NowPlaying| now.playback == Playback::Playing;
+
+    let pick = players
+        .iter()
+        .position(is_preferred)
+        .or_else(|| players.iter().position(|n| is_followed(n) && is_playing(n)))
+        .or_else(|| players.iter().position(is_playing))
+        .or_else(|| players.iter().position(is_followed))
+        .or((!players.is_empty()).then_some(0));
+    Ok(pick.map(|i| players.swap_remove(i)))
+}
+
+fn now_playing(bus: &str, properties: &HashMap<String, OwnedValue>) -> NowPlaying {
+    let property = |name: &str| properties.get(name).map(|v| inner(v));
+    let metadata: HashMap<String, &Value> = match property("Metadata") {
+        Some(Value::Dict(dict)) => dict
+            .iter()
+            .filter_map(|(key, value)| match key {
+                Value::Str(key) => Some((key.to_string(), inner(value))),
+                _ => None,
+            })
+            .collect(),
+        _ => HashMap::new(),
+    };
+    let field = |name: &str| metadata.get(name).copied();
+
+    let artist = match field("xesam:artist") {
+        Some(Value::Array(artists)) => {
+            let names: Vec<String> = artists.iter().filter_map(text).collect();
+            (!names.is_empty()).then(|| names.join(", "))
+        }
+        Some(other) => text(other),
+        None => None,
+    };
+    let playback = match property("PlaybackStatus").and_then(text).as_deref() {
+        Some("Playing") => Playback::Playing,
+        Some("Paused") => Playback::Paused,
+        _ => Playback::Stopped,
+    };
+    let track = match field("mpris:trackid") {
+        Some(Value::ObjectPath(path)) => Some(path.to_string()),
+        other => other.and_then(text),
+    };
+
+    NowPlaying {
+        player: bus.trim_start_matches(PREFIX).to_string(),
+        title: field("xesam:title")
+            .and_then(text)
+            .filter(|t| !t.is_empty()),
+        artist,
+        art: field("mpris:artUrl")
+            .and_then(text)
+            .and_then(|url| art_path(&url)),
+        playback,
+        volume: property("Volume")
+            .and_then(number)
+            .map(|v| (v * 100.0).clamp(0.0, 100.0)),
+        position: property("Position").and_then(number).map(|us| us / 1e6),
+        length: field("mpris:length").and_then(number).map(|us| us / 1e6),
+        bus: bus.to_string(),
+        track,
+    }
+}
+
+async fn call(connection: &Connection, now: &NowPlaying, request: &Request) -> zbus::Result<()> {
+    let bus = Some(now.bus.as_str());
+    match request {
+        Request::Command(command) => {
+            let method = match command {
+                MediaCommand::PlayPause => "PlayPause",
+                MediaCommand::Play => "Play",
+                MediaCommand::Pause => "Pause",
+                MediaCommand::Stop => "Stop",
+                MediaCommand::Next => "Next",
+                MediaCommand::Previous => "Previous",
+            };
+            connection
+                .call_method(bus, PATH, Some(PLAYER), method, &())
+                .await?;
+        }
+        Request::Volume(percent) => {
+            let volume = Value::F64(percent / 100.0);
+            connection
+                .call_method(
+                    bus,
+                    PATH,
+                    Some(PROPERTIES),
+                    "Set",
+                    &(PLAYER, "Volume", volume),
+                )
+                .await?;
+        }
+        Request::Position(seconds) => {
+            // Players ignore positions for any track but the one playing
+            let Some(track) = &now.track else {
+                return Ok(());
+            };
+            let track = ObjectPath::try_from(track.as_str())?;
+            let position = (seconds * 1e6) as i64;
+            connection
+                .call_method(bus, PATH, Some(PLAYER), "SetPosition", &(track, position))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+// Values inside `a{sv}` dictionaries come wrapped in another variant
+fn inner<'a>(value: &'a Value<'a>) -> &'a Value<'a> {
+    match value {
+        Value::Value(value) => inner(value),
+        other => other,
+    }
+}
+
+fn text(value: &Value) -> Option<String> {
+    match inner(value) {
+        Value::Str(text) => Some(text.to_string()),
+        _ => None,
+    }
+}
+
+// Players disagree on the integer types of lengths and positions
+fn number(value: &Value) -> Option<f64> {
+    match *inner(value) {
+        Value::F64(n) => Some(n),
+        Value::I64(n) => Some(n as f64),
+        Value::U64(n) => Some(n as f64),
+        Value::I32(n) => Some(n as f64),
+        Value::U32(n) => Some(n as f64),
+        _ => None,
+    }
+}
+
+// Only art the player saved to disk can be shown, URLs of web images can't
+fn art_path(url: &str) -> Option<PathBuf> {
+    let path = url.strip_prefix("file://")?;
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok())
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(OsString::from_vec(decoded)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::process::{Child, Command};
+
+    // A session bus of the test's own, gone when it's dropped
+    struct Bus {
+        address: String,
+        _daemon: Child,
+    }
+
+    impl Bus {
+        async fn start() -> Bus {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .kill_on_drop(true)
+                .spawn()
+                .expect("dbus-daemon has to be installed");
+            let stdout = daemon.stdout.take().unwrap();
+            let mut address = String::new();
+            BufReader::new(stdout)
+                .read_line(&mut address)
+                .await
+                .unwrap();
+            Bus {
+                address: address.trim().to_string(),
+                _daemon: daemon,
+            }
+        }
+
+        async fn connect(&self) -> Connection {
+            zbus::connection::Builder::address(self.address.as_str())
+                .unwrap()
+                .build()
+                .await
+                .unwrap()
+        }
+    }
+
+    // An MPRIS player that remembers what it was asked to do
+    #[derive(Clone, Default)]
+    struct Player {
+        status: Arc<Mutex<&'static str>>,
+        metadata: HashMap<String, OwnedValue>,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Player {
+        fn called(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+
+        fn play(&self, status: &'static str) {
+            *self.status.lock().unwrap() = status;
+        }
+
+        // Serves the player under `org.mpris.MediaPlayer2.<name>`
+        async fn start(self, bus: &Bus, name: &str) -> (Player, Connection) {
+            let connection = zbus::connection::Builder::address(bus.address.as_str())
+                .unwrap()
+                .name(format!("{}{}", PREFIX, name))
+                .unwrap()
+                .serve_at(PATH, self.clone())
+                .unwrap()
+                .build()
+                .await
+                .unwrap();
+            (self, connection)
+        }
+    }
+
+    #[zbus::interface(name = "org.mpris.MediaPlayer2.Player")]
+    impl Player {
+        fn play_pause(&self) {
+            self.called("PlayPause".to_string());
+        }
+
+        fn next(&self) {
+            self.called("Next".to_string());
+        }
+
+        fn set_position(&self, track: ObjectPath<'_>, position: i64) {
+            self.called(format!("SetPosition {} {}", track, position));
+        }
+
+        #[zbus(property)]
+        fn playback_status(&self) -> String {
+            self.status.lock().unwrap().to_string()
+        }
+
+        #[zbus(property)]
+        fn metadata(&self) -> HashMap<String, OwnedValue> {
+            self.metadata.clone()
+        }
+
+        #[zbus(property)]
+        fn volume(&self) -> f64 {
+            0.5
+        }
+
+        #[zbus(property)]
+        fn set_volume(&mut self, volume: f64) {
+            self.called(format!("Volume {}", volume));
+        }
+
+        #[zbus(property)]
+        fn position(&self) -> i64 {
+            42_000_000
+        }
+    }
+
+    fn owned(value: Value<'_>) -> OwnedValue {
+        value.try_into().unwrap()
+    }
+
+    fn song() -> HashMap<String, OwnedValue> {
+        HashMap::from([
+            ("xesam:title".to_string(), owned(Value::from("Song"))),
+            (
+                "xesam:artist".to_string(),
+                owned(Value::from(vec!["Ann", "Bob"])),
+            ),
+            (
+                "mpris:trackid".to_string(),
+                owned(Value::from(ObjectPath::try_from("/track/7").unwrap())),
+            ),
+            ("mpris:length".to_string(), owned(Value::I64(180_000_000))),
+            (
+                "mpris:artUrl".to_string(),
+                owned(Value::from("file:///tmp/cover%20art.png")),
+            ),
+        ])
+    }
+
+    #[tokio::test]
+    async fn picks_player_to_follow() {
+        let bus = Bus::start().await;
+        let client = bus.connect().await;
+        let (alpha, _a) = Player::default().start(&bus, "alpha").await;
+        let (beta, _b) = Player::default().start(&bus, "beta").await;
+        let (gamma, _c) = Player::default().start(&bus, "gamma").await;
+        alpha.play("Paused");
+        beta.play("Playing");
+        gamma.play("Stopped");
+
+        let pick = async |preferred, followed| {
+            let now = look(&client, preferred, followed).await.unwrap();
+            now.map(|n| n.player)
+        };
+        let followed = |name| format!("{}{}", PREFIX, name);
+
+        // Whenever the preferred player runs, whatever it's doing
+        assert_eq!(pick(Some("GAM"), None).await.as_deref(), Some("gamma"));
+        // A playing player beats one followed that isn't
+        let gamma_followed = followed("gamma");
+        assert_eq!(
+            pick(None, Some(&gamma_followed)).await.as_deref(),
+            Some("beta")
+        );
+        // The followed one keeps going among several playing, else the first
+        alpha.play("Playing");
+        let beta_followed = followed("beta");
+        assert_eq!(
+            pick(None, Some(&beta_followed)).await.as_deref(),
+            Some("beta")
+        );
+        assert_eq!(pick(None, None).await.as_deref(), Some("alpha"));
+        // With nothing playing, the followed one, else the first
+        alpha.play("Stopped");
+        beta.play("Paused");
+        assert_eq!(
+            pick(None, Some(&gamma_followed)).await.as_deref(),
+            Some("gamma")
+        );
+        assert_eq!(pick(None, None).await.as_deref(), Some("alpha"));
+        // A preferred player that isn't running changes nothing
+        assert_eq!(pick(Some("delta"), None).await.as_deref(), Some("alpha"));
+    }
+
+    #[tokio::test]
+    async fn reads_metadata() {
+        let bus = Bus::start().await;
+        let client = bus.connect().await;
+        assert_eq!(look(&client, None, None).await.unwrap(), None);
+
+        let player = Player {
+            metadata: song(),
+            ..Player::default()
+        };
+        let (player, _player) = player.start(&bus, "spotify.instance42").await;
+        player.play("Playing");
+
+        let now = look(&client, None, None).await.unwrap().unwrap();
+        assert_eq!(now.player, "spotify.instance42");
+        assert_eq!(now.title.as_deref(), Some("Song"));
+        assert_eq!(now.artist.as_deref(), Some("Ann, Bob"));
+        assert_eq!(now.art, Some(PathBuf::from("/tmp/cover art.png")));
+        assert_eq!(now.playback, Playback::Playing);
+        assert_eq!(now.volume, Some(50.0));
+        assert_eq!(now.position, Some(42.0));
+        assert_eq!(now.length, Some(180.0));
+        assert_eq!(now.track.as_deref(), Some("/track/7"));
+    }
+
+    #[tokio::test]
+    async fn calls_player() {
+        let bus = Bus::start().await;
+        let client = bus.connect().await;
+        let player = Player {
+            metadata: song(),
+            ..Player::default()
+        };
+        let (player, _player) = player.start(&bus, "vlc").await;
+        let now = look(&client, None, None).await.unwrap().unwrap();
+
+        for request in [
+            Request::Command(MediaCommand::PlayPause),
+            Request::Command(MediaCommand::Next),
+            Request::Volume(30.0),
+            Request::Position(12.5),
+        ] {
+            call(&client, &now, &request).await.unwrap();
+        }
+        assert_eq!(
+            *player.calls.lock().unwrap(),
+            [
+                "PlayPause",
+                "Next",
+                "Volume 0.3",
+                "SetPosition /track/7 12500000"
+            ]
+        );
+
+        // Methods the player doesn't have come back as errors
+        assert!(
+            call(&client, &now, &Request::Command(MediaCommand::Stop))
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn counts_position_on_while_playing() {
+        let heard = Instant::now();
+        let later = heard + std::time::Duration::from_secs(30);
+        let mut now = NowPlaying {
+            playback: Playback::Playing,
+            position: Some(42.0),
+            length: Some(60.0),
+            ..NowPlaying::default()
+        };
+        assert_eq!(now.position_at(heard, heard), Some(42.0));
+        // Never past the end of the track
+        assert_eq!(now.position_at(heard, later), Some(60.0));
+        now.length = None;
+        assert_eq!(now.position_at(heard, later), Some(72.0));
+        now.playback = Playback::Paused;
+        assert_eq!(now.position_at(heard, later), Some(42.0));
+        now.position = None;
+        assert_eq!(now.position_at(heard, later), None);
+    }
+
+    #[test]
+    fn decodes_art_paths() {
+        let path = |url| art_path(url).map(|p| p.into_os_string().into_vec());
+        assert_eq!(path("file:///a/b.png"), Some(b"/a/b.png".to_vec()));
+        assert_eq!(
+            path("file:///covers/%C3%A9t%C3%A9%20%2525.jpg"),
+            Some("/covers/été %25.jpg".as_bytes().to_vec())
+        );
+        // Bytes that aren't UTF-8 stay as they are
+        assert_eq!(path("file:///%FF.png"), Some(b"/\xFF.png".to_vec()));
+        // Escapes that aren't any are kept literally
+        assert_eq!(path("file:///100%.png"), Some(b"/100%.png".to_vec()));
+        assert_eq!(path("file:///%zz%4"), Some(b"/%zz%4".to_vec()));
+        assert_eq!(path("https://example.com/cover.png"), None);
+        assert_eq!(path("/no/scheme.png"), None);
+    }
+}
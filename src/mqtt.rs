@@ -0,0 +1,742 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use rumqttc::{AsyncClient, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS, Transport};
+use serde_json::Value;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::{Instant, sleep_until, timeout};
+
+use crate::animation::Animation;
+use crate::config::{Config, MqttBinding};
+use crate::control::{Event, EventKind};
+use crate::render::Icons;
+use crate::status::json_field;
+
+// Wait before connecting again after the broker went away
+const RETRY: Duration = Duration::from_secs(5);
+// Pings the broker this often, a deck that stops answering is offline after
+// one and a half times as long
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+// How long a disconnecting deck waits for "offline" to go out
+const TIMEOUT: Duration = Duration::from_secs(2);
+// Requests waiting for the connection, deck input beyond that is dropped
+const CAPACITY: usize = 64;
+
+// Where every deck connects to, from the config the program started with
+struct Broker {
+    options: MqttOptions,
+    address: String,
+    status_topic: String,
+    events_topic: Option<String>,
+}
+
+// Something that came in on a subscribed topic
+#[derive(Debug)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+// What a message has for a key or zone to show
+pub enum Shown {
+    Value(String),
+    // A decoded image, kept in the deck's icons under this path
+    Image(PathBuf),
+}
+
+// A topic a key or zone shows the messages of
+pub struct Binding {
+    topic: String,
+    json: Option<Vec<String>>,
+    title: Option<String>,
+    image: bool,
+}
+
+impl Binding {
+    pub fn new(config: &MqttBinding) -> Binding {
+        Binding {
+            topic: config.topic.clone(),
+            json: config.json.as_ref().map(|path| {
+                path.split('.')
+                    .filter(|part| !part.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }),
+            title: config.title.clone(),
+            image: config.image,
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    // The title with the value in it, if there is one
+    pub fn title(&self, value: &str) -> Option<String> {
+        Some(self.title.as_ref()?.replace("{value}", value))
+    }
+
+    // What the message shows, `None` for messages on topics the binding
+    // doesn't follow
+    pub fn read(&self, message: &Message, icons: &mut Icons) -> Option<Result<Shown, String>> {
+        if !rumqttc::matches(&message.topic, &self.topic) {
+            return None;
+        }
+        let shown = self
+            .value(&message.payload)
+            .and_then(|value| match self.image {
+                true => {
+                    // Bindings picking different images out of the same topic
+                    // keep them apart
+                    let field = self.json.as_ref().map(|path| path.join("."));
+                    let path = PathBuf::from(format!(
+                        "mqtt:{}#{}",
+                        message.topic,
+                        field.unwrap_or_default()
+                    ));
+                    icons.insert(path.clone(), decode(&value)?);
+                    Ok(Shown::Image(path))
+                }
+                false => Ok(Shown::Value(value)),
+            });
+        Some(shown)
+    }
+
+    fn value(&self, payload: &[u8]) -> Result<String, String> {
+        let text = std::str::from_utf8(payload)
+            .map_err(|_| "isn't text".to_string())?
+            .trim();
+        let Some(path) = &self.json else {
+            return Ok(text.to_string());
+        };
+        let json: Value = serde_json::from_str(text).map_err(|e| format!("isn't JSON: {}", e))?;
+        json_field(&json, path).ok_or(format!("no '{}' in it", path.join(".")))
+    }
+}
+
+// A base64 image, with or without a "data:image/png;base64," in front
+fn decode(value: &str) -> Result<Animation, String> {
+    let data = value.rsplit_once("base64,").map_or(value, |(_, data)| data);
+    let data: String = data.split_whitespace().collect();
+    let bytes = STANDARD
+        .decode(data)
+        .map_err(|e| format!("isn't base64: {}", e))?;
+    Animation::decode(&bytes).map_err(|e| format!("isn't an image: {}", e))
+}
+
+// A deck's connection to the broker, which says it went offline and
+// disconnects once this is dropped
+pub struct Connection {
+    messages: mpsc::Receiver<Message>,
+    _stop: oneshot::Sender<()>,
+}
+
+impl Connection {
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.messages.recv().await
+    }
+}
+
+static BROKER: OnceLock<Broker> = OnceLock::new();
+
+// Which broker decks connect to, from the config the program started with.
+// Certificates are read here so a missing one stops the program right away.
+pub fn configure(config: &Config) -> io::Result<()> {
+    if let Some(broker) = Broker::new(config)? {
+        let _ = BROKER.set(broker);
+    }
+    Ok(())
+}
+
+// Connects the deck to the broker, subscribing to the topics and publishing
+// its input from `events`. `None` when no broker is configured.
+pub fn connect(
+    serial: &str,
+    topics: Vec<String>,
+    events: broadcast::Receiver<Event>,
+) -> Option<Connection> {
+    Some(BROKER.get()?.connect(serial, topics, events))
+}
+
+impl Broker {
+    // `None` unless the config names a broker
+    fn new(config: &Config) -> io::Result<Option<Broker>> {
+        let mqtt = &config.mqtt;
+        let Some(host) = &mqtt.host else {
+            return Ok(None);
+        };
+
+        let mut options = MqttOptions::new(mqtt.client_id(), host, mqtt.port());
+        options.set_keep_alive(KEEP_ALIVE);
+        if let Some(username) = &mqtt.username {
+            options.set_credentials(username, mqtt.password.as_deref().unwrap_or_default());
+        }
+        if mqtt.tls {
+            let read = |file: &toml::Spanned<PathBuf>| {
+                let path = config.resolve_path(file.get_ref());
+                std::fs::read(&path).map_err(|e| {
+                    io::Error::new(e.kind(), format!("MQTT {}: {}", path.display(), e))
+                })
+            };
+            let transport = match &mqtt.ca {
+                Some(ca) => {
+                    let client_auth = match (&mqtt.client_cert, &mqtt.client_key) {
+                        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+                        _ => None,
+                    };
+                    Transport::tls(read(ca)?, client_auth, None)
+                }
+                None => Transport::tls_with_default_config(),
+            };
+            options.set_transport(transport);
+        }
+
+        Ok(Some(Broker {
+            options,
+            address: format!("{}:{}", host, mqtt.port()),
+            status_topic: mqtt.status_topic().to_string(),
+            events_topic: mqtt.events_topic.clone(),
+        }))
+    }
+
+    fn connect(
+        &'static self,
+        serial: &str,
+        topics: Vec<String>,
+        events: broadcast::Receiver<Event>,
+    ) -> Connection {
+        let status = self.status_topic.replace("{serial}", serial);
+
+        // The broker says the deck is offline for it when the program goes
+        // away without saying so itself
+        let mut options = self.options.clone();
+        options.set_client_id(format!("{}-{}", options.client_id(), serial));
+        options.set_last_will(LastWill::new(&status, "offline", QoS::AtLeastOnce, true));
+        let (client, eventloop) = AsyncClient::new(options, CAPACITY);
+
+        let (tx, messages) = mpsc::channel(CAPACITY);
+        let (stop, stopped) = oneshot::channel();
+        let session = Session {
+            broker: self,
+            serial: serial.to_string(),
+            client,
+            status,
+            topics,
+            connected: false,
+        };
+        tokio::spawn(session.serve(eventloop, tx, events, stopped));
+        Connection {
+            messages,
+            _stop: stop,
+        }
+    }
+}
+
+struct Session {
+    broker: &'static Broker,
+    serial: String,
+    client: AsyncClient,
+    status: String,
+    topics: Vec<String>,
+    connected: bool,
+}
+
+impl Session {
+    // Keeps the deck connected until the connection is dropped, then says
+    // it's offline
+    async fn serve(
+        mut self,
+        mut eventloop: EventLoop,
+        messages: mpsc::Sender<Message>,
+        mut events: broadcast::Receiver<Event>,
+        mut stopped: oneshot::Receiver<()>,
+    ) {
+        let mut last_error = None;
+        // Polling again right away would spin while the broker is gone
+        let mut poll_after = None;
+        loop {
+            tokio::select! {
+                polled = async {
+                    if let Some(at) = poll_after {
+                        sleep_until(at).await;
+                    }
+                    eventloop.poll().await
+                } => {
+                    poll_after = None;
+                    match polled {
+                        Ok(rumqttc::Event::Incoming(Packet::ConnAck(_))) => {
+                            println!("{}: connected to MQTT broker at {}", self.serial, self.broker.address);
+                            last_error = None;
+                            self.connected = true;
+                            self.online();
+                        }
+                        Ok(rumqttc::Event::Incoming(Packet::Publish(publish))) => {
+                            let message = Message {
+                                topic: publish.topic,
+                                payload: publish.payload.to_vec(),
+                            };
+                            if messages.send(message).await.is_err() {
+                                return;
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            let e = e.to_string();
+                            if self.connected {
+                                eprintln!("{}: lost MQTT broker at {}: {}", self.serial, self.broker.address, e);
+                            } else if last_error.as_ref() != Some(&e) {
+                                eprintln!("{}: can't reach MQTT broker at {}: {}", self.serial, self.broker.address, e);
+                            }
+                            last_error = Some(e);
+                            self.connected = false;
+                            poll_after = Some(Instant::now() + RETRY);
+                        }
+                    }
+                }
+                event = events.recv() => match event {
+                    Ok(event) => self.publish(&event),
+                    Err(RecvError::Lagged(skipped)) => {
+                        eprintln!("{}: {} events not published to MQTT", self.serial, skipped);
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                _ = &mut stopped => {
+                    self.offline(&mut eventloop).await;
+                    return;
+                }
+            }
+        }
+    }
+
+    // Retained so anyone subscribing later knows too, and subscribes again
+    // since the broker forgets subscriptions along with the session
+    fn online(&self) {
+        let _ = self
+            .client
+            .try_publish(&self.status, QoS::AtLeastOnce, true, "online");
+        if self.topics.is_empty() {
+            return;
+        }
+        let topics = self
+            .topics
+            .iter()
+            .map(|topic| rumqttc::SubscribeFilter::new(topic.clone(), QoS::AtMostOnce));
+        if let Err(e) = self.client.try_subscribe_many(topics) {
+            eprintln!("{}: MQTT subscribe: {}", self.serial, e);
+        }
+    }
+
+    async fn offline(&self, eventloop: &mut EventLoop) {
+        if !self.connected {
+            return;
+        }
+        let _ = self
+            .client
+            .try_publish(&self.status, QoS::AtLeastOnce, true, "offline");
+        let _ = self.client.try_disconnect();
+        let _ = timeout(TIMEOUT, async {
+            loop {
+                match eventloop.poll().await {
+                    Ok(rumqttc::Event::Outgoing(Outgoing::Disconnect)) | Err(_) => return,
+                    Ok(_) => {}
+                }
+            }
+        })
+        .await;
+        println!("{}: disconnected from MQTT broker", self.serial);
+    }
+
+    // Input on this deck, while connected. Anything else is for the control
+    // socket only, and events from while the broker was gone would be stale.
+    fn publish(&self, event: &Event) {
+        let input = matches!(
+            event.what,
+            EventKind::Down { .. }
+                | EventKind::Up { .. }
+                | EventKind::Twist { .. }
+                | EventKind::Touch { .. }
+                | EventKind::LongTouch { .. }
+                | EventKind::Swipe { .. }
+        );
+        let Some(topic) = &self.broker.events_topic else {
+            return;
+        };
+        if !input || !self.connected || event.serial != self.serial {
+            return;
+        }
+
+        let json = serde_json::to_value(event).expect("events serialize");
+        let name = json["event"].as_str().unwrap_or_default();
+        let topic = topic
+            .replace("{serial}", &self.serial)
+            .replace("{event}", name);
+        if let Err(e) = self
+            .client
+            .try_publish(topic, QoS::AtMostOnce, false, json.to_string())
+        {
+            eprintln!("{}: MQTT publish: {}", self.serial, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gesture::{Control, Swipe};
+    use crate::layout::Layout;
+    use crate::lcd::Lcd;
+    use bytes::BytesMut;
+    use elgato_streamdeck::info::Kind;
+    use rumqttc::{
+        ConnAck, ConnectReturnCode, PubAck, Publish, SubAck, SubscribeReasonCode, matches,
+    };
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const MAX_PACKET: usize = 1 << 20;
+
+    // What the broker saw, in order
+    #[derive(Debug, PartialEq)]
+    enum Seen {
+        // Client id and its will as topic, message and whether it's retained
+        Connect(String, Option<(String, String, bool)>),
+        Subscribe(Vec<String>),
+        Publish(String, String, bool),
+        Disconnect,
+    }
+
+    // Subscriptions and retained messages of every client
+    #[derive(Default)]
+    struct Topics {
+        subscribers: Vec<(Vec<String>, mpsc::UnboundedSender<Publish>)>,
+        retained: Vec<Publish>,
+    }
+
+    // A broker on a local port that keeps retained messages and fans out
+    // publishes, and tells the test everything clients send it
+    struct TestBroker {
+        port: u16,
+        topics: Arc<Mutex<Topics>>,
+        seen: mpsc::UnboundedReceiver<Seen>,
+    }
+
+    impl TestBroker {
+        async fn start() -> TestBroker {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let topics = Arc::new(Mutex::new(Topics::default()));
+            let (tx, seen) = mpsc::unbounded_channel();
+            let shared = topics.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve_client(stream, shared.clone(), tx.clone()));
+                }
+            });
+            TestBroker { port, topics, seen }
+        }
+
+        // What a config naming this broker connects to
+        fn broker(&self, extra: &str) -> &'static Broker {
+            let source = format!(
+                "[mqtt]\nhost = \"127.0.0.1\"\nport = {}\n\
+                 status_topic = \"decks/{{serial}}/status\"\n{}",
+                self.port, extra
+            );
+            let config = Config::parse(Path::new("test.toml"), source).unwrap();
+            Box::leak(Box::new(Broker::new(&config).unwrap().unwrap()))
+        }
+
+        // Publishes as some other client would
+        fn publish(&self, topic: &str, payload: &str, retain: bool) {
+            let mut publish = Publish::new(topic, QoS::AtMostOnce, payload.as_bytes().to_vec());
+            publish.retain = retain;
+            fan_out(&self.topics, publish);
+        }
+
+        async fn next(&mut self) -> Seen {
+            timeout(Duration::from_secs(2), self.seen.recv())
+                .await
+                .expect("broker saw nothing")
+                .unwrap()
+        }
+    }
+
+    fn fan_out(topics: &Mutex<Topics>, mut publish: Publish) {
+        let mut topics = topics.lock().unwrap();
+        if publish.retain {
+            topics.retained.retain(|p| p.topic != publish.topic);
+            topics.retained.push(publish.clone());
+            publish.retain = false;
+        }
+        publish.qos = QoS::AtMostOnce;
+        publish.pkid = 0;
+        for (filters, subscriber) in &topics.subscribers {
+            if filters.iter().any(|filter| matches(&publish.topic, filter)) {
+                let _ = subscriber.send(publish.clone());
+            }
+        }
+    }
+
+    async fn serve_client(
+        mut stream: TcpStream,
+        topics: Arc<Mutex<Topics>>,
+        seen: mpsc::UnboundedSender<Seen>,
+    ) {
+        let (tx, mut outgoing) = mpsc::unbounded_channel();
+        let mut incoming = BytesMut::new();
+        loop {
+            let mut reply = BytesMut::new();
+            while let Ok(packet) = rumqttc::Packet::read(&mut incoming, MAX_PACKET) {
+                let answer = match packet {
+                    Packet::Connect(connect) => {
+                        let will = connect.last_will.map(|will| {
+                            let message = String::from_utf8_lossy(&will.message).to_string();
+                            (will.topic, message, will.retain)
+                        });
+                        let _ = seen.send(Seen::Connect(connect.client_id, will));
+                        Some(Packet::ConnAck(ConnAck::new(
+                            ConnectReturnCode::Success,
+                            false,
+                        )))
+                    }
+                    Packet::Subscribe(subscribe) => {
+                        let filters: Vec<String> =
+                            subscribe.filters.iter().map(|f| f.path.clone()).collect();
+                        let mut topics = topics.lock().unwrap();
+                        for publish in &topics.retained {
+                            if filters.iter().any(|filter| matches(&publish.topic, filter)) {
+                                let _ = tx.send(publish.clone());
+                            }
+                        }
+                        topics.subscribers.push((filters.clone(), tx.clone()));
+                        let codes = filters
+                            .iter()
+                            .map(|_| SubscribeReasonCode::Success(QoS::AtMostOnce))
+                            .collect();
+                        let _ = seen.send(Seen::Subscribe(filters));
+                        Some(Packet::SubAck(SubAck::new(subscribe.pkid, codes)))
+                    }
+                    Packet::Publish(publish) => {
+                        let payload = String::from_utf8_lossy(&publish.payload).to_string();
+                        let _ = seen.send(Seen::Publish(
+                            publish.topic.clone(),
+                            payload,
+                            publish.retain,
+                        ));
+                        let ack = (publish.qos == QoS::AtLeastOnce)
+                            .then(|| Packet::PubAck(PubAck::new(publish.pkid)));
+                        fan_out(&topics, publish);
+                        ack
+                    }
+                    Packet::PingReq => Some(Packet::PingResp),
+                    Packet::Disconnect => {
+                        let _ = seen.send(Seen::Disconnect);
+                        return;
+                    }
+                    _ => None,
+                };
+                if let Some(answer) = answer {
+                    answer.write(&mut reply, MAX_PACKET).unwrap();
+                }
+            }
+            if !reply.is_empty() && stream.write_all(&reply).await.is_err() {
+                return;
+            }
+
+            tokio::select! {
+                read = stream.read_buf(&mut incoming) => {
+                    if matches!(read, Ok(0) | Err(_)) {
+                        return;
+                    }
+                }
+                Some(publish) = outgoing.recv() => {
+                    let mut out = BytesMut::new();
+                    Packet::Publish(publish).write(&mut out, MAX_PACKET).unwrap();
+                    if stream.write_all(&out).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn event(serial: &str, what: EventKind) -> Event {
+        Event {
+            serial: serial.to_string(),
+            what,
+        }
+    }
+
+    #[tokio::test]
+    async fn says_online_and_offline() {
+        let mut test = TestBroker::start().await;
+        let broker = test.broker("");
+        let (_events, rx) = broadcast::channel(16);
+
+        let connection = broker.connect("AAA", vec![], rx.resubscribe());
+        let will = ("decks/AAA/status".to_string(), "offline".to_string(), true);
+        assert_eq!(
+            test.next().await,
+            Seen::Connect("rust-streamdeck-AAA".to_string(), Some(will))
+        );
+        let online = Seen::Publish("decks/AAA/status".to_string(), "online".to_string(), true);
+        assert_eq!(test.next().await, online);
+
+        // Every deck has a status of its own
+        let other = broker.connect("BBB", vec![], rx);
+        assert!(matches!(test.next().await, Seen::Connect(id, _) if id == "rust-streamdeck-BBB"));
+        let online = Seen::Publish("decks/BBB/status".to_string(), "online".to_string(), true);
+        assert_eq!(test.next().await, online);
+
+        drop(connection);
+        let offline = Seen::Publish("decks/AAA/status".to_string(), "offline".to_string(), true);
+        assert_eq!(test.next().await, offline);
+        assert_eq!(test.next().await, Seen::Disconnect);
+        drop(other);
+        let offline = Seen::Publish("decks/BBB/status".to_string(), "offline".to_string(), true);
+        assert_eq!(test.next().await, offline);
+
+        // Retained for whoever asks later
+        let retained = test.topics.lock().unwrap().retained.clone();
+        let statuses: Vec<(String, Vec<u8>)> = retained
+            .into_iter()
+            .map(|p| (p.topic, p.payload.to_vec()))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("decks/AAA/status".to_string(), b"offline".to_vec()),
+                ("decks/BBB/status".to_string(), b"offline".to_vec()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn publishes_input() {
+        let mut test = TestBroker::start().await;
+        let broker = test.broker("events_topic = \"decks/{serial}/{event}\"");
+        let (events, rx) = broadcast::channel(16);
+        let _connection = broker.connect("AAA", vec![], rx);
+        test.next().await;
+        test.next().await;
+
+        let down = EventKind::Down {
+            control: Control::Key(3),
+        };
+        let swipe = EventKind::Swipe {
+            from: (10, 20),
+            to: (300, 25),
+            direction: Some(Swipe::Right),
+        };
+        // Neither input nor from this deck
+        events.send(event("AAA", EventKind::Disconnected)).unwrap();
+        let other = EventKind::Up {
+            control: Control::Key(3),
+        };
+        events.send(event("BBB", other)).unwrap();
+        events.send(event("AAA", down)).unwrap();
+        events.send(event("AAA", swipe)).unwrap();
+
+        let Seen::Publish(topic, payload, retain) = test.next().await else {
+            panic!("not published");
+        };
+        assert_eq!(topic, "decks/AAA/down");
+        assert!(!retain);
+        let payload: Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(
+            payload,
+            serde_json::json!({ "serial": "AAA", "event": "down", "control": { "key": 3 } })
+        );
+
+        let Seen::Publish(topic, payload, _) = test.next().await else {
+            panic!("not published");
+        };
+        assert_eq!(topic, "decks/AAA/swipe");
+        let payload: Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(
+            payload,
+            serde_json::json!({
+                "serial": "AAA",
+                "event": "swipe",
+                "from": [10, 20],
+                "to": [300, 25],
+                "direction": "right",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn shows_subscribed_topics() {
+        let source = r#"
+[[keys]]
+index = 1
+mqtt = { topic = "home/weather", json = "temperature", title = "{value}°C" }
+
+[[keys]]
+index = 2
+title = "Door"
+mqtt = { topic = "home/+/state" }
+
+[[lcd.zones]]
+encoder = 2
+widget = { type = "progress", label = "Battery", unit = "%" }
+mqtt = { topic = "phone/battery", json = "level" }
+"#;
+        let config = Config::parse(Path::new("test.toml"), source.to_string()).unwrap();
+        let mut layout = Layout::new(&config);
+        let mut lcd = Lcd::new(&config, Kind::Plus);
+        let mut icons = Icons::default();
+        let mut topics = layout.mqtt_topics();
+        topics.extend(lcd.mqtt_topics());
+        topics.sort();
+
+        let mut test = TestBroker::start().await;
+        // Shown as soon as the deck subscribes
+        test.publish("home/weather", r#"{"temperature": 21.5}"#, true);
+        let broker = test.broker("");
+        let (_events, rx) = broadcast::channel(16);
+        let mut connection = broker.connect("AAA", topics, rx);
+        test.next().await;
+        test.next().await;
+        assert_eq!(
+            test.next().await,
+            Seen::Subscribe(vec![
+                "home/+/state".to_string(),
+                "home/weather".to_string(),
+                "phone/battery".to_string(),
+            ])
+        );
+
+        let mut receive = async || {
+            let message = timeout(Duration::from_secs(2), connection.recv()).await;
+            message.expect("nothing came in").unwrap()
+        };
+        let message = receive().await;
+        assert!(layout.show_mqtt(&message, &mut icons));
+        lcd.show_mqtt(&message, &mut icons);
+        assert_eq!(layout.face(1).title.as_deref(), Some("21.5°C"));
+        assert_eq!(layout.face(2).title.as_deref(), Some("Door"));
+        lcd.take_dirty(false);
+
+        test.publish("home/front/state", "open", false);
+        let message = receive().await;
+        assert!(layout.show_mqtt(&message, &mut icons));
+        assert_eq!(layout.face(2).title.as_deref(), Some("open"));
+        // The same again changes nothing
+        assert!(!layout.show_mqtt(&message, &mut icons));
+
+        test.publish("phone/battery", r#"{"level": 80}"#, false);
+        let message = receive().await;
+        assert!(!layout.show_mqtt(&message, &mut icons));
+        lcd.show_mqtt(&message, &mut icons);
+        // The battery zone, the only one
+        assert_eq!(lcd.take_dirty(false), vec![0]);
+    }
+}
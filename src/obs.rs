@@ -0,0 +1,714 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use crate::config::{ActionConfig, ObsCommand, ObsConfig};
+use crate::websocket::{self, Link, Service, SessionError, TIMEOUT};
+
+// The obs-websocket RPC version spoken here, the one of obs-websocket 5
+const RPC_VERSION: u64 = 1;
+// Events about scenes, outputs and the sources in scenes
+const SUBSCRIPTIONS: u64 = (1 << 2) | (1 << 6) | (1 << 7);
+
+// Message opcodes of the protocol
+const HELLO: u64 = 0;
+const IDENTIFY: u64 = 1;
+const IDENTIFIED: u64 = 2;
+const EVENT: u64 = 5;
+const REQUEST: u64 = 6;
+const RESPONSE: u64 = 7;
+
+// What OBS is doing, as of its last event
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ObsState {
+    pub connected: bool,
+    // The scene on air
+    pub scene: Option<String>,
+    pub recording: bool,
+    pub streaming: bool,
+    // Sources of every scene, by scene name
+    items: HashMap<String, Vec<Item>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Item {
+    id: i64,
+    source: String,
+    enabled: bool,
+}
+
+impl ObsState {
+    pub fn is_live(&self, tally: &Tally) -> bool {
+        match tally {
+            Tally::Scene(scene) => self.scene.as_ref() == Some(scene),
+            Tally::Source { source, scene } => self
+                .item(scene.as_deref(), source)
+                .is_some_and(|item| item.enabled),
+            Tally::Recording => self.recording,
+            Tally::Streaming => self.streaming,
+        }
+    }
+
+    // A source in the scene, the one on air unless given
+    fn item(&self, scene: Option<&str>, source: &str) -> Option<&Item> {
+        let scene = scene.or(self.scene.as_deref())?;
+        self.items
+            .get(scene)?
+            .iter()
+            .find(|item| item.source == source)
+    }
+}
+
+// The part of OBS a key controls, shown in the tally color while it's live
+#[derive(Clone, Debug, PartialEq)]
+pub enum Tally {
+    Scene(String),
+    Source {
+        source: String,
+        scene: Option<String>,
+    },
+    Recording,
+    Streaming,
+}
+
+impl Tally {
+    pub fn of(action: &ActionConfig) -> Option<Tally> {
+        match action {
+            ActionConfig::ObsScene { scene } => Some(Tally::Scene(scene.clone())),
+            ActionConfig::ObsSource { source, scene } => Some(Tally::Source {
+                source: source.clone(),
+                scene: scene.clone(),
+            }),
+            ActionConfig::Obs { command } => Some(match command {
+                ObsCommand::ToggleRecording
+                | ObsCommand::StartRecording
+                | ObsCommand::StopRecording => Tally::Recording,
+                ObsCommand::ToggleStreaming
+                | ObsCommand::StartStreaming
+                | ObsCommand::StopStreaming => Tally::Streaming,
+            }),
+            _ => None,
+        }
+    }
+}
+
+// Something for OBS to do
+#[derive(Clone, Debug)]
+pub enum Request {
+    Scene(String),
+    // Show the source if it's hidden, hide it if it's showing
+    Source {
+        source: String,
+        scene: Option<String>,
+    },
+    Command(ObsCommand),
+}
+
+// The task talking to OBS, started by whatever needs it first
+struct Obs {
+    requests: mpsc::UnboundedSender<Request>,
+    state: watch::Receiver<ObsState>,
+}
+
+static SERVER: OnceLock<(String, Option<String>)> = OnceLock::new();
+
+// Where OBS listens, from the config the program started with
+pub fn configure(config: &ObsConfig) {
+    let _ = SERVER.set((config.url().to_string(), config.password.clone()));
+}
+
+// What OBS is doing, all off while it isn't connected
+pub fn watch() -> watch::Receiver<ObsState> {
+    obs().state.clone()
+}
+
+// Hands the request to OBS, failures are logged there
+pub fn send(request: Request) {
+    if let Err(e) = obs().requests.send(request) {
+        eprintln!("OBS unreachable, dropped {:?}", e.0);
+    }
+}
+
+fn obs() -> &'static Obs {
+    static OBS: OnceLock<Obs> = OnceLock::new();
+
+    OBS.get_or_init(|| {
+        let (requests, rx) = mpsc::unbounded_channel();
+        let (tx, state) = watch::channel(ObsState::default());
+        let (url, password) = SERVER
+            .get()
+            .cloned()
+            .unwrap_or_else(|| (ObsConfig::default().url().to_string(), None));
+        tokio::spawn(websocket::serve(Client { password }, url, rx, tx));
+        Obs { requests, state }
+    })
+}
+
+// What it takes to connect to OBS
+struct Client {
+    password: Option<String>,
+}
+
+impl Service for Client {
+    type Session = Session;
+    type Request = Request;
+    type State = ObsState;
+
+    const NAME: &'static str = "OBS";
+
+    async fn connect(&mut self, url: &str) -> Result<Session, SessionError> {
+        Session::connect(url, self.password.as_deref()).await
+    }
+
+    async fn run(
+        &mut self,
+        session: &mut Session,
+        requests: &mut mpsc::UnboundedReceiver<Request>,
+        state: &watch::Sender<ObsState>,
+    ) -> Result<(), SessionError> {
+        session.run(requests, state).await
+    }
+}
+
+// One connection to OBS, identified and subscribed to events
+struct Session {
+    link: Link,
+    next_id: u64,
+    state: ObsState,
+    // Scenes whose sources have to be listed again, or all of them
+    stale: HashSet<String>,
+    stale_all: bool,
+}
+
+impl Session {
+    async fn connect(url: &str, password: Option<&str>) -> Result<Session, SessionError> {
+        let deadline = Instant::now() + TIMEOUT;
+        let mut session = Session {
+            link: Link::open(url, deadline).await?,
+            next_id: 0,
+            state: ObsState::default(),
+            stale: HashSet::new(),
+            stale_all: true,
+        };
+
+        let hello = session.expect(HELLO, deadline).await?;
+        let mut identify = json!({
+            "rpcVersion": RPC_VERSION,
+            "eventSubscriptions": SUBSCRIPTIONS,
+        });
+        if let Some(auth) = hello.get("authentication") {
+            let password = password.ok_or_else(|| {
+                SessionError::Protocol("OBS wants a password, set one under [obs]".to_string())
+            })?;
+            let text = |key: &str| auth[key].as_str().unwrap_or_default().to_string();
+            identify["authentication"] =
+                authentication(password, &text("salt"), &text("challenge")).into();
+        }
+        session.send(IDENTIFY, identify).await?;
+        session.expect(IDENTIFIED, deadline).await?;
+
+        session.refresh().await?;
+        session.state.connected = true;
+        Ok(session)
+    }
+
+    // Does what's asked and follows OBS's events until the connection drops,
+    // or the program no longer has requests for it
+    async fn run(
+        &mut self,
+        requests: &mut mpsc::UnboundedReceiver<Request>,
+        state: &watch::Sender<ObsState>,
+    ) -> Result<(), SessionError> {
+        loop {
+            state.send_if_modified(|shown| {
+                let changed = *shown != self.state;
+                if changed {
+                    *shown = self.state.clone();
+                }
+                changed
+            });
+
+            tokio::select! {
+                request = requests.recv() => {
+                    let Some(request) = request else { return Ok(()) };
+                    match self.handle(&request).await {
+                        Err(e) if e.is_fatal() => return Err(e),
+                        Err(e) => eprintln!("OBS {:?}: {}", request, e),
+                        Ok(()) => {}
+                    }
+                }
+                message = self.link.next() => {
+                    if let (EVENT, data) = opcode(message?)? {
+                        self.event(&data);
+                    }
+                }
+            }
+            match self.refresh().await {
+                Err(e) if e.is_fatal() => return Err(e),
+                Err(e) => eprintln!("OBS: {}", e),
+                Ok(()) => {}
+            }
+        }
+    }
+
+    async fn handle(&mut self, request: &Request) -> Result<(), SessionError> {
+        match request {
+            Request::Scene(scene) => {
+                self.call("SetCurrentProgramScene", json!({ "sceneName": scene }))
+                    .await?;
+            }
+            Request::Source { source, scene } => {
+                let scene = scene
+                    .clone()
+                    .or(self.state.scene.clone())
+                    .ok_or_else(|| SessionError::Refused("no scene on air".to_string()))?;
+                let item = self.state.item(Some(&scene), source).ok_or_else(|| {
+                    SessionError::Refused(format!("no source '{}' in scene '{}'", source, scene))
+                })?;
+                let data = json!({
+                    "sceneName": scene,
+                    "sceneItemId": item.id,
+                    "sceneItemEnabled": !item.enabled,
+                });
+                self.call("SetSceneItemEnabled", data).await?;
+            }
+            Request::Command(command) => {
+                let request = match command {
+                    ObsCommand::ToggleRecording => "ToggleRecord",
+                    ObsCommand::StartRecording => "StartRecord",
+                    ObsCommand::StopRecording => "StopRecord",
+                    ObsCommand::ToggleStreaming => "ToggleStream",
+                    ObsCommand::StartStreaming => "StartStream",
+                    ObsCommand::StopStreaming => "StopStream",
+                };
+                self.call(request, json!({})).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Keeps the state in step with an event. Events that change which
+    // sources there are only mark scenes to be listed again.
+    fn event(&mut self, event: &Value) {
+        let data = &event["eventData"];
+        let scene = data["sceneName"].as_str().map(str::to_string);
+        match event["eventType"].as_str().unwrap_or_default() {
+            "CurrentProgramSceneChanged" => self.state.scene = scene,
+            "RecordStateChanged" => self.state.recording = active(data),
+            "StreamStateChanged" => self.state.streaming = active(data),
+            "SceneItemEnableStateChanged" => {
+                let id = data["sceneItemId"].as_i64();
+                let items = scene.and_then(|scene| self.state.items.get_mut(&scene));
+                if let Some(item) = items.into_iter().flatten().find(|item| Some(item.id) == id) {
+                    item.enabled = data["sceneItemEnabled"].as_bool().unwrap_or(item.enabled);
+                }
+            }
+            "SceneItemCreated" | "SceneItemRemoved" => self.stale.extend(scene),
+            "SceneCreated" | "SceneRemoved" | "SceneNameChanged" | "SceneListChanged" => {
+                self.stale_all = true;
+            }
+            _ => {}
+        }
+    }
+
+    // Asks OBS again for whatever events said is out of date
+    async fn refresh(&mut self) -> Result<(), SessionError> {
+        if std::mem::take(&mut self.stale_all) {
+            let scene = self.call("GetCurrentProgramScene", json!({})).await?;
+            self.state.scene = scene["currentProgramSceneName"]
+                .as_str()
+                .map(str::to_string);
+            self.state.recording = active(&self.call("GetRecordStatus", json!({})).await?);
+            self.state.streaming = active(&self.call("GetStreamStatus", json!({})).await?);
+
+            let list = self.call("GetSceneList", json!({})).await?;
+            self.state.items.clear();
+            self.stale = list["scenes"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|scene| scene["sceneName"].as_str())
+                .map(str::to_string)
+                .collect();
+        }
+
+        for scene in std::mem::take(&mut self.stale) {
+            let list = match self
+                .call("GetSceneItemList", json!({ "sceneName": scene }))
+                .await
+            {
+                Ok(list) => list,
+                // Removed in the meantime
+                Err(SessionError::Refused(_)) => {
+                    self.state.items.remove(&scene);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let items = list["sceneItems"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|item| {
+                    Some(Item {
+                        id: item["sceneItemId"].as_i64()?,
+                        source: item["sourceName"].as_str()?.to_string(),
+                        enabled: item["sceneItemEnabled"].as_bool()?,
+                    })
+                })
+                .collect();
+            self.state.items.insert(scene, items);
+        }
+        Ok(())
+    }
+
+    // Sends a request and waits for its response, keeping up with events
+    // that arrive in the meantime
+    async fn call(&mut self, request: &str, data: Value) -> Result<Value, SessionError> {
+        self.next_id += 1;
+        let id = self.next_id.to_string();
+        let message = json!({
+            "requestType": request,
+            "requestId": id,
+            "requestData": data,
+        });
+        self.send(REQUEST, message).await?;
+
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            match opcode(self.link.next_before(deadline).await?)? {
+                (EVENT, event) => self.event(&event),
+                (RESPONSE, response) if response["requestId"] == id.as_str() => {
+                    let status = &response["requestStatus"];
+                    if status["result"].as_bool() == Some(true) {
+                        return Ok(response["responseData"].clone());
+                    }
+                    let comment = status["comment"].as_str().unwrap_or("refused");
+                    let code = status["code"].as_i64().unwrap_or_default();
+                    return Err(SessionError::Refused(format!(
+                        "{}: {} ({})",
+                        request, comment, code
+                    )));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn send(&mut self, op: u64, data: Value) -> Result<(), SessionError> {
+        self.link.send(&message(op, data)).await
+    }
+
+    // Waits for the message that comes next in the handshake
+    async fn expect(&mut self, op: u64, deadline: Instant) -> Result<Value, SessionError> {
+        let (got, data) = opcode(self.link.next_before(deadline).await?)?;
+        if got != op {
+            return Err(SessionError::Protocol(format!(
+                "expected message {}, got {}",
+                op, got
+            )));
+        }
+        Ok(data)
+    }
+}
+
+fn message(op: u64, data: Value) -> Value {
+    json!({ "op": op, "d": data })
+}
+
+// Opcode and data of a protocol message
+fn opcode(mut message: Value) -> Result<(u64, Value), SessionError> {
+    match message["op"].as_u64() {
+        Some(op) => Ok((op, message["d"].take())),
+        None => Err(SessionError::Protocol(
+            "message without an opcode".to_string(),
+        )),
+    }
+}
+
+// Whether an output's state change or status says it's running
+fn active(data: &Value) -> bool {
+    data["outputActive"].as_bool().unwrap_or(false)
+}
+
+// The response to OBS's challenge, proving the password without sending it
+fn authentication(password: &str, salt: &str, challenge: &str) -> String {
+    let secret = BASE64.encode(Sha256::digest(format!("{}{}", password, salt)));
+    BASE64.encode(Sha256::digest(format!("{}{}", secret, challenge)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::RETRY;
+    use crate::websocket::fake::{Fake, Peer, Server, connect, until};
+    use std::time::Duration;
+
+    // The example of the obs-websocket protocol docs
+    const PASSWORD: &str = "supersecretpassword";
+    const SALT: &str = "lM1GncleQOaCu9lT1yeUZhFYnqhsLLP1G5lAGo3ixaI=";
+    const CHALLENGE: &str = "+IxH4CnCiqpX1rM9scsNynZzbOe4KhDeYcTNS3PDaeY=";
+    const ANSWER: &str = "1Ct943GAT+6YQUUX47Ia/ncufilbe6+oD6lY+5kaCu4=";
+
+    // What the fake OBS shows, with two scenes of one source each
+    struct Scenes {
+        password: Option<&'static str>,
+        scene: String,
+        // Whether the source of the other scene is enabled
+        screen: bool,
+        // Whether the program listed everything since it connected
+        listed: bool,
+    }
+
+    impl Scenes {
+        fn new(password: Option<&'static str>) -> Scenes {
+            Scenes {
+                password,
+                scene: "Main".to_string(),
+                screen: false,
+                listed: false,
+            }
+        }
+    }
+
+    // Speaks the protocol the way OBS does. The fake sees the requests that
+    // come after the handshake and the listing that follows it.
+    impl Fake for Scenes {
+        async fn greet(&mut self, peer: &mut Peer) -> bool {
+            let mut hello = json!({ "obsWebSocketVersion": "5.5.0", "rpcVersion": 1 });
+            if self.password.is_some() {
+                hello["authentication"] = json!({ "challenge": CHALLENGE, "salt": SALT });
+            }
+            peer.send(message(HELLO, hello)).await;
+
+            let Some(Ok((IDENTIFY, identify))) = peer.receive().await.map(opcode) else {
+                return false;
+            };
+            assert_eq!(identify["rpcVersion"], RPC_VERSION);
+            assert_eq!(identify["eventSubscriptions"], SUBSCRIPTIONS);
+            if self.password.is_some() && identify["authentication"] != ANSWER {
+                peer.close(4009, "Authentication failed.").await;
+                return false;
+            }
+            let identified = json!({ "negotiatedRpcVersion": 1 });
+            peer.send(message(IDENTIFIED, identified)).await;
+            true
+        }
+
+        async fn answer(&mut self, peer: &mut Peer, request: Value) {
+            let Ok((REQUEST, request)) = opcode(request) else {
+                return;
+            };
+            let kind = request["requestType"].as_str().unwrap().to_string();
+            let data = request["requestData"].clone();
+            // Answers to requests nobody made and events in between don't get
+            // in the way of the answer
+            let stray = json!({
+                "requestType": kind,
+                "requestId": "stray",
+                "requestStatus": { "result": true, "code": 100 },
+                "responseData": { "currentProgramSceneName": "Stray" },
+            });
+            peer.send(message(RESPONSE, stray)).await;
+
+            let mut event = None;
+            let answer = match kind.as_str() {
+                "GetCurrentProgramScene" => Ok(json!({ "currentProgramSceneName": self.scene })),
+                "GetRecordStatus" | "GetStreamStatus" => Ok(json!({ "outputActive": false })),
+                "GetSceneList" => Ok(json!({
+                    "scenes": [{ "sceneName": "Main" }, { "sceneName": "Other" }],
+                })),
+                "GetSceneItemList" => {
+                    self.listed |= data["sceneName"] == "Other";
+                    let (id, source, enabled) = match data["sceneName"].as_str() {
+                        Some("Main") => (1, "Cam", true),
+                        _ => (2, "Screen", self.screen),
+                    };
+                    Ok(json!({ "sceneItems": [{
+                        "sceneItemId": id,
+                        "sourceName": source,
+                        "sceneItemEnabled": enabled,
+                    }] }))
+                }
+                "SetCurrentProgramScene" => match data["sceneName"].as_str() {
+                    Some(name @ ("Main" | "Other")) => {
+                        self.scene = name.to_string();
+                        event = Some(json!({
+                            "eventType": "CurrentProgramSceneChanged",
+                            "eventData": { "sceneName": name },
+                        }));
+                        Ok(json!(null))
+                    }
+                    _ => Err("No scene was found by the name of `Missing`."),
+                },
+                "SetSceneItemEnabled" => {
+                    self.screen = data["sceneItemEnabled"].as_bool().unwrap();
+                    event = Some(json!({
+                        "eventType": "SceneItemEnableStateChanged",
+                        "eventData": data,
+                    }));
+                    Ok(json!(null))
+                }
+                _ => Ok(json!(null)),
+            };
+            if let Some(event) = event {
+                peer.send(message(EVENT, event)).await;
+            }
+
+            let status = match &answer {
+                Ok(_) => json!({ "result": true, "code": 100 }),
+                Err(comment) => json!({ "result": false, "code": 600, "comment": comment }),
+            };
+            let response = json!({
+                "requestType": kind,
+                "requestId": request["requestId"],
+                "requestStatus": status,
+                "responseData": answer.unwrap_or_default(),
+            });
+            peer.send(message(RESPONSE, response)).await;
+            // Only what's asked after the first full listing is of interest
+            if self.listed && !kind.starts_with("Get") {
+                peer.saw(json!({ "requestType": kind, "requestData": data }));
+            }
+        }
+
+        async fn push(&mut self, peer: &mut Peer, event: Value) {
+            peer.send(message(EVENT, event)).await;
+        }
+    }
+
+    fn client(password: Option<&str>) -> Client {
+        Client {
+            password: password.map(str::to_string),
+        }
+    }
+
+    fn event(kind: &str, data: Value) -> Value {
+        json!({ "eventType": kind, "eventData": data })
+    }
+
+    fn source(name: &str, scene: Option<&str>) -> Tally {
+        Tally::Source {
+            source: name.to_string(),
+            scene: scene.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_without_password() {
+        let obs = Server::start("").await;
+        let (_requests, mut state) = connect(client(None), &obs.url);
+        let _connection = obs.accept(Scenes::new(None)).await;
+
+        until(&mut state, |s| s.connected).await;
+        let now = state.borrow().clone();
+        assert_eq!(now.scene.as_deref(), Some("Main"));
+        assert!(now.is_live(&Tally::Scene("Main".to_string())));
+        assert!(now.is_live(&source("Cam", None)));
+        assert!(!now.is_live(&source("Screen", Some("Other"))));
+        assert!(!now.is_live(&Tally::Recording));
+    }
+
+    #[tokio::test]
+    async fn answers_challenge() {
+        let obs = Server::start("").await;
+        let (_requests, mut state) = connect(client(Some(PASSWORD)), &obs.url);
+        let _connection = obs.accept(Scenes::new(Some(PASSWORD))).await;
+        until(&mut state, |s| s.connected).await;
+    }
+
+    #[tokio::test]
+    async fn wrong_password_stays_disconnected() {
+        let obs = Server::start("").await;
+        let (_requests, state) = connect(client(Some("guess")), &obs.url);
+        obs.accept(Scenes::new(Some(PASSWORD))).await.closed().await;
+        assert!(!state.borrow().connected);
+
+        // Without a password it hangs up as soon as OBS asks for one
+        let (_requests, state) = connect(client(None), &obs.url);
+        obs.accept(Scenes::new(Some(PASSWORD))).await.closed().await;
+        assert!(!state.borrow().connected);
+    }
+
+    #[tokio::test]
+    async fn requests_and_events() {
+        let obs = Server::start("").await;
+        let (requests, mut state) = connect(client(None), &obs.url);
+        let mut connection = obs.accept(Scenes::new(None)).await;
+        until(&mut state, |s| s.connected).await;
+
+        requests.send(Request::Scene("Other".to_string())).unwrap();
+        assert_eq!(
+            connection.seen().await,
+            json!({
+                "requestType": "SetCurrentProgramScene",
+                "requestData": { "sceneName": "Other" },
+            })
+        );
+        until(&mut state, |s| s.scene.as_deref() == Some("Other")).await;
+
+        // Sources are those of the scene on air unless named
+        requests
+            .send(Request::Source {
+                source: "Screen".to_string(),
+                scene: None,
+            })
+            .unwrap();
+        assert_eq!(
+            connection.seen().await,
+            json!({
+                "requestType": "SetSceneItemEnabled",
+                "requestData": { "sceneName": "Other", "sceneItemId": 2, "sceneItemEnabled": true },
+            })
+        );
+        until(&mut state, |s| s.is_live(&source("Screen", None))).await;
+
+        // A refused request leaves the connection up
+        requests
+            .send(Request::Scene("Missing".to_string()))
+            .unwrap();
+        assert_eq!(
+            connection.seen().await["requestType"],
+            "SetCurrentProgramScene"
+        );
+        requests
+            .send(Request::Command(ObsCommand::ToggleRecording))
+            .unwrap();
+        assert_eq!(connection.seen().await["requestType"], "ToggleRecord");
+
+        connection.push(event("RecordStateChanged", json!({ "outputActive": true })));
+        until(&mut state, |s| s.recording && s.connected).await;
+        connection.push(event("StreamStateChanged", json!({ "outputActive": true })));
+        until(&mut state, |s| s.streaming).await;
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_drop() {
+        let obs = Server::start("").await;
+        let (requests, mut state) = connect(client(None), &obs.url);
+        let connection = obs.accept(Scenes::new(None)).await;
+        until(&mut state, |s| s.connected).await;
+
+        drop(connection);
+        until(&mut state, |s| *s == ObsState::default()).await;
+        // Dropped rather than sent once OBS is back
+        requests
+            .send(Request::Command(ObsCommand::StartStreaming))
+            .unwrap();
+
+        let dropped = Instant::now();
+        let mut connection = obs.accept(Scenes::new(None)).await;
+        assert!(dropped.elapsed() >= RETRY - Duration::from_millis(100));
+        until(&mut state, |s| s.connected).await;
+        requests
+            .send(Request::Command(ObsCommand::StopStreaming))
+            .unwrap();
+        assert_eq!(connection.seen().await["requestType"], "StopStream");
+    }
+}
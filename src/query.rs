@@ -0,0 +1,34 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::process::Command;
+
+// What a command printed and how it exited
+#[derive(Debug)]
+pub struct Output {
+    pub stdout: String,
+    // `None` when a signal ended it
+    pub code: Option<i32>,
+}
+
+// Runs a snippet through `sh -c` and collects its output. Commands that take
+// longer than `timeout` are killed.
+pub async fn run(command: &str, timeout: Duration) -> Result<Output, String> {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("failed to run '{}': {}", command, e))?;
+
+    let output = match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(output) => output.map_err(|e| format!("failed to wait for '{}': {}", command, e))?,
+        Err(_) => return Err(format!("'{}' took longer than {:?}", command, timeout)),
+    };
+    Ok(Output {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        code: output.status.code(),
+    })
+}
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use ab_glyph::{Font, FontArc, PxScale, ScaleFont, point};
+use image::{DynamicImage, GrayImage, Luma, Rgba, RgbaImage};
+
+use crate::animation::{Animation, Player, Target};
+use crate::config::{Align, Config, TextConfig};
+
+// Used for every title that doesn't pick a font of its own
+static BUNDLED_FONT: &[u8] = include_bytes!("../assets/fonts/DejaVuSansCondensed-Bold.ttf");
+
+// Room left between the title and the edge of the key
+const PADDING: f32 = 4.0;
+// Space between the end of a scrolling title and its start coming round again
+const MARQUEE_GAP: f32 = 24.0;
+
+// A `[text]` table with every gap filled in, ready to draw with
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextStyle {
+    pub font: Option<PathBuf>,
+    // Pixel height, `None` scales with the key
+    pub size: Option<f32>,
+    pub color: [u8; 3],
+    pub align: Align,
+    pub outline: u32,
+    pub outline_color: [u8; 3],
+}
+
+impl TextStyle {
+    pub fn new(config: &Config, text: &TextConfig) -> TextStyle {
+        let defaults = TextStyle::default();
+        TextStyle {
+            font: text.font.as_ref().map(|f| config.resolve_path(f.get_ref())),
+            size: text.size.as_ref().map(|s| *s.get_ref()),
+            color: text.color.unwrap_or(defaults.color),
+            align: text.align.unwrap_or(defaults.align),
+            outline: text.outline.unwrap_or(defaults.outline),
+            outline_color: text.outline_color.unwrap_or(defaults.outline_color),
+        }
+    }
+}
+
+// White with a thin black outline, readable over most icons
+impl Default for TextStyle {
+    fn default() -> TextStyle {
+        TextStyle {
+            font: None,
+            size: None,
+            color: [255, 255, 255],
+            align: Align::default(),
+            outline: 1,
+            outline_color: [0, 0, 0],
+        }
+    }
+}
+
+// Parsed fonts, so a title change doesn't re-read the font file
+#[derive(Default)]
+pub struct Fonts(HashMap<PathBuf, FontArc>);
+
+impl Fonts {
+    fn get(&mut self, path: Option<&Path>) -> FontArc {
+        let Some(path) = path else {
+            return bundled_font();
+        };
+        self.0
+            .entry(path.to_path_buf())
+            .or_insert_with(|| {
+                let font = std::fs::read(path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|data| FontArc::try_from_vec(data).map_err(|e| e.to_string()));
+                match font {
+                    Ok(font) => font,
+                    Err(e) => {
+                        eprintln!("Failed to load font {}: {}", path.display(), e);
+                        bundled_font()
+                    }
+                }
+            })
+            .clone()
+    }
+}
+
+// Decoded icons, so switching back and forth between pages doesn't hit the
+// disk, and where each animated one is at
+#[derive(Default)]
+pub struct Icons {
+    images: HashMap<PathBuf, Option<Arc<Animation>>>,
+    player: Player,
+}
+
+impl Icons {
+    // The image, or the first frame of an animation
+    pub fn get(&mut self, path: &Path) -> Option<DynamicImage> {
+        self.load(path).map(|animation| animation.first().clone())
+    }
+
+    // What the target shows of the image, the current frame when it's animated
+    pub fn frame(&mut self, target: Target, path: &Path) -> Option<DynamicImage> {
+        let Some(animation) = self.load(path) else {
+            self.player.stop(&target);
+            return None;
+        };
+        if !animation.is_animated() {
+            self.player.stop(&target);
+            return Some(animation.first().clone());
+        }
+        Some(self.player.frame(target, &animation))
+    }
+
+    // Shows the image wherever the path is used, for images that aren't in
+    // a file
+    pub fn insert(&mut self, path: PathBuf, animation: Animation) {
+        self.images.insert(path, Some(Arc::new(animation)));
+    }
+
+    pub fn forget(&mut self, path: &Path) {
+        self.images.remove(path);
+    }
+
+    pub fn player(&mut self) -> &mut Player {
+        &mut self.player
+    }
+
+    fn load(&mut self, path: &Path) -> Option<Arc<Animation>> {
+        self.images
+            .entry(path.to_path_buf())
+            .or_insert_with(|| match Animation::load(path) {
+                Ok(animation) => Some(Arc::new(animation)),
+                Err(e) => {
+                    eprintln!("Failed to load icon {}: {}", path.display(), e);
+                    None
+                }
+            })
+            .clone()
+    }
+}
+
+fn bundled_font() -> FontArc {
+    static FONT: OnceLock<FontArc> = OnceLock::new();
+    FONT.get_or_init(|| FontArc::try_from_slice(BUNDLED_FONT).expect("bundled font is valid"))
+        .clone()
+}
+
+// Draws `text` onto the image, wrapped to its width and placed according to the style
+pub fn draw_text(image: &mut RgbaImage, text: &str, style: &TextStyle, fonts: &mut Fonts) {
+    let (w, h) = image.dimensions();
+    let font = fonts.get(style.font.as_deref());
+    let font = font.as_scaled(scale(style, h));
+
+    let lines = wrap(&font, text, w as f32 - 2.0 * PADDING);
+    let line_height = font.height() + font.line_gap();
+    let top = top(style, h, line_height * lines.len() as f32);
+
+    // Coverage of every glyph, so the outline can be grown around all of them
+    let mut mask = GrayImage::new(w, h);
+    for (i, line) in lines.iter().enumerate() {
+        let baseline = top + i as f32 * line_height + font.ascent();
+        let x = (w as f32 - measure(&font, line)) / 2.0;
+        draw_line(&mut mask, &font, line, x, baseline);
+    }
+    paint(image, &mask, style);
+}
+
+// Draws `text` on a single line placed according to the style. Text too wide
+// for the image is moved `offset` pixels to the left, coming round again
+// after its end, so drawing it with a growing offset scrolls it.
+pub fn draw_marquee(
+    image: &mut RgbaImage,
+    text: &str,
+    style: &TextStyle,
+    fonts: &mut Fonts,
+    offset: f32,
+) {
+    let (w, h) = image.dimensions();
+    let font = fonts.get(style.font.as_deref());
+    let font = font.as_scaled(scale(style, h));
+
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let width = measure(&font, &line);
+    let baseline = top(style, h, font.height() + font.line_gap()) + font.ascent();
+
+    let mut mask = GrayImage::new(w, h);
+    if width <= w as f32 - 2.0 * PADDING {
+        draw_line(&mut mask, &font, &line, (w as f32 - width) / 2.0, baseline);
+    } else {
+        let period = width + MARQUEE_GAP;
+        let x = PADDING - offset.rem_euclid(period);
+        draw_line(&mut mask, &font, &line, x, baseline);
+        draw_line(&mut mask, &font, &line, x + period, baseline);
+    }
+    paint(image, &mask, style);
+}
+
+// Whether a marquee of `text` in an image of this size scrolls
+pub fn overflows(text: &str, style: &TextStyle, fonts: &mut Fonts, (w, h): (u32, u32)) -> bool {
+    let font = fonts.get(style.font.as_deref());
+    let font = font.as_scaled(scale(style, h));
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    measure(&font, &line) > w as f32 - 2.0 * PADDING
+}
+
+fn scale(style: &TextStyle, height: u32) -> PxScale {
+    PxScale::from(style.size.unwrap_or(height as f32 / 5.0))
+}
+
+// Where text `total` pixels high starts for the style's alignment
+fn top(style: &TextStyle, height: u32, total: f32) -> f32 {
+    match style.align {
+        Align::Top => PADDING,
+        Align::Middle => (height as f32 - total) / 2.0,
+        Align::Bottom => height as f32 - PADDING - total,
+    }
+}
+
+// Adds the coverage of a line of glyphs starting at `x` to the mask, whatever
+// falls outside of it is cut off
+fn draw_line<F: Font, SF: ScaleFont<F>>(
+    mask: &mut GrayImage,
+    font: &SF,
+    line: &str,
+    mut x: f32,
+    baseline: f32,
+) {
+    let (w, h) = mask.dimensions();
+    let mut previous = None;
+
+    for c in line.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            x += font.kern(previous, id);
+        }
+        previous = Some(id);
+
+        let glyph = id.with_scale_and_position(font.scale(), point(x, baseline));
+        x += font.h_advance(id);
+        let Some(outlined) = font.outline_glyph(glyph) else {
+            continue;
+        };
+
+        let bounds = outlined.px_bounds();
+        outlined.draw(|gx, gy, coverage| {
+            let px = bounds.min.x as i32 + gx as i32;
+            let py = bounds.min.y as i32 + gy as i32;
+            if px < 0 || py < 0 || px >= w as i32 || py >= h as i32 {
+                return;
+            }
+            let value = (coverage.clamp(0.0, 1.0) * 255.0) as u8;
+            let pixel = mask.get_pixel_mut(px as u32, py as u32);
+            pixel.0[0] = pixel.0[0].max(value);
+        });
+    }
+}
+
+// Paints the glyphs in the mask in the style's colors, outline first
+fn paint(image: &mut RgbaImage, mask: &GrayImage, style: &TextStyle) {
+    if style.outline > 0 {
+        blend(image, &dilate(mask, style.outline), style.outline_color);
+    }
+    blend(image, mask, style.color);
+}
+
+// Splits the text into lines no wider than `width`, honoring explicit newlines.
+// Words too long for a line of their own are broken wherever they run out of room.
+fn wrap<F: Font, SF: ScaleFont<F>>(font: &SF, text: &str, width: f32) -> Vec<String> {
+    let mut lines = vec![];
+
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let candidate = if line.is_empty() {
+                word.to_string()
+            } else {
+                format!("{} {}", line, word)
+            };
+            if line.is_empty() || measure(font, &candidate) <= width {
+                line = candidate;
+            } else {
+                lines.push(std::mem::replace(&mut line, word.to_string()));
+            }
+
+            while measure(font, &line) > width && line.chars().count() > 1 {
+                let mut split = line.len();
+                while split > 0 && measure(font, &line[..split]) > width {
+                    split = line[..split]
+                        .char_indices()
+                        .last()
+                        .map(|(i, _)| i)
+                        .unwrap_or(0);
+                }
+                // Always move at least one character along
+                if split == 0 {
+                    split = line
+                        .chars()
+                        .next()
+                        .map(char::len_utf8)
+                        .unwrap_or(line.len());
+                }
+                let rest = line.split_off(split);
+                lines.push(std::mem::replace(&mut line, rest));
+            }
+        }
+        lines.push(line);
+    }
+
+    lines
+}
+
+fn measure<F: Font, SF: ScaleFont<F>>(font: &SF, text: &str) -> f32 {
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            width += font.kern(previous, id);
+        }
+        width += font.h_advance(id);
+        previous = Some(id);
+    }
+    width
+}
+
+// Grows the mask by `radius` pixels in every direction
+fn dilate(mask: &GrayImage, radius: u32) -> GrayImage {
+    let (w, h) = mask.dimensions();
+    let r = radius as i32;
+
+    GrayImage::from_fn(w, h, |x, y| {
+        let mut value = 0;
+        for dy in -r..=r {
+            for dx in -r..=r {
+                if dx * dx + dy * dy > r * r {
+                    continue;
+                }
+                let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                if nx >= 0 && ny >= 0 && nx < w as i32 && ny < h as i32 {
+                    value = value.max(mask.get_pixel(nx as u32, ny as u32).0[0]);
+                }
+            }
+        }
+        Luma([value])
+    })
+}
+
+// Paints `color` over the image wherever the mask has coverage
+fn blend(image: &mut RgbaImage, mask: &GrayImage, color: [u8; 3]) {
+    for (pixel, coverage) in image.pixels_mut().zip(mask.pixels()) {
+        let alpha = coverage.0[0] as u32;
+        if alpha == 0 {
+            continue;
+        }
+        let Rgba([r, g, b, a]) = *pixel;
+        let mix = |under: u8, over: u8| {
+            ((under as u32 * (255 - alpha) + over as u32 * alpha) / 255) as u8
+        };
+        *pixel = Rgba([
+            mix(r, color[0]),
+            mix(g, color[1]),
+            mix(b, color[2]),
+            a.max(alpha as u8),
+        ]);
+    }
+}
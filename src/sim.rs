@@ -0,0 +1,492 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use elgato_streamdeck::images::ImageRect;
+use elgato_streamdeck::info::{ImageMode, Kind};
+use elgato_streamdeck::{DeviceStateUpdate, StreamDeckError};
+use hidapi::HidError;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::Config;
+use crate::control::Hub;
+use crate::deck::{Deck, DeckReader};
+use crate::device::{self, DeckState, Stop};
+use crate::error::Error;
+
+// Serial the simulated deck reports, `devices` entries can match on it
+const SERIAL: &str = "SIMULATED";
+
+// Batches of injected input, `None` once the deck is unplugged
+type Input = Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<Option<Vec<DeviceStateUpdate>>>>>;
+
+// Something the simulated deck was asked to show, in the order it would have
+// reached the hardware
+#[derive(Clone, Debug)]
+pub enum Frame {
+    Brightness(u8),
+    Key {
+        key: u8,
+        data: Vec<u8>,
+    },
+    Touchpoint {
+        point: u8,
+        color: [u8; 3],
+    },
+    Lcd {
+        x: u16,
+        y: u16,
+        w: u16,
+        h: u16,
+        data: Vec<u8>,
+    },
+    LcdFill {
+        data: Vec<u8>,
+    },
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Frame::Brightness(percent) => write!(f, "brightness {}%", percent),
+            Frame::Key { key, data } => write!(f, "key {} ({} bytes)", key, data.len()),
+            Frame::Touchpoint { point, color } => {
+                write!(f, "touch point {} color {:?}", point, color)
+            }
+            Frame::Lcd { x, y, w, h, data } => {
+                write!(f, "lcd {}x{} at {},{} ({} bytes)", w, h, x, y, data.len())
+            }
+            Frame::LcdFill { data } => write!(f, "lcd fill ({} bytes)", data.len()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Recorded {
+    frames: Vec<Frame>,
+    // Key images waiting for a flush, like the real deck's image cache
+    pending: Vec<(u8, Vec<u8>)>,
+}
+
+// In-memory deck of any kind. Records every frame instead of sending it over
+// USB and reads input from whatever is pushed through its `Injector`.
+#[derive(Clone)]
+pub struct SimDeck {
+    kind: Kind,
+    recorded: Arc<Mutex<Recorded>>,
+    input: Input,
+    // Directory every frame is also written to, to look at them without hardware
+    dump: Option<PathBuf>,
+}
+
+// Feeds input into a simulated deck
+#[derive(Clone)]
+pub struct Injector(mpsc::UnboundedSender<Option<Vec<DeviceStateUpdate>>>);
+
+impl Injector {
+    pub fn inject(&self, updates: Vec<DeviceStateUpdate>) {
+        let _ = self.0.send(Some(updates));
+    }
+
+    // Makes the next read fail the way it does when a deck is pulled out
+    pub fn unplug(&self) {
+        let _ = self.0.send(None);
+    }
+}
+
+impl SimDeck {
+    pub fn new(kind: Kind, dump: Option<PathBuf>) -> (SimDeck, Injector) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let deck = SimDeck {
+            kind,
+            recorded: Arc::default(),
+            input: Arc::new(tokio::sync::Mutex::new(rx)),
+            dump,
+        };
+        (deck, Injector(tx))
+    }
+
+    // Every frame shown so far, oldest first
+    pub fn frames(&self) -> Vec<Frame> {
+        self.recorded.lock().unwrap().frames.clone()
+    }
+
+    fn record(&self, frame: Frame) {
+        println!("sim: {}", frame);
+        if let Some(dir) = &self.dump {
+            self.dump_frame(dir, &frame);
+        }
+        self.recorded.lock().unwrap().frames.push(frame);
+    }
+
+    fn dump_frame(&self, dir: &Path, frame: &Frame) {
+        let extension = |mode| match mode {
+            ImageMode::BMP => "bmp",
+            _ => "jpg",
+        };
+        let (name, data) = match frame {
+            Frame::Key { key, data } => (
+                format!(
+                    "key-{}.{}",
+                    key,
+                    extension(self.kind.key_image_format().mode)
+                ),
+                data,
+            ),
+            Frame::Lcd { x, y, data, .. } => (format!("lcd-{}-{}.jpg", x, y), data),
+            Frame::LcdFill { data } => ("lcd.jpg".to_string(), data),
+            _ => return,
+        };
+        if let Err(e) = std::fs::write(dir.join(&name), data) {
+            eprintln!("sim: failed to write {}: {}", name, e);
+        }
+    }
+}
+
+impl Deck for SimDeck {
+    type Reader = SimReader;
+
+    fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    async fn set_brightness(&self, percent: u8) -> Result<(), StreamDeckError> {
+        self.record(Frame::Brightness(percent.min(100)));
+        Ok(())
+    }
+
+    async fn write_image(&self, key: u8, data: &[u8]) -> Result<(), StreamDeckError> {
+        if key >= self.kind.key_count() {
+            return Err(StreamDeckError::InvalidKeyIndex);
+        }
+        let mut recorded = self.recorded.lock().unwrap();
+        recorded.pending.push((key, data.to_vec()));
+        Ok(())
+    }
+
+    async fn clear_button_image(&self, key: u8) -> Result<(), StreamDeckError> {
+        if key >= self.kind.key_count() {
+            return Err(StreamDeckError::InvalidKeyIndex);
+        }
+        let data = self.kind.blank_image();
+        self.record(Frame::Key { key, data });
+        Ok(())
+    }
+
+    async fn clear_all_button_images(&self) -> Result<(), StreamDeckError> {
+        for key in 0..self.kind.key_count() {
+            self.clear_button_image(key).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_touchpoint_color(
+        &self,
+        point: u8,
+        red: u8,
+        green: u8,
+        blue: u8,
+    ) -> Result<(), StreamDeckError> {
+        if point >= self.kind.touchpoint_count() {
+            return Err(StreamDeckError::InvalidTouchPointIndex);
+        }
+        let color = [red, green, blue];
+        self.record(Frame::Touchpoint { point, color });
+        Ok(())
+    }
+
+    async fn write_lcd(&self, x: u16, y: u16, rect: &ImageRect) -> Result<(), StreamDeckError> {
+        // Same restriction as the hardware, only the Plus takes partial writes
+        if self.kind != Kind::Plus {
+            return Err(StreamDeckError::UnsupportedOperation);
+        }
+        self.record(Frame::Lcd {
+            x,
+            y,
+            w: rect.w,
+            h: rect.h,
+            data: rect.data.clone(),
+        });
+        Ok(())
+    }
+
+    async fn write_lcd_fill(&self, data: &[u8]) -> Result<(), StreamDeckError> {
+        if !matches!(self.kind, Kind::Plus | Kind::Neo) {
+            return Err(StreamDeckError::UnsupportedOperation);
+        }
+        self.record(Frame::LcdFill {
+            data: data.to_vec(),
+        });
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), StreamDeckError> {
+        let pending = std::mem::take(&mut self.recorded.lock().unwrap().pending);
+        for (key, data) in pending {
+            self.record(Frame::Key { key, data });
+        }
+        Ok(())
+    }
+
+    fn get_reader(&self) -> SimReader {
+        SimReader(self.input.clone())
+    }
+}
+
+pub struct SimReader(Input);
+
+impl DeckReader for SimReader {
+    // Waits for injected input rather than polling
+    async fn read(&self, _poll_rate: f32) -> Result<Vec<DeviceStateUpdate>, StreamDeckError> {
+        let mut input = self.0.lock().await;
+        match input.recv().await {
+            Some(Some(updates)) => Ok(updates),
+            // Unplugged, or every injector is gone. Reads keep failing like
+            // they do on a deck that was pulled out, so retries give up too.
+            _ => {
+                input.close();
+                while input.try_recv().is_ok() {}
+                Err(StreamDeckError::HidError(HidError::HidApiError {
+                    message: "simulated deck unplugged".to_string(),
+                }))
+            }
+        }
+    }
+}
+
+// Parses a line of simulator input like "press 3" or "twist 0 -2"
+pub fn parse_input(line: &str) -> Result<Vec<DeviceStateUpdate>, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some(command) = words.first() else {
+        return Ok(vec![]);
+    };
+    // Arguments after the command, each named for errors and fitting its type
+    let args = |names: &[&str]| -> Result<Vec<i64>, String> {
+        if words.len() != names.len() + 1 {
+            return Err(format!("'{}' takes {} numbers", command, names.len()));
+        }
+        words[1..]
+            .iter()
+            .map(|w| w.parse().map_err(|_| format!("'{}' isn't a number", w)))
+            .collect()
+    };
+    fn fit<T: TryFrom<i64>>(value: i64, name: &str) -> Result<T, String> {
+        T::try_from(value).map_err(|_| format!("{} {} is out of range", name, value))
+    }
+    let index = |name: &str| -> Result<u8, String> { fit(args(&[name])?[0], name) };
+    let point = |n: &[i64], names: [&str; 2]| -> Result<(u16, u16), String> {
+        Ok((fit(n[0], names[0])?, fit(n[1], names[1])?))
+    };
+
+    use DeviceStateUpdate::*;
+    let updates = match *command {
+        "down" => vec![ButtonDown(index("key")?)],
+        "up" => vec![ButtonUp(index("key")?)],
+        "press" => {
+            let key = index("key")?;
+            vec![ButtonDown(key), ButtonUp(key)]
+        }
+        "dial-down" => vec![EncoderDown(index("dial")?)],
+        "dial-up" => vec![EncoderUp(index("dial")?)],
+        "twist" => {
+            let n = args(&["dial", "ticks"])?;
+            vec![EncoderTwist(fit(n[0], "dial")?, fit(n[1], "ticks")?)]
+        }
+        "touch-down" => vec![TouchPointDown(index("touch point")?)],
+        "touch-up" => vec![TouchPointUp(index("touch point")?)],
+        "tap" => {
+            let n = args(&["x", "y"])?;
+            let (x, y) = point(&n, ["x", "y"])?;
+            vec![TouchScreenPress(x, y)]
+        }
+        "hold" => {
+            let n = args(&["x", "y"])?;
+            let (x, y) = point(&n, ["x", "y"])?;
+            vec![TouchScreenLongPress(x, y)]
+        }
+        "swipe" => {
+            let n = args(&["from x", "from y", "to x", "to y"])?;
+            vec![TouchScreenSwipe(
+                point(&n[..2], ["from x", "from y"])?,
+                point(&n[2..], ["to x", "to y"])?,
+            )]
+        }
+        other => return Err(format!("unknown input '{}'", other)),
+    };
+    Ok(updates)
+}
+
+// Runs the config against a simulated deck, taking input lines like
+// "press 3" from stdin until it closes or SIGINT or SIGTERM arrives
+pub async fn simulate(
+    config: Arc<Config>,
+    kind: Kind,
+    dump: Option<PathBuf>,
+    hub: Hub,
+) -> Result<(), Error> {
+    let profile = config.profile_for(kind, SERIAL);
+    profile.validate(kind)?;
+    if let Some(dir) = &dump {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let (deck, injector) = SimDeck::new(kind, dump);
+    println!("Simulating {:?} {}", kind, SERIAL);
+
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            // Lets scripted input give long presses and timers room to fire
+            if let Some(ms) = line.trim().strip_prefix("wait ") {
+                match ms.trim().parse() {
+                    Ok(ms) => tokio::time::sleep(Duration::from_millis(ms)).await,
+                    Err(_) => eprintln!("sim: 'wait' takes milliseconds"),
+                }
+                continue;
+            }
+            match parse_input(&line) {
+                Ok(updates) => injector.inject(updates),
+                Err(e) => eprintln!("sim: {}", e),
+            }
+        }
+        injector.unplug();
+    });
+
+    // Same signals as the daemon, so the control socket gets cleaned up either way
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let (stop, stopped) = oneshot::channel();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+        let _ = stop.send(Stop::Shutdown);
+    });
+
+    let state = DeckState::new(&profile, kind);
+    let remote = hub.attach(SERIAL, kind);
+    // Why the deck stopped is logged already, there's no reconnecting here
+    let _ = device::run(
+        deck.clone(),
+        SERIAL.to_string(),
+        profile,
+        state,
+        remote,
+        stopped,
+    )
+    .await;
+    hub.detach(SERIAL);
+    println!("sim: {} frames recorded", deck.frames().len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use DeviceStateUpdate::*;
+
+    #[test]
+    fn parses_input() {
+        let parsed = |line| parse_input(line).unwrap();
+        assert!(parsed("").is_empty());
+        assert!(matches!(
+            parsed("press 3")[..],
+            [ButtonDown(3), ButtonUp(3)]
+        ));
+        assert!(matches!(parsed("twist 1 -2")[..], [EncoderTwist(1, -2)]));
+        assert!(matches!(
+            parsed(" swipe 10 20  700 30 ")[..],
+            [TouchScreenSwipe((10, 20), (700, 30))]
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let error = |line| parse_input(line).unwrap_err();
+        assert_eq!(error("jump 1"), "unknown input 'jump'");
+        assert_eq!(error("press"), "'press' takes 1 numbers");
+        assert_eq!(error("tap 1 2 3"), "'tap' takes 2 numbers");
+        assert_eq!(error("down one"), "'one' isn't a number");
+        assert_eq!(error("down 256"), "key 256 is out of range");
+        assert_eq!(error("touch-up -1"), "touch point -1 is out of range");
+        assert_eq!(error("twist 0 128"), "ticks 128 is out of range");
+        assert_eq!(error("swipe 0 0 0 65536"), "to y 65536 is out of range");
+    }
+
+    #[tokio::test]
+    async fn records_keys_on_flush() {
+        let (deck, _injector) = SimDeck::new(Kind::Mk2, None);
+        deck.write_image(2, &[1, 2, 3]).await.unwrap();
+        assert!(deck.frames().is_empty());
+
+        deck.flush().await.unwrap();
+        assert!(matches!(
+            deck.frames().as_slice(),
+            [Frame::Key { key: 2, data }] if data == &[1, 2, 3]
+        ));
+        assert!(matches!(
+            deck.write_image(15, &[]).await,
+            Err(StreamDeckError::InvalidKeyIndex)
+        ));
+        // An Mk2 has no screen to fill, so it refuses like the hardware does
+        assert!(matches!(
+            deck.write_lcd_fill(&[]).await,
+            Err(StreamDeckError::UnsupportedOperation)
+        ));
+    }
+
+    #[tokio::test]
+    async fn stays_unplugged() {
+        let (deck, injector) = SimDeck::new(Kind::Plus, None);
+        let reader = deck.get_reader();
+        injector.inject(vec![EncoderDown(0)]);
+        injector.unplug();
+        injector.inject(vec![EncoderUp(0)]);
+
+        let read = reader.read(100.0).await.unwrap();
+        assert!(matches!(read[..], [EncoderDown(0)]));
+        assert!(reader.read(100.0).await.is_err());
+        assert!(reader.read(100.0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn runs_config_until_unplugged() {
+        let source = "brightness = 30\n[[keys]]\nindex = 0\ntitle = \"Hi\"\n";
+        let config = Arc::new(Config::parse(Path::new("test.toml"), source.to_string()).unwrap());
+        let (deck, injector) = SimDeck::new(Kind::Mini, None);
+        let hub = Hub::new();
+        let remote = hub.attach(SERIAL, Kind::Mini);
+        let (_stop, stopped) = oneshot::channel();
+        let state = DeckState::new(&config, Kind::Mini);
+        let running = tokio::spawn(device::run(
+            deck.clone(),
+            SERIAL.to_string(),
+            config,
+            state,
+            remote,
+            stopped,
+        ));
+
+        injector.inject(vec![ButtonDown(0), ButtonUp(0)]);
+        injector.unplug();
+        let (_, result) = running.await.unwrap();
+        assert!(matches!(result, Err(Error::DeviceGone { .. })));
+
+        let frames = deck.frames();
+        assert!(matches!(frames.first(), Some(Frame::Brightness(30))));
+        // Every key was cleared, and the titled one painted after
+        let keys: Vec<u8> = frames
+            .iter()
+            .filter_map(|frame| match frame {
+                Frame::Key { key, .. } => Some(*key),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(&keys[..6], &[0, 1, 2, 3, 4, 5]);
+        assert!(keys[6..].contains(&0));
+    }
+}
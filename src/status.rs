@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use regex::Regex;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::config::StatusConfig;
+use crate::query;
+
+// Title shown while a status key has no value
+const NO_VALUE: &str = "?";
+
+// A key that shows what a command printed, polled on its own task so a slow
+// command never holds up the deck
+#[derive(Debug)]
+pub struct Status {
+    command: String,
+    interval: Duration,
+    timeout: Duration,
+    pick: Pick,
+    title: Option<String>,
+    colors: HashMap<i32, [u8; 3]>,
+    error_color: Option<[u8; 3]>,
+    // The key's own background, for exit codes without a color
+    background: Option<[u8; 3]>,
+}
+
+#[derive(Debug)]
+enum Pick {
+    All,
+    Regex(Regex),
+    Json(Vec<String>),
+}
+
+// What a poll turned into
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reading {
+    pub title: String,
+    pub background: Option<[u8; 3]>,
+}
+
+impl Status {
+    // The config has been validated, a regex that doesn't compile can't get here
+    pub fn new(config: &StatusConfig, background: Option<[u8; 3]>) -> Status {
+        let pick = match (&config.regex, &config.json) {
+            (Some(regex), _) => Pick::Regex(Regex::new(regex.get_ref()).expect("validated regex")),
+            (None, Some(path)) => Pick::Json(
+                path.get_ref()
+                    .split('.')
+                    .filter(|part| !part.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            ),
+            (None, None) => Pick::All,
+        };
+        Status {
+            command: config.command.clone(),
+            interval: config.interval(),
+            timeout: config.timeout(),
+            pick,
+            title: config.title.clone(),
+            colors: config
+                .colors
+                .iter()
+                .filter_map(|(code, color)| Some((code.parse().ok()?, *color)))
+                .collect(),
+            error_color: config.colors.get("error").copied(),
+            background,
+        }
+    }
+
+    // What the key shows before the first poll is back
+    pub fn waiting(&self) -> Reading {
+        Reading {
+            title: self.title(NO_VALUE),
+            background: self.background,
+        }
+    }
+
+    // Runs the command once, errors still give a reading to show
+    pub async fn poll(&self) -> (Reading, Option<String>) {
+        let output = match query::run(&self.command, self.timeout).await {
+            Ok(output) => output,
+            Err(e) => return (self.failed(), Some(e)),
+        };
+        let value = match self.pick(&output.stdout) {
+            Ok(value) => value,
+            Err(e) => return (self.failed(), Some(e)),
+        };
+
+        let color = output.code.and_then(|code| self.colors.get(&code));
+        let reading = Reading {
+            title: self.title(&value),
+            background: color.copied().or(self.background),
+        };
+        (reading, None)
+    }
+
+    fn failed(&self) -> Reading {
+        Reading {
+            title: self.title(NO_VALUE),
+            background: self.error_color.or(self.background),
+        }
+    }
+
+    fn title(&self, value: &str) -> String {
+        match &self.title {
+            Some(title) => title.replace("{value}", value),
+            None => value.to_string(),
+        }
+    }
+
+    fn pick(&self, stdout: &str) -> Result<String, String> {
+        let stdout = stdout.trim();
+        match &self.pick {
+            Pick::All => Ok(stdout.to_string()),
+            Pick::Regex(regex) => {
+                let captures = regex
+                    .captures(stdout)
+                    .ok_or(format!("'{}' doesn't match the output", regex))?;
+                let value = captures
+                    .get(1)
+                    .or(captures.get(0))
+                    .map_or("", |m| m.as_str());
+                Ok(value.to_string())
+            }
+            Pick::Json(path) => {
+                let json: Value = serde_json::from_str(stdout)
+                    .map_err(|e| format!("output isn't JSON: {}", e))?;
+                json_field(&json, path).ok_or(format!("no '{}' in the output", path.join(".")))
+            }
+        }
+    }
+}
+
+// What's at the path in the JSON, strings without their quotes. Parts of the
+// path index into arrays as well as objects.
+pub fn json_field(json: &Value, path: &[String]) -> Option<String> {
+    let mut value = json;
+    for part in path {
+        value = match value {
+            Value::Array(items) => items.get(part.parse::<usize>().ok()?)?,
+            _ => value.get(part)?,
+        };
+    }
+    Some(match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    })
+}
+
+// Polls the status until the receiving end goes away, sending each reading
+// tagged with the page and key it's for. Failures are logged when they
+// change, not on every poll.
+pub fn spawn(
+    status: Arc<Status>,
+    page: String,
+    key: u8,
+    readings: mpsc::Sender<(String, u8, Reading)>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut last_error = None;
+        loop {
+            let (reading, error) = status.poll().await;
+            if error != last_error {
+                if let Some(e) = &error {
+                    eprintln!("Status of key {}: {}", key, e);
+                }
+                last_error = error;
+            }
+            if readings.send((page.clone(), key, reading)).await.is_err() {
+                return;
+            }
+            tokio::time::sleep(status.interval).await;
+        }
+    })
+}
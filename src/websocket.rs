@@ -0,0 +1,382 @@
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{Instant, sleep, timeout_at};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+
+// Wait between attempts to reach a service while it isn't running
+pub const RETRY: Duration = Duration::from_secs(5);
+// Services answer right away, one that doesn't in this long is stuck
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum SessionError {
+    WebSocket(tungstenite::Error),
+    // The service closed the connection, saying why when it does
+    Closed(Option<String>),
+    // The service didn't answer in time
+    Timeout,
+    // The service sent something that doesn't follow its protocol
+    Protocol(String),
+    // The password or access token is missing or wasn't accepted, trying
+    // again won't change that
+    Auth(String),
+    // The service refused a request, the connection is fine
+    Refused(String),
+}
+
+impl SessionError {
+    // Whether the connection can't be used anymore
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, SessionError::Refused(_))
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::WebSocket(e) => write!(f, "{}", e),
+            SessionError::Closed(Some(reason)) => write!(f, "closed: {}", reason),
+            SessionError::Closed(None) => write!(f, "closed"),
+            SessionError::Timeout => write!(f, "no answer"),
+            SessionError::Protocol(message)
+            | SessionError::Auth(message)
+            | SessionError::Refused(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<tungstenite::Error> for SessionError {
+    fn from(e: tungstenite::Error) -> SessionError {
+        SessionError::WebSocket(e)
+    }
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// A WebSocket carrying JSON messages
+pub struct Link {
+    socket: Socket,
+}
+
+impl Link {
+    pub async fn open(url: &str, deadline: Instant) -> Result<Link, SessionError> {
+        let (socket, _) = timeout_at(deadline, connect_async(url))
+            .await
+            .map_err(|_| SessionError::Timeout)??;
+        Ok(Link { socket })
+    }
+
+    pub async fn send(&mut self, message: &Value) -> Result<(), SessionError> {
+        let text = message.to_string();
+        self.socket.send(Message::Text(text.into())).await?;
+        Ok(())
+    }
+
+    // The next message whenever it comes, skipping pings and the like
+    pub async fn next(&mut self) -> Result<Value, SessionError> {
+        loop {
+            if let Some(message) = read(self.socket.next().await)? {
+                return Ok(message);
+            }
+        }
+    }
+
+    // The next message, as long as it comes before the deadline
+    pub async fn next_before(&mut self, deadline: Instant) -> Result<Value, SessionError> {
+        timeout_at(deadline, self.next())
+            .await
+            .map_err(|_| SessionError::Timeout)?
+    }
+}
+
+// A message of the service, `None` for pings and the like
+fn read(
+    message: Option<Result<Message, tungstenite::Error>>,
+) -> Result<Option<Value>, SessionError> {
+    let text = match message.ok_or(SessionError::Closed(None))?? {
+        Message::Text(text) => text,
+        Message::Close(frame) => {
+            let reason = frame
+                .map(|f| f.reason.to_string())
+                .filter(|r| !r.is_empty());
+            return Err(SessionError::Closed(reason));
+        }
+        _ => return Ok(None),
+    };
+    serde_json::from_str(&text)
+        .map(Some)
+        .map_err(|e| SessionError::Protocol(format!("bad message: {}", e)))
+}
+
+// A service the program keeps a session with, along with whatever carries
+// over from one session to the next
+pub trait Service: Send + 'static {
+    type Session: Send;
+    type Request: fmt::Debug + Send + 'static;
+    // What decks see of the service, the default while it's away
+    type State: Default + Send + Sync + 'static;
+
+    // What the service is called in logs
+    const NAME: &'static str;
+
+    // Opens a session with the service at `url`, ready to run
+    fn connect(
+        &mut self,
+        url: &str,
+    ) -> impl Future<Output = Result<Self::Session, SessionError>> + Send;
+
+    // Does what's asked and follows the service until the connection drops,
+    // or the program no longer has requests for it
+    fn run(
+        &mut self,
+        session: &mut Self::Session,
+        requests: &mut mpsc::UnboundedReceiver<Self::Request>,
+        state: &watch::Sender<Self::State>,
+    ) -> impl Future<Output = Result<(), SessionError>> + Send;
+
+    // Takes a request that came while the service is away, handing back the
+    // ones that are dropped
+    fn offline(&mut self, request: Self::Request) -> Option<Self::Request> {
+        Some(request)
+    }
+}
+
+// Stays connected to the service until the program exits, trying again every
+// few seconds while it isn't running
+pub async fn serve<S: Service>(
+    mut service: S,
+    url: String,
+    mut requests: mpsc::UnboundedReceiver<S::Request>,
+    state: watch::Sender<S::State>,
+) {
+    let mut last_error = None;
+    loop {
+        match service.connect(&url).await {
+            Ok(mut session) => {
+                println!("Connected to {} at {}", S::NAME, url);
+                let result = service.run(&mut session, &mut requests, &state).await;
+                state.send_replace(S::State::default());
+                match result {
+                    Ok(()) => return,
+                    Err(e) => eprintln!("Lost {} at {}: {}", S::NAME, url, e),
+                }
+                last_error = None;
+            }
+            // Trying again would be turned away the same way, and services
+            // like Home Assistant ban addresses that keep at it
+            Err(e @ SessionError::Auth(_)) => {
+                eprintln!("Gave up on {} at {}: {}", S::NAME, url, e);
+                while let Some(request) = requests.recv().await {
+                    drop_request(&mut service, request);
+                }
+                return;
+            }
+            Err(e) => {
+                let e = e.to_string();
+                if last_error.as_ref() != Some(&e) {
+                    eprintln!("Can't reach {} at {}: {}", S::NAME, url, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        // Requests while the service is away are dropped, not saved up for
+        // later
+        let retry = sleep(RETRY);
+        tokio::pin!(retry);
+        loop {
+            tokio::select! {
+                _ = &mut retry => break,
+                request = requests.recv() => match request {
+                    Some(request) => drop_request(&mut service, request),
+                    None => return,
+                },
+            }
+        }
+    }
+}
+
+fn drop_request<S: Service>(service: &mut S, request: S::Request) {
+    if let Some(request) = service.offline(request) {
+        eprintln!("{} isn't connected, dropped {:?}", S::NAME, request);
+    }
+}
+
+// A stand-in for a service on a local port, for the tests of the modules
+// talking to one
+#[cfg(test)]
+pub mod fake {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+    use tokio_tungstenite::accept_async;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+    // How long a test waits for the program to do something
+    const PATIENCE: Duration = Duration::from_secs(2);
+
+    // The service's side of the protocol, one per connection
+    pub trait Fake: Send + 'static {
+        // Shakes hands with the program, `false` when it's turned away
+        fn greet(&mut self, peer: &mut Peer) -> impl Future<Output = bool> + Send;
+
+        // Answers a message of the program
+        fn answer(&mut self, peer: &mut Peer, message: Value) -> impl Future<Output = ()> + Send;
+
+        // Sends what the test pushed, like an event
+        fn push(&mut self, peer: &mut Peer, pushed: Value) -> impl Future<Output = ()> + Send;
+    }
+
+    // The fake's end of a connection
+    pub struct Peer {
+        link: Link,
+        seen: mpsc::UnboundedSender<Value>,
+    }
+
+    impl Peer {
+        pub async fn send(&mut self, message: Value) {
+            let _ = self.link.send(&message).await;
+        }
+
+        // The next message of the program, `None` once it's gone
+        pub async fn receive(&mut self) -> Option<Value> {
+            self.link.next().await.ok()
+        }
+
+        // Tells the test about something the program did
+        pub fn saw(&self, what: Value) {
+            let _ = self.seen.send(what);
+        }
+
+        pub async fn close(&mut self, code: u16, reason: &str) {
+            let frame = CloseFrame {
+                code: code.into(),
+                reason: reason.into(),
+            };
+            let _ = self.link.socket.send(Message::Close(Some(frame))).await;
+        }
+    }
+
+    pub struct Server {
+        listener: TcpListener,
+        pub url: String,
+    }
+
+    // One connection to the fake, served on its own task until dropped
+    pub struct Connection {
+        seen: mpsc::UnboundedReceiver<Value>,
+        pushes: mpsc::UnboundedSender<Value>,
+        task: JoinHandle<()>,
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            self.task.abort();
+        }
+    }
+
+    impl Connection {
+        // The next thing the fake saw the program do
+        pub async fn seen(&mut self) -> Value {
+            tokio::time::timeout(PATIENCE, self.seen.recv())
+                .await
+                .expect("the fake saw nothing")
+                .expect("connection closed")
+        }
+
+        // Waits for the connection to end without the program doing anything
+        pub async fn closed(&mut self) {
+            let seen = tokio::time::timeout(PATIENCE, self.seen.recv())
+                .await
+                .expect("connection still open");
+            assert_eq!(seen, None);
+        }
+
+        pub fn push(&self, pushed: Value) {
+            self.pushes.send(pushed).unwrap();
+        }
+    }
+
+    impl Server {
+        // Listens on `path` of a free local port
+        pub async fn start(path: &str) -> Server {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}{}", listener.local_addr().unwrap(), path);
+            Server { listener, url }
+        }
+
+        // Takes the next connection of the program, which may be waiting to
+        // try again
+        pub async fn accept(&self, fake: impl Fake) -> Connection {
+            let (stream, _) = tokio::time::timeout(RETRY + PATIENCE, self.listener.accept())
+                .await
+                .expect("nobody connected")
+                .unwrap();
+            let socket = accept_async(MaybeTlsStream::Plain(stream)).await.unwrap();
+            let (seen, seen_rx) = mpsc::unbounded_channel();
+            let (pushes, pushes_rx) = mpsc::unbounded_channel();
+            let peer = Peer {
+                link: Link { socket },
+                seen,
+            };
+            Connection {
+                seen: seen_rx,
+                pushes,
+                task: tokio::spawn(serve_fake(fake, peer, pushes_rx)),
+            }
+        }
+
+        // Checks that the program doesn't connect, even once it could have
+        // tried again
+        pub async fn unvisited(&self) {
+            let accepted = tokio::time::timeout(RETRY + PATIENCE, self.listener.accept()).await;
+            assert!(accepted.is_err(), "the program connected");
+        }
+    }
+
+    async fn serve_fake(
+        mut fake: impl Fake,
+        mut peer: Peer,
+        mut pushes: mpsc::UnboundedReceiver<Value>,
+    ) {
+        if !fake.greet(&mut peer).await {
+            return;
+        }
+        loop {
+            tokio::select! {
+                message = peer.receive() => {
+                    let Some(message) = message else { return };
+                    fake.answer(&mut peer, message).await;
+                }
+                Some(pushed) = pushes.recv() => fake.push(&mut peer, pushed).await,
+            }
+        }
+    }
+
+    // The program's side of `service`, talking to the fake at `url`
+    pub fn connect<S: Service>(
+        service: S,
+        url: &str,
+    ) -> (mpsc::UnboundedSender<S::Request>, watch::Receiver<S::State>) {
+        let (requests, rx) = mpsc::unbounded_channel();
+        let (tx, state) = watch::channel(S::State::default());
+        tokio::spawn(serve(service, url.to_string(), rx, tx));
+        (requests, state)
+    }
+
+    // Waits for the state to be what the test expects
+    pub async fn until<T>(state: &mut watch::Receiver<T>, wanted: impl Fn(&T) -> bool) {
+        let waiting = state.wait_for(|state| wanted(state));
+        tokio::time::timeout(PATIENCE, waiting)
+            .await
+            .expect("the state never got there")
+            .unwrap();
+    }
+}